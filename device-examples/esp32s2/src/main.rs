@@ -22,17 +22,12 @@ static EP_OUT_BUFFER: ConstStaticCell<[u8; 128]> = ConstStaticCell::new([0u8; 12
 async fn defmtusb_wrapper(usb0: USB0<'static>, dp: GPIO20<'static>, dm: GPIO19<'static>) {
     let usb_peri = Usb::new(usb0, dp, dm);
     let usb_driver = Driver::new(usb_peri, EP_OUT_BUFFER.take(), Default::default());
-    let config = {
-        let mut c = embassy_usb::Config::new(0x1234, 0x5678);
-        c.serial_number = Some("defmt");
-        c.max_packet_size_0 = 64;
-        c.composite_with_iads = true;
-        c.device_class = 0xEF;
-        c.device_sub_class = 0x02;
-        c.device_protocol = 0x01;
-        c
-    };
-    defmt_embassy_usbserial::run(usb_driver, config).await;
+    let mut config = defmt_embassy_usbserial::default_config(0x1234, 0x5678);
+    config.serial_number = Some("defmt");
+    let logger_config = defmt_embassy_usbserial::LoggerConfig::new();
+    defmt_embassy_usbserial::run(usb_driver, config, logger_config)
+        .await
+        .unwrap();
 }
 
 #[esp_rtos::main]
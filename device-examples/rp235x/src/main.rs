@@ -16,18 +16,13 @@ use rtt_target::{rprintln, rtt_init_print};
 #[task]
 async fn defmtusb_wrapper(usb: Peri<'static, USB>) {
     let driver = embassy_rp::usb::Driver::new(usb, Irqs);
-    let config = {
-        let mut c = embassy_usb::Config::new(0x1234, 0x5678);
-        c.serial_number = Some("defmt");
-        c.max_packet_size_0 = 64;
-        c.composite_with_iads = true;
-        c.device_class = 0xEF;
-        c.device_sub_class = 0x02;
-        c.device_protocol = 0x01;
-        c
-    };
+    let mut config = defmt_embassy_usbserial::default_config(0x1234, 0x5678);
+    config.serial_number = Some("defmt");
     rprintln!("defmtusb::run");
-    defmt_embassy_usbserial::run(driver, config).await;
+    let logger_config = defmt_embassy_usbserial::LoggerConfig::new();
+    defmt_embassy_usbserial::run(driver, config, logger_config)
+        .await
+        .unwrap();
 }
 
 #[embassy_executor::main]
@@ -0,0 +1,142 @@
+//! Check and strip the CRC framing added by the `crc-framing` feature of
+//! `defmt-embassy-usbserial`.
+//!
+//! Reads the framed stream from standard input, or from the file given as the only argument, and
+//! writes the defmt frames that pass the CRC check to standard output, ready for `defmt-print`.
+//! Corrupted frames are skipped and reported on standard error.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    process::ExitCode,
+};
+
+/// Size of the length before each frame.
+const HEADER_LEN: usize = 2;
+
+/// Size of the CRC after each frame.
+const CRC_LEN: usize = 2;
+
+/// Largest length accepted before a frame is considered corrupted, so that a corrupted length does
+/// not hold up the frames behind it for long.
+const MAX_LEN: usize = 4096;
+
+/// Update `crc` with `bytes`, as CRC-16/CCITT-FALSE.
+fn crc16(mut crc: u16, bytes: &[u8]) -> u16 {
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Outcome of looking for a frame at the start of the input.
+enum Frame {
+    /// A frame that passed the CRC check, with the number of bytes it takes up.
+    Valid(usize),
+    /// Not a valid frame, so the first byte is skipped.
+    Corrupted,
+    /// More input is needed to tell.
+    Incomplete,
+}
+
+/// Look for a frame at the start of `input`.
+fn parse(input: &[u8]) -> Frame {
+    let Some(header) = input.get(..HEADER_LEN) else {
+        return Frame::Incomplete;
+    };
+    let len = usize::from(u16::from_le_bytes([header[0], header[1]]));
+    if len <= CRC_LEN || len > MAX_LEN {
+        return Frame::Corrupted;
+    }
+    let Some(record) = input.get(HEADER_LEN..HEADER_LEN + len) else {
+        return Frame::Incomplete;
+    };
+    let (frame, crc) = record.split_at(len - CRC_LEN);
+    if crc16(0xffff, frame) == u16::from_le_bytes([crc[0], crc[1]]) {
+        Frame::Valid(HEADER_LEN + len)
+    } else {
+        Frame::Corrupted
+    }
+}
+
+/// Write the valid frames at the start of `input` to `output`, and return the number of bytes
+/// consumed.
+///
+/// Frames that are still incomplete are left for later, unless the input has ended.
+fn unframe(
+    input: &[u8],
+    at_end: bool,
+    skipped: &mut usize,
+    output: &mut impl Write,
+) -> io::Result<usize> {
+    let mut start = 0;
+    while start < input.len() {
+        match parse(&input[start..]) {
+            Frame::Valid(len) => {
+                if *skipped > 0 {
+                    eprintln!("defmt-unframe: skipped {skipped} corrupted bytes");
+                    *skipped = 0;
+                }
+                output.write_all(&input[start + HEADER_LEN..start + len - CRC_LEN])?;
+                start += len;
+            }
+            Frame::Incomplete if !at_end => break,
+            Frame::Corrupted | Frame::Incomplete => {
+                *skipped += 1;
+                start += 1;
+            }
+        }
+    }
+    Ok(start)
+}
+
+fn run(mut input: impl Read) -> io::Result<()> {
+    let mut output = io::stdout().lock();
+    let mut pending = Vec::new();
+    let mut chunk = [0; 4096];
+    // Number of bytes skipped since the last valid frame.
+    let mut skipped = 0;
+
+    loop {
+        let read = input.read(&mut chunk)?;
+        pending.extend_from_slice(&chunk[..read]);
+        let consumed = unframe(&pending, read == 0, &mut skipped, &mut output)?;
+        pending.drain(..consumed);
+        output.flush()?;
+        if read == 0 {
+            break;
+        }
+    }
+
+    if skipped > 0 {
+        eprintln!("defmt-unframe: skipped {skipped} corrupted bytes at the end");
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args_os().skip(1);
+    let result = match (args.next(), args.next()) {
+        (None, _) => run(io::stdin().lock()),
+        (Some(path), None) => File::open(path).and_then(run),
+        _ => {
+            eprintln!("usage: defmt-unframe [FILE]");
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("defmt-unframe: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
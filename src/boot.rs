@@ -0,0 +1,84 @@
+//! Resetting into the `embassy-boot` bootloader on a command from the host
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_boot::BlockingFirmwareState;
+use embedded_storage::nor_flash::NorFlash;
+
+/// The boot state of `embassy-boot`, whatever the type of the flash it is stored in.
+trait BootState: Send {
+    /// Mark the boot state so that the bootloader enters DFU mode on the next boot, returning
+    /// whether it was written.
+    fn mark_dfu(&mut self) -> bool;
+}
+
+impl<STATE: NorFlash + Send> BootState for BlockingFirmwareState<'static, STATE> {
+    fn mark_dfu(&mut self) -> bool {
+        BlockingFirmwareState::mark_dfu(self).is_ok()
+    }
+}
+
+/// The boot state and reset function set with [`set_boot_state`].
+struct Boot {
+    /// The boot state.
+    state: &'static mut dyn BootState,
+    /// Function that resets the device.
+    reset: fn() -> !,
+}
+
+/// The boot state set with [`set_boot_state`], unless it is being used.
+static BOOT: Mutex<RefCell<Option<Boot>>> = Mutex::new(RefCell::new(None));
+
+/// Use `state`, the boot state partition of `embassy-boot`, and `reset` to reset into the
+/// bootloader with [`reset_to_bootloader`] and [`bootloader_command`].
+///
+/// `reset` resets the device, for example with `cortex_m::peripheral::SCB::sys_reset`. The
+/// bootloader then finds the DFU request in the boot state, and waits for the new firmware, for
+/// example from `embassy-usb-dfu` or over the serial port of the bootloader itself.
+pub fn set_boot_state<STATE: NorFlash + Send>(
+    state: &'static mut BlockingFirmwareState<'static, STATE>,
+    reset: fn() -> !,
+) {
+    critical_section::with(|cs| *BOOT.borrow_ref_mut(cs) = Some(Boot { state, reset }));
+}
+
+/// Mark the boot state set with [`set_boot_state`] so that the bootloader enters DFU mode, and
+/// reset.
+///
+/// This returns only if no boot state is set, or it could not be written, with a warning in the
+/// log. Call it from the handler of [`set_reset_handler`] for [`ResetRequest::Bootloader`], so
+/// that the vendor request and DFU detach of host tools enter the same bootloader.
+///
+/// [`set_reset_handler`]: crate::set_reset_handler
+/// [`ResetRequest::Bootloader`]: crate::ResetRequest::Bootloader
+pub fn reset_to_bootloader() {
+    // The boot state is written outside of a critical section, as erasing flash takes a while.
+    let Some(boot) = critical_section::with(|cs| BOOT.borrow_ref_mut(cs).take()) else {
+        defmt::warn!("no boot state to reset into the bootloader");
+        return;
+    };
+    if boot.state.mark_dfu() {
+        (boot.reset)();
+    }
+    defmt::warn!("failed to mark the boot state for the bootloader");
+    critical_section::with(|cs| {
+        BOOT.borrow_ref_mut(cs).get_or_insert(boot);
+    });
+}
+
+/// Handler of a text command with which the host resets the device into its bootloader with
+/// [`reset_to_bootloader`], for a [`CommandDispatcher`]. The arguments are ignored.
+///
+/// With it, a single USB port carries the whole development loop: the host reads the logs, sends
+/// commands, and resets the device to flash it again, without touching the board. Register it
+/// under a name of your choice:
+///
+/// ```ignore
+/// Command::Line { name: "bootloader", handler: defmt_embassy_usbserial::bootloader_command }
+/// ```
+///
+/// [`CommandDispatcher`]: crate::CommandDispatcher
+pub fn bootloader_command(_args: &str) {
+    reset_to_bootloader();
+}
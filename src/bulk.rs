@@ -0,0 +1,136 @@
+//! The defmt stream on a vendor-specific bulk interface, for hosts that read it with libusb
+
+use embassy_usb::{
+    Builder,
+    driver::{Driver, Endpoint, EndpointIn},
+    msos::{CompatibleIdFeatureDescriptor, PropertyData, RegistryPropertyFeatureDescriptor},
+};
+
+use crate::{
+    controller::CONTROLLER,
+    msos::DEFAULT_GUIDS,
+    task::{MAX_PACKET_SIZE, stream_packets},
+    vendor::{self, VendorHandler},
+};
+
+/// Class code of vendor-specific functions and interfaces.
+const USB_CLASS_VENDOR: u8 = 0xff;
+
+/// State of a [`DefmtBulkClass`], for the control handler it adds.
+pub struct DefmtBulkState {
+    /// Control handler of the vendor requests.
+    vendor: VendorHandler,
+}
+
+impl DefmtBulkState {
+    /// Create the state.
+    pub const fn new() -> Self {
+        Self {
+            vendor: VendorHandler,
+        }
+    }
+}
+
+impl Default for DefmtBulkState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The defmt stream on a vendor-specific interface with a single bulk IN endpoint, instead of a
+/// CDC ACM serial port.
+///
+/// Without serial port semantics there is no line coding or DTR to manage, no ModemManager opening
+/// the port, and no terminal program swallowing the stream: the host reads the endpoint with
+/// libusb, or WinUSB on Windows, and passes the data to `defmt-print` or another decoder. Every
+/// queued frame is sent as soon as the device is configured, and packets wait in the endpoint
+/// until the host reads them, so frames queue up, or are dropped, while no reader is running.
+///
+/// Add it to a builder of your own, after [`add_msos_descriptors`] so that Windows binds WinUSB to
+/// the interface without an INF file, and run the device and the logger together:
+///
+/// ```ignore
+/// static STATE: StaticCell<DefmtBulkState> = StaticCell::new();
+///
+/// let mut builder = embassy_usb::Builder::new(driver, usb_config, ...);
+/// defmt_embassy_usbserial::add_msos_descriptors(&mut builder);
+/// let defmt = DefmtBulkClass::new(&mut builder, STATE.init(DefmtBulkState::new()), 64);
+/// let mut usb = builder.build();
+///
+/// LoggerConfig::new().apply();
+/// join(usb.run(), defmt.run()).await;
+/// ```
+///
+/// Only the settings that concern the ring buffer and the flushing of frames apply, as the features
+/// built on the serial port, such as chunk headers, handshakes or commands, are not available. The
+/// vendor control requests of [`VENDOR_REQUEST_RESET`] and the like are handled as with
+/// [`DefmtClass`].
+///
+/// [`add_msos_descriptors`]: crate::add_msos_descriptors
+/// [`VENDOR_REQUEST_RESET`]: crate::VENDOR_REQUEST_RESET
+/// [`DefmtClass`]: crate::DefmtClass
+pub struct DefmtBulkClass<'d, D: Driver<'d>> {
+    /// Endpoint the stream is written to.
+    write_ep: D::EndpointIn,
+    /// Maximum packet size of the endpoint.
+    max_packet_size: u16,
+}
+
+impl<'d, D: Driver<'d>> DefmtBulkClass<'d, D> {
+    /// Add the vendor-specific interface to `builder`, with a bulk IN endpoint of
+    /// `max_packet_size` bytes.
+    ///
+    /// The builder must have MS OS 2.0 descriptors, such as those of [`add_msos_descriptors`].
+    ///
+    /// [`add_msos_descriptors`]: crate::add_msos_descriptors
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut DefmtBulkState,
+        max_packet_size: u16,
+    ) -> Self {
+        vendor::add_handler(builder, &mut state.vendor);
+        let mut function = builder.function(USB_CLASS_VENDOR, 0, 0);
+        function.msos_feature(CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+        function.msos_feature(RegistryPropertyFeatureDescriptor::new(
+            "DeviceInterfaceGUIDs",
+            PropertyData::RegMultiSz(&DEFAULT_GUIDS),
+        ));
+        let mut interface = function.interface();
+        let mut alt = interface.alt_setting(USB_CLASS_VENDOR, 0, 0, None);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        Self {
+            write_ep,
+            max_packet_size,
+        }
+    }
+
+    /// Write out the queued defmt frames over the bulk endpoint, forever.
+    ///
+    /// This must run alongside the USB device, built from the same builder.
+    pub async fn run(mut self) {
+        // Buffers holding the USB packet being sent, and the next one with double buffering.
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let mut spare = [0u8; MAX_PACKET_SIZE];
+        let max_packet_size = usize::from(self.max_packet_size).min(packet.len());
+
+        let stream = async {
+            loop {
+                // Wait for the host to configure the device.
+                self.write_ep.wait_enabled().await;
+                CONTROLLER.reconnected();
+                let write_ep = &mut self.write_ep;
+                let spare = &mut spare[..max_packet_size];
+                stream_packets(&mut packet[..max_packet_size], spare, async |data| {
+                    let mut result = write_ep.write(data).await;
+                    if result.is_ok() && data.len() == max_packet_size && CONTROLLER.is_empty() {
+                        // End the transfer so that the host reader returns the data.
+                        result = write_ep.write(&[]).await;
+                    }
+                    result
+                })
+                .await;
+            }
+        };
+        embassy_futures::join::join(stream, vendor::handle_resets()).await;
+    }
+}
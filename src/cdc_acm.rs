@@ -0,0 +1,516 @@
+//! The CDC ACM serial port of the defmt stream, which tells the host when frames are dropped
+
+use core::{
+    cell::{Cell, RefCell},
+    future::poll_fn,
+    sync::atomic::Ordering,
+    task::Poll,
+};
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use embassy_usb::{
+    Builder, Handler,
+    class::cdc_acm,
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut},
+    types::InterfaceNumber,
+};
+use portable_atomic::AtomicBool;
+
+/// Class code of the communications interface, and of CDC devices.
+const USB_CLASS_CDC: u8 = 0x02;
+
+/// Class code of the data interface.
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+
+/// Subclass code of the Abstract Control Model.
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+
+/// Protocol code of an interface without a specific protocol.
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+/// Protocol code of a communications interface with a vendor-specific protocol, which ModemManager
+/// does not take for a modem.
+const CDC_PROTOCOL_VENDOR: u8 = 0xff;
+
+/// Descriptor type of the CDC functional descriptors.
+const CS_INTERFACE: u8 = 0x24;
+
+/// Subtype of the header functional descriptor.
+const CDC_TYPE_HEADER: u8 = 0x00;
+
+/// Subtype of the call management functional descriptor.
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+
+/// Subtype of the abstract control management functional descriptor.
+const CDC_TYPE_ACM: u8 = 0x02;
+
+/// Subtype of the union functional descriptor.
+const CDC_TYPE_UNION: u8 = 0x06;
+
+/// Capabilities of the abstract control management functional descriptor: the line coding and
+/// serial state requests and notifications.
+const ACM_CAPABILITIES: u8 = 0x02;
+
+/// Class request that sends a command of the control protocol, which there is none of.
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+
+/// Class request that sets the line coding.
+const REQ_SET_LINE_CODING: u8 = 0x20;
+
+/// Class request that reads the line coding.
+const REQ_GET_LINE_CODING: u8 = 0x21;
+
+/// Class request that sets DTR and RTS.
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Length of the line coding: the baud rate as a little-endian `u32`, then the stop bits, the
+/// parity and the data bits.
+const LINE_CODING_LEN: usize = 7;
+
+/// Line coding until the host sets one: 8000 baud, 8 data bits, no parity and one stop bit, as
+/// with the CDC ACM class of `embassy-usb`.
+const DEFAULT_LINE_CODING: [u8; LINE_CODING_LEN] = [0x40, 0x1f, 0x00, 0x00, 0, 0, 8];
+
+/// Maximum packet size of the notification endpoint.
+const COMM_PACKET_SIZE: u16 = 16;
+
+/// Polling interval of the notification endpoint, in milliseconds.
+const COMM_INTERVAL_MS: u8 = 255;
+
+/// Notification code of `SERIAL_STATE`.
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+/// Serial state bit of the receive carrier, DCD.
+const SERIAL_STATE_RX_CARRIER: u16 = 1 << 0;
+
+/// Serial state bit of the transmission carrier, DSR.
+const SERIAL_STATE_TX_CARRIER: u16 = 1 << 1;
+
+/// Serial state bit of an overrun, which the host counts as data lost by the device.
+const SERIAL_STATE_OVERRUN: u16 = 1 << 6;
+
+/// Serial line state shared by the control handler and the halves of the port.
+struct Shared {
+    /// Line coding last set by the host.
+    line_coding: Mutex<Cell<[u8; LINE_CODING_LEN]>>,
+    /// Data Terminal Ready, set by the host.
+    dtr: AtomicBool,
+    /// Request To Send, set by the host.
+    rts: AtomicBool,
+    /// Whether the line state changed since [`ControlChanged::control_changed`] last returned.
+    changed: AtomicBool,
+    /// The task waiting in [`ControlChanged::control_changed`].
+    waker: Mutex<RefCell<WakerRegistration>>,
+}
+
+impl Shared {
+    /// The state of a port the host has not set up yet.
+    const fn new() -> Self {
+        Self {
+            line_coding: Mutex::new(Cell::new(DEFAULT_LINE_CODING)),
+            dtr: AtomicBool::new(false),
+            rts: AtomicBool::new(false),
+            changed: AtomicBool::new(false),
+            waker: Mutex::new(RefCell::new(WakerRegistration::new())),
+        }
+    }
+
+    /// The line coding last set by the host.
+    fn line_coding(&self) -> [u8; LINE_CODING_LEN] {
+        critical_section::with(|cs| self.line_coding.borrow(cs).get())
+    }
+
+    /// Wake the task waiting for the line state to change.
+    fn changed(&self) {
+        self.changed.store(true, Ordering::Release);
+        critical_section::with(|cs| self.waker.borrow_ref_mut(cs).wake());
+    }
+}
+
+/// Control handler of the CDC ACM class requests.
+struct Control<'d> {
+    /// Number of the communications interface.
+    comm_if: InterfaceNumber,
+    /// Line state set by the requests.
+    shared: &'d Shared,
+}
+
+impl Control<'_> {
+    /// Whether `req` is a class request addressed to the communications interface.
+    fn is_ours(&self, req: &Request) -> bool {
+        (req.request_type, req.recipient) == (RequestType::Class, Recipient::Interface)
+            && req.index == u16::from(self.comm_if.0)
+    }
+}
+
+impl Handler for Control<'_> {
+    fn reset(&mut self) {
+        critical_section::with(|cs| self.shared.line_coding.borrow(cs).set(DEFAULT_LINE_CODING));
+        self.shared.dtr.store(false, Ordering::Relaxed);
+        self.shared.rts.store(false, Ordering::Relaxed);
+        self.shared.changed();
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => Some(OutResponse::Accepted),
+            REQ_SET_LINE_CODING if data.len() >= LINE_CODING_LEN => {
+                let mut coding = [0; LINE_CODING_LEN];
+                coding.copy_from_slice(&data[..LINE_CODING_LEN]);
+                critical_section::with(|cs| self.shared.line_coding.borrow(cs).set(coding));
+                self.shared.changed();
+                Some(OutResponse::Accepted)
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.shared
+                    .dtr
+                    .store(req.value & 0x01 != 0, Ordering::Relaxed);
+                self.shared
+                    .rts
+                    .store(req.value & 0x02 != 0, Ordering::Relaxed);
+                self.shared.changed();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+        match req.request {
+            REQ_GET_LINE_CODING if buf.len() >= LINE_CODING_LEN => {
+                buf[..LINE_CODING_LEN].copy_from_slice(&self.shared.line_coding());
+                Some(InResponse::Accepted(&buf[..LINE_CODING_LEN]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// State of the defmt serial port: its line state, and its control handler, once added.
+pub(crate) struct CdcAcmState<'d> {
+    /// Line state shared by the control handler and the halves of the port.
+    shared: Shared,
+    /// Control handler of the class requests, once the port is added to a builder.
+    control: Option<Control<'d>>,
+    /// Protocol code of the communications interface.
+    comm_protocol: u8,
+}
+
+impl CdcAcmState<'_> {
+    /// Create the state.
+    pub(crate) const fn new() -> Self {
+        Self {
+            shared: Shared::new(),
+            control: None,
+            comm_protocol: CDC_PROTOCOL_NONE,
+        }
+    }
+
+    /// Give the communications interface a vendor-specific protocol if `vendor` is set, or none.
+    pub(crate) const fn set_vendor_protocol(&mut self, vendor: bool) {
+        self.comm_protocol = if vendor {
+            CDC_PROTOCOL_VENDOR
+        } else {
+            CDC_PROTOCOL_NONE
+        };
+    }
+}
+
+/// Sending half of the defmt serial port, with the notification endpoint.
+pub(crate) struct Sender<'d, D: Driver<'d>> {
+    /// Bulk IN endpoint of the data interface.
+    write_ep: D::EndpointIn,
+    /// Interrupt IN endpoint of the communications interface.
+    comm_ep: D::EndpointIn,
+    /// Number of the communications interface.
+    comm_if: InterfaceNumber,
+    /// Line state set by the host.
+    shared: &'d Shared,
+}
+
+/// Receiving half of the defmt serial port.
+pub(crate) struct Receiver<'d, D: Driver<'d>> {
+    /// Bulk OUT endpoint of the data interface.
+    read_ep: D::EndpointOut,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Read a packet sent by the host into `data`, returning its length.
+    pub(crate) async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.read_ep.read(data).await
+    }
+
+    /// Wait for the USB device to be configured by the host.
+    pub(crate) async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+}
+
+/// Notifications of changes to the line state of the defmt serial port.
+pub(crate) struct ControlChanged<'d> {
+    /// Line state set by the host.
+    shared: &'d Shared,
+}
+
+/// Add the defmt serial port to `builder`, with bulk endpoints of `max_packet_size` bytes, and
+/// return its halves.
+///
+/// Unlike the CDC ACM class of `embassy-usb`, the port keeps the interrupt endpoint of its
+/// communications interface, on which [`Sender::notify_overrun`] sends `SERIAL_STATE`.
+pub(crate) fn add_serial_port<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    state: &'d mut CdcAcmState<'d>,
+    max_packet_size: u16,
+) -> (Sender<'d, D>, Receiver<'d, D>, ControlChanged<'d>) {
+    let CdcAcmState {
+        shared,
+        control,
+        comm_protocol,
+    } = state;
+    let shared = &*shared;
+
+    let (comm_if, comm_ep, read_ep, write_ep) = {
+        let mut function = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, *comm_protocol);
+
+        let mut interface = function.interface();
+        let comm_if = interface.interface_number();
+        let data_if = comm_if.0 + 1;
+        let mut alt = interface.alt_setting(USB_CLASS_CDC, CDC_SUBCLASS_ACM, *comm_protocol, None);
+        // bcdCDC 1.10.
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_ACM, ACM_CAPABILITIES]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if.0, data_if]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, data_if]);
+        let comm_ep = alt.endpoint_interrupt_in(None, COMM_PACKET_SIZE, COMM_INTERVAL_MS);
+
+        let mut interface = function.interface();
+        let mut alt = interface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        (comm_if, comm_ep, read_ep, write_ep)
+    };
+
+    builder.handler(control.insert(Control { comm_if, shared }));
+    (
+        Sender {
+            write_ep,
+            comm_ep,
+            comm_if,
+            shared,
+        },
+        Receiver { read_ep },
+        ControlChanged { shared },
+    )
+}
+
+/// The `SERIAL_STATE` notification of the communications interface `interface`, with the serial
+/// state bits `state`.
+fn serial_state(interface: InterfaceNumber, state: u16) -> [u8; 10] {
+    let [low, high] = state.to_le_bytes();
+    // A class request to the interface, with a two-byte payload.
+    [
+        0xa1,
+        NOTIFICATION_SERIAL_STATE,
+        0,
+        0,
+        interface.0,
+        0,
+        2,
+        0,
+        low,
+        high,
+    ]
+}
+
+/// The sending half of a serial port, and its line state, as the logger task uses them.
+pub(crate) trait SerialSender {
+    /// Maximum packet size of the bulk IN endpoint.
+    fn max_packet_size(&self) -> u16;
+
+    /// Whether the host has set DTR.
+    fn dtr(&self) -> bool;
+
+    /// Whether the host has set RTS.
+    fn rts(&self) -> bool;
+
+    /// Baud rate last set by the host.
+    fn data_rate(&self) -> u32;
+
+    /// Write a single packet.
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError>;
+
+    /// Wait for the USB device to be configured by the host.
+    async fn wait_connection(&mut self);
+
+    /// Tell the host that frames were dropped, if the port can.
+    async fn notify_overrun(&mut self) {}
+}
+
+/// Notifications of changes to the line state of a serial port, as the logger task uses them.
+pub(crate) trait LineStateChanges {
+    /// Wait until the host changes the line state.
+    async fn control_changed(&self);
+}
+
+impl<'d, D: Driver<'d>> SerialSender for Sender<'d, D> {
+    fn max_packet_size(&self) -> u16 {
+        self.write_ep.info().max_packet_size
+    }
+
+    fn dtr(&self) -> bool {
+        self.shared.dtr.load(Ordering::Relaxed)
+    }
+
+    fn rts(&self) -> bool {
+        self.shared.rts.load(Ordering::Relaxed)
+    }
+
+    fn data_rate(&self) -> u32 {
+        let coding = self.shared.line_coding();
+        u32::from_le_bytes([coding[0], coding[1], coding[2], coding[3]])
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.write_ep.write(data).await
+    }
+
+    async fn wait_connection(&mut self) {
+        self.write_ep.wait_enabled().await;
+    }
+
+    async fn notify_overrun(&mut self) {
+        // The port is up as far as the device is concerned, so the carrier bits stay set, and
+        // the host does not hang up when they change.
+        let state = SERIAL_STATE_RX_CARRIER | SERIAL_STATE_TX_CARRIER | SERIAL_STATE_OVERRUN;
+        // A failure means the device was disconnected, which the next data write finds too.
+        let _ = self.comm_ep.write(&serial_state(self.comm_if, state)).await;
+    }
+}
+
+impl LineStateChanges for ControlChanged<'_> {
+    async fn control_changed(&self) {
+        poll_fn(|cx| {
+            if self.shared.changed.swap(false, Ordering::AcqRel) {
+                return Poll::Ready(());
+            }
+            critical_section::with(|cs| self.shared.waker.borrow_ref_mut(cs).register(cx.waker()));
+            // Check again, in case the line state changed before the waker was registered.
+            if self.shared.changed.swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl<'d, D: Driver<'d>> SerialSender for cdc_acm::Sender<'d, D> {
+    fn max_packet_size(&self) -> u16 {
+        cdc_acm::Sender::max_packet_size(self)
+    }
+
+    fn dtr(&self) -> bool {
+        cdc_acm::Sender::dtr(self)
+    }
+
+    fn rts(&self) -> bool {
+        cdc_acm::Sender::rts(self)
+    }
+
+    fn data_rate(&self) -> u32 {
+        self.line_coding().data_rate()
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        cdc_acm::Sender::write_packet(self, data).await
+    }
+
+    async fn wait_connection(&mut self) {
+        cdc_acm::Sender::wait_connection(self).await;
+    }
+}
+
+impl LineStateChanges for cdc_acm::ControlChanged<'_> {
+    async fn control_changed(&self) {
+        cdc_acm::ControlChanged::control_changed(self).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_usb::control::Direction;
+
+    use super::*;
+
+    /// A class request of type `request` to interface 2, with `value`.
+    fn request(direction: Direction, request: u8, value: u16) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index: 2,
+            length: LINE_CODING_LEN as u16,
+        }
+    }
+
+    #[test]
+    fn handles_the_line_state_requests_of_its_interface() {
+        let shared = Shared::new();
+        let mut control = Control {
+            comm_if: InterfaceNumber(2),
+            shared: &shared,
+        };
+        let set_state = request(Direction::Out, REQ_SET_CONTROL_LINE_STATE, 0x03);
+        assert_eq!(
+            control.control_out(set_state, &[]),
+            Some(OutResponse::Accepted)
+        );
+        assert!(shared.dtr.load(Ordering::Relaxed) && shared.rts.load(Ordering::Relaxed));
+        assert!(shared.changed.swap(false, Ordering::AcqRel));
+
+        // 115200 baud, 8N1.
+        let coding = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+        let set_coding = request(Direction::Out, REQ_SET_LINE_CODING, 0);
+        assert_eq!(
+            control.control_out(set_coding, &coding),
+            Some(OutResponse::Accepted)
+        );
+        let mut buf = [0; 64];
+        let get_coding = request(Direction::In, REQ_GET_LINE_CODING, 0);
+        assert_eq!(
+            control.control_in(get_coding, &mut buf),
+            Some(InResponse::Accepted(&coding[..]))
+        );
+
+        // Requests to other interfaces are left to their handlers.
+        let other = Request {
+            index: 0,
+            ..set_state
+        };
+        assert_eq!(control.control_out(other, &[]), None);
+
+        control.reset();
+        assert!(!shared.dtr.load(Ordering::Relaxed));
+        assert_eq!(shared.line_coding(), DEFAULT_LINE_CODING);
+    }
+
+    #[test]
+    fn serial_state_reports_an_overrun() {
+        let state = SERIAL_STATE_RX_CARRIER | SERIAL_STATE_TX_CARRIER | SERIAL_STATE_OVERRUN;
+        assert_eq!(
+            serial_state(InterfaceNumber(2), state),
+            [0xa1, 0x20, 0, 0, 2, 0, 2, 0, 0x43, 0]
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Logical channels that interleave data of the application with the defmt frames
+
+use crate::controller::CONTROLLER;
+
+/// Number of the channel of the defmt frames, when the stream is split with [`set_channels`].
+///
+/// [`set_channels`]: crate::set_channels
+pub const DEFMT_CHANNEL: u8 = 0;
+
+/// A channel on which the application sends binary data of its own over the defmt serial port,
+/// interleaved with the defmt frames, such as sensor samples or a trace.
+///
+/// Channels save the endpoints that another interface would need, on microcontrollers with few of
+/// them. Each write is sent as a frame of its own, as described in [`set_channels`], which must be
+/// enabled, and the host tells the frames apart by the number of their channel. Channel 0 is
+/// [`DEFMT_CHANNEL`], and the application numbers its channels from 1 on.
+///
+/// ```ignore
+/// const SAMPLES: Channel = Channel::new(1);
+///
+/// let config = LoggerConfig::new().channels(true);
+/// // ...
+/// SAMPLES.write(&sample.to_le_bytes());
+/// ```
+///
+/// [`set_channels`]: crate::set_channels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Channel {
+    /// Number of the channel.
+    id: u8,
+}
+
+impl Channel {
+    /// The channel numbered `id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is [`DEFMT_CHANNEL`].
+    pub const fn new(id: u8) -> Self {
+        assert!(id != DEFMT_CHANNEL, "channel 0 carries the defmt frames");
+        Self { id }
+    }
+
+    /// The number of the channel.
+    pub const fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Queue `data` to be sent on the channel as a single frame, returning whether it was queued.
+    ///
+    /// Like a defmt frame, the data is queued as a whole or not at all, and is subject to the
+    /// overflow policy and the drop counters. It is not queued if [`set_channels`] is disabled,
+    /// or if it is written from an interrupt handler that preempted a defmt frame in progress,
+    /// which only happens with the `short-critical-sections` feature.
+    ///
+    /// [`set_channels`]: crate::set_channels
+    pub fn write(&self, data: &[u8]) -> bool {
+        CONTROLLER.write_channel(self.id, data)
+    }
+}
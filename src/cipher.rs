@@ -0,0 +1,77 @@
+//! Encryption of the outgoing log stream
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+
+/// A stream cipher that encrypts everything the logger task sends, so that field units can keep
+/// logging enabled without exposing their internals to anyone who plugs in a USB cable.
+///
+/// This crate does not implement any cipher itself. Wrap one from a crate such as `chacha20`:
+///
+/// ```ignore
+/// use chacha20::{ChaCha20, cipher::{KeyIvInit, StreamCipher}};
+///
+/// struct LogEncryption(Option<ChaCha20>);
+///
+/// impl defmt_embassy_usbserial::LogCipher for LogEncryption {
+///     fn start_session(&mut self, header: &mut [u8]) -> usize {
+///         // The nonce must never repeat for the same key.
+///         let nonce: [u8; 12] = next_nonce();
+///         self.0 = Some(ChaCha20::new(&DEVICE_KEY.into(), &nonce.into()));
+///         header[..12].copy_from_slice(&nonce);
+///         12
+///     }
+///
+///     fn apply_keystream(&mut self, data: &mut [u8]) {
+///         if let Some(cipher) = &mut self.0 {
+///             cipher.apply_keystream(data);
+///         }
+///     }
+/// }
+/// ```
+pub trait LogCipher: Send {
+    /// Start a new session, when the host connects.
+    ///
+    /// The first `n` bytes of `header`, where `n` is the returned value, are sent unencrypted
+    /// before anything else in the session, for example to tell the host the nonce. `header` is
+    /// as long as a USB packet, which is at least 8 bytes.
+    fn start_session(&mut self, header: &mut [u8]) -> usize;
+
+    /// Encrypt `data` in place, continuing the keystream of the session.
+    fn apply_keystream(&mut self, data: &mut [u8]);
+}
+
+/// The cipher set with [`set_log_cipher`], unless the logger task is using it.
+static CIPHER: Mutex<RefCell<Option<&'static mut dyn LogCipher>>> = Mutex::new(RefCell::new(None));
+
+/// Encrypt everything the logger task sends from the next connection on with `cipher`.
+///
+/// Set it once, before the host connects: replacing the cipher in the middle of a session leaves
+/// the host unable to decrypt the rest of it.
+///
+/// Every session, from the host connecting until it disconnects, starts with the header written by
+/// [`LogCipher::start_session`], and the rest of it is encrypted with
+/// [`LogCipher::apply_keystream`] in the order it is sent. Chunk headers are left unencrypted. A
+/// packet discarded after [`OnWriteTimeout::Discard`] leaves the host out of step with the
+/// keystream until the next session, so use [`OnWriteTimeout::Retain`] with a write timeout.
+///
+/// [`OnWriteTimeout::Discard`]: crate::OnWriteTimeout::Discard
+/// [`OnWriteTimeout::Retain`]: crate::OnWriteTimeout::Retain
+pub fn set_log_cipher(cipher: &'static mut dyn LogCipher) {
+    critical_section::with(|cs| *CIPHER.borrow_ref_mut(cs) = Some(cipher));
+}
+
+/// Run `f` with the cipher, if one has been set.
+///
+/// The cipher is taken out of its slot while `f` runs, so that it is not used inside a critical
+/// section. Returns `None` if no cipher has been set.
+pub(crate) fn with_cipher<R>(f: impl FnOnce(&mut dyn LogCipher) -> R) -> Option<R> {
+    let cipher = critical_section::with(|cs| CIPHER.borrow_ref_mut(cs).take())?;
+    let result = f(&mut *cipher);
+    critical_section::with(|cs| {
+        // Keep a cipher set while `f` was running.
+        CIPHER.borrow_ref_mut(cs).get_or_insert(cipher);
+    });
+    Some(result)
+}
@@ -0,0 +1,169 @@
+//! The defmt CDC ACM function, for adding to a caller-owned USB device
+
+use embassy_usb::{
+    Builder,
+    class::cdc_acm::{CdcAcmClass, State},
+    driver::Driver,
+};
+
+use crate::{
+    cdc_acm::{self, CdcAcmState, ControlChanged, Receiver, Sender},
+    console,
+    receiver::receive,
+    send_break::{self, SendBreakHandler},
+    task::serve_serial_port,
+    vendor::{self, VendorHandler},
+};
+
+/// State of a [`DefmtClass`]: that of its serial port, and of the control handlers it adds.
+pub struct DefmtState<'d> {
+    /// State of the serial port.
+    serial: CdcAcmState<'d>,
+    /// Control handler of the break requests.
+    send_break: SendBreakHandler,
+    /// Control handler of the vendor requests.
+    vendor: VendorHandler,
+}
+
+impl DefmtState<'_> {
+    /// Create the state.
+    pub fn new() -> Self {
+        Self {
+            serial: CdcAcmState::new(),
+            send_break: SendBreakHandler,
+            vendor: VendorHandler,
+        }
+    }
+
+    /// Give the communications interface of the serial port the vendor-specific protocol `0xFF`
+    /// if `ignore` is set, instead of none, so that ModemManager leaves the port alone, see
+    /// [`LoggerConfig::modem_manager_ignore`].
+    ///
+    /// [`LoggerConfig::modem_manager_ignore`]: crate::LoggerConfig::modem_manager_ignore
+    pub fn modem_manager_ignore(mut self, ignore: bool) -> Self {
+        self.serial.set_vendor_protocol(ignore);
+        self
+    }
+}
+
+impl Default for DefmtState<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The defmt serial port as one function of a composite USB device, alongside HID, MIDI, another
+/// CDC function, or anything else.
+///
+/// Add it to the builder before building the device, and run the device and the logger together:
+///
+/// ```ignore
+/// static STATE: StaticCell<DefmtState> = StaticCell::new();
+///
+/// let mut builder = embassy_usb::Builder::new(driver, usb_config, ...);
+/// let defmt = DefmtClass::new(&mut builder, STATE.init(DefmtState::new()), 64);
+/// let hid = HidWriter::new(&mut builder, ...);
+/// let mut usb = builder.build();
+///
+/// LoggerConfig::new().apply();
+/// join3(usb.run(), defmt.run(), hid_task(hid)).await;
+/// ```
+///
+/// Unlike [`run`], this leaves the USB configuration to the caller, which must set
+/// `composite_with_iads` and the matching device class for the serial port to work; see
+/// [the library documentation][crate]. The [`LoggerConfig`] must be applied by the caller too.
+///
+/// [`run`]: crate::run
+/// [`LoggerConfig`]: crate::LoggerConfig
+pub struct DefmtClass<'d, D: Driver<'d>> {
+    /// Sending half of the serial port.
+    sender: Sender<'d, D>,
+    /// Receiving half of the serial port.
+    receiver: Receiver<'d, D>,
+    /// Notifications of changes to the serial line state.
+    ctrl: ControlChanged<'d>,
+    /// The serial port of the application console, if added.
+    console: Option<CdcAcmClass<'d, D>>,
+}
+
+impl<'d, D: Driver<'d>> DefmtClass<'d, D> {
+    /// Add the defmt serial port to `builder`, with endpoints of `max_packet_size` bytes.
+    ///
+    /// This also adds control handlers that take a break sent to any interface as a request to
+    /// flush, enable or disable logging with a vendor request, and pass the vendor reset requests
+    /// to the function set with [`set_reset_handler`], see [the library documentation][crate].
+    ///
+    /// The serial port tells the host when frames are dropped, with a `SERIAL_STATE` notification
+    /// of an overrun on the interrupt endpoint of its communications interface, which shows up in
+    /// the overrun count of the port, such as in `TIOCGICOUNT` on Linux.
+    ///
+    /// [`set_reset_handler`]: crate::set_reset_handler
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut DefmtState<'d>,
+        max_packet_size: u16,
+    ) -> Self {
+        let DefmtState {
+            serial,
+            send_break,
+            vendor,
+        } = state;
+        send_break::add_handler(builder, send_break);
+        vendor::add_handler(builder, vendor);
+        let (sender, receiver, ctrl) = cdc_acm::add_serial_port(builder, serial, max_packet_size);
+        Self {
+            sender,
+            receiver,
+            ctrl,
+            console: None,
+        }
+    }
+
+    /// Add a second serial port to `builder`, with endpoints of `max_packet_size` bytes, for the
+    /// application console read and written with [`console`].
+    ///
+    /// The console is served along with the defmt serial port by [`run`](DefmtClass::run). Two
+    /// serial ports need `composite_with_iads` in the USB configuration.
+    ///
+    /// [`console`]: crate::console()
+    pub fn add_console(
+        &mut self,
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        max_packet_size: u16,
+    ) {
+        self.console = Some(CdcAcmClass::new(builder, state, max_packet_size));
+    }
+
+    /// Write out the queued defmt frames over the serial port, forever.
+    ///
+    /// This must run alongside the USB device, built from the same builder. The data the host
+    /// sends over the serial port is discarded, once checked for the start sequence set with
+    /// [`set_start_handshake`].
+    ///
+    /// [`set_start_handshake`]: crate::set_start_handshake
+    pub async fn run(self) {
+        self.run_with_receiver(async |_| {}).await;
+    }
+
+    /// Write out the queued defmt frames over the serial port, and hand the data the host sends
+    /// over it to `on_receive`, forever.
+    ///
+    /// See [`run_with_receiver`] for how the received data is delivered.
+    ///
+    /// [`run_with_receiver`]: crate::run_with_receiver
+    pub async fn run_with_receiver(self, on_receive: impl AsyncFnMut(&[u8])) {
+        let console = async {
+            if let Some(class) = self.console {
+                console::serve(class).await;
+            }
+        };
+        embassy_futures::join::join4(
+            serve_serial_port(self.sender, self.ctrl),
+            receive(self.receiver, on_receive),
+            console,
+            vendor::handle_resets(),
+        )
+        .await;
+    }
+}
@@ -0,0 +1,209 @@
+//! Dispatching commands sent by the host over the serial port
+
+/// Longest command line, or binary command payload, that can be received.
+pub const MAX_COMMAND_LEN: usize = 64;
+
+/// Byte that starts a binary command when it is the first byte of a line.
+///
+/// It is followed by the tag of the command, the length of its payload as a `u8`, and the
+/// payload. A text line may not start with this byte.
+pub const BINARY_COMMAND_START: u8 = 0x01;
+
+/// A command that the host may send, and the function that handles it.
+///
+/// Handlers run in the task that receives the data, so they should be quick, and hand anything
+/// longer to another task, for example through an `embassy_sync` signal.
+#[derive(Clone, Copy)]
+pub enum Command {
+    /// A text command: a line, ended by `\r` or `\n`, whose first word is `name`. The handler is
+    /// called with the rest of the line, without surrounding whitespace.
+    Line {
+        /// First word of the line.
+        name: &'static str,
+        /// Handler of the command.
+        handler: fn(&str),
+    },
+    /// A binary command tagged `tag`. The handler is called with its payload.
+    Binary {
+        /// Tag of the command.
+        tag: u8,
+        /// Handler of the command.
+        handler: fn(&[u8]),
+    },
+}
+
+/// Where the dispatcher is in the incoming data.
+#[derive(Clone, Copy)]
+enum State {
+    /// Receiving a text line, or waiting for the first byte of a command.
+    Line,
+    /// Skipping the rest of a text line that is too long.
+    SkipLine,
+    /// Waiting for the tag of a binary command.
+    Tag,
+    /// Waiting for the payload length of a binary command.
+    Len { tag: u8 },
+    /// Receiving the payload of a binary command.
+    Payload { tag: u8, len: usize },
+    /// Skipping the payload of a binary command that is too long.
+    SkipPayload { remaining: usize },
+}
+
+/// Parser of the data the host sends, which calls the handler of each command it receives.
+///
+/// Feed it the data passed to the callback of [`run_with_receiver`]:
+///
+/// ```ignore
+/// static COMMANDS: [Command; 2] = [
+///     Command::Line { name: "reset", handler: |_| cortex_m::peripheral::SCB::sys_reset() },
+///     Command::Binary { tag: 0x10, handler: set_led },
+/// ];
+///
+/// let mut commands = CommandDispatcher::new(&COMMANDS);
+/// defmt_embassy_usbserial::run_with_receiver(
+///     driver,
+///     usb_config,
+///     LoggerConfig::new(),
+///     async |data| commands.feed(data),
+/// )
+/// .await
+/// .unwrap();
+/// ```
+///
+/// Unknown commands, lines that are not UTF-8, and commands longer than [`MAX_COMMAND_LEN`] are
+/// dropped with a warning in the log.
+///
+/// [`run_with_receiver`]: crate::run_with_receiver
+pub struct CommandDispatcher<'a> {
+    /// The commands to dispatch.
+    commands: &'a [Command],
+    /// Where the dispatcher is in the incoming data.
+    state: State,
+    /// The line or payload received so far.
+    buffer: [u8; MAX_COMMAND_LEN],
+    /// Number of bytes in `buffer`.
+    len: usize,
+}
+
+impl<'a> CommandDispatcher<'a> {
+    /// Create a dispatcher of `commands`.
+    pub const fn new(commands: &'a [Command]) -> Self {
+        Self {
+            commands,
+            state: State::Line,
+            buffer: [0; MAX_COMMAND_LEN],
+            len: 0,
+        }
+    }
+
+    /// Parse `data` received from the host, and call the handlers of the commands it completes.
+    ///
+    /// Commands may be split across calls.
+    pub fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.push(byte);
+        }
+    }
+
+    /// Parse the next byte received from the host.
+    fn push(&mut self, byte: u8) {
+        self.state = match self.state {
+            State::Line => match byte {
+                b'\r' | b'\n' => {
+                    self.dispatch_line();
+                    self.len = 0;
+                    State::Line
+                }
+                BINARY_COMMAND_START if self.len == 0 => State::Tag,
+                _ if self.len < MAX_COMMAND_LEN => {
+                    self.buffer[self.len] = byte;
+                    self.len += 1;
+                    State::Line
+                }
+                _ => {
+                    defmt::warn!("command line longer than {=usize} bytes", MAX_COMMAND_LEN);
+                    self.len = 0;
+                    State::SkipLine
+                }
+            },
+            State::SkipLine => match byte {
+                b'\r' | b'\n' => State::Line,
+                _ => State::SkipLine,
+            },
+            State::Tag => State::Len { tag: byte },
+            State::Len { tag } => match usize::from(byte) {
+                0 => {
+                    self.dispatch_binary(tag);
+                    State::Line
+                }
+                len if len <= MAX_COMMAND_LEN => State::Payload { tag, len },
+                len => {
+                    defmt::warn!(
+                        "binary command {=u8:#x} longer than {=usize} bytes",
+                        tag,
+                        MAX_COMMAND_LEN
+                    );
+                    State::SkipPayload { remaining: len }
+                }
+            },
+            State::Payload { tag, len } => {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+                if self.len < len {
+                    State::Payload { tag, len }
+                } else {
+                    self.dispatch_binary(tag);
+                    self.len = 0;
+                    State::Line
+                }
+            }
+            State::SkipPayload { remaining: 1 } => State::Line,
+            State::SkipPayload { remaining } => State::SkipPayload {
+                remaining: remaining - 1,
+            },
+        };
+    }
+
+    /// Call the handler of the text line in the buffer, if it is not empty.
+    fn dispatch_line(&self) {
+        let Ok(line) = core::str::from_utf8(&self.buffer[..self.len]) else {
+            defmt::warn!("command line is not UTF-8");
+            return;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        let (name, args) = match line.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim_start()),
+            None => (line, ""),
+        };
+
+        let handler = self.commands.iter().find_map(|command| match *command {
+            Command::Line {
+                name: command_name,
+                handler,
+            } if command_name == name => Some(handler),
+            _ => None,
+        });
+        match handler {
+            Some(handler) => handler(args),
+            None => defmt::warn!("unknown command: {=str}", name),
+        }
+    }
+
+    /// Call the handler of the binary command tagged `tag`, with the payload in the buffer.
+    fn dispatch_binary(&self, tag: u8) {
+        let handler = self.commands.iter().find_map(|command| match *command {
+            Command::Binary {
+                tag: command_tag,
+                handler,
+            } if command_tag == tag => Some(handler),
+            _ => None,
+        });
+        match handler {
+            Some(handler) => handler(&self.buffer[..self.len]),
+            None => defmt::warn!("unknown binary command: {=u8:#x}", tag),
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Configuration of the USB device and of the runtime behavior of the logger
+
+use embassy_time::Duration;
+
+use crate::controller::{
+    OnWriteTimeout, OverflowPolicy, PacketTermination, set_adaptive_flush, set_buffer_while_closed,
+    set_buffer_while_disconnected, set_channels, set_chunk_headers, set_double_buffering,
+    set_emergency_reserve, set_flush_deadline, set_flush_timeout, set_flush_watermark,
+    set_line_state_debounce, set_overflow_policy, set_packet_coalescing, set_packet_termination,
+    set_self_report_interval, set_session_marker, set_software_flow_control, set_source_tags,
+    set_stale_timeout, set_start_handshake, set_wait_on_full, set_write_timeout,
+};
+
+/// Runtime behavior of the logger, passed to [`run`].
+///
+/// Every setting but [`max_packet_size`](LoggerConfig::max_packet_size),
+/// [`webusb_landing_page`](LoggerConfig::webusb_landing_page),
+/// [`console`](LoggerConfig::console) and
+/// [`modem_manager_ignore`](LoggerConfig::modem_manager_ignore), which shape the USB descriptors,
+/// has a function of the same
+/// name with a `set_` prefix, which documents it and can be used to change it later on. The
+/// defaults are those of the crate, so that only the settings that differ need to be given:
+///
+/// ```no_run
+/// use defmt_embassy_usbserial::{LoggerConfig, OverflowPolicy};
+/// use embassy_time::Duration;
+///
+/// let config = LoggerConfig::new()
+///     .overflow_policy(OverflowPolicy::DropOldest)
+///     .flush_deadline(Some(Duration::from_millis(20)))
+///     .stale_timeout(Some(Duration::from_secs(5)));
+/// ```
+///
+/// [`run`]: crate::run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoggerConfig {
+    /// See [`set_overflow_policy`].
+    overflow_policy: OverflowPolicy,
+    /// See [`set_wait_on_full`].
+    wait_on_full: Option<Duration>,
+    /// See [`set_emergency_reserve`].
+    emergency_reserve: usize,
+    /// See [`set_flush_watermark`].
+    flush_watermark: usize,
+    /// See [`set_flush_deadline`].
+    flush_deadline: Option<Duration>,
+    /// See [`set_adaptive_flush`].
+    adaptive_flush: Option<Duration>,
+    /// See [`set_packet_coalescing`].
+    packet_coalescing: Option<Duration>,
+    /// See [`set_double_buffering`].
+    double_buffering: bool,
+    /// See [`set_packet_termination`].
+    packet_termination: PacketTermination,
+    /// See [`set_write_timeout`].
+    write_timeout: (Option<Duration>, OnWriteTimeout),
+    /// See [`set_stale_timeout`].
+    stale_timeout: Option<Duration>,
+    /// See [`set_flush_timeout`].
+    flush_timeout: Option<Duration>,
+    /// See [`set_self_report_interval`].
+    self_report_interval: Option<Duration>,
+    /// See [`set_buffer_while_disconnected`].
+    buffer_while_disconnected: bool,
+    /// See [`set_buffer_while_closed`].
+    buffer_while_closed: bool,
+    /// See [`set_start_handshake`].
+    start_handshake: (Option<&'static [u8]>, bool),
+    /// See [`set_software_flow_control`].
+    software_flow_control: bool,
+    /// See [`set_session_marker`].
+    session_marker: bool,
+    /// See [`set_chunk_headers`].
+    chunk_headers: bool,
+    /// See [`set_channels`].
+    channels: bool,
+    /// See [`set_source_tags`].
+    source_tags: bool,
+    /// See [`set_line_state_debounce`].
+    line_state_debounce: Duration,
+    /// See [`LoggerConfig::max_packet_size`].
+    max_packet_size: Option<u16>,
+    /// See [`LoggerConfig::webusb_landing_page`].
+    webusb_landing_page: Option<&'static str>,
+    /// See [`LoggerConfig::console`].
+    console: bool,
+    /// See [`LoggerConfig::modem_manager_ignore`].
+    modem_manager_ignore: bool,
+}
+
+impl LoggerConfig {
+    /// The default configuration.
+    pub const fn new() -> Self {
+        Self {
+            overflow_policy: OverflowPolicy::DropNewest,
+            wait_on_full: None,
+            emergency_reserve: 0,
+            flush_watermark: 1,
+            flush_deadline: None,
+            adaptive_flush: None,
+            packet_coalescing: None,
+            double_buffering: false,
+            packet_termination: PacketTermination::Never,
+            write_timeout: (None, OnWriteTimeout::Retain),
+            stale_timeout: None,
+            flush_timeout: None,
+            self_report_interval: None,
+            buffer_while_disconnected: false,
+            buffer_while_closed: true,
+            start_handshake: (None, false),
+            software_flow_control: false,
+            session_marker: false,
+            chunk_headers: false,
+            channels: false,
+            source_tags: false,
+            line_state_debounce: Duration::from_millis(10),
+            max_packet_size: None,
+            webusb_landing_page: None,
+            console: false,
+            modem_manager_ignore: false,
+        }
+    }
+
+    /// Set the policy used when a frame does not fit, see [`set_overflow_policy`].
+    pub const fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Set how long the logger may wait for room, see [`set_wait_on_full`].
+    pub const fn wait_on_full(mut self, timeout: Option<Duration>) -> Self {
+        self.wait_on_full = timeout;
+        self
+    }
+
+    /// Set the space reserved for emergency frames, see [`set_emergency_reserve`].
+    pub const fn emergency_reserve(mut self, bytes: usize) -> Self {
+        self.emergency_reserve = bytes;
+        self
+    }
+
+    /// Set how many bytes must be queued before sending, see [`set_flush_watermark`].
+    pub const fn flush_watermark(mut self, bytes: usize) -> Self {
+        self.flush_watermark = bytes;
+        self
+    }
+
+    /// Set how long frames may stay below the watermark, see [`set_flush_deadline`].
+    pub const fn flush_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.flush_deadline = deadline;
+        self
+    }
+
+    /// Let the flush deadline adapt to the logging rate, see [`set_adaptive_flush`].
+    pub const fn adaptive_flush(mut self, max_deadline: Option<Duration>) -> Self {
+        self.adaptive_flush = max_deadline;
+        self
+    }
+
+    /// Set how long to wait to fill a packet, see [`set_packet_coalescing`].
+    pub const fn packet_coalescing(mut self, idle: Option<Duration>) -> Self {
+        self.packet_coalescing = idle;
+        self
+    }
+
+    /// Set whether the next packet is read while one is sent, see [`set_double_buffering`].
+    pub const fn double_buffering(mut self, enabled: bool) -> Self {
+        self.double_buffering = enabled;
+        self
+    }
+
+    /// Set how transfers ending with a full packet are ended, see [`set_packet_termination`].
+    pub const fn packet_termination(mut self, termination: PacketTermination) -> Self {
+        self.packet_termination = termination;
+        self
+    }
+
+    /// Set how long the host may take to read a packet, see [`set_write_timeout`].
+    pub const fn write_timeout(
+        mut self,
+        timeout: Option<Duration>,
+        on_timeout: OnWriteTimeout,
+    ) -> Self {
+        self.write_timeout = (timeout, on_timeout);
+        self
+    }
+
+    /// Set when queued frames are out of date, see [`set_stale_timeout`].
+    pub const fn stale_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.stale_timeout = timeout;
+        self
+    }
+
+    /// Set how long `defmt::flush()` may wait, see [`set_flush_timeout`].
+    pub const fn flush_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.flush_timeout = timeout;
+        self
+    }
+
+    /// Set how often the logger task reports on itself, see [`set_self_report_interval`].
+    pub const fn self_report_interval(mut self, interval: Option<Duration>) -> Self {
+        self.self_report_interval = interval;
+        self
+    }
+
+    /// Set whether frames are kept while disconnected, see [`set_buffer_while_disconnected`].
+    pub const fn buffer_while_disconnected(mut self, buffer: bool) -> Self {
+        self.buffer_while_disconnected = buffer;
+        self
+    }
+
+    /// Set whether frames are kept while the serial port is closed, see
+    /// [`set_buffer_while_closed`].
+    pub const fn buffer_while_closed(mut self, buffer: bool) -> Self {
+        self.buffer_while_closed = buffer;
+        self
+    }
+
+    /// Set the sequence the host must send before anything is sent, see [`set_start_handshake`].
+    pub const fn start_handshake(mut self, sequence: Option<&'static [u8]>, backlog: bool) -> Self {
+        self.start_handshake = (sequence, backlog);
+        self
+    }
+
+    /// Set whether the host can pause the stream with XOFF, see [`set_software_flow_control`].
+    pub const fn software_flow_control(mut self, enabled: bool) -> Self {
+        self.software_flow_control = enabled;
+        self
+    }
+
+    /// Set whether a marker is logged on every connection, see [`set_session_marker`].
+    pub const fn session_marker(mut self, enabled: bool) -> Self {
+        self.session_marker = enabled;
+        self
+    }
+
+    /// Set whether packets start with a header, see [`set_chunk_headers`].
+    pub const fn chunk_headers(mut self, enabled: bool) -> Self {
+        self.chunk_headers = enabled;
+        self
+    }
+
+    /// Set whether the stream is split into channels, see [`set_channels`].
+    pub const fn channels(mut self, enabled: bool) -> Self {
+        self.channels = enabled;
+        self
+    }
+
+    /// Set whether defmt frames are tagged with their source, see [`set_source_tags`].
+    pub const fn source_tags(mut self, enabled: bool) -> Self {
+        self.source_tags = enabled;
+        self
+    }
+
+    /// Set how long to wait after the serial line state changes, see
+    /// [`set_line_state_debounce`].
+    pub const fn line_state_debounce(mut self, debounce: Duration) -> Self {
+        self.line_state_debounce = debounce;
+        self
+    }
+
+    /// Set the maximum packet size of the CDC ACM endpoints built by [`run`](crate::run).
+    ///
+    /// By default it is the `max_packet_size_0` of the USB configuration, which is at most 64
+    /// bytes. High-speed drivers, such as the OTG-HS peripheral of some STM32 chips, support bulk
+    /// packets of 512 bytes, which needs the `high-speed` feature and gives up to eight times the
+    /// throughput. The chunk headers enabled with [`set_chunk_headers`] limit packets to 258 bytes.
+    pub const fn max_packet_size(mut self, size: u16) -> Self {
+        self.max_packet_size = Some(size);
+        self
+    }
+
+    /// The maximum packet size of the CDC ACM endpoints, if set.
+    pub(crate) const fn packet_size(&self) -> Option<u16> {
+        self.max_packet_size
+    }
+
+    /// Add WebUSB descriptors to the device built by [`run`](crate::run), with the URL of a
+    /// landing page.
+    ///
+    /// Chrome and other browsers with WebUSB then offer to open `url` when the device is plugged
+    /// in, so that a browser-based viewer can read the stream without native tools, which is handy
+    /// for demos. The viewer reads the data interface of the serial port, so it only gets access
+    /// where no driver of the operating system has claimed it; elsewhere, it can read the port
+    /// with Web Serial instead. The URL must start with `https://` or `http://`. The descriptors
+    /// take 24 bytes of the BOS descriptor buffer, which is too small with the `minimal-ram`
+    /// feature. There are none by default.
+    pub const fn webusb_landing_page(mut self, url: Option<&'static str>) -> Self {
+        self.webusb_landing_page = url;
+        self
+    }
+
+    /// The URL of the WebUSB landing page, if set.
+    pub(crate) const fn landing_page(&self) -> Option<&'static str> {
+        self.webusb_landing_page
+    }
+
+    /// Add a second serial port to the device built by [`run`](crate::run), for an application
+    /// console read and written with [`console`](crate::console()).
+    ///
+    /// The defmt stream stays on a port of its own, so that an interactive shell, or any other
+    /// byte stream of the application, does not have to share it. The port has the same maximum
+    /// packet size as the defmt one. Two serial ports need `composite_with_iads` in the USB
+    /// configuration, and 66 more bytes of configuration descriptor buffer, which is too small
+    /// with the `minimal-ram` feature. There is no console by default.
+    pub const fn console(mut self, enabled: bool) -> Self {
+        self.console = enabled;
+        self
+    }
+
+    /// Whether a console serial port is added.
+    pub(crate) const fn console_enabled(&self) -> bool {
+        self.console
+    }
+
+    /// Give the communications interface of the serial port built by [`run`](crate::run) the
+    /// vendor-specific protocol `0xFF`, instead of none, so that ModemManager leaves it alone.
+    ///
+    /// ModemManager opens new serial ports and sends AT commands to them to find modems, which
+    /// consumes the first seconds of the stream. It only does so for the CDC ACM interfaces that
+    /// report an AT command protocol, or those without a protocol with some filter policies, and
+    /// not for a vendor-specific one. The `cdc_acm` driver of Linux, and `usbser.sys` on Windows
+    /// 10 and later, bind to the port all the same. The udev rule in the `host-tools/udev`
+    /// directory of the repository keeps ModemManager away from devices built without this. It is
+    /// off by default.
+    pub const fn modem_manager_ignore(mut self, ignore: bool) -> Self {
+        self.modem_manager_ignore = ignore;
+        self
+    }
+
+    /// Whether the communications interface has a vendor-specific protocol.
+    pub(crate) const fn modem_manager_ignored(&self) -> bool {
+        self.modem_manager_ignore
+    }
+
+    /// Apply every setting, replacing any made before.
+    ///
+    /// [`run`](crate::run) does this when it starts. Call it directly when using
+    /// [`logger`](crate::logger) instead.
+    pub fn apply(&self) {
+        set_overflow_policy(self.overflow_policy);
+        set_wait_on_full(self.wait_on_full);
+        set_emergency_reserve(self.emergency_reserve);
+        set_flush_watermark(self.flush_watermark);
+        set_flush_deadline(self.flush_deadline);
+        set_adaptive_flush(self.adaptive_flush);
+        set_packet_coalescing(self.packet_coalescing);
+        set_double_buffering(self.double_buffering);
+        set_packet_termination(self.packet_termination);
+        set_write_timeout(self.write_timeout.0, self.write_timeout.1);
+        set_stale_timeout(self.stale_timeout);
+        set_flush_timeout(self.flush_timeout);
+        set_self_report_interval(self.self_report_interval);
+        set_buffer_while_disconnected(self.buffer_while_disconnected);
+        set_buffer_while_closed(self.buffer_while_closed);
+        set_start_handshake(self.start_handshake.0, self.start_handshake.1);
+        set_software_flow_control(self.software_flow_control);
+        set_session_marker(self.session_marker);
+        set_chunk_headers(self.chunk_headers);
+        set_channels(self.channels);
+        set_source_tags(self.source_tags);
+        set_line_state_debounce(self.line_state_debounce);
+    }
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A USB configuration for a device whose only function is the defmt serial port.
+///
+/// This sets `composite_with_iads` along with the device class, subclass and protocol that it
+/// requires, `0xEF`, `0x02` and `0x01`, and a control endpoint packet size of 64 bytes. Set the
+/// strings and power settings on the returned configuration as needed:
+///
+/// ```no_run
+/// let mut usb_config = defmt_embassy_usbserial::default_config(0x1234, 0x5678);
+/// usb_config.serial_number = Some("defmt");
+/// ```
+pub const fn default_config(vid: u16, pid: u16) -> embassy_usb::Config<'static> {
+    let mut config = embassy_usb::Config::new(vid, pid);
+    config.composite_with_iads = true;
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.max_packet_size_0 = 64;
+    config
+}
+
+/// A USB configuration for a single-function CDC device, whose only function is the defmt serial
+/// port, without an Interface Association Descriptor.
+///
+/// This clears `composite_with_iads` and sets the CDC device class, `0x02`, with subclass and
+/// protocol `0x00`, and a control endpoint packet size of 64 bytes. The device descriptor then
+/// says what the device is, which some old hosts and USB stacks need, but no other function can be
+/// added to the device. Prefer [`default_config`] otherwise.
+pub const fn single_function_config(vid: u16, pid: u16) -> embassy_usb::Config<'static> {
+    let mut config = embassy_usb::Config::new(vid, pid);
+    config.composite_with_iads = false;
+    config.device_class = 0x02;
+    config.device_sub_class = 0x00;
+    config.device_protocol = 0x00;
+    config.max_packet_size_0 = 64;
+    config
+}
+
+/// The pid.codes vendor ID, used by [`pid_codes_test_config`].
+pub const PID_CODES_VID: u16 = 0x1209;
+
+/// A pid.codes product ID reserved for testing, used by [`pid_codes_test_config`].
+pub const PID_CODES_TEST_PID: u16 = 0x0001;
+
+/// The product string set by [`pid_codes_test_config`], which host tools can look for.
+pub const DEFMT_PRODUCT: &str = "defmt-logs";
+
+/// A [`default_config`] with the pid.codes test vendor and product IDs, and [`DEFMT_PRODUCT`] as
+/// the product string.
+///
+/// Host tools can then find the serial port by its USB descriptors instead of the user looking
+/// for the right `/dev/ttyACM*` or `COM` port, for example with a udev rule matching
+/// `ATTRS{idVendor}=="1209", ATTRS{product}=="defmt-logs"`. The test product ID is only meant for
+/// development and private use: allocate your own for anything that is distributed. The string is
+/// the product string rather than the string of the CDC interface, which `embassy-usb` does not
+/// let its CDC ACM class set. The `host-tools/udev` directory of the repository has a rule that
+/// matches it to keep ModemManager away from the serial port.
+pub const fn pid_codes_test_config() -> embassy_usb::Config<'static> {
+    let mut config = default_config(PID_CODES_VID, PID_CODES_TEST_PID);
+    config.product = Some(DEFMT_PRODUCT);
+    config
+}
@@ -0,0 +1,153 @@
+//! State of the connection to the host
+
+use core::{
+    cell::{Cell, RefCell},
+    future::poll_fn,
+    sync::atomic::Ordering,
+    task::Poll,
+};
+
+use critical_section::Mutex;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    waitqueue::MultiWakerRegistration,
+    watch::{DynReceiver, Watch},
+};
+use portable_atomic::AtomicBool;
+
+/// Largest number of receivers of the connection state that may exist at once.
+pub const MAX_CONNECTION_RECEIVERS: usize = 4;
+
+/// Largest number of receivers of the serial line state that may exist at once.
+pub const MAX_LINE_STATE_RECEIVERS: usize = 4;
+
+/// State of the serial line control signals set by the host.
+///
+/// Programs on the host usually assert both signals when they open the serial port, and drop them
+/// when they close it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct LineState {
+    /// Data Terminal Ready.
+    pub dtr: bool,
+    /// Request To Send.
+    pub rts: bool,
+}
+
+/// Whether the USB device is connected to a host and its CDC ACM interface is enabled.
+static CONNECTED: Watch<CriticalSectionRawMutex, bool, MAX_CONNECTION_RECEIVERS> =
+    Watch::new_with(false);
+
+/// The serial line state last seen by the logger task.
+static LINE_STATE: Watch<CriticalSectionRawMutex, LineState, MAX_LINE_STATE_RECEIVERS> =
+    Watch::new_with(LineState {
+        dtr: false,
+        rts: false,
+    });
+
+/// Whether the host has opened the serial port, by asserting DTR and RTS.
+static HOST_READY: AtomicBool = AtomicBool::new(false);
+
+/// Tasks waiting in [`wait_for_host`].
+static HOST_WAKERS: Mutex<RefCell<MultiWakerRegistration<4>>> =
+    Mutex::new(RefCell::new(MultiWakerRegistration::new()));
+
+/// Whether the USB device is connected to a host and its serial port is enabled.
+///
+/// This does not tell whether a program on the host has opened the serial port; use
+/// [`wait_for_host`] for that.
+pub fn is_connected() -> bool {
+    CONNECTED.try_get() == Some(true)
+}
+
+/// Wait until a program on the host, such as `defmt-print`, has opened the serial port.
+///
+/// Frames logged before then are queued, but may be dropped if the buffer overflows. Awaiting this
+/// before logging a startup banner and self-test results makes sure they are not lost.
+pub async fn wait_for_host() {
+    poll_fn(|cx| {
+        critical_section::with(|cs| {
+            if HOST_READY.load(Ordering::Relaxed) {
+                Poll::Ready(())
+            } else {
+                HOST_WAKERS.borrow_ref_mut(cs).register(cx.waker());
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/// Get a receiver of the connection state, which is `true` while the USB device is connected to a
+/// host and its serial port is enabled.
+///
+/// This lets application tasks defer expensive diagnostics until someone is listening:
+///
+/// ```ignore
+/// let mut connected = defmt_embassy_usbserial::connection_receiver().unwrap();
+/// loop {
+///     connected.changed_and(|connected| *connected).await;
+///     defmt::info!("{}", diagnostics());
+/// }
+/// ```
+///
+/// Returns `None` if [`MAX_CONNECTION_RECEIVERS`] receivers already exist.
+pub fn connection_receiver() -> Option<DynReceiver<'static, bool>> {
+    CONNECTED.dyn_receiver()
+}
+
+/// The state of the serial line control signals set by the host.
+pub fn line_state() -> LineState {
+    LINE_STATE.try_get().unwrap_or_default()
+}
+
+/// Get a receiver of the state of the serial line control signals set by the host.
+///
+/// This lets firmware react to a program on the host opening the serial port, for example by
+/// logging a status summary every time someone attaches:
+///
+/// ```ignore
+/// let mut line_state = defmt_embassy_usbserial::line_state_receiver().unwrap();
+/// loop {
+///     line_state.changed_and(|state| state.dtr).await;
+///     defmt::info!("{}", status_summary());
+/// }
+/// ```
+///
+/// Changes are seen by the logger task, which reports them once the state has settled, or once it
+/// is done with a packet the host is not reading, if frames are kept while the port is closed.
+/// Both signals read as deasserted while the device is disconnected.
+///
+/// Returns `None` if [`MAX_LINE_STATE_RECEIVERS`] receivers already exist.
+pub fn line_state_receiver() -> Option<DynReceiver<'static, LineState>> {
+    LINE_STATE.dyn_receiver()
+}
+
+/// Publish the serial line state, returning the previous one.
+pub(crate) fn set_line_state(state: LineState) -> LineState {
+    let previous = Cell::new(LineState::default());
+    LINE_STATE.sender().send_if_modified(|value| {
+        previous.set(value.unwrap_or_default());
+        *value = Some(state);
+        previous.get() != state
+    });
+    previous.get()
+}
+
+/// Publish whether the USB device is connected.
+pub(crate) fn set_connected(connected: bool) {
+    CONNECTED.sender().send_if_modified(|value| {
+        let modified = *value != Some(connected);
+        *value = Some(connected);
+        modified
+    });
+}
+
+/// Publish whether a program on the host has opened the serial port.
+pub(crate) fn set_host_ready(ready: bool) {
+    critical_section::with(|cs| {
+        HOST_READY.store(ready, Ordering::Relaxed);
+        if ready {
+            HOST_WAKERS.borrow_ref_mut(cs).wake();
+        }
+    });
+}
@@ -0,0 +1,157 @@
+//! A second serial port for an application console, alongside the defmt one
+
+use core::convert::Infallible;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, Receiver, Sender},
+    driver::{Driver, EndpointError},
+};
+
+use crate::task::MAX_PACKET_SIZE;
+
+/// Number of bytes buffered in each direction of the console.
+pub const CONSOLE_BUFFER_LEN: usize = 128;
+
+/// Data the host sent to the console, until the application reads it.
+static FROM_HOST: Pipe<CriticalSectionRawMutex, CONSOLE_BUFFER_LEN> = Pipe::new();
+
+/// Data the application wrote to the console, until it is sent to the host.
+static TO_HOST: Pipe<CriticalSectionRawMutex, CONSOLE_BUFFER_LEN> = Pipe::new();
+
+/// Handle of the console added with [`LoggerConfig::console`], a serial port of its own for an
+/// interactive shell or any other byte stream of the application.
+///
+/// It reads what the host sends to the console and writes to the host through the
+/// `embedded-io-async` traits, so it can be handed to a line editor or shell crate directly.
+/// Reads wait for the host to send data. Writes wait for room in a buffer of
+/// [`CONSOLE_BUFFER_LEN`] bytes, which is emptied continuously, and what is written while the host
+/// does not have the port open is dropped, so that an application writing to the console does not
+/// stall when nobody is listening. Copies of the handle share the same port.
+///
+/// ```ignore
+/// let mut console = defmt_embassy_usbserial::console();
+/// let mut line = [0; 64];
+/// loop {
+///     let len = console.read(&mut line).await.unwrap();
+///     console.write_all(&line[..len]).await.unwrap();
+/// }
+/// ```
+///
+/// [`LoggerConfig::console`]: crate::LoggerConfig::console
+#[derive(Clone, Copy, Debug)]
+pub struct Console {
+    /// Keeps the handle from being created outside of [`console`].
+    _private: (),
+}
+
+/// The handle of the console added with [`LoggerConfig::console`].
+///
+/// [`LoggerConfig::console`]: crate::LoggerConfig::console
+pub fn console() -> Console {
+    Console { _private: () }
+}
+
+impl embedded_io_async::ErrorType for Console {
+    type Error = Infallible;
+}
+
+impl embedded_io_async::Read for Console {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(FROM_HOST.read(buf).await)
+    }
+}
+
+impl embedded_io_async::Write for Console {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(TO_HOST.write(buf).await)
+    }
+}
+
+/// Bridge the console added with [`LoggerConfig::console`] to a hardware UART, forever, so that
+/// the device is also a USB to serial adapter for a neighboring chip.
+///
+/// `rx` and `tx` are the halves of the UART, such as those of the buffered UART drivers of the
+/// embassy HALs, which implement the `embedded-io-async` traits. What the host sends to the
+/// console is written to `tx`, and what is read from `rx` is sent to the host, while the port is
+/// open. The line coding the host sets on the console is not applied to the UART, so configure its
+/// baud rate to match the neighboring chip. Read and write errors of the UART, such as overruns,
+/// are logged and the data involved is lost. Do not use [`console`] while the bridge runs.
+///
+/// ```ignore
+/// let uart = BufferedUart::new(p.UART0, p.PIN_0, p.PIN_1, Irqs, tx_buf, rx_buf, uart_config);
+/// let (tx, rx) = uart.split();
+/// let logger_config = LoggerConfig::new().console(true);
+/// join(
+///     defmt_embassy_usbserial::run(driver, usb_config, logger_config),
+///     defmt_embassy_usbserial::bridge_uart(rx, tx),
+/// )
+/// .await;
+/// ```
+///
+/// [`LoggerConfig::console`]: crate::LoggerConfig::console
+pub async fn bridge_uart<R, W>(mut rx: R, mut tx: W)
+where
+    R: embedded_io_async::Read,
+    W: embedded_io_async::Write,
+{
+    let to_uart = async {
+        let mut buf = [0u8; CONSOLE_BUFFER_LEN];
+        loop {
+            let len = FROM_HOST.read(&mut buf).await;
+            if tx.write_all(&buf[..len]).await.is_err() {
+                defmt::warn!("UART bridge: write error");
+            }
+        }
+    };
+    let from_uart = async {
+        let mut buf = [0u8; CONSOLE_BUFFER_LEN];
+        loop {
+            match rx.read(&mut buf).await {
+                Ok(len) => TO_HOST.write_all(&buf[..len]).await,
+                Err(_) => defmt::warn!("UART bridge: read error"),
+            }
+        }
+    };
+    embassy_futures::join::join(to_uart, from_uart).await;
+}
+
+/// Move data between the console serial port `class` and the handles, forever.
+pub(crate) async fn serve<'d, D: Driver<'d>>(class: CdcAcmClass<'d, D>) {
+    let (sender, receiver) = class.split();
+    embassy_futures::join::join(send(sender), receive(receiver)).await;
+}
+
+/// Send what the application writes to the host, dropping it while the port is closed.
+async fn send<'d, D: Driver<'d>>(mut sender: Sender<'d, D>) {
+    let mut packet = [0u8; MAX_PACKET_SIZE];
+    let max_packet_size = usize::from(sender.max_packet_size()).min(packet.len());
+    loop {
+        let len = TO_HOST.read(&mut packet[..max_packet_size]).await;
+        if !sender.dtr() {
+            continue;
+        }
+        let mut result = sender.write_packet(&packet[..len]).await;
+        if result.is_ok() && len == max_packet_size && TO_HOST.is_empty() {
+            // End the transfer so that the host returns the data to the reader.
+            result = sender.write_packet(&[]).await;
+        }
+        // The data is dropped if the device was disconnected meanwhile.
+        let _ = result;
+    }
+}
+
+/// Hand what the host sends to the application, holding off the host while the buffer is full.
+async fn receive<'d, D: Driver<'d>>(mut receiver: Receiver<'d, D>) {
+    let mut packet = [0u8; MAX_PACKET_SIZE];
+    loop {
+        receiver.wait_connection().await;
+        loop {
+            match receiver.read_packet(&mut packet).await {
+                Ok(len) => FROM_HOST.write_all(&packet[..len]).await,
+                Err(EndpointError::BufferOverflow) => {}
+                Err(EndpointError::Disabled) => break,
+            }
+        }
+    }
+}
@@ -1,11 +1,24 @@
 //! Logger buffers and the buffer controller
 
-use core::{cell::UnsafeCell, sync::atomic::Ordering};
+use core::{cell::UnsafeCell, future::poll_fn, sync::atomic::Ordering, task::Poll};
 
-use loopq::embassy::{AsyncBuffer, AsyncProducer};
+use embassy_futures::select::{Either, select};
+use embassy_sync::waitqueue::{MultiWakerRegistration, WakerRegistration};
+use embassy_time::{Duration, Instant, Timer};
 use portable_atomic::AtomicBool;
 
-/// The buffer size.
+use crate::{
+    channel::DEFMT_CHANNEL,
+    filter::Level,
+    forward::LOCAL_SOURCE,
+    ident::FirmwareIdent,
+    persist::{self, PersistentHeader},
+    stats::LoggerStats,
+    transform::{FrameTransform, MAX_FRAME_AFFIX},
+    vendor::ResetRequest,
+};
+
+/// The size of the default buffer.
 #[cfg(feature = "buffersize-64")]
 const BUFFERSIZE: usize = 64;
 
@@ -21,36 +34,1049 @@ const BUFFERSIZE: usize = 512;
 #[cfg(feature = "buffersize-1024")]
 const BUFFERSIZE: usize = 1024;
 
-/// The global ring buffer.
-pub(super) static RING_BUFFER: AsyncBuffer<BUFFERSIZE> = AsyncBuffer::new();
+#[cfg(not(any(
+    feature = "buffersize-64",
+    feature = "buffersize-128",
+    feature = "buffersize-256",
+    feature = "buffersize-512",
+    feature = "buffersize-1024",
+)))]
+const BUFFERSIZE: usize = 0;
+
+/// The default ring buffer storage, used until [`init`] is called.
+///
+/// Its contents are only ever written before being read, so it may be placed in a `NOLOAD`
+/// section.
+#[cfg_attr(feature = "link-section", unsafe(link_section = ".defmt_usbserial"))]
+static DEFAULT_BUFFER: DefaultBuffer = DefaultBuffer(UnsafeCell::new([0; BUFFERSIZE]));
+
+/// Wrapper to allow the default buffer to be stored in a static.
+struct DefaultBuffer(UnsafeCell<[u8; BUFFERSIZE]>);
+
+unsafe impl Sync for DefaultBuffer {}
+
+/// Size of the length prefix stored before each frame in the ring buffer.
+pub(crate) const HEADER_LEN: usize = 2;
+
+/// Largest frame that fits in a record.
+const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
+/// Size of the trailer stored after each frame in the ring buffer.
+#[cfg(feature = "crc-framing")]
+const TRAILER_LEN: usize = crate::crc::CRC_LEN;
+#[cfg(not(feature = "crc-framing"))]
+const TRAILER_LEN: usize = 0;
+
+/// Largest number of bytes of records that [`Controller::move_to_front`] moves.
+const MAX_MOVED_LEN: usize = 64;
+
+/// What to do when a frame does not fit in the ring buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum OverflowPolicy {
+    /// Drop the frame being written, keeping the frames already queued.
+    ///
+    /// This is the default.
+    DropNewest,
+    /// Drop the oldest queued frames until the frame being written fits.
+    ///
+    /// This keeps the most recent messages, which are usually the most useful when debugging a
+    /// crash. If the USB task is part-way through sending the oldest frame, the rest of it is
+    /// dropped and a `0x00` frame delimiter is sent in its place so that an rzcobs decoder on the
+    /// host resynchronizes at the next frame.
+    DropOldest,
+}
+
+/// Amount of logged data dropped because the ring buffer was full.
+///
+/// Both counts wrap around on overflow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct DropCounters {
+    /// Number of frames dropped, including frames evicted under [`OverflowPolicy::DropOldest`].
+    pub frames: u32,
+    /// Number of encoded frame bytes dropped.
+    pub bytes: u32,
+}
+
+impl DropCounters {
+    /// Count a dropped frame of `len` bytes.
+    fn frame(&mut self, len: usize) {
+        self.frames = self.frames.wrapping_add(1);
+        self.bytes(len);
+    }
+
+    /// Count `len` dropped bytes.
+    fn bytes(&mut self, len: usize) {
+        self.bytes = self.bytes.wrapping_add(len as u32);
+    }
+}
+
+/// How the USB task ends a transfer whose last packet is full.
+///
+/// Some host CDC drivers only hand received data to the application at the end of a transfer,
+/// which is marked by a packet shorter than the maximum packet size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PacketTermination {
+    /// Send packets as they are, without terminating transfers that end with a full packet.
+    ///
+    /// This is the default, and works with the CDC drivers of Linux and macOS.
+    Never,
+    /// Send a zero-length packet after a full packet when nothing else is queued.
+    ZeroLengthPacket,
+    /// Never send full packets, so that every packet terminates a transfer.
+    ///
+    /// This costs one byte per packet, but avoids zero-length packets, which some host stacks
+    /// handle poorly.
+    ShortPacket,
+}
+
+/// What the USB task does with a packet that the host did not read in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum OnWriteTimeout {
+    /// Keep the packet, and send it again once the host is ready.
+    Retain,
+    /// Drop the packet and the rest of the frame it was part of.
+    ///
+    /// A `0x00` frame delimiter is sent before the next frame if the host may have received the
+    /// start of the dropped frame.
+    Discard,
+}
+
+/// Set the policy used when a defmt frame does not fit in the ring buffer.
+pub fn set_overflow_policy(policy: OverflowPolicy) {
+    CONTROLLER.with_inner(|inner| inner.policy = policy);
+}
+
+/// Set how long the logger may wait for the USB task to make room when a frame does not fit.
+///
+/// With `None` (the default) the logger never waits, and the [`OverflowPolicy`] applies
+/// immediately. With `Some(timeout)` the logger busy-waits for up to `timeout` each time a write
+/// does not fit, and only applies the overflow policy once it expires. This trades latency in the
+/// logging call for fewer lost messages, which can be useful during bring-up.
+///
+/// The critical section held by the logger is released while waiting, so that the USB task can
+/// run. This only helps if the USB task can make progress while the logging code is spinning,
+/// that is if it runs on an interrupt executor with a higher priority or on another core. Frames
+/// logged meanwhile by code that preempts the waiting logger, such as an interrupt handler or a
+/// task on an interrupt executor, are dropped, as the logger is still taken.
+pub fn set_wait_on_full(timeout: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| inner.wait_on_full = timeout);
+}
+
+/// Set how many bytes at the end of the ring buffer are reserved for emergency frames.
+///
+/// Frames logged inside [`emergency`] may use the whole ring buffer, while all other frames are
+/// limited to the space outside the reserve. This ensures that a final panic or assertion message
+/// still fits when the buffer is saturated by chatty logging. The reserve is zero by default.
+pub fn set_emergency_reserve(bytes: usize) {
+    CONTROLLER.with_inner(|inner| inner.reserve = bytes);
+}
+
+/// Log the frames emitted by `f` as emergency frames, which may use the space reserved with
+/// [`set_emergency_reserve`].
+///
+/// This is intended for the final messages of a panic handler:
+///
+/// ```no_run
+/// # fn report(info: &core::panic::PanicInfo) -> ! {
+/// defmt_embassy_usbserial::emergency(|| {
+///     defmt::error!("{}", defmt::Display2Format(info));
+/// });
+/// # loop {}
+/// # }
+/// ```
+pub fn emergency<R>(f: impl FnOnce() -> R) -> R {
+    let was_emergency = EMERGENCY.swap(true, Ordering::Relaxed);
+    let result = f();
+    EMERGENCY.store(was_emergency, Ordering::Relaxed);
+    result
+}
+
+/// Set how many bytes must be queued before the USB task starts sending them.
+///
+/// By default (with a watermark of 1) every frame is sent as soon as it is logged, which gives the
+/// lowest latency but may send many short USB packets under light load. A higher watermark, such
+/// as a quarter of the buffer, batches frames into full packets. Frames below the watermark stay
+/// queued until more are logged, or until [`drained`] is awaited. Once sending has started, the
+/// frame being sent is always finished. A watermark larger than the buffer acts as a full buffer.
+pub fn set_flush_watermark(bytes: usize) {
+    CONTROLLER.with_inner(|inner| inner.watermark = bytes.max(1));
+}
+
+/// Set the longest time a frame may stay queued below the flush watermark.
+///
+/// With `Some(deadline)`, queued frames are sent once the oldest of them has waited for
+/// `deadline`, even if fewer bytes than the [watermark](set_flush_watermark) are queued, so that
+/// sporadic messages are not held back indefinitely. With `None` (the default) only the watermark
+/// applies.
+pub fn set_flush_deadline(deadline: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| inner.flush_deadline = deadline);
+}
+
+/// Let the flush deadline adapt to the logging rate, up to `max_deadline`.
+///
+/// With `Some(max_deadline)`, frames are held back until a full USB packet is queued, or until a
+/// deadline that adapts to the rate at which frames are logged. The deadline is halved every time
+/// a full packet is sent, so that a quickly filling buffer is drained without delay, and doubled
+/// (up to `max_deadline`) every time it expires, so that sporadic messages are batched while the
+/// logger is mostly idle. This replaces [`set_flush_watermark`] and [`set_flush_deadline`] while
+/// enabled. With `None` (the default) the fixed watermark and deadline apply.
+pub fn set_adaptive_flush(max_deadline: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| {
+        inner.adaptive_max = max_deadline;
+        inner.adaptive_deadline = Duration::from_ticks(0);
+    });
+}
+
+/// Set how long the USB task may wait for more frames to fill a partially filled packet.
+///
+/// With `Some(idle)`, a packet that is not full is kept open until more frames fill it or no frame
+/// is logged for `idle`, and only then sent as a short packet. At high logging rates this fills
+/// most packets, which substantially improves throughput, at the cost of up to `idle` of added
+/// latency per packet. With `None` (the default) packets are sent as soon as they are read.
+pub fn set_packet_coalescing(idle: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| inner.coalesce = idle);
+}
+
+/// Set whether the USB task takes the next packet out of the ring buffer while the previous one
+/// is being sent.
+///
+/// With `true`, the USB task alternates between two packet buffers: while the USB driver sends
+/// one, the next packet is read into the other as soon as enough frames are queued, and sent right
+/// after. The logger then has the room of that packet in the ring buffer while the host is slow to
+/// read, and the USB task does not wait on the ring buffer between packets, which keeps a
+/// saturated stream going at the full rate of the endpoint. This takes a second packet buffer of
+/// 64 bytes, or 512 with the `high-speed` feature, in the USB task. [`drained`] and [`flush`] wait
+/// for both packets to be sent, and a packet dropped by [`set_write_timeout`] takes the one read
+/// after it along. With `false` (the default) the next packet is only read once the previous one
+/// has been sent.
+pub fn set_double_buffering(enabled: bool) {
+    CONTROLLER.with_inner(|inner| inner.double_buffering = enabled);
+}
+
+/// Set how the USB task ends a transfer whose last packet is full.
+pub fn set_packet_termination(termination: PacketTermination) {
+    CONTROLLER.with_inner(|inner| inner.termination = termination);
+}
+
+/// Set how long the USB task waits for the host to read each packet.
+///
+/// With `Some(timeout)`, a packet that the host has not read after `timeout` is handled as set by
+/// `on_timeout`, and the USB task checks again that the host is ready before sending anything
+/// else. This keeps the task from blocking indefinitely part-way through a frame when the host
+/// stops reading. With `None` (the default) the task waits for as long as it takes.
+///
+/// The USB driver may have already queued a packet that timed out, in which case the host may
+/// receive it twice with [`OnWriteTimeout::Retain`].
+pub fn set_write_timeout(timeout: Option<Duration>, on_timeout: OnWriteTimeout) {
+    CONTROLLER.with_inner(|inner| inner.write_timeout = timeout.map(|t| (t, on_timeout)));
+}
+
+/// Set how long the host may go without reading before the queued frames are considered out of
+/// date.
+///
+/// With `Some(timeout)`, if the host does not read anything for `timeout`, for example because
+/// `defmt-print` was closed, the queued frames are dropped and sending resumes at the next frame
+/// boundary. This keeps a host that starts reading again from first receiving old messages. The
+/// timeout only runs once the host has read something, so the frames queued before then are
+/// always kept. With `None` (the default) nothing is dropped. Frames dropped this way are not
+/// counted by [`drop_counters`].
+pub fn set_stale_timeout(timeout: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| inner.stale_timeout = timeout);
+}
+
+/// Set how long `defmt::flush()` may wait for the USB task to send every queued frame.
+///
+/// With `None` (the default) `defmt::flush()` returns immediately. With `Some(timeout)` it
+/// busy-waits until the queued frames have been sent or `timeout` expires, which makes it useful
+/// right before a reset. As with [`set_wait_on_full`], the critical section held by the logger is
+/// released while waiting, this only helps if the USB task can run while the caller spins, and
+/// frames logged meanwhile by code that preempts the caller are dropped.
+pub fn set_flush_timeout(timeout: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| inner.flush_timeout = timeout);
+}
+
+/// Set how often the logger task logs a summary of its throughput and drop counts.
+///
+/// With `Some(interval)`, the USB task logs an info message every `interval` while the host is
+/// connected, so that transport problems of long-running devices show up in their logs. With
+/// `None` (the default) it does not.
+pub fn set_self_report_interval(interval: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| inner.self_report = interval);
+}
+
+/// Set whether frames are kept while the USB device is disconnected.
+///
+/// By default the queued frames are dropped when the USB device is disconnected, along with those
+/// logged until it is connected again, so that the host only receives current messages. With
+/// `true`, frames keep being queued while disconnected, evicting the oldest ones when the buffer is
+/// full as with [`OverflowPolicy::DropOldest`], and the backlog is sent once the host reconnects.
+/// Only the rest of a frame that was part-way through being sent is dropped.
+pub fn set_buffer_while_disconnected(buffer: bool) {
+    CONTROLLER.with_inner(|inner| inner.buffer_while_disconnected = buffer);
+}
+
+/// Set whether frames are kept while the host has the serial port closed.
+///
+/// The logger task only sends while the host asserts DTR and RTS, that is while a program such as
+/// `defmt-print` has the port open. By default frames logged while the port is closed are queued,
+/// and sent once it is opened again. With `false`, closing the port drops the queued frames, gives
+/// up on a packet the host has not read yet, and drops the frames logged until the port is opened
+/// again, so that nothing is left to flow out of a port that nobody reads. Frames logged before
+/// the port is first opened are always kept. A packet given up on this way may be sent twice if
+/// the host changes the line state without closing the port, as with [`OnWriteTimeout::Retain`].
+pub fn set_buffer_while_closed(buffer: bool) {
+    CONTROLLER.with_inner(|inner| inner.buffer_while_closed = buffer);
+}
+
+/// Set the sequence the host must send over the serial port before anything is sent to it.
+///
+/// Terminal programs, and ModemManager on Linux, may open any serial port that appears and read
+/// from it, consuming and corrupting the defmt stream before the real tool attaches. With
+/// `Some(sequence)`, the logger task keeps quiet, queuing frames as usual, until the host sends
+/// `sequence`, such as [`DEFAULT_START_SEQUENCE`], and then starts sending. With `backlog`, the
+/// frames queued until then are sent first, otherwise they are dropped. The host must send the
+/// sequence again every time it opens the port. The data the host sends before the end of the
+/// sequence is not passed to the callback of [`run_with_receiver`]. With `None` (the default) the
+/// logger task starts sending as soon as the host opens the port.
+///
+/// [`DEFAULT_START_SEQUENCE`]: crate::DEFAULT_START_SEQUENCE
+/// [`run_with_receiver`]: crate::run_with_receiver
+pub fn set_start_handshake(sequence: Option<&'static [u8]>, backlog: bool) {
+    CONTROLLER.with_inner(|inner| inner.start_handshake = sequence.map(|s| (s, backlog)));
+}
+
+/// Set whether the host can pause and resume the stream by sending XOFF and XON.
+///
+/// Terminal programs on slow machines may not keep up with the stream, and many apply
+/// backpressure by sending [`XOFF`] when their buffers fill up, and [`XON`] once they have caught
+/// up. With `true`, the logger task stops sending after it receives [`XOFF`], queuing frames as
+/// usual, and resumes after it receives [`XON`] or the host closes the serial port. The packet
+/// being sent when [`XOFF`] arrives is still sent. The host must send these bytes over the serial
+/// port, so this requires [`run_with_receiver`] or [`DefmtClass::run`], and they are removed from
+/// the data passed to the callback of [`run_with_receiver`], which can then no longer contain
+/// them. Disabled by default.
+///
+/// [`XON`]: crate::XON
+/// [`XOFF`]: crate::XOFF
+/// [`run_with_receiver`]: crate::run_with_receiver
+/// [`DefmtClass::run`]: crate::DefmtClass::run
+pub fn set_software_flow_control(enabled: bool) {
+    CONTROLLER.with_inner(|inner| inner.software_flow_control = enabled);
+}
+
+/// Set whether the logger task logs a session marker every time the host connects.
+///
+/// The marker is an info message with the number of connections since the device booted, which
+/// starts at 1 again after a reset, and the reason for the reset if set with [`set_reset_reason`].
+/// Host tooling can use it to detect reboots and split logs per session. It is not logged by
+/// default.
+pub fn set_session_marker(enabled: bool) {
+    CONTROLLER.with_inner(|inner| inner.session_marker = enabled);
+}
+
+/// Set the reason for the last reset, reported in the session marker.
+///
+/// See [`set_session_marker`].
+pub fn set_reset_reason(reason: &'static str) {
+    CONTROLLER.with_inner(|inner| inner.reset_reason = Some(reason));
+}
+
+/// Set the firmware build reported with [`log_ident`].
+///
+/// With `Some`, the logger task also logs the ident every time the host connects, after the
+/// session marker if enabled, so that the host learns which build it is talking to without asking.
+/// There is no ident by default.
+///
+/// [`log_ident`]: crate::log_ident
+pub fn set_firmware_ident(ident: Option<FirmwareIdent>) {
+    CONTROLLER.with_inner(|inner| inner.firmware_ident = ident);
+}
+
+/// Set the ID of the firmware build, such as its GNU build ID from [`gnu_build_id`] or a hash of
+/// the user's choice.
+///
+/// The host can read it with the vendor control request [`VENDOR_REQUEST_BUILD_ID`] as soon as
+/// the device enumerates, before reading anything from the serial port, and it is also part of
+/// the ident logged with [`log_ident`]. A host wrapper can compare it with the build ID of its
+/// ELF files to pick the right one to decode the stream with, rather than decoding garbage with
+/// the wrong one. There is no build ID by default.
+///
+/// [`gnu_build_id`]: crate::gnu_build_id
+/// [`VENDOR_REQUEST_BUILD_ID`]: crate::VENDOR_REQUEST_BUILD_ID
+/// [`log_ident`]: crate::log_ident
+pub fn set_build_id(build_id: Option<&'static [u8]>) {
+    CONTROLLER.with_inner(|inner| inner.build_id = build_id);
+}
+
+/// Set whether every packet starts with a header carrying a sequence number.
+///
+/// With `true`, each packet sent to the host starts with a three-byte header: the length of the
+/// rest of the packet as a `u8`, followed by a little-endian `u16` sequence number that is
+/// incremented for every packet read from the ring buffer. A host-side shim can strip the headers
+/// before passing the stream to `defmt-print`, and detect packets lost to timeouts or USB errors
+/// as gaps in the sequence. The stream can no longer be decoded directly while this is enabled,
+/// which is not the case by default.
+pub fn set_chunk_headers(enabled: bool) {
+    CONTROLLER.with_inner(|inner| inner.chunk_headers = enabled);
+}
+
+/// Set whether the stream is split into channels, so that the application can send data of its
+/// own with [`Channel`] interleaved with the defmt frames.
+///
+/// With `true`, every frame is sent as its length, as a little-endian `u16`, followed by the
+/// number of its channel and its data: [`DEFMT_CHANNEL`] and the encoded frame for defmt frames,
+/// or the number of a [`Channel`] and the bytes written to it. The length counts the channel
+/// number and the data, along with the CRC that follows with the `crc-framing` feature. The host
+/// must split the stream by channel before passing channel 0 to `defmt-print`. Enable it before
+/// anything is logged, as frames already queued are sent as they were queued. It is disabled by
+/// default.
+///
+/// [`Channel`]: crate::Channel
+/// [`DEFMT_CHANNEL`]: crate::DEFMT_CHANNEL
+pub fn set_channels(enabled: bool) {
+    CONTROLLER.with_inner(|inner| inner.channels = enabled);
+}
+
+/// Set whether every defmt frame is tagged with the ID of its source, so that the host can tell
+/// the frames of several producers apart.
+///
+/// With `true`, every defmt frame starts with a byte holding the ID of its source, ahead of the
+/// prefix of the [`FrameTransform`] and after the channel number of [`set_channels`]: the frames
+/// of the logger have [`LOCAL_SOURCE`], and those queued with [`write_frame_from`] have the ID
+/// passed to it, such as that of another core or of a microcontroller forwarded with
+/// [`forward_uart`]. Without the length sent with [`set_channels`] or the `crc-framing` feature,
+/// the tag is the first byte after each `0x00` delimiter. The host must strip the tags before
+/// passing the frames to `defmt-print`, and can label or split the log with them. Enable it before
+/// anything is logged, as frames already queued are sent as they were queued. It is disabled by
+/// default.
+///
+/// [`LOCAL_SOURCE`]: crate::LOCAL_SOURCE
+/// [`write_frame_from`]: crate::write_frame_from
+/// [`forward_uart`]: crate::forward_uart
+pub fn set_source_tags(enabled: bool) {
+    CONTROLLER.with_inner(|inner| inner.source_tags = enabled);
+}
+
+/// Set how long the logger task waits after the host changes the serial line state before
+/// checking it.
+///
+/// Hosts often toggle DTR and RTS in quick succession when opening a port, so the task waits for
+/// the state to settle before it starts sending. The default is 10 ms.
+pub fn set_line_state_debounce(debounce: Duration) {
+    CONTROLLER.with_inner(|inner| inner.line_state_debounce = debounce);
+}
+
+/// Set the function called when the host requests a reset by opening the serial port at 1200 baud
+/// and closing it again.
+///
+/// This is the convention the Arduino tools use to enter the bootloader of a board: with
+/// `Some(handler)`, the logger task calls `handler` when the host drops DTR while the line coding
+/// is set to 1200 baud. The handler typically resets into the ROM or UF2 bootloader, for example
+/// with `embassy_rp::rom_data::reset_to_usb_boot`, so that the board can be flashed without
+/// pressing any button. Nothing is done by default.
+pub fn set_baud_touch(handler: Option<fn()>) {
+    CONTROLLER.with_inner(|inner| inner.baud_touch = handler);
+}
+
+/// Set the function called when the host requests a reset with a vendor control request.
+///
+/// Host tooling can reset the device, or reset it into its bootloader to flash it, by sending a
+/// vendor request to the device with no data and a `bRequest` of [`VENDOR_REQUEST_RESET`] or
+/// [`VENDOR_REQUEST_BOOTLOADER`]. With `Some(handler)`, `handler` is called with the
+/// [`ResetRequest`] and does the actual reset, which keeps the magic baud rates of
+/// [`set_baud_touch`] out of the way. The logger task calls the handler a few milliseconds after
+/// the request has been acknowledged, so that the host does not see it fail, and the handler may
+/// reset right away. The logger task of [`DefmtClass`], [`DefmtBulkClass`] or [`DefmtHidClass`]
+/// must be running for that. With `None` (the default) the requests are left to the other
+/// handlers of the device, if any.
+///
+/// [`VENDOR_REQUEST_RESET`]: crate::VENDOR_REQUEST_RESET
+/// [`VENDOR_REQUEST_BOOTLOADER`]: crate::VENDOR_REQUEST_BOOTLOADER
+/// [`DefmtClass`]: crate::DefmtClass
+/// [`DefmtBulkClass`]: crate::DefmtBulkClass
+/// [`DefmtHidClass`]: crate::DefmtHidClass
+pub fn set_reset_handler(handler: Option<fn(ResetRequest)>) {
+    CONTROLLER.with_inner(|inner| inner.reset_handler = handler);
+}
+
+/// Set whether logged frames are queued at all.
+///
+/// With `false`, frames logged from then on are discarded as they are logged, without being
+/// counted as dropped, while the frames already queued are still sent. This lets a field technician
+/// silence a chatty unit without flashing it again, either from the host with the vendor request
+/// [`VENDOR_REQUEST_SET_LOGGING`], or with an application command such as one registered with a
+/// [`CommandDispatcher`]. Logging is enabled by default.
+///
+/// [`VENDOR_REQUEST_SET_LOGGING`]: crate::VENDOR_REQUEST_SET_LOGGING
+/// [`CommandDispatcher`]: crate::CommandDispatcher
+pub fn set_logging_enabled(enabled: bool) {
+    CONTROLLER.with_inner(|inner| inner.enabled = enabled);
+}
+
+/// Whether logged frames are queued, see [`set_logging_enabled`].
+pub fn logging_enabled() -> bool {
+    CONTROLLER.with_inner(|inner| inner.enabled)
+}
+
+/// Set the baud rates with which the host selects the minimum level of the log statements whose
+/// frames are queued.
+///
+/// The baud rate means nothing to a USB serial port, so any serial terminal can use it to adjust
+/// the volume of the logs without a protocol of its own: when the host sets one of the baud rates
+/// of `presets`, the logger task calls [`set_min_level`] with the level paired with it. For
+/// example, [`DEFAULT_VERBOSITY_PRESETS`] has 9600 baud queue errors only, and 115200 baud queue
+/// everything. Other baud rates leave the level as it is. There are no presets by default.
+///
+/// [`set_min_level`]: crate::set_min_level
+/// [`DEFAULT_VERBOSITY_PRESETS`]: crate::DEFAULT_VERBOSITY_PRESETS
+pub fn set_verbosity_presets(presets: &'static [(u32, Level)]) {
+    CONTROLLER.with_inner(|inner| inner.verbosity_presets = presets);
+}
+
+/// Set how long the logger task waits for the USB device to be connected before writing the
+/// frames to RTT instead, or `None` to write every frame to RTT as well as to the ring buffer.
+///
+/// With `Some`, frames are only written to RTT while the fallback is active. It becomes active
+/// when the logger task has waited for `window` without the host configuring the device, for
+/// example because no host is attached, the cable is broken or only supplies power, and the frames
+/// still queued are then written to RTT as well, so that early boot messages are not lost. It
+/// stops being active once the device is connected. The queued frames are only written if they are
+/// plain defmt frames, without a [`FrameTransform`], channels, source tags or CRC framing. The
+/// default is `None`.
+#[cfg(feature = "rtt")]
+pub fn set_rtt_fallback(window: Option<Duration>) {
+    CONTROLLER.with_inner(|inner| {
+        inner.rtt_fallback = window;
+        inner.rtt_active = false;
+    });
+}
+
+/// Pass every frame through `transform` on its way into the ring buffer.
+///
+/// See [`FrameTransform`]. Set it before anything is logged, as frames already queued are sent as
+/// they are, and the host has no way of telling where the transformed frames start.
+pub fn set_frame_transform(transform: &'static mut dyn FrameTransform) {
+    CONTROLLER.with_inner(|inner| inner.transform = Some(transform));
+}
+
+/// Provide the storage for the ring buffer.
+///
+/// This lets the application choose the size and placement of the buffer at runtime, instead of
+/// using the static buffer sized by the `buffersize-*` features. Build without any of those
+/// features to avoid reserving the static buffer, in which case all messages are dropped until
+/// this is called. Call it early, for example at the start of `main`.
+///
+/// Frames already queued are moved into the new buffer if they fit, and dropped otherwise.
+pub fn init(buf: &'static mut [u8]) {
+    // SAFETY: The buffer is valid forever, and we have exclusive access to it.
+    unsafe { CONTROLLER.replace_storage(buf.as_mut_ptr(), buf.len()) };
+}
+
+/// Allocate the storage for the ring buffer from the global allocator.
+///
+/// This is equivalent to calling [`init`] with a leaked `size`-byte allocation, and is useful for
+/// large buffers in applications that already have an allocator. As with [`init`], build without
+/// any `buffersize-*` feature to avoid also reserving the static buffer.
+#[cfg(feature = "alloc")]
+pub fn init_alloc(size: usize) {
+    init(alloc::vec![0; size].leak());
+}
+
+/// Number of bytes queued in the ring buffer, including a small per-frame overhead.
+///
+/// Together with [`free_space`], this lets the application throttle verbose logging when the
+/// buffer is nearly full.
+pub fn fill_level() -> usize {
+    CONTROLLER.with_inner(|inner| inner.committed)
+}
+
+/// Largest number of bytes ever queued in the ring buffer, as returned by [`fill_level`].
+///
+/// Compare it with the size of the buffer to tell how close the logger came to dropping frames,
+/// and size the buffer accordingly.
+pub fn high_watermark() -> usize {
+    CONTROLLER.with_inner(|inner| inner.high_watermark)
+}
+
+/// Reset the value returned by [`high_watermark`] to the current fill level.
+pub fn reset_high_watermark() {
+    CONTROLLER.with_inner(|inner| inner.high_watermark = inner.committed);
+}
+
+/// Number of bytes available in the ring buffer for new frames, excluding any emergency reserve.
+///
+/// Each frame takes two bytes more than its encoded length.
+pub fn free_space() -> usize {
+    CONTROLLER.with_inner(|inner| inner.free())
+}
+
+/// Wait until every queued frame has been written to the USB endpoint.
+///
+/// This resolves once the ring buffer is empty and the last packet taken from it has been
+/// accepted by the USB driver, so that the application can make sure its logs are delivered
+/// before entering deep sleep or resetting. Frames logged while waiting are waited for as well.
+///
+/// Nothing is sent while the host is not connected, so this does not resolve until it connects.
+pub async fn drained() {
+    poll_fn(|cx| {
+        CONTROLLER.with_inner(|inner| {
+            if inner.is_drained() {
+                Poll::Ready(())
+            } else {
+                inner.drain_waker.register(cx.waker());
+                // Send everything, regardless of the flush watermark.
+                inner.draining = true;
+                inner.waker.wake();
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/// Wait until the frames queued when this is called have been written to the USB endpoint.
+///
+/// Unlike [`drained`], this does not wait for frames logged in the meantime, so it resolves
+/// promptly even while other tasks keep logging. Use it right before a reset or entering a
+/// bootloader. Frames dropped before they could be sent are not waited for.
+pub async fn flush() {
+    let target = CONTROLLER.with_inner(|inner| inner.queued_total);
+    poll_fn(|cx| {
+        CONTROLLER.with_inner(|inner| {
+            if inner.sent_total().wrapping_sub(target) as isize >= 0 {
+                Poll::Ready(())
+            } else {
+                inner.drain_waker.register(cx.waker());
+                // Send everything, regardless of the flush watermark.
+                inner.draining = true;
+                inner.waker.wake();
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/// Amount of logged data dropped so far because the ring buffer was full.
+///
+/// Messages missing on the host that are not counted here were lost after leaving the device.
+pub fn drop_counters() -> DropCounters {
+    CONTROLLER.with_inner(|inner| inner.dropped)
+}
+
+/// Counters describing the activity of the logger so far, for reporting its health.
+pub fn stats() -> LoggerStats {
+    CONTROLLER.with_inner(|inner| LoggerStats {
+        frames_dropped: inner.dropped.frames,
+        bytes_dropped: inner.dropped.bytes,
+        fill_level: inner.committed,
+        high_watermark: inner.high_watermark,
+        ..inner.stats
+    })
+}
+
+/// When a packet was last written to the USB endpoint, or `None` if none has been yet.
+///
+/// A supervisor task can use this to detect that the USB task has stalled, for example because of
+/// a wedged endpoint: if frames are queued (see [`fill_level`]) but nothing has been sent for a
+/// while, it may reset the USB peripheral. [`LoggerStats::packets_sent`] also counts every packet
+/// sent.
+pub fn last_flush_instant() -> Option<Instant> {
+    CONTROLLER.with_inner(|inner| inner.last_flush)
+}
+
+/// Reset the counts returned by [`drop_counters`] to zero.
+pub fn reset_drop_counters() {
+    CONTROLLER.with_inner(|inner| inner.dropped = DropCounters::default());
+}
+
+/// Whether frames being logged may use the emergency reserve.
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
 
 /// The buffer controller of the logger.
 pub(super) static CONTROLLER: Controller = Controller::new();
 
 /// Controller of the buffers of the logger.
+///
+/// The ring buffer stores each defmt frame as a record made of a little-endian `u16` length
+/// followed by the frame bytes. Bytes written between [`start_frame`] and [`end_frame`] are held
+/// back from the consumer until the frame is complete, and the whole frame is dropped if it does
+/// not fit in the ring buffer. This keeps the stream decodable when the buffer overflows, and lets
+/// the consumer skip the rest of a frame it could not finish sending.
+///
+/// [`start_frame`]: Controller::start_frame
+/// [`end_frame`]: Controller::end_frame
 pub struct Controller {
-    /// The producer handle.
-    ///
-    /// The producer is initialized lazily on the first write.
-    /// It is wrapped in an `UnsafeCell` to allow interior mutability required to get a mutable
-    /// reference from a shared reference in `write`.
+    /// The ring buffer and its bookkeeping.
     ///
-    /// SAFETY: Write access to this is only obtained within a critical section (guaranteed by
-    /// `defmt::Logger`), so it is safe to act as if we have exclusive access.
-    producer: UnsafeCell<Option<AsyncProducer<'static, BUFFERSIZE>>>,
+    /// SAFETY: This is only accessed within a critical section, either the one held by the
+    /// `defmt::Logger` implementation or one taken by the consumer, so it is safe to act as if we
+    /// have exclusive access.
+    inner: UnsafeCell<Inner>,
 }
 
 unsafe impl Sync for Controller {}
 
+/// The ring buffer state shared between the logger and the USB task.
+struct Inner {
+    /// Pointer to the ring buffer storage, either the default buffer or one passed to [`init`].
+    buf: *mut u8,
+    /// Length of the ring buffer storage.
+    len: usize,
+    /// Bookkeeping to keep up to date if the storage is a persistent buffer, otherwise null.
+    persist: *mut PersistentHeader,
+    /// Index of the oldest committed byte.
+    head: usize,
+    /// Number of committed bytes, including record headers, which are visible to the consumer.
+    committed: usize,
+    /// Number of frame bytes of the record at the head that the consumer has yet to read, which
+    /// with CRC framing include its header and CRC.
+    ///
+    /// If zero, the head is at the start of a record header.
+    frame_remaining: usize,
+    /// Number of bytes of the record in progress, stored after the committed bytes.
+    pending: usize,
+    /// Whether the frame in progress has been dropped because it did not fit.
+    dropping: bool,
+    /// Whether logged frames are queued at all.
+    enabled: bool,
+    /// Whether the frame in progress is being discarded because logging is disabled, or its call
+    /// site is blocked.
+    muted: bool,
+    /// Whether a frame delimiter must be sent before the next record, because the rest of a
+    /// partially sent frame was dropped or the host has just connected.
+    resync: bool,
+    /// Transformation applied to every frame.
+    transform: Option<&'static mut dyn FrameTransform>,
+    /// How long the logger task waits for the USB device before falling back to RTT, if it does
+    /// not write every frame to RTT.
+    #[cfg(feature = "rtt")]
+    rtt_fallback: Option<Duration>,
+    /// Whether frames are written to RTT because the USB device was not connected in time.
+    #[cfg(feature = "rtt")]
+    rtt_active: bool,
+    /// The defmt frames queued for the sinks.
+    #[cfg(feature = "log-sinks")]
+    sink_queue: crate::sink::SinkQueue,
+    /// Whether the frames are replayed from a persisted log, so are not passed to RTT or the sinks.
+    replaying: bool,
+    /// CRC of the bytes of the frame in progress.
+    #[cfg(feature = "crc-framing")]
+    crc: u16,
+    /// What to do when a frame does not fit.
+    policy: OverflowPolicy,
+    /// How long the logger may wait for room before applying the overflow policy.
+    wait_on_full: Option<Duration>,
+    /// Number of bytes at the end of the buffer that only emergency frames may use.
+    reserve: usize,
+    /// Number of committed bytes at which the consumer starts reading.
+    watermark: usize,
+    /// Whether a task is waiting for everything to be sent, so the watermark does not apply.
+    draining: bool,
+    /// Longest time committed bytes may wait below the watermark.
+    flush_deadline: Option<Duration>,
+    /// Upper bound of the adaptive flush deadline, if enabled.
+    adaptive_max: Option<Duration>,
+    /// Current adaptive flush deadline.
+    adaptive_deadline: Duration,
+    /// How long the consumer may wait for more bytes to fill a packet.
+    coalesce: Option<Duration>,
+    /// How the consumer ends a transfer whose last packet is full.
+    termination: PacketTermination,
+    /// How long the consumer waits for each packet to be read, and what it does then.
+    write_timeout: Option<(Duration, OnWriteTimeout)>,
+    /// How long the host may go without reading before the queued frames are dropped.
+    stale_timeout: Option<Duration>,
+    /// How long `defmt::flush()` may wait for every queued frame to be sent.
+    flush_timeout: Option<Duration>,
+    /// How often the consumer logs a summary of its activity.
+    self_report: Option<Duration>,
+    /// Whether frames are kept while the USB device is disconnected.
+    buffer_while_disconnected: bool,
+    /// Whether the USB device has been disconnected, and not connected again yet.
+    disconnected: bool,
+    /// Whether frames are kept while the host has the serial port closed.
+    buffer_while_closed: bool,
+    /// Sequence the host must send before anything is sent, and whether the frames queued until
+    /// then are sent.
+    start_handshake: Option<(&'static [u8], bool)>,
+    /// Whether the host can pause the stream with XOFF.
+    software_flow_control: bool,
+    /// Whether the host has closed the serial port, and frames are dropped until it opens it
+    /// again.
+    port_closed: bool,
+    /// Whether the consumer logs a marker on every connection.
+    session_marker: bool,
+    /// Whether the consumer prefixes every packet with a header.
+    chunk_headers: bool,
+    /// Whether every frame starts with the number of its channel, and is sent with its length.
+    channels: bool,
+    /// Whether every defmt frame starts with the ID of its source.
+    source_tags: bool,
+    /// How long the consumer waits for the serial line state to settle.
+    line_state_debounce: Duration,
+    /// Function called when the host drops DTR at 1200 baud.
+    baud_touch: Option<fn()>,
+    /// Baud rates with which the host selects the minimum level, and their levels.
+    verbosity_presets: &'static [(u32, Level)],
+    /// Function called when the host requests a reset with a vendor control request.
+    reset_handler: Option<fn(ResetRequest)>,
+    /// Reason for the last reset, reported in the session marker.
+    reset_reason: Option<&'static str>,
+    /// Firmware build reported in the ident.
+    firmware_ident: Option<FirmwareIdent>,
+    /// ID of the firmware build.
+    build_id: Option<&'static [u8]>,
+    /// When the ring buffer last became non-empty, if there is a flush deadline.
+    queued_since: Instant,
+    /// Waker of the consumer, woken when a frame is committed.
+    waker: WakerRegistration,
+    /// Amount of data dropped because the buffer was full.
+    dropped: DropCounters,
+    /// Counts of the data queued and sent, except for the dropped data.
+    stats: LoggerStats,
+    /// Largest number of committed bytes so far.
+    high_watermark: usize,
+    /// When the consumer last sent a packet.
+    last_flush: Option<Instant>,
+    /// Number of frames dropped since the logger task last reported drops in the stream.
+    unreported_drops: u32,
+    /// Packets the consumer has taken out of the ring buffer and has yet to send, oldest first.
+    in_flight: [InFlight; 2],
+    /// Number of packets in flight, which is at most one unless double buffering is enabled.
+    in_flight_len: usize,
+    /// Whether the consumer takes the next packet while the previous one is being sent.
+    double_buffering: bool,
+    /// Wakers of the tasks waiting in [`drained`] or [`flush`], woken when a packet has been sent.
+    drain_waker: MultiWakerRegistration<4>,
+    /// Number of bytes ever committed, wrapping around on overflow.
+    queued_total: usize,
+    /// Number of committed bytes ever removed from the ring buffer, wrapping around on overflow.
+    dequeued_total: usize,
+}
+
+/// A packet the consumer has taken out of the ring buffer and has yet to send.
+#[derive(Clone, Copy)]
+struct InFlight {
+    /// Value of `dequeued_total` when the packet was taken.
+    start: usize,
+    /// Number of frame bytes in the packet.
+    bytes: usize,
+    /// Number of frames whose last byte is part of the packet.
+    frames: u32,
+    /// Whether the packet starts part-way through a frame.
+    mid_frame: bool,
+}
+
+impl InFlight {
+    /// An empty packet, taken when `dequeued_total` was `start`.
+    const fn new(start: usize, mid_frame: bool) -> Self {
+        Self {
+            start,
+            bytes: 0,
+            frames: 0,
+            mid_frame,
+        }
+    }
+}
+
 impl Controller {
     /// Static initializer.
     pub const fn new() -> Self {
         Self {
-            producer: UnsafeCell::new(None),
+            inner: UnsafeCell::new(Inner {
+                buf: DEFAULT_BUFFER.0.get() as *mut u8,
+                len: BUFFERSIZE,
+                persist: core::ptr::null_mut(),
+                head: 0,
+                committed: 0,
+                frame_remaining: 0,
+                transform: None,
+                #[cfg(feature = "rtt")]
+                rtt_fallback: None,
+                #[cfg(feature = "rtt")]
+                rtt_active: false,
+                #[cfg(feature = "log-sinks")]
+                sink_queue: crate::sink::SinkQueue::new(),
+                replaying: false,
+                #[cfg(feature = "crc-framing")]
+                crc: crate::crc::INIT,
+                pending: 0,
+                dropping: false,
+                enabled: true,
+                muted: false,
+                resync: false,
+                policy: OverflowPolicy::DropNewest,
+                wait_on_full: None,
+                reserve: 0,
+                watermark: 1,
+                draining: false,
+                flush_deadline: None,
+                adaptive_max: None,
+                adaptive_deadline: Duration::from_ticks(0),
+                coalesce: None,
+                termination: PacketTermination::Never,
+                write_timeout: None,
+                stale_timeout: None,
+                flush_timeout: None,
+                self_report: None,
+                buffer_while_disconnected: false,
+                disconnected: false,
+                buffer_while_closed: true,
+                start_handshake: None,
+                software_flow_control: false,
+                port_closed: false,
+                session_marker: false,
+                chunk_headers: false,
+                channels: false,
+                source_tags: false,
+                line_state_debounce: Duration::from_millis(10),
+                baud_touch: None,
+                verbosity_presets: &[],
+                reset_handler: None,
+                reset_reason: None,
+                firmware_ident: None,
+                build_id: None,
+                queued_since: Instant::from_ticks(0),
+                waker: WakerRegistration::new(),
+                dropped: DropCounters {
+                    frames: 0,
+                    bytes: 0,
+                },
+                unreported_drops: 0,
+                stats: LoggerStats {
+                    bytes_enqueued: 0,
+                    frames_enqueued: 0,
+                    bytes_sent: 0,
+                    frames_sent: 0,
+                    frames_dropped: 0,
+                    bytes_dropped: 0,
+                    packets_sent: 0,
+                    fill_level: 0,
+                    high_watermark: 0,
+                },
+                high_watermark: 0,
+                last_flush: None,
+                in_flight: [InFlight::new(0, false); 2],
+                in_flight_len: 0,
+                double_buffering: false,
+                drain_waker: MultiWakerRegistration::new(),
+                queued_total: 0,
+                dequeued_total: 0,
+            }),
         }
     }
 
-    /// Write defmt-encoded bytes to the ring buffer.
+    /// Run `f` with exclusive access to the ring buffer state, inside a critical section.
+    fn with_inner<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        critical_section::with(|_| {
+            // SAFETY: We are in a critical section, so we have exclusive access.
+            let inner = unsafe { &mut *self.inner.get() };
+            f(inner)
+        })
+    }
+
+    /// Switch to new ring buffer storage, returning the previous storage.
+    ///
+    /// Frames already queued are moved into the new buffer if they fit, and dropped otherwise.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for reads and writes of `len` bytes, and must not be accessed by
+    /// anything else, until it is replaced by another call to this function.
+    pub(super) unsafe fn replace_storage(&self, buf: *mut u8, len: usize) -> (*mut u8, usize) {
+        self.with_inner(|inner| {
+            // SAFETY: Guaranteed by the caller.
+            let new = unsafe { core::slice::from_raw_parts_mut(buf, len) };
+            let previous = (inner.buf, inner.len);
+            inner.replace_storage(new);
+            previous
+        })
+    }
+
+    /// Switch to persistent ring buffer storage, recovering the records it held before a reset.
+    ///
+    /// Frames already queued are added after the recovered ones if they fit, and dropped
+    /// otherwise. Returns whether any frames were recovered.
+    ///
+    /// # Safety
+    ///
+    /// `header` must be valid for reads and writes, and `data` for reads and writes of `len`
+    /// bytes, and neither may be accessed by anything else from now on.
+    pub(super) unsafe fn replace_storage_persistent(
+        &self,
+        header: *mut PersistentHeader,
+        data: *mut u8,
+        len: usize,
+    ) -> bool {
+        self.with_inner(|inner| {
+            // SAFETY: Guaranteed by the caller.
+            let recovered = unsafe { persist::recover(header, data, len) };
+
+            // Drop the rest of any frame that was part-way through being sent, so that the
+            // queued records can be moved whole.
+            let remaining = inner.frame_remaining;
+            inner.advance(remaining);
+            inner.frame_remaining = 0;
+            let (old_buf, old_len, old_head, old_committed) =
+                (inner.buf, inner.len, inner.head, inner.committed);
+
+            let (head, committed, frame_remaining) = recovered.unwrap_or((0, 0, 0));
+            inner.buf = data;
+            inner.len = len;
+            inner.head = head;
+            inner.committed = committed;
+            inner.queued_total = inner.queued_total.wrapping_add(committed);
+            // The host will not be able to decode the rest of a partially sent frame.
+            inner.advance(frame_remaining);
+
+            if old_committed > 0 && old_committed <= inner.len - inner.committed {
+                // SAFETY: The old storage remains valid, and is no longer used by anything else.
+                let old = unsafe { core::slice::from_raw_parts(old_buf, old_len) };
+                let first = core::cmp::min(old_committed, old_len - old_head);
+                inner.copy_in(inner.committed, &old[old_head..old_head + first]);
+                inner.copy_in(inner.committed + first, &old[..old_committed - first]);
+                inner.committed += old_committed;
+            } else {
+                inner.dequeued_total = inner.dequeued_total.wrapping_add(old_committed);
+            }
+
+            inner.persist = header;
+            inner.persist();
+            recovered.is_some_and(|(_, committed, frame_remaining)| committed > frame_remaining)
+        })
+    }
+
+    /// Begin a new defmt frame, reserving space for its record header.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    #[inline]
+    pub(super) unsafe fn start_frame(&self) {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        let inner = unsafe { &mut *self.inner.get() };
+        inner.start_frame(LOCAL_SOURCE);
+    }
+
+    /// Discard the current frame, without counting it as dropped.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    pub(super) unsafe fn mute_frame(&self) {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        let inner = unsafe { &mut *self.inner.get() };
+        inner.muted = true;
+        inner.pending = 0;
+        inner.dropping = false;
+        #[cfg(feature = "log-sinks")]
+        inner.sink_queue.discard_frame();
+    }
+
+    /// Write defmt-encoded bytes of the current frame to the ring buffer.
+    ///
+    /// If the bytes do not fit, the whole frame is dropped.
     ///
     /// # Safety
     ///
@@ -58,29 +1084,1533 @@ impl Controller {
     /// inside a critical section.
     #[inline]
     pub(super) unsafe fn write(&self, bytes: &[u8]) {
-        // SAFETY: We are in a critical section, so we have exclusive access to the producer.
+        // SAFETY: We are in a critical section, so we have exclusive access.
         // We wrap the dereference in an unsafe block to satisfy the `unsafe_op_in_unsafe_fn` lint.
-        let producer_opt = unsafe { &mut *self.producer.get() };
+        let inner = unsafe { &mut *self.inner.get() };
+        inner.append(bytes, true);
+    }
+
+    /// How long the logger may wait for room before applying the overflow policy.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    #[inline]
+    pub(super) unsafe fn wait_on_full(&self) -> Option<Duration> {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        unsafe { (*self.inner.get()).wait_on_full }
+    }
+
+    /// How long `defmt::flush()` may wait for every queued frame to be sent.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    #[inline]
+    pub(super) unsafe fn flush_timeout(&self) -> Option<Duration> {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        unsafe { (*self.inner.get()).flush_timeout }
+    }
+
+    /// Have the consumer send every queued frame regardless of the watermark.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    pub(super) unsafe fn start_draining(&self) {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        let inner = unsafe { &mut *self.inner.get() };
+        inner.draining = true;
+        inner.waker.wake();
+    }
+
+    /// Whether every committed frame has been sent.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    pub(super) unsafe fn is_drained(&self) -> bool {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        unsafe { (*self.inner.get()).is_drained() }
+    }
+
+    /// Whether `len` more bytes of the current frame fit without dropping anything.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    #[inline]
+    pub(super) unsafe fn has_room(&self, len: usize) -> bool {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        let inner = unsafe { &*self.inner.get() };
+        inner.dropping || len <= inner.free()
+    }
+
+    /// Finish the current defmt frame, making it visible to the consumer if it was not dropped.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure they are inside a critical section.
+    #[inline]
+    pub(super) unsafe fn end_frame(&self) {
+        // SAFETY: We are in a critical section, so we have exclusive access.
+        let inner = unsafe { &mut *self.inner.get() };
+        inner.end_frame();
+    }
+
+    /// Start queueing the defmt frames for the sinks.
+    #[cfg(feature = "log-sinks")]
+    pub(super) fn enable_sinks(&self) {
+        self.with_inner(|inner| {
+            let mid_frame = inner.frame_in_progress();
+            inner.sink_queue.enable(mid_frame);
+        });
+    }
+
+    /// Queue the defmt frames for the sinks in `storage`, dropping those queued before.
+    #[cfg(feature = "log-sinks")]
+    pub(super) fn set_sink_queue(&self, storage: &'static mut [u8]) {
+        self.with_inner(|inner| {
+            let mid_frame = inner.frame_in_progress();
+            inner.sink_queue.set_storage(storage, mid_frame);
+        });
+    }
 
-        // Lazily initialize the producer if it hasn't been already.
-        let producer = producer_opt.get_or_insert_with(|| RING_BUFFER.producer());
+    /// Wait for defmt frames queued for the sinks and move as many bytes of them as fit into
+    /// `buf`.
+    ///
+    /// Returns the number of bytes copied, which is never zero.
+    #[cfg(feature = "log-sinks")]
+    pub(super) async fn read_sink_queue(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| {
+            self.with_inner(|inner| match inner.sink_queue.read(buf, cx.waker()) {
+                0 => Poll::Pending,
+                len => Poll::Ready(len),
+            })
+        })
+        .await
+    }
+
+    /// Call `f`, within a critical section, with the frames it logs or writes not passed to RTT
+    /// or the sinks, since they are replayed from a persisted log.
+    #[cfg(any(feature = "flash-log", feature = "sd-log"))]
+    pub(super) fn replay<R>(&self, f: impl FnOnce() -> R) -> R {
+        critical_section::with(|_| {
+            self.with_inner(|inner| inner.replaying = true);
+            let result = f();
+            self.with_inner(|inner| inner.replaying = false);
+            result
+        })
+    }
 
-        let mut remaining = bytes;
-        while !remaining.is_empty() {
-            // Get writable bytes.
-            // We use try_writable_bytes because this is a synchronous context and we cannot await.
-            let mut writable = producer.try_writable_bytes();
-            // We can only write as much as is available in the contiguous slice.
-            if writable.is_empty() {
-                // Buffer full.
-                break;
+    /// Queue `frame`, a defmt frame encoded elsewhere by `source`, as if it had been logged.
+    ///
+    /// Returns whether it was queued, which it is not if logging is disabled, a frame is in
+    /// progress, or it does not fit.
+    pub(super) fn write_frame(&self, source: u8, frame: &[u8]) -> bool {
+        self.with_inner(|inner| {
+            if inner.frame_in_progress() {
+                return false;
             }
+            inner.start_frame(source);
+            inner.append(frame, true);
+            inner.end_frame()
+        })
+    }
 
-            let chunk_len = core::cmp::min(writable.len(), remaining.len());
-            writable[..chunk_len].copy_from_slice(&remaining[..chunk_len]);
-            writable.commit(chunk_len);
+    /// Queue `data` as a frame of its own on `channel`, as sent with [`set_channels`].
+    ///
+    /// Returns whether it was queued, which it is not if channels are disabled, a frame is in
+    /// progress, or it does not fit.
+    pub(super) fn write_channel(&self, channel: u8, data: &[u8]) -> bool {
+        self.with_inner(|inner| {
+            if !inner.channels || inner.frame_in_progress() {
+                return false;
+            }
+            inner.start_record();
+            inner.append(&[channel], false);
+            inner.append(data, false);
+            inner.commit()
+        })
+    }
 
-            remaining = &remaining[chunk_len..];
+    /// Wait for committed bytes and move as many as fit into `buf` out of the ring buffer.
+    ///
+    /// Returns the number of bytes copied, which is never zero.
+    pub(super) async fn read(&self, buf: &mut [u8]) -> usize {
+        loop {
+            let deadline = self.with_inner(|inner| inner.deadline());
+            let ready = poll_fn(|cx| {
+                critical_section::with(|_| {
+                    // SAFETY: We are in a critical section, so we have exclusive access.
+                    let inner = unsafe { &mut *self.inner.get() };
+                    let readable = inner.readable(buf.len());
+                    if readable || inner.deadline() <= Instant::now() {
+                        let len = inner.take_packet(buf);
+                        inner.adapt(len == buf.len(), !readable);
+                        return Poll::Ready(Some(len));
+                    }
+                    if inner.deadline() != deadline {
+                        // Start over with the new deadline.
+                        return Poll::Ready(None);
+                    }
+                    inner.waker.register(cx.waker());
+                    Poll::Pending
+                })
+            });
+            if let Either::First(Some(len)) = select(ready, Timer::at(deadline)).await {
+                return len;
+            }
         }
     }
+
+    /// Wait for any committed bytes, regardless of the watermark, and move as many as fit into
+    /// `buf` out of the ring buffer, as part of the packet returned by the last [`read`].
+    ///
+    /// Returns the number of bytes copied, which is never zero.
+    ///
+    /// [`read`]: Controller::read
+    pub(super) async fn read_any(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| {
+            critical_section::with(|_| {
+                // SAFETY: We are in a critical section, so we have exclusive access.
+                let inner = unsafe { &mut *self.inner.get() };
+                if inner.committed == 0 && !inner.resync {
+                    inner.waker.register(cx.waker());
+                    return Poll::Pending;
+                }
+                Poll::Ready(inner.take_more(buf))
+            })
+        })
+        .await
+    }
+
+    /// How long the consumer may wait for more bytes to fill a packet, if at all.
+    pub(super) fn coalesce_timeout(&self) -> Option<Duration> {
+        self.with_inner(|inner| inner.coalesce)
+    }
+
+    /// Whether the consumer takes the next packet while the previous one is being sent.
+    pub(super) fn double_buffering(&self) -> bool {
+        self.with_inner(|inner| inner.double_buffering)
+    }
+
+    /// How the consumer ends a transfer whose last packet is full.
+    pub(super) fn packet_termination(&self) -> PacketTermination {
+        self.with_inner(|inner| inner.termination)
+    }
+
+    /// How long the consumer waits for each packet to be read, and what it does then.
+    pub(super) fn write_timeout(&self) -> Option<(Duration, OnWriteTimeout)> {
+        self.with_inner(|inner| inner.write_timeout)
+    }
+
+    /// How long the host may go without reading before the queued frames are dropped.
+    pub(super) fn stale_timeout(&self) -> Option<Duration> {
+        self.with_inner(|inner| inner.stale_timeout)
+    }
+
+    /// How often the consumer logs a summary of its activity, if at all.
+    pub(super) fn self_report_interval(&self) -> Option<Duration> {
+        self.with_inner(|inner| inner.self_report)
+    }
+
+    /// Whether the consumer logs a marker on every connection, and the reason for the last reset.
+    pub(super) fn session_marker(&self) -> Option<Option<&'static str>> {
+        self.with_inner(|inner| inner.session_marker.then_some(inner.reset_reason))
+    }
+
+    /// The reason for the last reset, if set.
+    #[cfg(feature = "sd-log")]
+    pub(super) fn reset_reason(&self) -> Option<&'static str> {
+        self.with_inner(|inner| inner.reset_reason)
+    }
+
+    /// Whether the consumer prefixes every packet with a header.
+    pub(super) fn chunk_headers(&self) -> bool {
+        self.with_inner(|inner| inner.chunk_headers)
+    }
+
+    /// Whether the stream is split into channels.
+    pub(super) fn channels(&self) -> bool {
+        self.with_inner(|inner| inner.channels)
+    }
+
+    /// Whether every defmt frame is tagged with the ID of its source.
+    pub(super) fn source_tags(&self) -> bool {
+        self.with_inner(|inner| inner.source_tags)
+    }
+
+    /// How long the consumer waits for the serial line state to settle.
+    pub(super) fn line_state_debounce(&self) -> Duration {
+        self.with_inner(|inner| inner.line_state_debounce)
+    }
+
+    /// Function called when the host drops DTR at 1200 baud, if any.
+    pub(super) fn baud_touch(&self) -> Option<fn()> {
+        self.with_inner(|inner| inner.baud_touch)
+    }
+
+    /// Baud rates with which the host selects the minimum level, and their levels.
+    pub(super) fn verbosity_presets(&self) -> &'static [(u32, Level)] {
+        self.with_inner(|inner| inner.verbosity_presets)
+    }
+
+    /// Function called when the host requests a reset with a vendor control request, if any.
+    pub(super) fn reset_handler(&self) -> Option<fn(ResetRequest)> {
+        self.with_inner(|inner| inner.reset_handler)
+    }
+
+    /// Sequence the host must send before anything is sent, and whether the frames queued until
+    /// then are sent, if any.
+    pub(super) fn start_handshake(&self) -> Option<(&'static [u8], bool)> {
+        self.with_inner(|inner| inner.start_handshake)
+    }
+
+    /// Firmware build reported in the ident, if set.
+    pub(super) fn firmware_ident(&self) -> Option<FirmwareIdent> {
+        self.with_inner(|inner| inner.firmware_ident)
+    }
+
+    /// ID of the firmware build, if set.
+    pub(super) fn build_id(&self) -> Option<&'static [u8]> {
+        self.with_inner(|inner| inner.build_id)
+    }
+
+    /// Size of the ring buffer.
+    pub(super) fn buffer_size(&self) -> usize {
+        self.with_inner(|inner| inner.len)
+    }
+
+    /// Whether the host can pause the stream with XOFF.
+    pub(super) fn software_flow_control(&self) -> bool {
+        self.with_inner(|inner| inner.software_flow_control)
+    }
+
+    /// Whether frames are kept while the host has the serial port closed.
+    pub(super) fn buffer_while_closed(&self) -> bool {
+        self.with_inner(|inner| inner.buffer_while_closed)
+    }
+
+    /// Whether there are no committed bytes to read.
+    pub(super) fn is_empty(&self) -> bool {
+        self.with_inner(|inner| inner.committed == 0 && !inner.resync)
+    }
+
+    /// Record that the oldest packet returned by [`read`] and not done yet has been sent, or given
+    /// up on.
+    ///
+    /// `sent` tells whether the bytes were written to the USB endpoint.
+    ///
+    /// [`read`]: Controller::read
+    pub(super) fn packet_done(&self, sent: bool) {
+        self.with_inner(|inner| {
+            if let Some(packet) = inner.finish_packet()
+                && sent
+            {
+                let stats = &mut inner.stats;
+                stats.bytes_sent = stats.bytes_sent.wrapping_add(packet.bytes as u32);
+                stats.frames_sent = stats.frames_sent.wrapping_add(packet.frames);
+                stats.packets_sent = stats.packets_sent.wrapping_add(1);
+                inner.last_flush = Some(Instant::now());
+            }
+            if inner.is_drained() {
+                inner.draining = false;
+            }
+            inner.drain_waker.wake();
+        });
+    }
+
+    /// Give up on sending the packets returned by [`read`] that are not done yet, and drop the rest
+    /// of the frame the last one was part of.
+    ///
+    /// If the host may have received the start of a dropped frame, a frame delimiter is sent
+    /// first.
+    ///
+    /// [`read`]: Controller::read
+    pub(super) fn abandon_packet(&self) {
+        self.with_inner(|inner| {
+            let remaining = inner.frame_remaining;
+            inner.advance(remaining);
+            inner.frame_remaining = 0;
+            inner.resync |= inner.in_flight_len > 0 && inner.in_flight[0].mid_frame;
+            inner.persist();
+            inner.in_flight_len = 0;
+            inner.drain_waker.wake();
+        });
+    }
+
+    /// Drop all committed bytes, including those in flight, so that the next read starts at a frame
+    /// boundary.
+    ///
+    /// If the host may have received the start of a frame, a frame delimiter is sent first.
+    pub(super) fn clear(&self) {
+        self.with_inner(|inner| {
+            let mid_frame = if inner.in_flight_len > 0 {
+                inner.in_flight[0].mid_frame
+            } else {
+                inner.frame_remaining > 0
+            };
+            let committed = inner.committed;
+            inner.advance(committed);
+            inner.frame_remaining = 0;
+            inner.resync |= mid_frame;
+            inner.persist();
+            inner.in_flight_len = 0;
+            inner.drain_waker.wake();
+        });
+    }
+
+    /// Record that the USB device has been disconnected, dropping the queued frames unless they are
+    /// to be kept while disconnected.
+    pub(super) fn disconnected(&self) {
+        let keep = self.with_inner(|inner| {
+            inner.disconnected = true;
+            inner.buffer_while_disconnected
+        });
+        if keep {
+            self.skip_partial_frame();
+        } else {
+            self.clear();
+        }
+    }
+
+    /// Record that the consumer has stopped, giving up on the packet in flight and dropping the
+    /// rest of the frame it was part of, so that a restarted consumer begins at a frame boundary.
+    pub(super) fn stopped(&self) {
+        if self.with_inner(|inner| inner.in_flight_len > 0) {
+            self.abandon_packet();
+        } else {
+            self.skip_partial_frame();
+        }
+    }
+
+    /// Record that the USB device has been connected, dropping the frames logged while it was
+    /// disconnected unless they are to be kept.
+    ///
+    /// The frames logged before it was first connected are always kept, so that boot messages are
+    /// not lost.
+    pub(super) fn reconnected(&self) {
+        let drop = self.with_inner(|inner| {
+            core::mem::take(&mut inner.disconnected) && !inner.buffer_while_disconnected
+        });
+        if drop {
+            self.clear();
+        }
+    }
+
+    /// Record that the host has closed the serial port, dropping the queued frames unless they are
+    /// to be kept while it is closed, and returning whether they were dropped.
+    pub(super) fn port_closed(&self) -> bool {
+        let keep = self.with_inner(|inner| {
+            inner.port_closed = !inner.buffer_while_closed;
+            inner.buffer_while_closed
+        });
+        if !keep {
+            self.clear();
+        }
+        !keep
+    }
+
+    /// Record that the host has opened the serial port again, dropping the frames logged while it
+    /// was closed unless they are to be kept.
+    pub(super) fn port_opened(&self) {
+        if self.with_inner(|inner| core::mem::take(&mut inner.port_closed)) {
+            self.clear();
+        }
+    }
+
+    /// Drop the rest of the frame the consumer is part-way through reading, if any, so that the
+    /// next read starts at a frame boundary.
+    pub(super) fn skip_partial_frame(&self) {
+        self.with_inner(|inner| {
+            let remaining = inner.frame_remaining;
+            inner.advance(remaining);
+            inner.frame_remaining = 0;
+            inner.resync |= remaining > 0;
+            inner.persist();
+        });
+    }
+
+    /// Send a frame delimiter before the next record.
+    pub(super) fn resync(&self) {
+        self.with_inner(|inner| {
+            inner.resync = true;
+            inner.waker.wake();
+        });
+    }
+
+    /// Send every committed byte, regardless of the watermark.
+    pub(super) fn drain(&self) {
+        self.with_inner(|inner| {
+            inner.draining = true;
+            inner.waker.wake();
+        });
+    }
+
+    /// Number of bytes ever committed, wrapping around on overflow.
+    pub(super) fn queued_total(&self) -> usize {
+        self.with_inner(|inner| inner.queued_total)
+    }
+
+    /// Move the records committed since [`queued_total`] returned `since` ahead of the other
+    /// committed records, after the rest of the frame the consumer is part-way through reading.
+    ///
+    /// Returns whether they were moved, which they are not if they are longer than
+    /// [`MAX_MOVED_LEN`] or there is no room in front of the head of the ring buffer.
+    ///
+    /// [`queued_total`]: Controller::queued_total
+    pub(super) fn move_to_front(&self, since: usize) -> bool {
+        self.with_inner(|inner| {
+            let len = inner.queued_total.wrapping_sub(since);
+            if len == 0
+                || len > MAX_MOVED_LEN
+                || len > inner.committed
+                || inner.committed + len > inner.len
+                || inner.pending > 0
+            {
+                return false;
+            }
+            let ring = inner.len;
+            let head = inner.head;
+            let tail = head + inner.committed - len;
+            let partial = inner.frame_remaining;
+            let new_head = (head + ring - len) % ring;
+            let buf = inner.buf();
+
+            let mut records = [0; MAX_MOVED_LEN];
+            for (i, byte) in records[..len].iter_mut().enumerate() {
+                *byte = buf[(tail + i) % ring];
+            }
+            // Shift the rest of the partial frame into the free space before the head, and put the
+            // records after it.
+            for i in 0..partial {
+                buf[(new_head + i) % ring] = buf[(head + i) % ring];
+            }
+            for (i, &byte) in records[..len].iter().enumerate() {
+                buf[(new_head + partial + i) % ring] = byte;
+            }
+            inner.head = new_head;
+            inner.persist();
+            true
+        })
+    }
+
+    /// How long the logger task waits for the USB device before falling back to RTT, if it does
+    /// not write every frame to RTT.
+    #[cfg(feature = "rtt")]
+    pub(super) fn rtt_fallback(&self) -> Option<Duration> {
+        self.with_inner(|inner| inner.rtt_fallback)
+    }
+
+    /// Start or stop writing the frames to RTT, writing the frames still queued when starting if
+    /// they are plain defmt frames.
+    #[cfg(feature = "rtt")]
+    pub(super) fn fall_back_to_rtt(&self, active: bool) {
+        self.with_inner(|inner| {
+            let starting = active && !inner.rtt_active;
+            inner.rtt_active = active;
+            if !starting || inner.sends_header() || inner.source_tags || inner.transform.is_some() {
+                return;
+            }
+            let mut chunk = [0; 32];
+            let mut offset = inner.frame_remaining;
+            while offset < inner.committed {
+                let frame_len = inner.record_len(offset);
+                let mut done = 0;
+                while done < frame_len {
+                    let len = chunk.len().min(frame_len - done);
+                    inner.peek_at(offset + HEADER_LEN + done, &mut chunk[..len]);
+                    crate::rtt::write(&chunk[..len]);
+                    done += len;
+                }
+                offset += HEADER_LEN + frame_len;
+            }
+        });
+    }
+
+    /// Copy the newest committed frames that fit into `out`, as the consumer would send them, but
+    /// leave them queued. Returns the number of bytes copied.
+    ///
+    /// The rest of a frame the consumer is part-way through reading is skipped, so the copy starts
+    /// at the start of a frame.
+    pub(super) fn snapshot(&self, out: &mut [u8]) -> usize {
+        self.with_inner(|inner| {
+            // With CRC framing or channels, the record header is sent along with the frame.
+            let sent_header = if inner.sends_header() { HEADER_LEN } else { 0 };
+            let start = inner.frame_remaining;
+            let mut total = 0;
+            let mut offset = start;
+            while offset < inner.committed {
+                let frame_len = inner.record_len(offset);
+                total += sent_header + frame_len;
+                offset += HEADER_LEN + frame_len;
+            }
+
+            // Skip the oldest frames that do not fit.
+            let mut offset = start;
+            while total > out.len() {
+                let frame_len = inner.record_len(offset);
+                total -= sent_header + frame_len;
+                offset += HEADER_LEN + frame_len;
+            }
+
+            let mut len = 0;
+            while offset < inner.committed {
+                let frame_len = inner.record_len(offset);
+                let sent_len = sent_header + frame_len;
+                inner.peek_at(
+                    offset + HEADER_LEN - sent_header,
+                    &mut out[len..len + sent_len],
+                );
+                len += sent_len;
+                offset += HEADER_LEN + frame_len;
+            }
+            len
+        })
+    }
+
+    /// Call `report` with the number of frames dropped since the last report, if any, for it to
+    /// log a frame about them.
+    ///
+    /// The frames dropped while `report` logs are left for the next report. So are the ones it
+    /// reported if its own frame was not queued, except for that frame itself if it was dropped.
+    /// This runs within a critical section, so that no other frame is logged meanwhile.
+    pub(super) fn report_drops(&self, report: impl FnOnce(u32)) {
+        critical_section::with(|_| {
+            let (frames, enqueued) = self.with_inner(|inner| {
+                let frames = core::mem::take(&mut inner.unreported_drops);
+                (frames, inner.stats.frames_enqueued)
+            });
+            if frames == 0 {
+                return;
+            }
+            report(frames);
+            self.with_inner(|inner| {
+                if inner.stats.frames_enqueued == enqueued {
+                    let unreported = inner.unreported_drops.saturating_sub(1);
+                    inner.unreported_drops = unreported.saturating_add(frames);
+                }
+            });
+        });
+    }
+}
+
+impl Inner {
+    /// Bytes of the buffer that the frame in progress may use, in total.
+    fn capacity(&self) -> usize {
+        if EMERGENCY.load(Ordering::Relaxed) {
+            self.len
+        } else {
+            self.len.saturating_sub(self.reserve)
+        }
+    }
+
+    /// Bytes available for the frame in progress.
+    fn free(&self) -> usize {
+        self.capacity()
+            .saturating_sub(self.committed + self.pending)
+    }
+
+    /// Whether the consumer should read the committed bytes now.
+    ///
+    /// `packet_len` is the largest number of bytes the consumer can read at once.
+    fn readable(&self, packet_len: usize) -> bool {
+        let watermark = if self.adaptive_max.is_some() {
+            packet_len
+        } else {
+            self.watermark
+        };
+        let watermark = watermark.min(self.capacity()).max(1);
+        self.resync
+            || self.committed >= watermark
+            || (self.committed > 0 && (self.draining || self.frame_remaining > 0))
+    }
+
+    /// Number of committed bytes ever sent or dropped, wrapping around on overflow.
+    fn sent_total(&self) -> usize {
+        if self.in_flight_len > 0 {
+            self.in_flight[0].start
+        } else {
+            self.dequeued_total
+        }
+    }
+
+    /// Whether every committed byte has been sent.
+    fn is_drained(&self) -> bool {
+        self.committed == 0 && !self.resync && self.in_flight_len == 0
+    }
+
+    /// When the committed bytes must be read even if they are below the watermark.
+    fn deadline(&self) -> Instant {
+        match self.flush_deadline() {
+            Some(deadline) if self.committed > 0 => self.queued_since + deadline,
+            _ => Instant::MAX,
+        }
+    }
+
+    /// Longest time committed bytes may wait below the watermark, if any.
+    fn flush_deadline(&self) -> Option<Duration> {
+        match self.adaptive_max {
+            Some(_) => Some(self.adaptive_deadline),
+            None => self.flush_deadline,
+        }
+    }
+
+    /// Adapt the flush deadline after a read that filled a packet if `full`, or that was forced by
+    /// the deadline if `expired`.
+    fn adapt(&mut self, full: bool, expired: bool) {
+        let Some(max) = self.adaptive_max else {
+            return;
+        };
+        if full {
+            self.adaptive_deadline /= 2;
+        } else if expired {
+            let doubled = (self.adaptive_deadline * 2).max(Duration::from_millis(1));
+            self.adaptive_deadline = doubled.min(max);
+        }
+    }
+
+    /// Ensure `len` bytes are free for the frame in progress, evicting the oldest records if the
+    /// overflow policy allows it.
+    ///
+    /// Returns whether there is now enough space.
+    fn make_room(&mut self, len: usize) -> bool {
+        if self.pending + len > self.capacity() {
+            // Would not fit even in an otherwise empty buffer, so don't evict anything for it.
+            return false;
+        }
+        let keep_newest = self.disconnected && self.buffer_while_disconnected;
+        if self.policy == OverflowPolicy::DropOldest || keep_newest {
+            while len > self.free() {
+                self.evict_oldest();
+            }
+        }
+        len <= self.free()
+    }
+
+    /// Drop the oldest committed record, or the rest of it if the consumer has started reading it.
+    fn evict_oldest(&mut self) {
+        if self.frame_remaining > 0 {
+            let remaining = self.frame_remaining;
+            self.advance(remaining);
+            self.frame_remaining = 0;
+            self.resync = true;
+            self.drop_frame(remaining);
+        } else {
+            let mut header = [0; HEADER_LEN];
+            self.copy_out(&mut header);
+            let len = usize::from(u16::from_le_bytes(header));
+            self.advance(len);
+            self.drop_frame(len);
+        }
+        self.persist();
+    }
+
+    /// Whether the record header is sent along with the frame.
+    fn sends_header(&self) -> bool {
+        cfg!(feature = "crc-framing") || self.channels
+    }
+
+    /// Whether a frame has been started and not finished, so that no other may start.
+    fn frame_in_progress(&self) -> bool {
+        self.pending > 0 || self.muted || self.dropping
+    }
+
+    /// Start a defmt frame of `source`, with its channel number, its source tag and the prefix of
+    /// the frame transform.
+    fn start_frame(&mut self, source: u8) {
+        self.pending = 0;
+        self.muted = !self.enabled;
+        if self.muted {
+            return;
+        }
+        self.start_record();
+        if self.channels {
+            self.append(&[DEFMT_CHANNEL], false);
+        }
+        if self.source_tags {
+            self.append(&[source], false);
+        }
+
+        if let Some(transform) = self.transform.take() {
+            let mut prefix = [0; MAX_FRAME_AFFIX];
+            let len = transform.start_frame(&mut prefix).min(MAX_FRAME_AFFIX);
+            self.transform = Some(transform);
+            self.append(&prefix[..len], false);
+        }
+    }
+
+    /// Finish a defmt frame, with the suffix of the frame transform, and return whether it was
+    /// committed.
+    fn end_frame(&mut self) -> bool {
+        if core::mem::take(&mut self.muted) {
+            return false;
+        }
+        #[cfg(feature = "log-sinks")]
+        if !self.replaying {
+            self.sink_queue.end_frame();
+        }
+
+        if let Some(transform) = self.transform.take() {
+            let mut suffix = [0; MAX_FRAME_AFFIX];
+            let len = transform.end_frame(&mut suffix).min(MAX_FRAME_AFFIX);
+            self.transform = Some(transform);
+            if self.pending > HEADER_LEN {
+                self.append(&suffix[..len], false);
+            }
+        }
+        self.commit()
+    }
+
+    /// Start a record, reserving space for its header.
+    fn start_record(&mut self) {
+        self.dropping = !self.make_room(HEADER_LEN);
+        if !self.dropping {
+            self.pending = HEADER_LEN;
+        }
+        #[cfg(feature = "crc-framing")]
+        {
+            self.crc = crate::crc::INIT;
+        }
+    }
+
+    /// Finish the frame in progress, committing it if it was not dropped, and return whether it
+    /// was committed.
+    fn commit(&mut self) -> bool {
+        // Store the CRC after the frame, dropping the frame if it does not fit.
+        #[cfg(feature = "crc-framing")]
+        if !self.dropping && self.pending > HEADER_LEN {
+            if self.make_room(crate::crc::CRC_LEN) {
+                self.copy_in(self.committed + self.pending, &self.crc.to_le_bytes());
+                self.pending += crate::crc::CRC_LEN;
+            } else {
+                self.dropped.bytes(self.pending - HEADER_LEN);
+                self.dropping = true;
+            }
+        }
+
+        let committed = !self.dropping && self.pending > HEADER_LEN;
+        if committed {
+            let len = (self.pending - HEADER_LEN) as u16;
+            self.copy_in(self.committed, &len.to_le_bytes());
+            if self.committed == 0 && self.flush_deadline().is_some() {
+                self.queued_since = Instant::now();
+            }
+            self.committed += self.pending;
+            self.high_watermark = self.high_watermark.max(self.committed);
+            self.queued_total = self.queued_total.wrapping_add(self.pending);
+            let stats = &mut self.stats;
+            stats.bytes_enqueued = stats.bytes_enqueued.wrapping_add(u32::from(len));
+            stats.frames_enqueued = stats.frames_enqueued.wrapping_add(1);
+            self.persist();
+            self.waker.wake();
+        } else if self.dropping {
+            self.drop_frame(0);
+        }
+
+        self.pending = 0;
+        self.dropping = false;
+        committed
+    }
+
+    /// Count a dropped frame of `len` bytes.
+    fn drop_frame(&mut self, len: usize) {
+        self.dropped.frame(len);
+        self.unreported_drops = self.unreported_drops.saturating_add(1);
+    }
+
+    /// Update the persistent bookkeeping, if the storage is a persistent buffer.
+    fn persist(&mut self) {
+        if !self.persist.is_null() {
+            // SAFETY: The pointer was set along with the persistent storage, which is only
+            // accessed through this struct.
+            let header = unsafe { &mut *self.persist };
+            header.store(self.head, self.committed, self.frame_remaining);
+        }
+    }
+
+    /// The ring buffer storage.
+    fn buf(&mut self) -> &mut [u8] {
+        // SAFETY: `buf` and `len` always describe either the default buffer or the buffer passed
+        // to `init`, both of which are only accessed through this struct.
+        unsafe { core::slice::from_raw_parts_mut(self.buf, self.len) }
+    }
+
+    /// Switch to new ring buffer storage, moving the committed records into it if they fit.
+    ///
+    /// Must not be called while a frame is in progress.
+    fn replace_storage(&mut self, new: &mut [u8]) {
+        let committed = self.committed;
+        let fits = committed <= new.len();
+        if fits {
+            self.copy_out(&mut new[..committed]);
+        } else {
+            self.advance(committed);
+            self.frame_remaining = 0;
+        }
+        self.buf = new.as_mut_ptr();
+        self.len = new.len();
+        self.head = 0;
+        self.committed = if fits { committed } else { 0 };
+        self.persist = core::ptr::null_mut();
+    }
+
+    /// Add `bytes` to the frame in progress, passing them through the frame transform and queueing
+    /// them for the sinks if they are `encoded` bytes of a defmt frame, rather than framing around
+    /// it.
+    ///
+    /// If the bytes do not fit, the whole frame is dropped.
+    fn append(&mut self, bytes: &[u8], encoded: bool) {
+        if self.muted {
+            return;
+        }
+        if encoded && !self.replaying {
+            #[cfg(feature = "rtt")]
+            if self.rtt_fallback.is_none() || self.rtt_active {
+                crate::rtt::write(bytes);
+            }
+            #[cfg(feature = "log-sinks")]
+            self.sink_queue.write(bytes);
+        }
+        if self.dropping {
+            self.dropped.bytes(bytes.len());
+            return;
+        }
+
+        if self.pending - HEADER_LEN + bytes.len() + TRAILER_LEN > MAX_FRAME_LEN
+            || !self.make_room(bytes.len())
+        {
+            // Buffer full, discard everything written for this frame so far.
+            self.dropped.bytes(self.pending - HEADER_LEN + bytes.len());
+            self.dropping = true;
+            self.pending = 0;
+            return;
+        }
+
+        self.copy_in(self.committed + self.pending, bytes);
+        self.pending += bytes.len();
+        if encoded && let Some(transform) = self.transform.take() {
+            let (first, second) = self.pending_tail(bytes.len());
+            transform.transform(first);
+            if !second.is_empty() {
+                transform.transform(second);
+            }
+            self.transform = Some(transform);
+        }
+        #[cfg(feature = "crc-framing")]
+        {
+            let crc = self.crc;
+            let (first, second) = self.pending_tail(bytes.len());
+            self.crc = crate::crc::update(crate::crc::update(crc, first), second);
+        }
+    }
+
+    /// The last `len` bytes of the frame in progress, split where the ring buffer wraps around.
+    fn pending_tail(&mut self, len: usize) -> (&mut [u8], &mut [u8]) {
+        let start = (self.head + self.committed + self.pending - len) % self.len;
+        let first = core::cmp::min(len, self.len - start);
+        let (before, after) = self.buf().split_at_mut(start);
+        (&mut after[..first], &mut before[..len - first])
+    }
+
+    /// Copy `bytes` into the ring buffer, `offset` bytes past the head.
+    fn copy_in(&mut self, offset: usize, bytes: &[u8]) {
+        let len = self.len;
+        let start = (self.head + offset) % len;
+        let first = core::cmp::min(bytes.len(), len - start);
+        let buf = self.buf();
+        buf[start..start + first].copy_from_slice(&bytes[..first]);
+        buf[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+    }
+
+    /// Move committed frame bytes into a new packet in flight, as with [`pop`](Inner::pop).
+    ///
+    /// If as many packets are in flight as double buffering allows, the bytes are added to the
+    /// last one instead.
+    fn take_packet(&mut self, out: &mut [u8]) -> usize {
+        let max = if self.double_buffering { 2 } else { 1 };
+        if self.in_flight_len < max {
+            let mid_frame = self.frame_remaining > 0;
+            self.in_flight[self.in_flight_len] = InFlight::new(self.dequeued_total, mid_frame);
+            self.in_flight_len += 1;
+        }
+        self.take_more(out)
+    }
+
+    /// Move committed frame bytes into the last packet in flight, or a new one if there is none,
+    /// as with [`pop`](Inner::pop).
+    fn take_more(&mut self, out: &mut [u8]) -> usize {
+        if self.in_flight_len == 0 {
+            return self.take_packet(out);
+        }
+        let (len, frames) = self.pop(out);
+        let packet = &mut self.in_flight[self.in_flight_len - 1];
+        packet.bytes += len;
+        packet.frames += frames;
+        self.persist();
+        len
+    }
+
+    /// Remove the oldest packet in flight, if any, and return it.
+    fn finish_packet(&mut self) -> Option<InFlight> {
+        if self.in_flight_len == 0 {
+            return None;
+        }
+        let packet = self.in_flight[0];
+        self.in_flight[0] = self.in_flight[1];
+        self.in_flight_len -= 1;
+        Some(packet)
+    }
+
+    /// Move committed frame bytes from the head of the ring buffer into `out`, stripping the
+    /// record headers unless CRC framing is enabled.
+    ///
+    /// Returns the number of bytes moved, and the number of frames whose last byte they include.
+    fn pop(&mut self, out: &mut [u8]) -> (usize, u32) {
+        let mut frames = 0;
+        let mut len = 0;
+        if self.resync && self.frame_remaining == 0 && !out.is_empty() {
+            out[0] = 0;
+            len += 1;
+            self.resync = false;
+        }
+        while len < out.len() && self.committed > 0 {
+            if self.frame_remaining == 0 {
+                let mut header = [0; HEADER_LEN];
+                self.peek(&mut header);
+                self.frame_remaining = usize::from(u16::from_le_bytes(header));
+                // With CRC framing or channels, the header is sent along with the frame.
+                if self.sends_header() {
+                    self.frame_remaining += HEADER_LEN;
+                } else {
+                    self.advance(HEADER_LEN);
+                }
+            }
+            let chunk_len = core::cmp::min(out.len() - len, self.frame_remaining);
+            self.copy_out(&mut out[len..len + chunk_len]);
+            self.frame_remaining -= chunk_len;
+            len += chunk_len;
+            if self.frame_remaining == 0 {
+                frames += 1;
+            }
+        }
+        (len, frames)
+    }
+
+    /// Move `out.len()` committed bytes from the head of the ring buffer into `out`.
+    fn copy_out(&mut self, out: &mut [u8]) {
+        self.peek(out);
+        self.advance(out.len());
+    }
+
+    /// Copy `out.len()` committed bytes from the head of the ring buffer into `out`, leaving
+    /// them in place.
+    fn peek(&mut self, out: &mut [u8]) {
+        let head = self.head;
+        let len = out.len();
+        let first = core::cmp::min(len, self.len - head);
+        let buf = self.buf();
+        out[..first].copy_from_slice(&buf[head..head + first]);
+        out[first..].copy_from_slice(&buf[..len - first]);
+    }
+
+    /// Copy `out.len()` committed bytes from `offset` bytes past the head of the ring buffer into
+    /// `out`, leaving them in place.
+    fn peek_at(&mut self, offset: usize, out: &mut [u8]) {
+        let len = self.len;
+        let start = (self.head + offset) % len;
+        let first = core::cmp::min(out.len(), len - start);
+        let buf = self.buf();
+        out[..first].copy_from_slice(&buf[start..start + first]);
+        let rest = out.len() - first;
+        out[first..].copy_from_slice(&buf[..rest]);
+    }
+
+    /// Frame length stored in the header of the record `offset` bytes past the head of the ring
+    /// buffer.
+    fn record_len(&mut self, offset: usize) -> usize {
+        let mut header = [0; HEADER_LEN];
+        self.peek_at(offset, &mut header);
+        usize::from(u16::from_le_bytes(header))
+    }
+
+    /// Discard `len` committed bytes from the head of the ring buffer.
+    fn advance(&mut self, len: usize) {
+        if len > 0 {
+            self.head = (self.head + len) % self.len;
+            self.committed -= len;
+            self.dequeued_total = self.dequeued_total.wrapping_add(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::mem::MaybeUninit;
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    /// Length of the test frames.
+    const FRAME_LEN: usize = 5;
+
+    /// Size of the record of a test frame in the ring buffer.
+    const RECORD_LEN: usize = HEADER_LEN + FRAME_LEN + TRAILER_LEN;
+
+    /// A controller whose ring buffer is `buf`.
+    fn controller(buf: &mut [u8]) -> Controller {
+        let controller = Controller::new();
+        // SAFETY: Every test keeps `buf` alive, and leaves it alone, while it uses the controller.
+        unsafe { controller.replace_storage(buf.as_mut_ptr(), buf.len()) };
+        controller
+    }
+
+    /// A test frame filled with `byte`.
+    fn frame(byte: u8) -> [u8; FRAME_LEN] {
+        [byte; FRAME_LEN]
+    }
+
+    /// Queue the test frame filled with `byte`, returning whether it was committed.
+    fn log(controller: &Controller, byte: u8) -> bool {
+        controller.write_frame(LOCAL_SOURCE, &frame(byte))
+    }
+
+    /// What the consumer sends for `frame`.
+    fn sent(frame: &[u8]) -> Vec<u8> {
+        let mut sent = Vec::new();
+        #[cfg(feature = "crc-framing")]
+        sent.extend_from_slice(&((frame.len() + TRAILER_LEN) as u16).to_le_bytes());
+        sent.extend_from_slice(frame);
+        #[cfg(feature = "crc-framing")]
+        sent.extend_from_slice(&crate::crc::update(crate::crc::INIT, frame).to_le_bytes());
+        sent
+    }
+
+    /// Take up to `max` bytes out of the ring buffer and send them, as the consumer does.
+    fn pop(controller: &Controller, max: usize) -> Vec<u8> {
+        let packet = take(controller, max);
+        controller.packet_done(true);
+        packet
+    }
+
+    /// Take a packet of up to `max` bytes out of the ring buffer, without sending it yet.
+    fn take(controller: &Controller, max: usize) -> Vec<u8> {
+        let mut out = vec![0; max];
+        let len = controller.with_inner(|inner| inner.take_packet(&mut out));
+        out.truncate(len);
+        out
+    }
+
+    /// Enable or disable double buffering on `controller`.
+    fn set_double_buffering_of(controller: &Controller, enabled: bool) {
+        controller.with_inner(|inner| inner.double_buffering = enabled);
+    }
+
+    fn set_policy(controller: &Controller, policy: OverflowPolicy) {
+        controller.with_inner(|inner| inner.policy = policy);
+    }
+
+    fn dropped(controller: &Controller) -> DropCounters {
+        controller.with_inner(|inner| inner.dropped)
+    }
+
+    #[test]
+    fn records_wrap_around_the_end_of_the_buffer() {
+        let mut buf = [0; 2 * RECORD_LEN + 1];
+        let controller = controller(&mut buf);
+        for byte in 1..=5 {
+            assert!(log(&controller, byte));
+            assert_eq!(pop(&controller, 64), sent(&frame(byte)));
+        }
+    }
+
+    #[test]
+    fn record_ending_at_the_end_of_the_buffer() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert_eq!(pop(&controller, sent(&frame(1)).len()), sent(&frame(1)));
+        assert!(log(&controller, 3));
+        assert_eq!(
+            controller.with_inner(|inner| inner.committed),
+            2 * RECORD_LEN
+        );
+        assert_eq!(
+            pop(&controller, 64),
+            [sent(&frame(2)), sent(&frame(3))].concat()
+        );
+        assert_eq!(controller.with_inner(|inner| inner.head), RECORD_LEN);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_frames() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert!(!log(&controller, 3));
+        let expected = DropCounters {
+            frames: 1,
+            bytes: FRAME_LEN as u32,
+        };
+        assert_eq!(dropped(&controller), expected);
+        assert_eq!(
+            pop(&controller, 64),
+            [sent(&frame(1)), sent(&frame(2))].concat()
+        );
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_oldest_frames() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        set_policy(&controller, OverflowPolicy::DropOldest);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert!(log(&controller, 3));
+        let expected = DropCounters {
+            frames: 1,
+            bytes: (FRAME_LEN + TRAILER_LEN) as u32,
+        };
+        assert_eq!(dropped(&controller), expected);
+        assert_eq!(
+            pop(&controller, 64),
+            [sent(&frame(2)), sent(&frame(3))].concat()
+        );
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_rest_of_a_partially_sent_frame() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        set_policy(&controller, OverflowPolicy::DropOldest);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert_eq!(pop(&controller, 3), sent(&frame(1))[..3]);
+        assert!(log(&controller, 3));
+        assert_eq!(dropped(&controller).frames, 1);
+        // The host is sent a delimiter to discard the start of the evicted frame.
+        assert_eq!(
+            pop(&controller, 64),
+            [vec![0], sent(&frame(2)), sent(&frame(3))].concat()
+        );
+    }
+
+    /// Report the unreported drops with `report`, returning the number reported.
+    fn report_drops(controller: &Controller, report: impl FnOnce()) -> u32 {
+        let mut reported = 0;
+        controller.report_drops(|frames| {
+            reported = frames;
+            report();
+        });
+        reported
+    }
+
+    #[test]
+    fn drops_during_a_report_are_reported_once() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert!(!log(&controller, 3));
+        pop(&controller, 64);
+        let reported = report_drops(&controller, || {
+            // The warning, then an interrupt that logs one frame and drops another.
+            assert!(log(&controller, 9));
+            assert!(log(&controller, 4));
+            assert!(!log(&controller, 5));
+        });
+        assert_eq!(reported, 1);
+        pop(&controller, 64);
+        let warn = || assert!(log(&controller, 9));
+        assert_eq!(report_drops(&controller, warn), 1);
+        assert_eq!(report_drops(&controller, warn), 0);
+    }
+
+    #[test]
+    fn drops_are_reported_again_if_the_report_is_dropped() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert!(!log(&controller, 3));
+        let reported = report_drops(&controller, || {
+            // The warning, then an interrupt that drops a frame.
+            assert!(!log(&controller, 9));
+            assert!(!log(&controller, 4));
+        });
+        assert_eq!(reported, 1);
+        // The frame dropped during the report, and the one reported, but not the report itself.
+        pop(&controller, 64);
+        let warn = || assert!(log(&controller, 9));
+        assert_eq!(report_drops(&controller, warn), 2);
+    }
+
+    #[test]
+    fn frames_logged_before_the_first_connection_are_sent_first() {
+        let mut buf = [0; 4 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        // The logger task on connection.
+        controller.reconnected();
+        controller.resync();
+        assert!(log(&controller, 3));
+        let expected = [vec![0], sent(&frame(1)), sent(&frame(2)), sent(&frame(3))];
+        assert_eq!(pop(&controller, 64), expected.concat());
+    }
+
+    #[test]
+    fn frames_logged_while_disconnected_are_dropped_unless_kept() {
+        let mut buf = [0; 4 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        controller.reconnected();
+        assert!(log(&controller, 1));
+        controller.disconnected();
+        assert!(log(&controller, 2));
+        controller.reconnected();
+        assert!(log(&controller, 3));
+        assert_eq!(pop(&controller, 64), sent(&frame(3)));
+
+        controller.with_inner(|inner| inner.buffer_while_disconnected = true);
+        assert!(log(&controller, 4));
+        controller.disconnected();
+        assert!(log(&controller, 5));
+        controller.reconnected();
+        assert_eq!(
+            pop(&controller, 64),
+            [sent(&frame(4)), sent(&frame(5))].concat()
+        );
+    }
+
+    #[test]
+    fn zero_size_buffer_drops_every_frame() {
+        let mut buf = [0; 0];
+        let controller = controller(&mut buf);
+        assert!(!log(&controller, 1));
+        set_policy(&controller, OverflowPolicy::DropOldest);
+        assert!(!log(&controller, 2));
+        assert_eq!(dropped(&controller).frames, 2);
+        assert!(pop(&controller, 64).is_empty());
+        assert_eq!(controller.snapshot(&mut [0; 64]), 0);
+    }
+
+    #[test]
+    fn undersized_buffer_drops_the_frames_that_do_not_fit() {
+        let mut buf = [0; RECORD_LEN - 1];
+        let controller = controller(&mut buf);
+        assert!(!log(&controller, 1));
+        assert_eq!(dropped(&controller).frames, 1);
+        let short = [2; FRAME_LEN - 1];
+        assert!(controller.write_frame(LOCAL_SOURCE, &short));
+        assert_eq!(pop(&controller, 64), sent(&short));
+    }
+
+    #[cfg(feature = "crc-framing")]
+    #[test]
+    fn crc_covers_frames_that_wrap_around() {
+        let mut buf = [0; RECORD_LEN + 3];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        pop(&controller, 64);
+        assert!(log(&controller, 2));
+        let crc = crate::crc::update(crate::crc::INIT, &frame(2)).to_le_bytes();
+        let len = ((FRAME_LEN + TRAILER_LEN) as u16).to_le_bytes();
+        assert_eq!(pop(&controller, 64), [&len[..], &frame(2), &crc].concat());
+    }
+
+    #[cfg(feature = "crc-framing")]
+    #[test]
+    fn frame_without_room_for_its_crc_is_dropped() {
+        let mut buf = [0; HEADER_LEN + FRAME_LEN + 1];
+        let controller = controller(&mut buf);
+        assert!(!log(&controller, 1));
+        let expected = DropCounters {
+            frames: 1,
+            bytes: FRAME_LEN as u32,
+        };
+        assert_eq!(dropped(&controller), expected);
+        assert!(pop(&controller, 64).is_empty());
+    }
+
+    #[test]
+    fn move_to_front_sends_the_moved_records_next() {
+        let mut buf = [0; 4 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert_eq!(pop(&controller, 3), sent(&frame(1))[..3]);
+        let since = controller.queued_total();
+        assert!(log(&controller, 3));
+        assert!(controller.move_to_front(since));
+        let expected = [
+            sent(&frame(1))[3..].to_vec(),
+            sent(&frame(3)),
+            sent(&frame(2)),
+        ];
+        assert_eq!(pop(&controller, 64), expected.concat());
+    }
+
+    #[test]
+    fn move_to_front_needs_room_before_the_head() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        let since = controller.queued_total();
+        assert!(log(&controller, 2));
+        assert!(!controller.move_to_front(since));
+        assert_eq!(
+            pop(&controller, 64),
+            [sent(&frame(1)), sent(&frame(2))].concat()
+        );
+    }
+
+    #[test]
+    fn snapshot_copies_the_newest_frames_and_keeps_them_queued() {
+        let mut buf = [0; 4 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        for byte in 1..=3 {
+            assert!(log(&controller, byte));
+        }
+        assert_eq!(pop(&controller, 3), sent(&frame(1))[..3]);
+
+        let sent_len = sent(&frame(1)).len();
+        let mut out = [0; 64];
+        let len = controller.snapshot(&mut out[..2 * sent_len + 1]);
+        assert_eq!(out[..len], [sent(&frame(2)), sent(&frame(3))].concat());
+        let len = controller.snapshot(&mut out[..2 * sent_len - 1]);
+        assert_eq!(out[..len], sent(&frame(3)));
+
+        let expected = [
+            sent(&frame(1))[3..].to_vec(),
+            sent(&frame(2)),
+            sent(&frame(3)),
+        ];
+        assert_eq!(pop(&controller, 64), expected.concat());
+    }
+
+    #[test]
+    fn replace_storage_moves_the_queued_frames() {
+        let mut small = [0; RECORD_LEN + 1];
+        let mut large = [0; 3 * RECORD_LEN];
+        let controller = controller(&mut small);
+        assert!(log(&controller, 1));
+        pop(&controller, 64);
+        assert!(log(&controller, 2));
+        // SAFETY: `large` outlives the controller.
+        let previous = unsafe { controller.replace_storage(large.as_mut_ptr(), large.len()) };
+        assert_eq!(previous, (small.as_mut_ptr(), small.len()));
+        assert!(log(&controller, 3));
+        assert_eq!(
+            pop(&controller, 64),
+            [sent(&frame(2)), sent(&frame(3))].concat()
+        );
+    }
+
+    #[test]
+    fn replace_storage_drops_the_frames_that_do_not_fit() {
+        let mut buf = [0; 2 * RECORD_LEN];
+        let mut tiny = [0; RECORD_LEN + 1];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        // SAFETY: `tiny` outlives the controller.
+        unsafe { controller.replace_storage(tiny.as_mut_ptr(), tiny.len()) };
+        assert!(pop(&controller, 64).is_empty());
+        assert!(log(&controller, 3));
+        assert_eq!(pop(&controller, 64), sent(&frame(3)));
+    }
+
+    #[test]
+    fn persistent_storage_recovers_the_frames_after_a_reset() {
+        let mut header = MaybeUninit::<PersistentHeader>::zeroed();
+        let mut data = [0; 3 * RECORD_LEN];
+        let mut buf = [0; 2 * RECORD_LEN];
+
+        let before = controller(&mut buf);
+        assert!(log(&before, 1));
+        // SAFETY: `header` and `data` outlive both controllers, which do not use them at once.
+        let recovered = unsafe {
+            before.replace_storage_persistent(header.as_mut_ptr(), data.as_mut_ptr(), data.len())
+        };
+        assert!(!recovered);
+        assert!(log(&before, 2));
+        assert_eq!(pop(&before, 3), sent(&frame(1))[..3]);
+
+        let after = Controller::new();
+        // SAFETY: As above.
+        let recovered = unsafe {
+            after.replace_storage_persistent(header.as_mut_ptr(), data.as_mut_ptr(), data.len())
+        };
+        assert!(recovered);
+        // The rest of the partially sent frame is dropped.
+        assert_eq!(pop(&after, 64), sent(&frame(2)));
+    }
+
+    #[test]
+    fn double_buffering_keeps_two_packets_in_flight() {
+        let mut buf = [0; 4 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        set_double_buffering_of(&controller, true);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        let first_len = sent(&frame(1)).len();
+        assert_eq!(take(&controller, first_len), sent(&frame(1)));
+        assert_eq!(take(&controller, 64), sent(&frame(2)));
+
+        // Each packet is done on its own, oldest first.
+        controller.packet_done(true);
+        controller.with_inner(|inner| {
+            assert!(!inner.is_drained());
+            assert_eq!(inner.sent_total(), RECORD_LEN);
+            assert_eq!(inner.stats.frames_sent, 1);
+        });
+        controller.packet_done(true);
+        controller.with_inner(|inner| {
+            assert!(inner.is_drained());
+            assert_eq!(inner.stats.frames_sent, 2);
+            assert_eq!(inner.stats.packets_sent, 2);
+        });
+    }
+
+    #[test]
+    fn without_double_buffering_reads_add_to_the_packet_in_flight() {
+        let mut buf = [0; 4 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        take(&controller, sent(&frame(1)).len());
+        take(&controller, 64);
+        controller.packet_done(true);
+        controller.with_inner(|inner| {
+            assert!(inner.is_drained());
+            assert_eq!(inner.stats.frames_sent, 2);
+            assert_eq!(inner.stats.packets_sent, 1);
+        });
+    }
+
+    #[test]
+    fn abandoning_a_packet_gives_up_on_the_next_one_too() {
+        let mut buf = [0; 4 * RECORD_LEN];
+        let controller = controller(&mut buf);
+        set_double_buffering_of(&controller, true);
+        assert!(log(&controller, 1));
+        assert!(log(&controller, 2));
+        assert_eq!(pop(&controller, 2), sent(&frame(1))[..2]);
+        // The first packet in flight starts part-way through frame 1, the second one part-way
+        // through frame 2.
+        let first_len = sent(&frame(1)).len() - 2;
+        assert_eq!(take(&controller, first_len), sent(&frame(1))[2..]);
+        assert_eq!(take(&controller, 2), sent(&frame(2))[..2]);
+        controller.abandon_packet();
+        assert!(log(&controller, 3));
+        // The host is sent a delimiter to discard the start of frame 1, and the rest of frame 2 is
+        // dropped.
+        assert_eq!(pop(&controller, 64), [vec![0], sent(&frame(3))].concat());
+        controller.with_inner(|inner| assert!(inner.is_drained()));
+    }
 }
@@ -0,0 +1,23 @@
+//! CRC16 used to protect each frame when CRC framing is enabled
+
+/// Initial value of the CRC of a frame.
+pub(crate) const INIT: u16 = 0xffff;
+
+/// Size of the CRC stored after each frame.
+pub(crate) const CRC_LEN: usize = 2;
+
+/// Update `crc` with `bytes`, as CRC-16/CCITT-FALSE (polynomial `0x1021`, not reflected, no final
+/// XOR).
+pub(crate) fn update(mut crc: u16, bytes: &[u8]) -> u16 {
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,199 @@
+//! A DFU runtime interface with which `dfu-util` resets the device into its bootloader
+
+use embassy_usb::{
+    Builder, Handler,
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    driver::Driver,
+    msos::{CompatibleIdFeatureDescriptor, PropertyData, RegistryPropertyFeatureDescriptor},
+    types::InterfaceNumber,
+};
+
+use crate::{
+    controller::CONTROLLER,
+    vendor::{ResetRequest, request_reset},
+};
+
+/// Device interface GUID of the DFU runtime interface, under which Windows registers it for
+/// WinUSB.
+pub const DFU_INTERFACE_GUID: &str = "{5A2D8C3E-7B41-4F0A-9E6D-2C8B1F4A7D93}";
+
+/// Device interface GUIDs of the DFU runtime interface.
+static DFU_GUIDS: [&str; 1] = [DFU_INTERFACE_GUID];
+
+/// Class code of application-specific interfaces.
+const USB_CLASS_APPLICATION: u8 = 0xfe;
+
+/// Subclass code of Device Firmware Upgrade.
+const DFU_SUBCLASS: u8 = 0x01;
+
+/// Protocol code of the runtime interface, as opposed to that of DFU mode.
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+
+/// Descriptor type of the DFU functional descriptor.
+const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+
+/// Attributes of the functional descriptor: the bootloader accepts downloads, and the device
+/// detaches from the bus by itself on `DFU_DETACH`.
+const DFU_ATTRIBUTES: u8 = 0x01 | 0x08;
+
+/// Longest time, in milliseconds, the host waits for the device to come back in DFU mode.
+const DFU_DETACH_TIMEOUT_MS: u16 = 1000;
+
+/// Largest block the bootloader accepts in a download.
+const DFU_TRANSFER_SIZE: u16 = 64;
+
+/// Class request that resets the device into its bootloader.
+const REQUEST_DFU_DETACH: u8 = 0x00;
+
+/// Class request that reads the status of the interface.
+const REQUEST_DFU_GETSTATUS: u8 = 0x03;
+
+/// Class request that reads the state of the interface.
+const REQUEST_DFU_GETSTATE: u8 = 0x05;
+
+/// State of the interface before `DFU_DETACH`.
+const STATE_APP_IDLE: u8 = 0;
+
+/// State of the interface after `DFU_DETACH`, until the device resets.
+const STATE_APP_DETACH: u8 = 1;
+
+/// Control handler of the DFU class requests.
+struct DfuHandler {
+    /// Number of the DFU runtime interface.
+    interface: InterfaceNumber,
+    /// State of the interface.
+    state: u8,
+}
+
+impl DfuHandler {
+    /// Whether `req` is a class request addressed to the DFU runtime interface.
+    fn is_ours(&self, req: &Request) -> bool {
+        (req.request_type, req.recipient) == (RequestType::Class, Recipient::Interface)
+            && req.index == u16::from(self.interface.0)
+    }
+}
+
+impl Handler for DfuHandler {
+    fn reset(&mut self) {
+        self.state = STATE_APP_IDLE;
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.is_ours(&req) || req.request != REQUEST_DFU_DETACH {
+            return None;
+        }
+        if CONTROLLER.reset_handler().is_none() {
+            return Some(OutResponse::Rejected);
+        }
+        self.state = STATE_APP_DETACH;
+        request_reset(ResetRequest::Bootloader);
+        Some(OutResponse::Accepted)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+        let response: &[u8] = match req.request {
+            // OK status, no poll timeout, and no status description.
+            REQUEST_DFU_GETSTATUS => &[0, 0, 0, 0, self.state, 0],
+            REQUEST_DFU_GETSTATE => &[self.state],
+            _ => return None,
+        };
+        let len = response.len().min(buf.len());
+        buf[..len].copy_from_slice(&response[..len]);
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+/// State of the DFU runtime interface added by [`add_dfu_runtime`], which must outlive the USB
+/// device.
+pub struct DfuRuntimeState {
+    /// Control handler of the DFU class requests.
+    handler: DfuHandler,
+}
+
+impl DfuRuntimeState {
+    /// Create the state.
+    pub const fn new() -> Self {
+        Self {
+            handler: DfuHandler {
+                interface: InterfaceNumber(0),
+                state: STATE_APP_IDLE,
+            },
+        }
+    }
+}
+
+impl Default for DfuRuntimeState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Add a DFU runtime interface to `builder`, so that `dfu-util -e`, or any other DFU 1.1 host
+/// tool, can reset the device into its bootloader.
+///
+/// The host sends `DFU_DETACH` to the interface, after which the logger task calls the handler
+/// set with [`set_reset_handler`] with [`ResetRequest::Bootloader`], as for the vendor request
+/// [`VENDOR_REQUEST_BOOTLOADER`], once the request has been acknowledged. The handler resets into
+/// a bootloader with a DFU mode interface, such as that of `embassy-usb-dfu`, after marking the
+/// DFU request in the boot state with `embassy-boot` if that is how the bootloader is entered. The
+/// request is rejected if no handler is set. The interface tells the host that the device detaches
+/// from the bus by itself, and that it comes back in DFU mode within a second.
+///
+/// Add it alongside [`DefmtClass`] in a builder of your own, after [`add_msos_descriptors`] so
+/// that Windows binds WinUSB to the interface for `dfu-util`, with [`DFU_INTERFACE_GUID`]:
+///
+/// ```ignore
+/// static STATE: StaticCell<DfuRuntimeState> = StaticCell::new();
+///
+/// let mut builder = embassy_usb::Builder::new(driver, usb_config, ...);
+/// defmt_embassy_usbserial::add_msos_descriptors(&mut builder);
+/// let defmt = DefmtClass::new(&mut builder, ...);
+/// defmt_embassy_usbserial::add_dfu_runtime(&mut builder, STATE.init(DfuRuntimeState::new()));
+/// let mut usb = builder.build();
+/// ```
+///
+/// [`set_reset_handler`]: crate::set_reset_handler
+/// [`VENDOR_REQUEST_BOOTLOADER`]: crate::VENDOR_REQUEST_BOOTLOADER
+/// [`DefmtClass`]: crate::DefmtClass
+/// [`add_msos_descriptors`]: crate::add_msos_descriptors
+pub fn add_dfu_runtime<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    state: &'d mut DfuRuntimeState,
+) {
+    {
+        let mut function =
+            builder.function(USB_CLASS_APPLICATION, DFU_SUBCLASS, DFU_PROTOCOL_RUNTIME);
+        function.msos_feature(CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+        function.msos_feature(RegistryPropertyFeatureDescriptor::new(
+            "DeviceInterfaceGUIDs",
+            PropertyData::RegMultiSz(&DFU_GUIDS),
+        ));
+        let mut interface = function.interface();
+        state.handler.interface = interface.interface_number();
+        let mut alt = interface.alt_setting(
+            USB_CLASS_APPLICATION,
+            DFU_SUBCLASS,
+            DFU_PROTOCOL_RUNTIME,
+            None,
+        );
+        let [timeout_low, timeout_high] = DFU_DETACH_TIMEOUT_MS.to_le_bytes();
+        let [size_low, size_high] = DFU_TRANSFER_SIZE.to_le_bytes();
+        // bcdDFUVersion 1.1.
+        alt.descriptor(
+            DFU_FUNCTIONAL_DESCRIPTOR,
+            &[
+                DFU_ATTRIBUTES,
+                timeout_low,
+                timeout_high,
+                size_low,
+                size_high,
+                0x10,
+                0x01,
+            ],
+        );
+    }
+    builder.handler(&mut state.handler);
+}
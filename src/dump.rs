@@ -0,0 +1,48 @@
+//! Sending everything queued at once, as a block delimited by markers in the log
+
+use crate::controller::{CONTROLLER, drop_counters, fill_level};
+
+/// Send every frame queued in the ring buffer right away, between two markers in the log.
+///
+/// The first marker, an info message with the number of bytes queued and the counts of
+/// [`drop_counters`], is placed ahead of the queued frames, after the rest of a frame being sent,
+/// and the second one, `buffer dump end`, after them. Every queued frame, including those held
+/// back by [`set_flush_watermark`] or [`set_flush_deadline`], is then sent regardless of the
+/// watermark, so that the state of a wedged system can be examined interactively. Nothing is sent
+/// while the host is not ready, as usual.
+///
+/// If the first marker cannot be placed ahead of the queued frames, because the ring buffer is
+/// nearly full, it follows them. The markers are not logged at all while logging is disabled, or
+/// if the minimum level is above info.
+///
+/// [`drop_counters`]: crate::drop_counters
+/// [`set_flush_watermark`]: crate::set_flush_watermark
+/// [`set_flush_deadline`]: crate::set_flush_deadline
+pub fn dump_buffer() {
+    critical_section::with(|_| {
+        let queued = fill_level();
+        let dropped = drop_counters();
+        let since = CONTROLLER.queued_total();
+        defmt::info!(
+            "buffer dump: {=usize} bytes queued, {=u32} frames and {=u32} bytes dropped",
+            queued,
+            dropped.frames,
+            dropped.bytes,
+        );
+        CONTROLLER.move_to_front(since);
+    });
+    defmt::info!("buffer dump end");
+    CONTROLLER.drain();
+}
+
+/// Handler of a binary command with which the host requests a [`dump_buffer`], for a
+/// [`CommandDispatcher`]. The payload is ignored. Register it under a tag of your choice:
+///
+/// ```ignore
+/// Command::Binary { tag: 0x21, handler: defmt_embassy_usbserial::dump_buffer_command }
+/// ```
+///
+/// [`CommandDispatcher`]: crate::CommandDispatcher
+pub fn dump_buffer_command(_payload: &[u8]) {
+    dump_buffer();
+}
@@ -0,0 +1,168 @@
+//! Errors reported when starting the logger
+
+use core::fmt;
+
+/// Why [`run`] could not start.
+///
+/// [`run`]: crate::run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    /// `composite_with_iads` is set, but the device class, subclass and protocol are not the
+    /// Interface Association Descriptor triplet `0xEF`, `0x02`, `0x01`, which `embassy-usb`
+    /// requires.
+    IadClassMismatch {
+        /// The configured `device_class`.
+        device_class: u8,
+        /// The configured `device_sub_class`.
+        device_sub_class: u8,
+        /// The configured `device_protocol`.
+        device_protocol: u8,
+    },
+    /// `composite_with_iads` is not set, but the device class, subclass and protocol are neither
+    /// those of a CDC device, `0x02`, `0x00`, `0x00`, nor `0x00`, `0x00`, `0x00`, which leaves the
+    /// class to each interface. Without an Interface Association Descriptor to group the two
+    /// interfaces of the serial port, any other triplet, such as that of a composite device,
+    /// `0xEF`, `0x02`, `0x01`, keeps hosts from binding their serial driver to the device.
+    NonCompositeClassMismatch {
+        /// The configured `device_class`.
+        device_class: u8,
+        /// The configured `device_sub_class`.
+        device_sub_class: u8,
+        /// The configured `device_protocol`.
+        device_protocol: u8,
+    },
+    /// `max_packet_size_0` is not 8, 16, 32 or 64.
+    InvalidMaxPacketSize(u8),
+    /// The packet size of the CDC ACM endpoints is not 8, 16, 32 or 64, or 512 with the
+    /// `high-speed` feature.
+    InvalidBulkPacketSize(u16),
+    /// [`LoggerConfig::console`] is set, but `composite_with_iads` is not, without which hosts do
+    /// not tell the two serial ports apart.
+    ///
+    /// [`LoggerConfig::console`]: crate::LoggerConfig::console
+    ConsoleWithoutIads,
+    /// The logger is already running, or was started with [`build`], and its USB buffers or
+    /// state are in use.
+    ///
+    /// [`build`]: crate::build
+    AlreadyRunning,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IadClassMismatch {
+                device_class,
+                device_sub_class,
+                device_protocol,
+            } => write!(
+                f,
+                "composite_with_iads requires the device class triplet 0xEF/0x02/0x01, \
+                 got {device_class:#04X}/{device_sub_class:#04X}/{device_protocol:#04X}"
+            ),
+            Self::NonCompositeClassMismatch {
+                device_class,
+                device_sub_class,
+                device_protocol,
+            } => write!(
+                f,
+                "without composite_with_iads the device class triplet must be 0x02/0x00/0x00 \
+                 or 0x00/0x00/0x00, \
+                 got {device_class:#04X}/{device_sub_class:#04X}/{device_protocol:#04X}"
+            ),
+            Self::InvalidMaxPacketSize(size) => {
+                write!(f, "max_packet_size_0 must be 8, 16, 32 or 64, got {size}")
+            }
+            Self::InvalidBulkPacketSize(size) => write!(
+                f,
+                "the CDC packet size must be 8, 16, 32 or 64, or 512 with the high-speed feature, \
+                 got {size}"
+            ),
+            Self::ConsoleWithoutIads => {
+                f.write_str("a console serial port requires composite_with_iads")
+            }
+            Self::AlreadyRunning => f.write_str("the USB logger is already running"),
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
+/// Check that `config` describes a device `embassy-usb` can build with a CDC ACM class.
+pub(crate) fn validate(config: &embassy_usb::Config<'_>) -> Result<(), ConfigError> {
+    let triplet = (
+        config.device_class,
+        config.device_sub_class,
+        config.device_protocol,
+    );
+    if config.composite_with_iads && triplet != (0xEF, 0x02, 0x01) {
+        return Err(ConfigError::IadClassMismatch {
+            device_class: config.device_class,
+            device_sub_class: config.device_sub_class,
+            device_protocol: config.device_protocol,
+        });
+    }
+    if !config.composite_with_iads && !matches!(triplet, (0x02, 0x00, 0x00) | (0x00, 0x00, 0x00)) {
+        return Err(ConfigError::NonCompositeClassMismatch {
+            device_class: config.device_class,
+            device_sub_class: config.device_sub_class,
+            device_protocol: config.device_protocol,
+        });
+    }
+    if !matches!(config.max_packet_size_0, 8 | 16 | 32 | 64) {
+        return Err(ConfigError::InvalidMaxPacketSize(config.max_packet_size_0));
+    }
+    Ok(())
+}
+
+/// Check that a console serial port can be added to a device with `config`, if `console` is set.
+pub(crate) fn validate_console(
+    config: &embassy_usb::Config<'_>,
+    console: bool,
+) -> Result<(), ConfigError> {
+    if console && !config.composite_with_iads {
+        return Err(ConfigError::ConsoleWithoutIads);
+    }
+    Ok(())
+}
+
+/// Check that the CDC ACM endpoints can have a maximum packet size of `packet_size`.
+pub(crate) fn validate_packet_size(packet_size: u16) -> Result<(), ConfigError> {
+    let high_speed = cfg!(feature = "high-speed") && packet_size == 512;
+    if !matches!(packet_size, 8 | 16 | 32 | 64) && !high_speed {
+        return Err(ConfigError::InvalidBulkPacketSize(packet_size));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration without IADs, with the device class triplet `triplet`.
+    fn single_function(triplet: (u8, u8, u8)) -> embassy_usb::Config<'static> {
+        let mut config = embassy_usb::Config::new(0x1209, 0x0001);
+        config.composite_with_iads = false;
+        (
+            config.device_class,
+            config.device_sub_class,
+            config.device_protocol,
+        ) = triplet;
+        config.max_packet_size_0 = 64;
+        config
+    }
+
+    #[test]
+    fn single_function_devices_accept_the_cdc_and_interface_classes() {
+        assert_eq!(validate(&single_function((0x02, 0x00, 0x00))), Ok(()));
+        assert_eq!(validate(&single_function((0x00, 0x00, 0x00))), Ok(()));
+        assert_eq!(
+            validate(&single_function((0xEF, 0x02, 0x01))),
+            Err(ConfigError::NonCompositeClassMismatch {
+                device_class: 0xEF,
+                device_sub_class: 0x02,
+                device_protocol: 0x01,
+            })
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Discarding the frames of blocked call sites, and of log statements below a minimum level
+
+use core::{cell::RefCell, sync::atomic::Ordering};
+
+use critical_section::Mutex;
+use portable_atomic::AtomicU8;
+
+/// Largest number of call sites that can be blocked at once.
+pub const MAX_BLOCKED_CALL_SITES: usize = 32;
+
+/// Operation of a filter command that unblocks every call site.
+pub const FILTER_CLEAR: u8 = 0x00;
+
+/// Operation of a filter command that blocks the call sites it lists.
+pub const FILTER_BLOCK: u8 = 0x01;
+
+/// Operation of a filter command that unblocks the call sites it lists.
+pub const FILTER_UNBLOCK: u8 = 0x02;
+
+/// Severity of a log statement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Level {
+    /// `defmt::trace!`.
+    Trace,
+    /// `defmt::debug!`.
+    Debug,
+    /// `defmt::info!`.
+    Info,
+    /// `defmt::warn!`.
+    Warn,
+    /// `defmt::error!`.
+    Error,
+}
+
+unsafe extern "C" {
+    // Markers placed by the defmt linker script between the format strings of each level, which
+    // are sorted by severity. Their addresses are interned string indices.
+    static __DEFMT_MARKER_TRACE_START: u8;
+    static __DEFMT_MARKER_DEBUG_START: u8;
+    static __DEFMT_MARKER_INFO_START: u8;
+    static __DEFMT_MARKER_WARN_START: u8;
+    static __DEFMT_MARKER_ERROR_START: u8;
+}
+
+impl Level {
+    /// All levels, from the least severe.
+    const ALL: [Self; 5] = [
+        Self::Trace,
+        Self::Debug,
+        Self::Info,
+        Self::Warn,
+        Self::Error,
+    ];
+
+    /// Interned index of the first format string of this level.
+    fn first_index(self) -> usize {
+        let marker = match self {
+            Self::Trace => &raw const __DEFMT_MARKER_TRACE_START,
+            Self::Debug => &raw const __DEFMT_MARKER_DEBUG_START,
+            Self::Info => &raw const __DEFMT_MARKER_INFO_START,
+            Self::Warn => &raw const __DEFMT_MARKER_WARN_START,
+            Self::Error => &raw const __DEFMT_MARKER_ERROR_START,
+        };
+        marker as usize
+    }
+}
+
+/// Baud rates that select each level with [`set_verbosity_presets`], from errors only at 9600 baud
+/// to everything at 115200 baud.
+///
+/// [`set_verbosity_presets`]: crate::set_verbosity_presets
+pub const DEFAULT_VERBOSITY_PRESETS: &[(u32, Level)] = &[
+    (9600, Level::Error),
+    (19200, Level::Warn),
+    (38400, Level::Info),
+    (57600, Level::Debug),
+    (115200, Level::Trace),
+];
+
+/// The least severe level of the log statements whose frames are queued, as an index into
+/// [`Level::ALL`].
+static MIN_LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+
+/// Discard the frames of the log statements less severe than `level`, before they are queued.
+///
+/// This filters on top of the levels compiled in with `DEFMT_LOG`, at runtime: for example, a
+/// device built with every level can be set to only send warnings and errors until more detail is
+/// needed. Frames that do not come from a log statement, such as those of `defmt::println!`, are
+/// always queued. Every level is queued by default. See also [`set_verbosity_presets`] to let the
+/// host choose the level with the baud rate.
+///
+/// [`set_verbosity_presets`]: crate::set_verbosity_presets
+pub fn set_min_level(level: Level) {
+    MIN_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// The least severe level of the log statements whose frames are queued, see [`set_min_level`].
+pub fn min_level() -> Level {
+    Level::ALL[usize::from(MIN_LEVEL.load(Ordering::Relaxed))]
+}
+
+/// The blocked call sites.
+struct Filter {
+    /// Interned string indices of the blocked call sites.
+    blocked: [u16; MAX_BLOCKED_CALL_SITES],
+    /// Number of entries of `blocked` in use.
+    len: usize,
+}
+
+impl Filter {
+    /// The entries in use.
+    fn blocked(&self) -> &[u16] {
+        &self.blocked[..self.len]
+    }
+}
+
+/// The blocked call sites.
+static FILTER: Mutex<RefCell<Filter>> = Mutex::new(RefCell::new(Filter {
+    blocked: [0; MAX_BLOCKED_CALL_SITES],
+    len: 0,
+}));
+
+/// Drop the frames logged from the call site whose format string has the interned index `index`,
+/// before they are queued.
+///
+/// The index is the one defmt gives the format string in the ELF file of the firmware, which
+/// `defmt-print` and other host tools also use to decode frames, so a host tool can look up the
+/// call sites to block. This saves both buffer space and USB bandwidth, unlike filtering on the
+/// host. Returns `false` if [`MAX_BLOCKED_CALL_SITES`] call sites are already blocked.
+pub fn block_call_site(index: u16) -> bool {
+    critical_section::with(|cs| {
+        let mut filter = FILTER.borrow_ref_mut(cs);
+        if filter.blocked().contains(&index) {
+            return true;
+        }
+        if filter.len == MAX_BLOCKED_CALL_SITES {
+            return false;
+        }
+        let len = filter.len;
+        filter.blocked[len] = index;
+        filter.len += 1;
+        true
+    })
+}
+
+/// Queue the frames logged from the call site with the interned index `index` again.
+pub fn unblock_call_site(index: u16) {
+    critical_section::with(|cs| {
+        let mut filter = FILTER.borrow_ref_mut(cs);
+        if let Some(position) = filter
+            .blocked()
+            .iter()
+            .position(|&blocked| blocked == index)
+        {
+            let last = filter.len - 1;
+            filter.blocked.swap(position, last);
+            filter.len = last;
+        }
+    })
+}
+
+/// Queue the frames logged from every call site again.
+pub fn clear_call_site_filter() {
+    critical_section::with(|cs| FILTER.borrow_ref_mut(cs).len = 0);
+}
+
+/// Handler of a binary command with which the host sets the call-site filter, for a
+/// [`CommandDispatcher`].
+///
+/// The payload is an operation, [`FILTER_CLEAR`], [`FILTER_BLOCK`] or [`FILTER_UNBLOCK`], followed
+/// for the last two by the interned indices of the call sites, each a little-endian `u16`. Register
+/// it under a tag of your choice:
+///
+/// ```ignore
+/// Command::Binary { tag: 0x20, handler: defmt_embassy_usbserial::call_site_filter_command }
+/// ```
+///
+/// [`CommandDispatcher`]: crate::CommandDispatcher
+pub fn call_site_filter_command(payload: &[u8]) {
+    let Some((&operation, indices)) = payload.split_first() else {
+        return;
+    };
+    let indices = indices
+        .chunks_exact(2)
+        .map(|index| u16::from_le_bytes([index[0], index[1]]));
+    match operation {
+        FILTER_CLEAR => clear_call_site_filter(),
+        FILTER_BLOCK => {
+            for index in indices {
+                if !block_call_site(index) {
+                    defmt::warn!("call-site filter full");
+                    break;
+                }
+            }
+        }
+        FILTER_UNBLOCK => indices.for_each(unblock_call_site),
+        _ => defmt::warn!("unknown call-site filter operation: {=u8:#x}", operation),
+    }
+}
+
+/// Whether the frame whose first bytes are `header` comes from a blocked call site, or from a log
+/// statement below the minimum level.
+///
+/// defmt starts every frame with the interned index of its format string, as a little-endian
+/// `u16`.
+pub(crate) fn is_blocked(header: &[u8]) -> bool {
+    let [low, high, ..] = *header else {
+        return false;
+    };
+    let index = u16::from_le_bytes([low, high]);
+    let below_level =
+        (Level::Trace.first_index()..min_level().first_index()).contains(&usize::from(index));
+    below_level || critical_section::with(|cs| FILTER.borrow_ref(cs).blocked().contains(&index))
+}
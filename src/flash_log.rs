@@ -0,0 +1,292 @@
+//! A circular log of the defmt frames in flash, for devices that run without a host
+
+use core::ops::Range;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use embassy_time::Duration;
+use embedded_storage_async::nor_flash::NorFlash;
+use static_cell::StaticCell;
+
+use crate::{
+    history::{self, Replay},
+    sink::{PipeSink, add_log_sink, coalesce},
+};
+
+/// Number of bytes of frames buffered in RAM until they are written to flash.
+pub const FLASH_LOG_BUFFER_LEN: usize = 1024;
+
+/// Largest number of bytes written to flash at once, length included.
+const CHUNK_LEN: usize = 256;
+
+/// Size of the length at the start of each chunk.
+const LEN_SIZE: usize = 2;
+
+/// Size of the header at the start of each sector: [`MAGIC`] and the sequence number of the
+/// sector.
+const SECTOR_HEADER_LEN: usize = 8;
+
+/// Start of every sector of the log.
+const MAGIC: [u8; 4] = *b"dLOG";
+
+/// Length read from erased flash, where the chunks of a sector end.
+const ERASED_LEN: u16 = 0xffff;
+
+/// How long to wait for more frames to fill a chunk.
+const COALESCE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Frames waiting to be written to flash.
+static FRAMES: Pipe<CriticalSectionRawMutex, FLASH_LOG_BUFFER_LEN> = Pipe::new();
+
+/// The sink that feeds [`FRAMES`].
+static SINK: StaticCell<PipeSink<FLASH_LOG_BUFFER_LEN>> = StaticCell::new();
+
+/// A circular log of the defmt frames in a region of flash, such as an external SPI flash, so that
+/// a device deployed without a host still keeps its most recent logs for later retrieval.
+///
+/// Create it with [`FlashLog::new`], which adds a [`LogSink`] that buffers every frame in
+/// [`FLASH_LOG_BUFFER_LEN`] bytes of RAM, and run [`FlashLog::run`] in a task of its own to write
+/// them to flash, along with [`run_log_sinks`], which feeds the sink from the queue set with
+/// [`set_log_sink_queue`]. Frames logged faster than the flash is written are dropped once the
+/// buffer is full. Only one log may be created.
+///
+/// The region is made of whole erase sectors, which are written one after the other, wrapping
+/// around from the last to the first, so that every sector is erased as often as the others and
+/// the oldest logs are overwritten first. Each sector starts with `dLOG` and a sequence number,
+/// as a little-endian `u32`, that grows by one for every sector written, from which the log
+/// resumes after the newest sector on the next boot. Then come chunks of the stream, each a
+/// little-endian `u16` length followed by that many bytes of frames, padded to the write size of
+/// the flash. An empty chunk marks the start of every boot. The stream is what `defmt-print`
+/// decodes, so the chunks of a sector can be read out, for example with a debug probe, and decoded
+/// with the ELF file of the firmware. A sector that was overwritten may leave an incomplete frame
+/// at the start of the next one, which `defmt-print` reports as malformed.
+///
+/// ```ignore
+/// static LOG: StaticCell<FlashLog<Flash>> = StaticCell::new();
+///
+/// let log = LOG.init(FlashLog::new(flash, 0x10_0000..0x20_0000).await.unwrap());
+/// let _error = log.run().await;
+/// defmt::error!("writing the flash log failed");
+/// ```
+///
+/// [`LogSink`]: crate::LogSink
+/// [`run_log_sinks`]: crate::run_log_sinks
+/// [`set_log_sink_queue`]: crate::set_log_sink_queue
+pub struct FlashLog<F> {
+    /// The flash the log is in.
+    flash: F,
+    /// Offset of the first sector of the log in the flash.
+    start: u32,
+    /// Number of sectors of the log.
+    sectors: u32,
+    /// Index of the sector being written.
+    sector: u32,
+    /// Sequence number of the sector being written.
+    sequence: u32,
+    /// Offset of the next chunk in the sector being written.
+    offset: usize,
+}
+
+impl<F: NorFlash> FlashLog<F> {
+    /// Create a log in `range` of `flash`, resuming after the newest sector already written there,
+    /// and start passing it the frames.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is not made of at least two whole erase sectors of `flash`, or if the
+    /// write or read size of `flash` is not a power of two of at most 64 bytes.
+    pub async fn new(flash: F, range: Range<u32>) -> Result<Self, F::Error> {
+        let sector_size = F::ERASE_SIZE as u32;
+        assert!(
+            range.start.is_multiple_of(sector_size)
+                && range.end.is_multiple_of(sector_size)
+                && range.end >= range.start + 2 * sector_size,
+            "the flash log must span at least two whole sectors",
+        );
+        assert!(
+            Self::align().is_power_of_two() && Self::align() <= CHUNK_LEN / 4,
+            "unsupported write or read size",
+        );
+        if let Some(sink) = SINK.try_init(PipeSink::new(&FRAMES)) {
+            add_log_sink(sink);
+        }
+
+        let mut log = Self {
+            flash,
+            start: range.start,
+            sectors: (range.end - range.start) / sector_size,
+            sector: 0,
+            sequence: 0,
+            offset: 0,
+        };
+        log.resume().await?;
+        log.write_chunk(&[]).await?;
+        Ok(log)
+    }
+
+    /// Write the frames to flash as they are logged, and replay them when the host requests it
+    /// with [`dump_history`], until reading or writing fails, returning the error.
+    ///
+    /// Frames are gathered into chunks of up to 254 bytes, each written once no more frames have
+    /// been logged for 100 ms or the chunk is full.
+    ///
+    /// [`dump_history`]: crate::dump_history
+    pub async fn run(&mut self) -> F::Error {
+        let mut data = [0u8; CHUNK_LEN - LEN_SIZE];
+        loop {
+            let result = match select(FRAMES.read(&mut data), history::requested()).await {
+                Either::First(len) => {
+                    let len = coalesce(&FRAMES, &mut data, len, COALESCE_TIMEOUT).await;
+                    self.write_chunk(&data[..len]).await
+                }
+                Either::Second(()) => self.replay().await,
+            };
+            if let Err(error) = result {
+                return error;
+            }
+        }
+    }
+
+    /// Granularity of the reads and writes.
+    fn align() -> usize {
+        F::WRITE_SIZE.max(F::READ_SIZE)
+    }
+
+    /// Offset of the start of `sector` in the flash.
+    fn sector_start(&self, sector: u32) -> u32 {
+        self.start + sector * F::ERASE_SIZE as u32
+    }
+
+    /// Find the newest sector and the end of its chunks, or start with the first sector if the
+    /// log is empty.
+    async fn resume(&mut self) -> Result<(), F::Error> {
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..self.sectors {
+            let Some(sequence) = self.read_sequence(sector).await? else {
+                continue;
+            };
+            // Sequence numbers wrap around, so compare them by their difference.
+            if newest.is_none_or(|(_, newest)| sequence.wrapping_sub(newest) as i32 > 0) {
+                newest = Some((sector, sequence));
+            }
+        }
+        match newest {
+            Some((sector, sequence)) => {
+                self.sector = sector;
+                self.sequence = sequence;
+                self.offset = self.find_end(sector).await?;
+                Ok(())
+            }
+            None => self.open_sector(0, 0).await,
+        }
+    }
+
+    /// Read the sequence number of `sector`, if it is part of the log.
+    async fn read_sequence(&mut self, sector: u32) -> Result<Option<u32>, F::Error> {
+        let mut header = [0u8; CHUNK_LEN];
+        let header = &mut header[..SECTOR_HEADER_LEN.next_multiple_of(Self::align())];
+        self.flash.read(self.sector_start(sector), header).await?;
+        if header[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+        let sequence = [header[4], header[5], header[6], header[7]];
+        Ok(Some(u32::from_le_bytes(sequence)))
+    }
+
+    /// Find the offset after the last chunk written to `sector`, which is the size of the sector
+    /// if it is full or its chunks do not make sense.
+    async fn find_end(&mut self, sector: u32) -> Result<usize, F::Error> {
+        let mut offset = SECTOR_HEADER_LEN.next_multiple_of(Self::align());
+        while offset + LEN_SIZE.next_multiple_of(Self::align()) <= F::ERASE_SIZE {
+            let len = self.read_len(sector, offset).await?;
+            if len == ERASED_LEN {
+                return Ok(offset);
+            }
+            if usize::from(len) > CHUNK_LEN - LEN_SIZE {
+                break;
+            }
+            offset += (LEN_SIZE + usize::from(len)).next_multiple_of(Self::align());
+        }
+        Ok(F::ERASE_SIZE)
+    }
+
+    /// Read the length of the chunk at `offset` in `sector`, which is [`ERASED_LEN`] past the last
+    /// chunk.
+    async fn read_len(&mut self, sector: u32, offset: usize) -> Result<u16, F::Error> {
+        let mut buf = [0u8; CHUNK_LEN];
+        let buf = &mut buf[..LEN_SIZE.next_multiple_of(Self::align())];
+        let at = self.sector_start(sector) + offset as u32;
+        self.flash.read(at, buf).await?;
+        Ok(u16::from_le_bytes([buf[0], buf[1]]))
+    }
+
+    /// Replay the chunks of every sector to the host, from the oldest sector to the one being
+    /// written.
+    async fn replay(&mut self) -> Result<(), F::Error> {
+        let mut replay = Replay::start();
+        // The oldest sector may start part-way through a frame.
+        replay.resync();
+        let mut complete = true;
+        'sectors: for i in 1..=self.sectors {
+            let sector = (self.sector + i) % self.sectors;
+            if self.read_sequence(sector).await?.is_none() {
+                continue;
+            }
+            let mut offset = SECTOR_HEADER_LEN.next_multiple_of(Self::align());
+            while offset + LEN_SIZE.next_multiple_of(Self::align()) <= F::ERASE_SIZE {
+                let len = usize::from(self.read_len(sector, offset).await?);
+                if len > CHUNK_LEN - LEN_SIZE {
+                    break;
+                }
+                let mut chunk = [0u8; CHUNK_LEN];
+                let chunk = &mut chunk[..(LEN_SIZE + len).next_multiple_of(Self::align())];
+                let at = self.sector_start(sector) + offset as u32;
+                self.flash.read(at, chunk).await?;
+                if len == 0 {
+                    replay.start_session();
+                } else if !replay.feed(&chunk[LEN_SIZE..LEN_SIZE + len]).await {
+                    complete = false;
+                    break 'sectors;
+                }
+                offset += chunk.len();
+            }
+        }
+        replay.finish(complete);
+        Ok(())
+    }
+
+    /// Erase `sector` and start writing it, with `sequence` as its sequence number.
+    async fn open_sector(&mut self, sector: u32, sequence: u32) -> Result<(), F::Error> {
+        let start = self.sector_start(sector);
+        self.flash
+            .erase(start, start + F::ERASE_SIZE as u32)
+            .await?;
+        let mut header = [0xffu8; CHUNK_LEN];
+        let header = &mut header[..SECTOR_HEADER_LEN.next_multiple_of(Self::align())];
+        header[..MAGIC.len()].copy_from_slice(&MAGIC);
+        header[MAGIC.len()..SECTOR_HEADER_LEN].copy_from_slice(&sequence.to_le_bytes());
+        self.flash.write(start, header).await?;
+        self.sector = sector;
+        self.sequence = sequence;
+        self.offset = header.len();
+        Ok(())
+    }
+
+    /// Write `data`, of at most `CHUNK_LEN - LEN_SIZE` bytes, as a chunk, moving on to the next
+    /// sector if it does not fit in this one.
+    async fn write_chunk(&mut self, data: &[u8]) -> Result<(), F::Error> {
+        let len = (LEN_SIZE + data.len()).next_multiple_of(Self::align());
+        if self.offset + len > F::ERASE_SIZE {
+            let next = (self.sector + 1) % self.sectors;
+            self.open_sector(next, self.sequence.wrapping_add(1))
+                .await?;
+        }
+        let mut chunk = [0xffu8; CHUNK_LEN];
+        chunk[..LEN_SIZE].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        chunk[LEN_SIZE..LEN_SIZE + data.len()].copy_from_slice(data);
+        let at = self.sector_start(self.sector) + self.offset as u32;
+        self.flash.write(at, &chunk[..len]).await?;
+        self.offset += len;
+        Ok(())
+    }
+}
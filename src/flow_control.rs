@@ -0,0 +1,76 @@
+//! Pausing the stream when the host sends XOFF, until it sends XON
+
+use core::{cell::RefCell, future::poll_fn, sync::atomic::Ordering, task::Poll};
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use portable_atomic::AtomicBool;
+
+/// Byte with which the host resumes the stream, see [`set_software_flow_control`].
+///
+/// [`set_software_flow_control`]: crate::set_software_flow_control
+pub const XON: u8 = 0x11;
+
+/// Byte with which the host pauses the stream, see [`set_software_flow_control`].
+///
+/// [`set_software_flow_control`]: crate::set_software_flow_control
+pub const XOFF: u8 = 0x13;
+
+/// Whether the host has sent XOFF, and no XON since.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// The logger task, waiting in [`wait_resumed`].
+static WAKER: Mutex<RefCell<WakerRegistration>> =
+    Mutex::new(RefCell::new(WakerRegistration::new()));
+
+/// Whether the host has paused the stream.
+pub(crate) fn paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Pause or resume the stream.
+fn set_paused(paused: bool) {
+    critical_section::with(|cs| {
+        PAUSED.store(paused, Ordering::Relaxed);
+        if !paused {
+            WAKER.borrow_ref_mut(cs).wake();
+        }
+    });
+}
+
+/// Resume the stream, after the host closed the serial port.
+pub(crate) fn reset() {
+    set_paused(false);
+}
+
+/// Wait until the host resumes the stream.
+pub(crate) async fn wait_resumed() {
+    poll_fn(|cx| {
+        critical_section::with(|cs| {
+            if PAUSED.load(Ordering::Relaxed) {
+                WAKER.borrow_ref_mut(cs).register(cx.waker());
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+    })
+    .await
+}
+
+/// Apply the XON and XOFF bytes in `data`, the last one winning, and remove them, returning the
+/// length of what is left.
+pub(crate) fn strip(data: &mut [u8]) -> usize {
+    let mut len = 0;
+    for i in 0..data.len() {
+        match data[i] {
+            XON => set_paused(false),
+            XOFF => set_paused(true),
+            byte => {
+                data[len] = byte;
+                len += 1;
+            }
+        }
+    }
+    len
+}
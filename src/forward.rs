@@ -0,0 +1,123 @@
+//! Queueing defmt frames encoded elsewhere along with those of the logger
+
+use crate::{channel::Channel, controller::CONTROLLER};
+
+/// Source ID of the frames logged by this logger, when frames are tagged with
+/// [`set_source_tags`].
+///
+/// [`set_source_tags`]: crate::set_source_tags
+pub const LOCAL_SOURCE: u8 = 0;
+
+/// Queue `frame`, a complete defmt frame encoded elsewhere, to be sent as if it had been logged,
+/// returning whether it was queued.
+///
+/// This forwards the logs of another core or another microcontroller of the same firmware, such as
+/// frames received over a UART or an inter-core queue, over the same USB serial port. `frame` is
+/// encoded as the logger encodes its own frames, with rzcobs and ending with its `0x00` delimiter,
+/// and with the interned strings of the ELF file the host decodes the stream with. It is queued
+/// as a whole, between the frames of the logger rather than part-way through one, and goes through
+/// the [`FrameTransform`], the CRC of the `crc-framing` feature and the overflow policy like any
+/// other frame. The call site filter and the minimum level are not applied to it.
+///
+/// It is not queued if logging is disabled, if it does not fit, or if it is written from an
+/// interrupt handler that preempted a frame being logged, which only happens with the
+/// `short-critical-sections` feature. To forward data of another kind, such as the logs of a
+/// firmware decoded with another ELF file, use a [`Channel`] instead.
+///
+/// ```ignore
+/// let mut frame = [0; 256];
+/// loop {
+///     let len = read_frame_from_coprocessor(&mut frame).await;
+///     defmt_embassy_usbserial::write_frame(&frame[..len]);
+/// }
+/// ```
+///
+/// [`FrameTransform`]: crate::FrameTransform
+/// [`Channel`]: crate::Channel
+pub fn write_frame(frame: &[u8]) -> bool {
+    write_frame_from(LOCAL_SOURCE, frame)
+}
+
+/// Queue `frame` as [`write_frame`] does, tagged with `source` if frames are tagged with
+/// [`set_source_tags`], returning whether it was queued.
+///
+/// Give every producer an ID of its own other than [`LOCAL_SOURCE`], such as 1 for another core
+/// and 2 for a microcontroller forwarded with [`forward_uart`], so that the host can label them.
+///
+/// [`set_source_tags`]: crate::set_source_tags
+pub fn write_frame_from(source: u8, frame: &[u8]) -> bool {
+    CONTROLLER.write_frame(source, frame)
+}
+
+/// Where [`forward_uart`] sends the frames it receives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardTo {
+    /// Queue them with [`write_frame_from`] with this source ID, for a microcontroller running
+    /// the same firmware.
+    Source(u8),
+    /// Write them to this channel, so that the host can decode them with the ELF file of another
+    /// firmware.
+    Channel(Channel),
+}
+
+/// Longest frame that [`forward_uart`] forwards, in bytes, delimiter included.
+pub const MAX_FORWARDED_FRAME_LEN: usize = 256;
+
+/// Forward the defmt frames that another microcontroller sends over a UART, such as with
+/// `defmt-serial`, to the host, forever, so that a board with several microcontrollers gives
+/// access to all of their logs through one USB connector.
+///
+/// `rx` is the receiving half of the UART, such as that of the buffered UART drivers of the embassy
+/// HALs. The stream is split into frames at their `0x00` delimiters, and each frame is sent as
+/// `to` says. Frames longer than [`MAX_FORWARDED_FRAME_LEN`] bytes are dropped, as is the frame in
+/// progress when reading fails, with a warning in the log. The first frame may be incomplete if
+/// the other microcontroller started logging first, which `defmt-print` reports as malformed.
+///
+/// ```ignore
+/// let uart = BufferedUartRx::new(p.UART1, Irqs, p.PIN_5, rx_buf, uart_config);
+/// let logger_config = LoggerConfig::new().source_tags(true);
+/// join(
+///     defmt_embassy_usbserial::run(driver, usb_config, logger_config),
+///     defmt_embassy_usbserial::forward_uart(uart, ForwardTo::Source(1)),
+/// )
+/// .await;
+/// ```
+pub async fn forward_uart<R: embedded_io_async::Read>(mut rx: R, to: ForwardTo) {
+    let mut frame = [0u8; MAX_FORWARDED_FRAME_LEN];
+    let mut len = 0;
+    // Whether the rest of the frame is being discarded, up to its delimiter.
+    let mut discarding = false;
+    let mut chunk = [0u8; 64];
+    loop {
+        let Ok(read) = rx.read(&mut chunk).await else {
+            defmt::warn!("UART forwarding: read error");
+            len = 0;
+            discarding = true;
+            continue;
+        };
+        for &byte in &chunk[..read] {
+            if discarding {
+                discarding = byte != 0;
+                continue;
+            }
+            if len == frame.len() {
+                defmt::warn!(
+                    "UART forwarding: frame longer than {=usize} bytes",
+                    frame.len()
+                );
+                len = 0;
+                discarding = byte != 0;
+                continue;
+            }
+            frame[len] = byte;
+            len += 1;
+            if byte == 0 {
+                match to {
+                    ForwardTo::Source(source) => write_frame_from(source, &frame[..len]),
+                    ForwardTo::Channel(channel) => channel.write(&frame[..len]),
+                };
+                len = 0;
+            }
+        }
+    }
+}
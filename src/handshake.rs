@@ -0,0 +1,129 @@
+//! Waiting for the host to send a start sequence before streaming
+
+use core::{cell::RefCell, future::poll_fn, sync::atomic::Ordering, task::Poll};
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use portable_atomic::AtomicBool;
+
+/// Start sequence suggested for [`set_start_handshake`].
+///
+/// [`set_start_handshake`]: crate::set_start_handshake
+pub const DEFAULT_START_SEQUENCE: &[u8] = b"\x00defmt-start\x00";
+
+/// Whether the host has sent the start sequence since it last opened the serial port.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The logger task, waiting in [`wait`].
+static WAKER: Mutex<RefCell<WakerRegistration>> =
+    Mutex::new(RefCell::new(WakerRegistration::new()));
+
+/// Whether the host has sent the start sequence.
+pub(crate) fn started() -> bool {
+    STARTED.load(Ordering::Relaxed)
+}
+
+/// Record that the host has sent the start sequence.
+pub(crate) fn start() {
+    critical_section::with(|cs| {
+        STARTED.store(true, Ordering::Relaxed);
+        WAKER.borrow_ref_mut(cs).wake();
+    });
+}
+
+/// Wait for the start sequence again, after the host closed the serial port.
+pub(crate) fn reset() {
+    STARTED.store(false, Ordering::Relaxed);
+}
+
+/// Wait until the host has sent the start sequence.
+pub(crate) async fn wait() {
+    poll_fn(|cx| {
+        critical_section::with(|cs| {
+            if STARTED.load(Ordering::Relaxed) {
+                Poll::Ready(())
+            } else {
+                WAKER.borrow_ref_mut(cs).register(cx.waker());
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/// Search for the start sequence in the data received from the host, across packets.
+pub(crate) struct Matcher {
+    /// Number of bytes of the sequence matched so far.
+    matched: usize,
+}
+
+impl Matcher {
+    /// Start searching.
+    pub(crate) const fn new() -> Self {
+        Self { matched: 0 }
+    }
+
+    /// Look for the rest of `sequence` in `data`, returning the data that follows it once it has
+    /// been found.
+    pub(crate) fn find<'a>(&mut self, sequence: &[u8], data: &'a [u8]) -> Option<&'a [u8]> {
+        if sequence.is_empty() {
+            return Some(data);
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            // The longest start of the sequence that the bytes matched so far, followed by this
+            // one, end with. On a mismatch, the sequence may still start part-way through the
+            // bytes matched, as with `aab` in `aaab`.
+            let matched = self.matched;
+            self.matched = (1..=matched + 1)
+                .rev()
+                .find(|&len| {
+                    sequence[len - 1] == byte
+                        && sequence[..len - 1] == sequence[matched + 1 - len..matched]
+                })
+                .unwrap_or(0);
+            if self.matched == sequence.len() {
+                self.matched = 0;
+                return Some(&data[i + 1..]);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_sequence_and_returns_what_follows() {
+        let mut matcher = Matcher::new();
+        assert_eq!(matcher.find(b"start", b"xxstartyy"), Some(&b"yy"[..]));
+        assert_eq!(matcher.find(b"", b"data"), Some(&b"data"[..]));
+        assert_eq!(matcher.find(b"start", b"stair"), None);
+    }
+
+    #[test]
+    fn finds_the_sequence_across_packets() {
+        let mut matcher = Matcher::new();
+        assert_eq!(matcher.find(DEFAULT_START_SEQUENCE, b"\x00defmt-"), None);
+        assert_eq!(matcher.find(DEFAULT_START_SEQUENCE, b"sta"), None);
+        assert_eq!(
+            matcher.find(DEFAULT_START_SEQUENCE, b"rt\x00\x01"),
+            Some(&b"\x01"[..])
+        );
+    }
+
+    #[test]
+    fn finds_self_overlapping_sequences() {
+        let mut matcher = Matcher::new();
+        assert_eq!(matcher.find(b"aab", b"aaab"), Some(&b""[..]));
+        assert_eq!(matcher.find(b"abac", b"ababac!"), Some(&b"!"[..]));
+        assert_eq!(matcher.find(b"aab", b"a"), None);
+        assert_eq!(matcher.find(b"aab", b"a"), None);
+        assert_eq!(matcher.find(b"aab", b"ab"), Some(&b""[..]));
+        assert_eq!(
+            matcher.find(DEFAULT_START_SEQUENCE, b"\x00defm\x00defmt-start\x00"),
+            Some(&b""[..])
+        );
+    }
+}
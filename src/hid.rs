@@ -0,0 +1,133 @@
+//! The defmt stream as HID input reports, for hosts where no other driver can be installed
+
+use embassy_usb::{
+    Builder,
+    class::hid::{Config, HidWriter, State},
+    driver::Driver,
+};
+
+use crate::{
+    controller::CONTROLLER,
+    task::stream_packets,
+    vendor::{self, VendorHandler},
+};
+
+/// Size of the input reports: a length byte followed by up to 63 bytes of the stream.
+pub const HID_REPORT_LEN: usize = 64;
+
+/// Report descriptor of a vendor-defined device with a single input report of
+/// [`HID_REPORT_LEN`] bytes.
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xff,           // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,                 // Usage (0x01)
+    0xa1, 0x01,                 // Collection (Application)
+    0x09, 0x02,                 //   Usage (0x02)
+    0x15, 0x00,                 //   Logical Minimum (0)
+    0x26, 0xff, 0x00,           //   Logical Maximum (255)
+    0x75, 0x08,                 //   Report Size (8)
+    0x95, HID_REPORT_LEN as u8, //   Report Count (64)
+    0x81, 0x02,                 //   Input (Data, Variable, Absolute)
+    0xc0,                       // End Collection
+];
+
+/// State of a [`DefmtHidClass`]: that of its HID interface, and of the control handler it adds.
+pub struct DefmtHidState<'d> {
+    /// State of the HID interface.
+    hid: State<'d>,
+    /// Control handler of the vendor requests.
+    vendor: VendorHandler,
+}
+
+impl DefmtHidState<'_> {
+    /// Create the state.
+    pub fn new() -> Self {
+        Self {
+            hid: State::new(),
+            vendor: VendorHandler,
+        }
+    }
+}
+
+impl Default for DefmtHidState<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The defmt stream as the input reports of a vendor-defined HID interface, instead of a CDC ACM
+/// serial port.
+///
+/// Every operating system has a HID driver built in, which a locked-down host, such as a kiosk
+/// or a managed Windows machine on which no CDC or WinUSB driver can be installed, still loads.
+/// The host reads the reports with hidapi or WebHID. Each report is [`HID_REPORT_LEN`] bytes
+/// long, and starts with the number of bytes of the stream that follow it, up to 63. The
+/// interface uses the vendor-defined usage page `0xFF00`, with usage `0x01`.
+///
+/// Add it to a builder of your own and run the device and the logger together:
+///
+/// ```ignore
+/// static STATE: StaticCell<DefmtHidState> = StaticCell::new();
+///
+/// let mut builder = embassy_usb::Builder::new(driver, usb_config, ...);
+/// let defmt = DefmtHidClass::new(&mut builder, STATE.init(DefmtHidState::new()));
+/// let mut usb = builder.build();
+///
+/// LoggerConfig::new().apply();
+/// join(usb.run(), defmt.run()).await;
+/// ```
+///
+/// HID interrupt endpoints send at most one report per millisecond at full speed, so the stream
+/// is limited to 63 kB/s. As with [`DefmtBulkClass`], only the settings that concern the ring
+/// buffer and the flushing of frames apply.
+///
+/// [`DefmtBulkClass`]: crate::DefmtBulkClass
+pub struct DefmtHidClass<'d, D: Driver<'d>> {
+    /// Writer of the input reports.
+    writer: HidWriter<'d, D, HID_REPORT_LEN>,
+}
+
+impl<'d, D: Driver<'d>> DefmtHidClass<'d, D> {
+    /// Add the HID interface to `builder`.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut DefmtHidState<'d>) -> Self {
+        let DefmtHidState { hid, vendor } = state;
+        vendor::add_handler(builder, vendor);
+        let config = Config {
+            report_descriptor: REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 1,
+            max_packet_size: HID_REPORT_LEN as u16,
+        };
+        Self {
+            writer: HidWriter::new(builder, hid, config),
+        }
+    }
+
+    /// Write out the queued defmt frames as input reports, forever.
+    ///
+    /// This must run alongside the USB device, built from the same builder.
+    pub async fn run(mut self) {
+        // Stream bytes of the report being sent, and of the next one with double buffering.
+        let mut packet = [0u8; HID_REPORT_LEN - 1];
+        let mut spare = [0u8; HID_REPORT_LEN - 1];
+        // The report being sent.
+        let mut report = [0u8; HID_REPORT_LEN];
+
+        let stream = async {
+            loop {
+                // Wait for the host to configure the device.
+                self.writer.ready().await;
+                CONTROLLER.reconnected();
+                let writer = &mut self.writer;
+                stream_packets(&mut packet, &mut spare, async |data| {
+                    report[0] = data.len() as u8;
+                    report[1..=data.len()].copy_from_slice(data);
+                    report[data.len() + 1..].fill(0);
+                    writer.write(&report).await
+                })
+                .await;
+            }
+        };
+        embassy_futures::join::join(stream, vendor::handle_resets()).await;
+    }
+}
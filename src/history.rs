@@ -0,0 +1,168 @@
+//! Replaying the frames persisted by the flash and SD card logs to the host
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{
+    controller::{CONTROLLER, free_space},
+    forward::LOCAL_SOURCE,
+};
+
+/// Longest persisted frame that is replayed, in bytes, delimiter included.
+pub const MAX_REPLAYED_FRAME_LEN: usize = 256;
+
+/// Room left in the ring buffer for the frames logged during a replay, in bytes.
+const REPLAY_RESERVE: usize = 64;
+
+/// How long a replayed frame waits for room in the ring buffer before the replay is abandoned.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Signalled when the host requests the history.
+static REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Have the `FlashLog` or `SdCardLog` send the frames it has persisted over the USB serial port,
+/// oldest first, so that the device serves as its own black-box recorder.
+///
+/// The log replays its history from its task, between writes, by queueing the persisted frames
+/// as if they had been logged, so the host decodes them with `defmt-print` and the ELF file of the
+/// firmware that logged them, interleaved with the frames logged in the meantime. The replay is
+/// delimited by `history dump start` and `history dump end` info messages. The flash log marks the
+/// start of every boot with a `history session` info message, numbered from the oldest boot in
+/// the history, and the frames before the first one are from a boot whose start was overwritten.
+/// The SD card log has its session headers in the file already. None of these frames are persisted
+/// again.
+///
+/// The replay waits for the host to make room in the ring buffer, and is abandoned, with a warning
+/// in the log, if it does not within a second. Frames longer than [`MAX_REPLAYED_FRAME_LEN`] bytes
+/// are skipped. Frames logged during the replay may be dropped once the buffer of the log fills
+/// up, since the log is not written meanwhile.
+pub fn dump_history() {
+    REQUEST.signal(());
+}
+
+/// Handler of a binary command with which the host requests a [`dump_history`], for a
+/// [`CommandDispatcher`]. The payload is ignored. Register it under a tag of your choice, or
+/// call [`dump_history`] from a text command:
+///
+/// ```ignore
+/// Command::Binary { tag: 0x22, handler: defmt_embassy_usbserial::dump_history_command },
+/// Command::Line { name: "dump-history", handler: |_| defmt_embassy_usbserial::dump_history() },
+/// ```
+///
+/// [`CommandDispatcher`]: crate::CommandDispatcher
+pub fn dump_history_command(_payload: &[u8]) {
+    dump_history();
+}
+
+/// Wait for the host to request the history.
+pub(crate) async fn requested() {
+    REQUEST.wait().await;
+}
+
+/// A replay of a persisted stream of frames, which splits it into frames and queues them.
+pub(crate) struct Replay {
+    /// The frame received so far.
+    frame: [u8; MAX_REPLAYED_FRAME_LEN],
+    /// Number of bytes in `frame`.
+    len: usize,
+    /// Whether the rest of the frame is being discarded, up to its delimiter.
+    discarding: bool,
+    /// Number of sessions replayed.
+    #[cfg(feature = "flash-log")]
+    sessions: u32,
+    /// Number of frames replayed.
+    frames: u32,
+}
+
+impl Replay {
+    /// Start a replay, with a marker in the log.
+    pub(crate) fn start() -> Self {
+        CONTROLLER.replay(|| defmt::info!("history dump start"));
+        Self {
+            frame: [0; MAX_REPLAYED_FRAME_LEN],
+            len: 0,
+            discarding: false,
+            #[cfg(feature = "flash-log")]
+            sessions: 0,
+            frames: 0,
+        }
+    }
+
+    /// Skip the bytes up to the next delimiter, where the stream may start part-way through a
+    /// frame.
+    #[cfg(feature = "flash-log")]
+    pub(crate) fn resync(&mut self) {
+        self.len = 0;
+        self.discarding = true;
+    }
+
+    /// Start a session, a boot of the device, with a marker in the log, dropping the incomplete
+    /// frame the previous one may have ended with.
+    #[cfg(feature = "flash-log")]
+    pub(crate) fn start_session(&mut self) {
+        self.len = 0;
+        self.discarding = false;
+        self.sessions += 1;
+        CONTROLLER.replay(|| defmt::info!("history session {=u32}", self.sessions));
+    }
+
+    /// Queue the frames completed by `data`, the next bytes of the stream, returning whether the
+    /// host read them in time.
+    pub(crate) async fn feed(&mut self, data: &[u8]) -> bool {
+        for &byte in data {
+            if self.discarding {
+                self.discarding = byte != 0;
+                continue;
+            }
+            if self.len == self.frame.len() {
+                self.len = 0;
+                self.discarding = byte != 0;
+                continue;
+            }
+            self.frame[self.len] = byte;
+            self.len += 1;
+            if byte == 0 {
+                let len = core::mem::take(&mut self.len);
+                if !self.queue(len).await {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Queue the first `len` bytes of `frame` once there is room for them, returning whether there
+    /// was in time.
+    async fn queue(&mut self, len: usize) -> bool {
+        let deadline = Instant::now() + REPLAY_TIMEOUT;
+        loop {
+            let frame = &self.frame[..len];
+            if free_space() >= len + REPLAY_RESERVE
+                && CONTROLLER.replay(|| CONTROLLER.write_frame(LOCAL_SOURCE, frame))
+            {
+                self.frames += 1;
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            Timer::after_millis(10).await;
+        }
+    }
+
+    /// Finish the replay, with a marker in the log, or a warning if it was not `complete`.
+    pub(crate) fn finish(self, complete: bool) {
+        if complete {
+            CONTROLLER.replay(|| {
+                defmt::info!("history dump end: {=u32} frames", self.frames);
+            });
+        } else {
+            CONTROLLER.replay(|| {
+                defmt::warn!(
+                    "history dump abandoned after {=u32} frames: the host is not reading",
+                    self.frames,
+                );
+            });
+        }
+    }
+}
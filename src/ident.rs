@@ -0,0 +1,144 @@
+//! Identifying the firmware to the host, so that it can tell which build is on which serial port
+
+use crate::controller::CONTROLLER;
+
+/// Encoding of the frames in the stream.
+fn encoding() -> &'static str {
+    let crc = cfg!(feature = "crc-framing");
+    match (CONTROLLER.channels(), CONTROLLER.source_tags(), crc) {
+        (false, false, false) => "rzcobs",
+        (false, false, true) => "rzcobs+crc16",
+        (false, true, false) => "rzcobs+sources",
+        (false, true, true) => "rzcobs+sources+crc16",
+        (true, false, false) => "rzcobs+channels",
+        (true, false, true) => "rzcobs+channels+crc16",
+        (true, true, false) => "rzcobs+channels+sources",
+        (true, true, true) => "rzcobs+channels+sources+crc16",
+    }
+}
+
+/// Metadata of the firmware build, reported with [`log_ident`].
+///
+/// Fill it in at build time, for example from Cargo and from a git hash exported by a build
+/// script:
+///
+/// ```ignore
+/// defmt_embassy_usbserial::set_firmware_ident(Some(FirmwareIdent {
+///     name: env!("CARGO_PKG_NAME"),
+///     version: env!("CARGO_PKG_VERSION"),
+///     git_hash: env!("GIT_HASH"),
+/// }));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct FirmwareIdent {
+    /// Name of the firmware.
+    pub name: &'static str,
+    /// Version of the firmware.
+    pub version: &'static str,
+    /// Git commit the firmware was built from.
+    pub git_hash: &'static str,
+}
+
+/// Type of the ELF note holding the GNU build ID.
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// The build ID in the contents of the `.note.gnu.build-id` section of the firmware, for
+/// [`set_build_id`].
+///
+/// The linker writes a hash of the whole ELF file there when passed `--build-id`, for example
+/// with `-C link-arg=--build-id` in `.cargo/config.toml`, so that the host can tell whether an ELF
+/// file matches the firmware on a device. The section must be kept in flash and given bounds in a
+/// linker script of its own, such as:
+///
+/// ```text
+/// SECTIONS {
+///     .note.gnu.build-id : {
+///         __build_id_start = .;
+///         KEEP(*(.note.gnu.build-id));
+///         __build_id_end = .;
+///     } > FLASH
+/// } INSERT AFTER .rodata;
+/// ```
+///
+/// ```ignore
+/// unsafe extern "C" {
+///     static __build_id_start: u8;
+///     static __build_id_end: u8;
+/// }
+///
+/// let start = &raw const __build_id_start;
+/// let len = (&raw const __build_id_end as usize) - start as usize;
+/// // SAFETY: The linker script places the section between the two symbols.
+/// let note = unsafe { core::slice::from_raw_parts(start, len) };
+/// defmt_embassy_usbserial::set_build_id(defmt_embassy_usbserial::gnu_build_id(note));
+/// ```
+///
+/// Returns `None` if `note` is not a GNU build ID note.
+///
+/// [`set_build_id`]: crate::set_build_id
+pub fn gnu_build_id(note: &'static [u8]) -> Option<&'static [u8]> {
+    let word = |offset: usize| {
+        let bytes = note.get(offset..offset + 4)?;
+        Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let (name_len, desc_len) = (word(0)? as usize, word(4)? as usize);
+    if word(8)? != NT_GNU_BUILD_ID || note.get(12..12 + name_len)? != b"GNU\0" {
+        return None;
+    }
+    // The name is padded to a multiple of four bytes.
+    let desc_start = 12 + name_len.next_multiple_of(4);
+    note.get(desc_start..desc_start + desc_len)
+}
+
+/// Everything reported with [`log_ident`].
+#[derive(defmt::Format)]
+struct Ident {
+    /// The firmware, if set with [`set_firmware_ident`].
+    ///
+    /// [`set_firmware_ident`]: crate::set_firmware_ident
+    firmware: Option<FirmwareIdent>,
+    /// Version of this crate.
+    logger: &'static str,
+    /// Encoding of the frames in the stream.
+    encoding: &'static str,
+    /// Size of the ring buffer, in bytes.
+    buffer_size: usize,
+}
+
+/// Log an info message identifying the firmware and the configuration of the logger.
+///
+/// The message is a structure with the [`FirmwareIdent`] set with [`set_firmware_ident`], the
+/// version of this crate, the encoding of the stream, such as `rzcobs`, `rzcobs+crc16` with the
+/// `crc-framing` feature or `rzcobs+channels+sources` with [`set_channels`] and
+/// [`set_source_tags`], and the size of the ring
+/// buffer, followed by the build ID set with [`set_build_id`] in hexadecimal, empty if there is
+/// none. A lab with many devices can use it to map each serial port to the firmware build behind
+/// it, and to pick the ELF file to decode it with. The logger task logs it every time the host
+/// connects once an ident is set, and the host can request it at any time with [`ident_command`].
+///
+/// [`set_firmware_ident`]: crate::set_firmware_ident
+/// [`set_build_id`]: crate::set_build_id
+/// [`set_channels`]: crate::set_channels
+/// [`set_source_tags`]: crate::set_source_tags
+pub fn log_ident() {
+    let ident = Ident {
+        firmware: CONTROLLER.firmware_ident(),
+        logger: env!("CARGO_PKG_VERSION"),
+        encoding: encoding(),
+        buffer_size: CONTROLLER.buffer_size(),
+    };
+    let build_id = CONTROLLER.build_id().unwrap_or(&[]);
+    defmt::info!("ident: {}, build ID: {=[u8]:02x}", ident, build_id);
+}
+
+/// Handler of a text command with which the host requests a [`log_ident`], for a
+/// [`CommandDispatcher`]. The arguments are ignored:
+///
+/// ```ignore
+/// Command::Line { name: "ident", handler: defmt_embassy_usbserial::ident_command }
+/// ```
+///
+/// [`CommandDispatcher`]: crate::CommandDispatcher
+pub fn ident_command(_args: &str) {
+    log_ident();
+}
@@ -12,7 +12,7 @@
 //! # #![no_std]
 //! # #![no_main]
 //! # use embassy_rp::{bind_interrupts, Peri};
-//! # use embassy_time::Instant;
+//! # use embassy_time::{Duration, Instant};
 //! # use embedded_hal_async::delay::DelayNs;
 //! # use panic_halt as _;
 //! # bind_interrupts!(struct Irqs {
@@ -21,17 +21,12 @@
 //! #[embassy_executor::task]
 //! async fn defmtusb_wrapper(usb: Peri<'static, embassy_rp::peripherals::USB>) {
 //!     let driver = embassy_rp::usb::Driver::new(usb, Irqs);
-//!     let usb_config = {
-//!         let mut c = embassy_usb::Config::new(0x1234, 0x5678);
-//!         c.serial_number = Some("defmt");
-//!         c.max_packet_size_0 = 64;
-//!         c.composite_with_iads = true;
-//!         c.device_class = 0xEF;
-//!         c.device_sub_class = 0x02;
-//!         c.device_protocol = 0x01;
-//!         c
-//!     };
-//!     defmt_embassy_usbserial::run(driver, usb_config).await;
+//!     let mut usb_config = defmt_embassy_usbserial::default_config(0x1234, 0x5678);
+//!     usb_config.serial_number = Some("defmt");
+//!     let logger_config = defmt_embassy_usbserial::LoggerConfig::new();
+//!     defmt_embassy_usbserial::run(driver, usb_config, logger_config)
+//!         .await
+//!         .unwrap();
 //! }
 //! #
 //! # #[embassy_executor::main]
@@ -56,12 +51,45 @@
 //! ultimately the only requirement is that it awaits [`defmt_embassy_usbserial::run`].
 //!
 //! Of course, `run` is just an async function whose returned future can be `join`ed, etc.
+//! To run the USB device and the logger on different executors or at different priorities, get
+//! them separately from [`build`] instead.
+//!
+//! Dropping the future returned by `run` stops the USB device and the logger, after which `run`
+//! can be called again, for example to recover from a USB peripheral error. To stop cleanly, call
+//! [`shutdown`], which sends the queued frames, detaches the device from the bus and makes `run`
+//! return.
+//!
+//! `run` builds a USB device whose only function is the serial port. To expose other functions
+//! from the same device, such as HID or MIDI, build the device yourself and add the serial port to
+//! it with [`DefmtClass`]. If the device only needs a builder set up beyond its configuration, for
+//! example with custom descriptors or handlers, pass the builder to [`run_with_builder`].
+//! [`DefmtBulkClass`] sends the stream over a vendor-specific bulk interface instead of a serial
+//! port, for hosts that read it with libusb, and [`DefmtHidClass`] as HID input reports, for hosts
+//! on which no driver can be installed. [`DefmtMscClass`] adds a USB drive to the device, with
+//! the queued frames in a file that anyone can copy without any tooling, and
+//! [`add_dfu_runtime`] a DFU runtime interface with which `dfu-util -e` resets the device into its
+//! bootloader. On boards without USB, [`logger_with_writer`] writes the stream to a UART or any
+//! other `embedded-io-async` writer instead.
+//!
+//! The serial port can carry data from the host back to the device too, such as simple commands:
+//! [`run_with_receiver`] hands whatever the host sends to an async callback. To turn the port into
+//! a small debug console, feed that data to a [`CommandDispatcher`], which parses text lines and
+//! tagged binary commands and calls the handler registered for each. For a full interactive shell,
+//! [`LoggerConfig::console`] adds a second serial port instead, read and written through
+//! [`console()`], so that the defmt stream keeps a port of its own, or bridged to a hardware UART
+//! with [`bridge_uart`], so that the device doubles as a USB to serial adapter.
+//!
+//! With the `embassy-boot` feature, register `bootloader_command` in a [`CommandDispatcher`] and
+//! pass the boot state of `embassy-boot` to `set_boot_state`, and the host can reset the device
+//! into its bootloader to flash it again, so that logging, commands and flashing all go through the
+//! one USB port.
 //!
 //! ## Configuration
 //!
 //! For USB-CDC to be set up properly, you _must_ set the correct values in the configuration
 //! struct. If `composite_with_iads` is `true` (the default), you _must_ use the following values
-//! as `embassy-usb` will [fail an assertion][eusb-assert] if you do not:
+//! as `embassy-usb` will [fail an assertion][eusb-assert] if you do not. [`run`] checks this before
+//! building the device, and returns [`ConfigError::IadClassMismatch`] instead:
 //!
 //! | Field | Value |
 //! |-------|-------|
@@ -69,10 +97,316 @@
 //! |`device_sub_class`|`0x02`|
 //! |`device_protocol`|`0x01`|
 //!
-//! If `composite_with_iads` is `false`, you do not have to use these exact values: the standard
-//! CDC device class code (`device_class`) is `0x02`. You should choose the values appropriate to
-//! your application. If your only concern is transporting defmt logs over USB serial, default to
-//! the values in the table above.
+//! [`default_config`] returns a configuration with these values already set, and
+//! [`pid_codes_test_config`] also sets the pid.codes test IDs and a product string that host tools
+//! can use to find the serial port.
+//!
+//! To tell boards apart on a test bench with several of them, give each a unique serial number
+//! with [`serial_number`], for example from the unique ID of the chip with [`HexSerialNumber`].
+//!
+//! If `composite_with_iads` is `false`, the device is a single-function CDC device, and you _must_
+//! use either the CDC device class, or `0x00`, which leaves the class to each interface, or hosts
+//! do not bind their serial driver to it. [`run`] checks this too, and returns
+//! [`ConfigError::NonCompositeClassMismatch`] otherwise, for example for the composite device
+//! class above without IADs:
+//!
+//! | Field | CDC class | Per interface |
+//! |-------|-----------|---------------|
+//! |`device_class`|`0x02`|`0x00`|
+//! |`device_sub_class`|`0x00`|`0x00`|
+//! |`device_protocol`|`0x00`|`0x00`|
+//!
+//! [`single_function_config`] returns a configuration with the CDC class set. No other function
+//! can then be added to the device, so if your only concern is transporting defmt logs over USB
+//! serial, default to the values in the first table.
+//!
+//! The runtime behavior of the logger, such as what happens when the buffer is full and when
+//! queued frames are sent, is set with the [`LoggerConfig`] passed to [`run`]. Each setting can
+//! also be changed later with the function of the same name with a `set_` prefix, such as
+//! [`set_overflow_policy`].
+//!
+//! ## Buffer size
+//!
+//! defmt frames are queued in a ring buffer until the USB task sends them. By default this is a
+//! static buffer whose size is chosen with one of the `buffersize-*` features (256 bytes unless
+//! changed).
+//!
+//! [`run_with_ring_buffer`] takes the size of a buffer stored in its future instead, so
+//! `run_with_ring_buffer::<_, 1024>` uses a 1 KiB buffer from then on. Frames logged before it
+//! starts still go to the static buffer, and are moved over.
+//!
+//! Alternatively, pass a buffer of any size to [`init`] at startup, and disable the default
+//! features so that the static buffer is not reserved:
+//!
+//! ```no_run
+//! use static_cell::ConstStaticCell;
+//!
+//! static LOG_BUFFER: ConstStaticCell<[u8; 4096]> = ConstStaticCell::new([0; 4096]);
+//! defmt_embassy_usbserial::init(LOG_BUFFER.take());
+//! ```
+//!
+//! With the `alloc` feature, [`init_alloc`] allocates the buffer from the global allocator instead.
+//!
+//! Because [`init`] accepts any `&'static mut [u8]`, the buffer may live in external RAM. For
+//! example, on ESP32-S2 and ESP32-S3 with `esp-hal`, the whole of PSRAM can hold a multi-megabyte
+//! backlog for when the host only connects occasionally:
+//!
+//! ```ignore
+//! let (start, size) = esp_hal::psram::psram_raw_parts(&peripherals.PSRAM);
+//! // SAFETY: PSRAM is not used for anything else, such as a heap.
+//! let psram = unsafe { core::slice::from_raw_parts_mut(start, size) };
+//! defmt_embassy_usbserial::init(psram);
+//! ```
+//!
+//! The USB device itself needs descriptor and control buffers, which [`run`] takes from four
+//! static buffers of 256 bytes. The `minimal-ram` feature shrinks them to what the defmt serial
+//! port alone needs, 224 bytes in all, or pass buffers of your own to [`run_with_buffers`].
+//! [`LoggerConfig::webusb_landing_page`] adds WebUSB descriptors, so
+//! that browsers offer to open a web-based viewer when the device is plugged in.
+//!
+//! ### Linker section
+//!
+//! On parts with several RAM regions, enable the `link-section` feature to place the static ring
+//! buffer and the USB descriptor and control buffers in a `.defmt_usbserial` section, and map that
+//! section in your linker script. Its contents never need initializing, so it can be `NOLOAD`:
+//!
+//! ```text
+//! SECTIONS {
+//!   .defmt_usbserial (NOLOAD) : ALIGN(4) {
+//!     *(.defmt_usbserial .defmt_usbserial.*);
+//!   } > RAM2
+//! } INSERT AFTER .bss;
+//! ```
+//!
+//! A buffer passed to [`init`] can be placed with a `#[unsafe(link_section = "...")]` attribute on
+//! its static instead.
+//!
+//! ### Interrupt latency
+//!
+//! Like other defmt loggers, this crate holds a critical section for the whole of each defmt frame,
+//! including the time spent formatting values such as `Debug2Format` payloads. With the
+//! `short-critical-sections` feature, the critical section is instead only held while taking the
+//! logger and while copying each chunk of encoded bytes into the ring buffer. A frame logged from
+//! an interrupt handler that preempts another frame is then dropped, rather than causing a panic.
+//! This feature is only suitable for single-core use, as a frame logged from another core at the
+//! same time would be treated the same way.
+//!
+//! The encoded bytes are written in place: the defmt encoder passes its output to the logger a
+//! few bytes at a time, and the logger copies them straight into the ring buffer, with no
+//! intermediate buffer. This single copy is unavoidable, as the encoder can only hand over its
+//! output through a callback.
+//!
+//! To check the logger's impact on interrupt latency, enable the `cs-instrumentation` feature,
+//! pass a cycle counter to [`set_cycle_counter`], and read the worst case so far with
+//! [`max_critical_section_cycles`].
+//!
+//! ### Keeping logs across a reset
+//!
+//! A [`PersistentBuffer`] placed in memory that is not initialized at startup, and passed to
+//! [`init_persistent`], keeps the frames that were still queued when the device was reset, for
+//! example by a watchdog or a panic handler. They are sent once the host connects.
+//!
+//! ### Messages logged before the host connects
+//!
+//! Frames logged before the USB device is enumerated, or before a program on the host opens the
+//! serial port, are queued and sent once it does, in order. Nothing is dropped until the ring
+//! buffer is full, so up to the buffer size minus the [emergency reserve](set_emergency_reserve)
+//! of encoded frames (counting two extra bytes per frame) is guaranteed to be delivered. With the
+//! default [`OverflowPolicy::DropNewest`], the earliest messages, such as the boot reason, are the
+//! ones that are kept when more is logged. If `run` is given a buffer of its own, the frames are
+//! moved into it if they fit. The first connection does not drop them, and [`set_stale_timeout`]
+//! only applies once the host has read something. Only a disconnection before they are sent drops
+//! them, unless [`set_buffer_while_disconnected`] is set.
+//!
+//! ## Buffer overflow
+//!
+//! When a frame does not fit in the ring buffer, by default it is dropped and the frames already
+//! queued are kept. Call [`set_overflow_policy`] with [`OverflowPolicy::DropOldest`] to instead
+//! evict the oldest queued frames, keeping the most recent messages.
+//!
+//! [`set_wait_on_full`] lets the logger busy-wait for a bounded time for the USB task to make room
+//! before the overflow policy applies. Read its documentation for the conditions under which this
+//! is useful.
+//!
+//! [`fill_level`] and [`free_space`] report how full the buffer is, so that the application can
+//! hold back verbose logging when it is nearly full. [`high_watermark`] reports the largest fill
+//! level reached, to help choose the size of the buffer. [`drop_counters`] reports how many frames
+//! were dropped, which tells apart messages lost on the device from those lost on the way to the
+//! host. [`stats`] returns these along with counts of the data queued and sent. Dropped frames are
+//! also reported inline: the logger task logs a warning with the number of frames dropped as soon
+//! as there is room for it. [`set_self_report_interval`] has it also log a summary of its
+//! throughput and drops at a regular interval, and [`last_flush_instant`] tells whether it is still
+//! making progress. [`dump_buffer`], which the host can trigger with [`dump_buffer_command`], sends
+//! everything queued right away between two markers, along with the drop counts.
+//!
+//! To make sure a final panic message is sent even when the buffer is saturated, reserve part of
+//! it with [`set_emergency_reserve`] and log the message inside [`emergency`].
+//!
+//! ## Latency and batching
+//!
+//! There is no flush interval to tune: the USB task is woken as soon as a frame is logged, and
+//! sends it in the next packet the host polls for. Under light load this means short USB packets;
+//! [`set_flush_watermark`] holds frames back until enough are queued to fill packets, and
+//! [`set_flush_deadline`] bounds how long they may be held back. [`set_adaptive_flush`] instead
+//! adjusts that bound to the logging rate. [`set_packet_coalescing`] keeps a partially filled
+//! packet open for a short time to fill it with the frames that follow. Under sustained load,
+//! [`set_double_buffering`] reads the next packet while the previous one is being sent, so that
+//! packets go out back to back.
+//!
+//! Transfers that end with a full packet are not terminated by default. If the host driver holds
+//! back data until the end of a transfer, select another [`PacketTermination`] with
+//! [`set_packet_termination`].
+//!
+//! If the host stops reading, the USB task waits for it part-way through a frame. Use
+//! [`set_write_timeout`] to limit how long it waits for each packet, and to choose whether the
+//! packet is then kept or dropped. [`set_chunk_headers`] prefixes every packet with a sequence
+//! number, so that a host-side shim can tell how many packets were lost. To lose none at all,
+//! [`set_retransmit_window`] keeps the packets sent until the host acknowledges them, and sends
+//! them again after the host reconnects or reopens the port.
+//!
+//! Await [`drained`] to make sure that everything logged so far has been sent, for example before
+//! entering deep sleep or resetting the device. [`flush`] only waits for the frames queued when it
+//! is called, and so resolves promptly even while other tasks keep logging. Outside of async code,
+//! set a timeout with [`set_flush_timeout`] to make `defmt::flush()` wait for the same, which works
+//! under the same conditions as [`set_wait_on_full`].
+//!
+//! The host can force out frames held back by these settings by sending a break on the serial
+//! port, with the CDC `SEND_BREAK` request, without any protocol of its own. `embassy-usb` does not
+//! advertise break support in the CDC ACM descriptors, so some host drivers, such as Linux
+//! `cdc-acm`, refuse to send one through the serial port API; send the control request directly,
+//! for example with libusb, instead. As the CDC ACM class does not tell which interface it uses,
+//! a break sent to any interface of the device is taken as a flush request.
+//!
+//! The executor stops running the USB task after a panic, so the message logged by a panic
+//! handler is normally lost. With the `panic-flush` feature, call `flush_after_panic` from the
+//! panic handler to poll the USB device directly until the message has been sent.
+//! `flush_blocking_with_budget` does the same from an interrupt handler, such as the early warning
+//! interrupt of a watchdog, within a time budget.
+//!
+//! Packets are at most 64 bytes, the limit of full-speed USB. On high-speed hardware, enable the
+//! `high-speed` feature and set [`LoggerConfig::max_packet_size`] to 512 for up to eight times the
+//! throughput.
+//!
+//! ## Connection state
+//!
+//! [`connection_receiver`] returns an [`embassy_sync::watch`] receiver of whether the host is
+//! connected, so that application tasks can defer expensive diagnostics until someone is listening.
+//! [`is_connected`] returns the current state, and [`wait_for_host`] waits until a program on the
+//! host has opened the serial port, which is useful to hold back the startup banner until
+//! `defmt-print` is attached. [`line_state_receiver`] reports every change of the DTR and RTS
+//! signals, for firmware that reacts each time a program on the host opens or closes the port.
+//! [`set_baud_touch`] follows the Arduino convention of entering the bootloader when the host opens
+//! the port at 1200 baud and closes it, so that flashing tools can reset the board. Tools that can
+//! send control requests can instead use the vendor requests handled by [`set_reset_handler`].
+//!
+//! Logging can be switched off and on at runtime with [`set_logging_enabled`], or from the host
+//! with the vendor request [`VENDOR_REQUEST_SET_LOGGING`], to silence a chatty unit in the field.
+//! To silence individual log statements instead, block their call sites with [`block_call_site`],
+//! or let the host do it by registering [`call_site_filter_command`] with a [`CommandDispatcher`].
+//! Frames from blocked call sites are discarded before they are queued. [`set_min_level`] does the
+//! same for the log statements below a level, which [`set_verbosity_presets`] lets any serial
+//! terminal select by setting the baud rate.
+//! [`set_session_marker`] has the logger task log a marker on every
+//! connection, with a session counter and the reset reason set with [`set_reset_reason`], so that
+//! host tooling can detect reboots. [`set_firmware_ident`] has it also log a structured ident with
+//! the firmware version and git hash, the encoding and the buffer size, which the host can request
+//! with [`ident_command`], so that a lab can map serial ports to firmware builds. [`set_build_id`]
+//! adds the GNU build ID, which the host can also read with [`VENDOR_REQUEST_BUILD_ID`] to pick the
+//! matching ELF file.
+//!
+//! Programs such as ModemManager may open the serial port and read from it before the real tool
+//! attaches, consuming part of the stream. [`set_start_handshake`] has the logger task keep quiet
+//! until the host sends a start sequence, such as [`DEFAULT_START_SEQUENCE`], optionally followed
+//! by the frames queued in the meantime. On Linux, [`LoggerConfig::modem_manager_ignore`] gives the
+//! serial port a vendor-specific protocol, which ModemManager does not probe. ModemManager can also
+//! be told to leave the port alone with the `ID_MM_DEVICE_IGNORE` udev property: the
+//! `host-tools/udev` directory of the repository has a rule that sets it for devices with the
+//! product string of [`pid_codes_test_config`]. [`set_software_flow_control`] lets a
+//! terminal program that cannot keep up pause the stream with [`XOFF`] and resume it with [`XON`].
+//!
+//! ## CRC framing
+//!
+//! The defmt stream relies on the USB link to deliver every byte intact, and a corrupted frame
+//! is decoded as garbage or derails the decoder. With the `crc-framing` feature every frame is
+//! sent as its length, as a little-endian `u16`, followed by the encoded frame and a CRC of it,
+//! also a little-endian `u16`. The length counts the frame and the CRC. The CRC is
+//! CRC-16/CCITT-FALSE: polynomial `0x1021`, initial value `0xffff`, not reflected and with no
+//! final XOR.
+//!
+//! The stream is then no longer understood by `defmt-print` directly. The `defmt-unframe` tool in
+//! the `host-tools` directory of the repository checks and strips the framing, skipping corrupted
+//! frames, so that the output can be piped into `defmt-print`:
+//!
+//! ```text
+//! defmt-unframe < /dev/ttyACM0 | defmt-print -e firmware.elf
+//! ```
+//!
+//! The tool does not understand [`set_chunk_headers`], so leave it disabled.
+//!
+//! ## Frame transforms
+//!
+//! [`set_frame_transform`] passes every frame through a [`FrameTransform`] on its way into the
+//! ring buffer, which can add a prefix and a suffix and transform the encoded bytes in place, for
+//! example to tag frames or protect them with a CRC. [`Identity`] and [`Crc16`] are provided, and
+//! the host must undo anything else before passing the stream to `defmt-print`.
+//!
+//! ## Channels
+//!
+//! An application that needs to send binary data of its own, such as sensor samples, can do so
+//! over the defmt serial port rather than with another interface, which saves endpoints on
+//! microcontrollers with few of them. [`set_channels`] sends every frame with its length and the
+//! number of its channel, [`DEFMT_CHANNEL`] for the defmt frames, and each write to a [`Channel`]
+//! is sent as a frame of its own on the channel numbered by the application. The host must split
+//! the stream by channel before passing the defmt frames to `defmt-print`, which `defmt-unframe`
+//! does not do yet. Enable the `crc-framing` feature as well so that the host can find the next
+//! frame after a corrupted or truncated one.
+//!
+//! Logs of another core or microcontroller running the same firmware can be forwarded as they
+//! are: [`write_frame`] queues a defmt frame encoded elsewhere between the frames of the logger,
+//! so that it reaches the host as if it had been logged locally. [`forward_uart`] does so for the
+//! frames another microcontroller sends over a UART, as with `defmt-serial`, or on a channel of
+//! their own when it runs another firmware. [`set_source_tags`] tags every defmt frame with the ID
+//! of its producer, given to [`write_frame_from`], so that the host can label the frames of each
+//! core or microcontroller rather than show them all as one log.
+//!
+//! ## Encryption
+//!
+//! Field units can keep logging enabled without exposing their internals to anyone who plugs in a
+//! USB cable by encrypting the stream. Implement [`LogCipher`] with a stream cipher such as
+//! ChaCha20 and a device key, and pass it to [`set_log_cipher`] before the host connects. Each
+//! session then starts with an unencrypted header, such as a nonce, and everything after it is
+//! encrypted. The host must decrypt the stream before passing it to `defmt-print`.
+//!
+//! ## Log sinks
+//!
+//! With the `log-sinks` feature, every defmt frame can be sent elsewhere as well as to the USB
+//! serial port, for example to a UART or to a log in flash. Give the sinks a queue with
+//! `set_log_sink_queue`, implement `LogSink`, add it with `add_log_sink`, and run
+//! `run_log_sinks` in a task of its own: it receives the frames in the format `defmt-print`
+//! decodes, whether or not the host is reading from the serial port. The logger only copies the
+//! frames into the queue, and the sinks are called from that task, outside of the critical section
+//! of the logger. Without the feature, there is no queue, and the frames are not copied for it.
+//!
+//! With the `rtt` feature, every frame is also written to an RTT up channel named `defmt`, as
+//! `defmt-rtt` does, so that the same firmware logs through a debug probe on the bench, with
+//! `probe-rs`, and through USB in the field. The channel has a buffer of `RTT_BUFFER_LEN` bytes,
+//! and what does not fit is dropped unless the probe sets the channel to block. The feature
+//! defines the RTT control block, so it cannot be combined with another crate that does, such as
+//! `rtt-target`. To keep RTT out of the way while USB works, `set_rtt_fallback` only writes to it
+//! when the USB device is not connected within a given time, so that failures early in boot are
+//! still visible through a probe.
+//!
+//! With the `flash-log` feature, `FlashLog` keeps the most recent frames in a region of a NOR
+//! flash, such as an external SPI flash, through the `embedded-storage-async` traits, so that a
+//! device deployed without a host can be inspected later. It writes the sectors of the region in
+//! turn, overwriting the oldest, and resumes after the newest one on boot. With the `sd-log`
+//! feature, `SdCardLog` appends them instead to a file on an SD card through `embedded-sdmmc`,
+//! after a session header on every boot, for long unattended runs. Either of them replays what it
+//! has persisted over the USB serial port when the host requests it with `dump_history`, for
+//! example through `dump_history_command` registered with a [`CommandDispatcher`], with markers
+//! around the replay and between the boots, so that the device is its own black-box recorder.
+//! Both are fed through a log sink, so they enable the `log-sinks` feature, and need a queue set
+//! with `set_log_sink_queue` and `run_log_sinks` running too.
 //!
 //! ## Examples
 //!
@@ -88,18 +422,22 @@
 //! several complete out-out-date messages), and the first up-to-date defmt message will be
 //! corrupt.
 //!
-//! This is ultimately because the internal buffers are not aware of defmt frame boundaries. The
-//! first case will occur because the writing task will block part-way through writing an internal
-//! buffer to the USB serial port, and continues writing that now-stale buffer when you start
-//! reading again. (This may be avoided in a future release by way of a timeout.)
-//!
-//! The second is because that buffer may end part-way through a defmt message, and the next buffer
-//! that is written will likely start part-way through a defmt message. `defmt-print` may
-//! explicitly report these frames as malformed, or may silently misinterpret values to be included
-//! in a format message.
+//! Each defmt frame is committed to the internal ring buffer as a whole, and frames that do not fit
+//! are dropped entirely, so overflowing the buffer does not corrupt the stream. However, the
+//! writing task will block part-way through sending a frame to the USB serial port, and continues
+//! with the now-stale data when you start reading again. `defmt-print` may explicitly report the
+//! partial frame as malformed, or may silently misinterpret values to be included in a format
+//! message. Use [`set_stale_timeout`] to drop the queued frames when the host has not read
+//! anything for a while, and resume cleanly at the next frame boundary. If the host closes the
+//! port properly, dropping DTR, [`set_buffer_while_closed`] with `false` does the same right away,
+//! and keeps dropping frames until the port is opened again.
 //!
-//! Note as well that ceasing to read from the serial port does not disable defmt logging; it seems
-//! that only disconnecting from USB will trigger the event that toggles the logger.
+//! Note as well that ceasing to read from the serial port is not detected as a disconnection; it
+//! seems that only disconnecting from USB does that. On disconnection, the queued frames are
+//! dropped, as are the frames logged until the host connects again, so that it only receives
+//! current messages. Frames logged before the host first connects are kept. Call
+//! [`set_buffer_while_disconnected`] to also keep the frames logged while disconnected, and send
+//! them once the host reconnects.
 //!
 //! ## Acknowledgements
 //!
@@ -119,15 +457,142 @@
 
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "embassy-boot")]
+mod boot;
+mod bulk;
+mod cdc_acm;
+mod channel;
+mod cipher;
+mod class;
+mod command;
+mod config;
+mod connection;
+mod console;
 mod controller;
+mod crc;
+mod dfu;
+mod dump;
+mod error;
+mod filter;
+#[cfg(feature = "flash-log")]
+mod flash_log;
+mod flow_control;
+mod forward;
+mod handshake;
+mod hid;
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+mod history;
+mod ident;
+mod msc;
+mod msos;
+#[cfg(feature = "panic-flush")]
+mod panic;
+mod persist;
+mod receiver;
+mod reliable;
+#[cfg(feature = "rtt")]
+mod rtt;
+#[cfg(feature = "sd-log")]
+mod sd_log;
+mod send_break;
+mod serial_number;
+mod shutdown;
+#[cfg(feature = "log-sinks")]
+mod sink;
+mod stats;
 mod task;
+mod transform;
+mod vendor;
 
 use core::{
     cell::UnsafeCell,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-pub use task::{logger, run};
+use embassy_time::{Duration, Instant};
+
+#[cfg(feature = "embassy-boot")]
+pub use boot::{bootloader_command, reset_to_bootloader, set_boot_state};
+pub use bulk::{DefmtBulkClass, DefmtBulkState};
+pub use channel::{Channel, DEFMT_CHANNEL};
+pub use cipher::{LogCipher, set_log_cipher};
+pub use class::{DefmtClass, DefmtState};
+pub use command::{BINARY_COMMAND_START, Command, CommandDispatcher, MAX_COMMAND_LEN};
+pub use config::{
+    DEFMT_PRODUCT, LoggerConfig, PID_CODES_TEST_PID, PID_CODES_VID, default_config,
+    pid_codes_test_config, single_function_config,
+};
+pub use connection::{
+    LineState, MAX_CONNECTION_RECEIVERS, MAX_LINE_STATE_RECEIVERS, connection_receiver,
+    is_connected, line_state, line_state_receiver, wait_for_host,
+};
+pub use console::{CONSOLE_BUFFER_LEN, Console, bridge_uart, console};
+#[cfg(feature = "alloc")]
+pub use controller::init_alloc;
+#[cfg(feature = "rtt")]
+pub use controller::set_rtt_fallback;
+pub use controller::{
+    DropCounters, OnWriteTimeout, OverflowPolicy, PacketTermination, drained, drop_counters,
+    emergency, fill_level, flush, free_space, high_watermark, init, last_flush_instant,
+    logging_enabled, reset_drop_counters, reset_high_watermark, set_adaptive_flush, set_baud_touch,
+    set_buffer_while_closed, set_buffer_while_disconnected, set_build_id, set_channels,
+    set_chunk_headers, set_double_buffering, set_emergency_reserve, set_firmware_ident,
+    set_flush_deadline, set_flush_timeout, set_flush_watermark, set_frame_transform,
+    set_line_state_debounce, set_logging_enabled, set_overflow_policy, set_packet_coalescing,
+    set_packet_termination, set_reset_handler, set_reset_reason, set_self_report_interval,
+    set_session_marker, set_software_flow_control, set_source_tags, set_stale_timeout,
+    set_start_handshake, set_verbosity_presets, set_wait_on_full, set_write_timeout, stats,
+};
+pub use dfu::{DFU_INTERFACE_GUID, DfuRuntimeState, add_dfu_runtime};
+pub use dump::{dump_buffer, dump_buffer_command};
+pub use error::ConfigError;
+pub use filter::{
+    DEFAULT_VERBOSITY_PRESETS, FILTER_BLOCK, FILTER_CLEAR, FILTER_UNBLOCK, Level,
+    MAX_BLOCKED_CALL_SITES, block_call_site, call_site_filter_command, clear_call_site_filter,
+    min_level, set_min_level, unblock_call_site,
+};
+#[cfg(feature = "flash-log")]
+pub use flash_log::{FLASH_LOG_BUFFER_LEN, FlashLog};
+pub use flow_control::{XOFF, XON};
+pub use forward::{
+    ForwardTo, LOCAL_SOURCE, MAX_FORWARDED_FRAME_LEN, forward_uart, write_frame, write_frame_from,
+};
+pub use handshake::DEFAULT_START_SEQUENCE;
+pub use hid::{DefmtHidClass, DefmtHidState, HID_REPORT_LEN};
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+pub use history::{MAX_REPLAYED_FRAME_LEN, dump_history, dump_history_command};
+pub use ident::{FirmwareIdent, gnu_build_id, ident_command, log_ident};
+pub use msc::{DefmtMscClass, MscState};
+pub use msos::{DEFMT_INTERFACE_GUID, MSOS_VENDOR_CODE, add_msos_descriptors};
+#[cfg(feature = "panic-flush")]
+pub use panic::{flush_after_panic, flush_blocking_with_budget};
+pub use persist::{PersistentBuffer, init_persistent};
+pub use reliable::{RELIABLE_ACK, set_retransmit_window};
+#[cfg(feature = "rtt")]
+pub use rtt::RTT_BUFFER_LEN;
+#[cfg(feature = "sd-log")]
+pub use sd_log::{SD_LOG_BUFFER_LEN, SdCardLog};
+pub use serial_number::{
+    HexSerialNumber, MAX_SERIAL_NUMBER_LEN, SerialNumberProvider, serial_number,
+};
+pub use shutdown::shutdown;
+#[cfg(feature = "log-sinks")]
+pub use sink::{LogSink, MAX_LOG_SINKS, add_log_sink, run_log_sinks, set_log_sink_queue};
+pub use stats::LoggerStats;
+#[cfg(feature = "cs-instrumentation")]
+pub use stats::{max_critical_section_cycles, set_cycle_counter};
+pub use task::{
+    DescriptorBuffers, build, logger, logger_with_writer, run, run_with_buffers, run_with_builder,
+    run_with_receiver, run_with_ring_buffer,
+};
+pub use transform::{Crc16, FrameTransform, Identity, MAX_FRAME_AFFIX};
+pub use vendor::{
+    ResetRequest, VENDOR_REQUEST_BOOTLOADER, VENDOR_REQUEST_BUILD_ID, VENDOR_REQUEST_RESET,
+    VENDOR_REQUEST_SET_LOGGING,
+};
 
 static USB_ENCODER: UsbEncoder = UsbEncoder::new();
 
@@ -137,12 +602,26 @@ struct UsbEncoder {
     /// Is `true` when `acquire` has been called and we have exclusive access to the
     /// rest of this struct.
     taken: AtomicBool,
+    /// Number of frames started while the logger was taken, which are being dropped.
+    nested: AtomicUsize,
+    /// Whether the logger is waiting outside of its critical section, in [`spin`], so that a
+    /// frame started meanwhile by an interrupt handler is dropped.
+    ///
+    /// [`spin`]: UsbEncoder::spin
+    #[cfg(not(feature = "short-critical-sections"))]
+    spinning: AtomicBool,
     /// Critical section restore state
     ///
     /// Needed to exit a critical section.
+    #[cfg(not(feature = "short-critical-sections"))]
     restore: UnsafeCell<critical_section::RestoreState>,
     /// A defmt Encoder for encoding frames
     encoder: UnsafeCell<defmt::Encoder>,
+    /// Whether nothing has been written to the current frame yet, so that the next write holds
+    /// the index of its format string.
+    frame_start: UnsafeCell<bool>,
+    /// Whether the current frame comes from a blocked call site and is being discarded.
+    filtered: UnsafeCell<bool>,
 }
 
 unsafe impl Sync for UsbEncoder {}
@@ -151,8 +630,14 @@ impl UsbEncoder {
     const fn new() -> Self {
         Self {
             taken: AtomicBool::new(false),
+            nested: AtomicUsize::new(0),
+            #[cfg(not(feature = "short-critical-sections"))]
+            spinning: AtomicBool::new(false),
+            #[cfg(not(feature = "short-critical-sections"))]
             restore: UnsafeCell::new(critical_section::RestoreState::invalid()),
             encoder: UnsafeCell::new(defmt::Encoder::new()),
+            frame_start: UnsafeCell::new(false),
+            filtered: UnsafeCell::new(false),
         }
     }
 
@@ -160,32 +645,78 @@ impl UsbEncoder {
     ///
     /// This acquires a critical section and begins a defmt frame.
     ///
+    /// With the `short-critical-sections` feature, the critical section is only held while taking
+    /// the boolean lock, and a frame started while the logger is already taken (by an interrupt
+    /// handler preempting the logging code) is dropped instead.
+    ///
+    /// Without it, a frame started by an interrupt handler while the logger waits for room or for a
+    /// flush, outside of its critical section, is dropped in the same way.
+    ///
     /// # Panics
     ///
-    /// This will panic if you attempt to acquire the logger re-entrantly.
+    /// Without the `short-critical-sections` feature, this will panic if you attempt to acquire
+    /// the logger re-entrantly otherwise.
     fn acquire(&self) {
-        // Get in a critical section.
-        //
-        // SAFETY: Must be paired with a call to release, as it is in the contract of
-        // the Logger trait.
-        let restore_state = unsafe { critical_section::acquire() };
-
-        // Fail if the logger is acquired re-entrantly, to avoid two places with
-        // mutable access to the logger state.
-        if self.taken.load(Ordering::Relaxed) {
-            panic!("defmt logger taken reentrantly");
-        }
+        #[cfg(not(feature = "short-critical-sections"))]
+        {
+            // Get in a critical section.
+            //
+            // SAFETY: Must be paired with a call to release, as it is in the contract of
+            // the Logger trait.
+            let restore_state = unsafe { critical_section::acquire() };
+            stats::critical_section_entered();
 
-        // Set the boolean lock now that we're in a critical section and we know
-        // it is not already taken.
-        self.taken.store(true, Ordering::Relaxed);
+            // Fail if the logger is acquired re-entrantly, to avoid two places with
+            // mutable access to the logger state.
+            if self.taken.load(Ordering::Relaxed) {
+                if !self.spinning.load(Ordering::Relaxed) {
+                    panic!("defmt logger taken reentrantly");
+                }
+                // An interrupt handler preempted the logger while it was waiting, so drop this
+                // frame, without holding the critical section for it.
+                let nested = self.nested.load(Ordering::Relaxed);
+                self.nested.store(nested + 1, Ordering::Relaxed);
+                stats::critical_section_exiting();
+                // SAFETY: Paired with the acquire above.
+                unsafe { critical_section::release(restore_state) };
+                return;
+            }
+
+            // Set the boolean lock now that we're in a critical section and we know
+            // it is not already taken.
+            self.taken.store(true, Ordering::Relaxed);
 
-        // SAFETY: Accessing the UnsafeCells is OK because we are in a critical section.
-        unsafe {
             // Store the value needed to exit the critical section.
-            self.restore.get().write(restore_state);
+            //
+            // SAFETY: Accessing the UnsafeCell is OK because we are in a critical section.
+            unsafe { self.restore.get().write(restore_state) };
+        }
+
+        #[cfg(feature = "short-critical-sections")]
+        {
+            // Take the boolean lock, or note that this frame is nested and must be dropped.
+            let nested = critical_section::with(|_| {
+                if self.taken.load(Ordering::Relaxed) {
+                    let nested = self.nested.load(Ordering::Relaxed);
+                    self.nested.store(nested + 1, Ordering::Relaxed);
+                    true
+                } else {
+                    self.taken.store(true, Ordering::Relaxed);
+                    false
+                }
+            });
+            if nested {
+                return;
+            }
+        }
 
+        // SAFETY: Accessing the UnsafeCells is OK because we hold the boolean lock, and the
+        // controller is only accessed within a critical section.
+        unsafe {
             // Start the defmt frame.
+            Self::in_critical_section(|| controller::CONTROLLER.start_frame());
+            self.frame_start.get().write(true);
+            self.filtered.get().write(false);
             let encoder = &mut *self.encoder.get();
             encoder.start_frame(Self::inner);
         }
@@ -204,26 +735,61 @@ impl UsbEncoder {
             panic!("defmt release outside of critical section.")
         }
 
+        if self.is_nested() {
+            critical_section::with(|_| {
+                let nested = self.nested.load(Ordering::Relaxed);
+                self.nested.store(nested - 1, Ordering::Relaxed);
+            });
+            return;
+        }
+
         // SAFETY: Accessing the UnsafeCells and finally releasing the critical section
-        // is OK because we know we are in a critical section at this point.
+        // is OK because we know we hold the boolean lock and the critical section at this point.
         unsafe {
             let encoder = &mut *self.encoder.get();
             encoder.end_frame(Self::inner);
+            Self::in_critical_section(|| controller::CONTROLLER.end_frame());
 
-            let restore_state = self.restore.get().read();
+            #[cfg(not(feature = "short-critical-sections"))]
+            {
+                let restore_state = self.restore.get().read();
+                self.taken.store(false, Ordering::Relaxed);
+                stats::critical_section_exiting();
+                critical_section::release(restore_state);
+            }
+
+            #[cfg(feature = "short-critical-sections")]
             self.taken.store(false, Ordering::Relaxed);
-            critical_section::release(restore_state);
         }
     }
 
     /// Flush the current buffer.
     ///
+    /// This waits, outside of any critical section, for the USB task to send every queued frame,
+    /// for up to the time set with [`set_flush_timeout`]. By default it does nothing.
+    ///
     /// # Safety
     ///
     /// Must be called after calling `acquire` and before calling `release`.
     unsafe fn flush(&self) {
-        // Ideally this would block until everything has been written to the USB serial port.
-        // However, this is not possible in a synchronous context, so we do nothing.
+        if self.is_nested() {
+            return;
+        }
+
+        // SAFETY: The controller is accessed within a critical section.
+        let timeout = Self::in_critical_section(|| unsafe {
+            let timeout = controller::CONTROLLER.flush_timeout();
+            if timeout.is_some() {
+                controller::CONTROLLER.start_draining();
+            }
+            timeout
+        });
+        if let Some(timeout) = timeout {
+            // SAFETY: The controller is accessed within a critical section.
+            unsafe {
+                self.spin(timeout, || controller::CONTROLLER.is_drained());
+            }
+        }
     }
 
     /// Write bytes to the defmt encoder.
@@ -232,14 +798,114 @@ impl UsbEncoder {
     ///
     /// Must be called after calling `acquire` and before calling `release`.
     unsafe fn write(&self, bytes: &[u8]) {
-        let encoder = unsafe { &mut *self.encoder.get() };
-        encoder.write(bytes, Self::inner)
+        if self.is_nested() {
+            return;
+        }
+
+        // SAFETY: Accessing the UnsafeCells is OK because we hold the boolean lock, and the
+        // controller is only accessed within a critical section.
+        unsafe {
+            // Discard the frame if its call site is blocked, before anything is encoded.
+            if self.frame_start.get().replace(false) && filter::is_blocked(bytes) {
+                self.filtered.get().write(true);
+                Self::in_critical_section(|| controller::CONTROLLER.mute_frame());
+            }
+            if self.filtered.get().read() {
+                return;
+            }
+
+            let encoder = &mut *self.encoder.get();
+            encoder.write(bytes, Self::inner)
+        }
+    }
+
+    /// Whether the frame being logged was started while the logger was already taken.
+    fn is_nested(&self) -> bool {
+        self.nested.load(Ordering::Relaxed) > 0
+    }
+
+    /// Run `f` in a critical section.
+    ///
+    /// Without the `short-critical-sections` feature, the logger already holds a critical section
+    /// for the whole frame, so this just calls `f`.
+    #[inline]
+    fn in_critical_section<R>(f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "short-critical-sections")]
+        return critical_section::with(|_| {
+            stats::critical_section_entered();
+            let result = f();
+            stats::critical_section_exiting();
+            result
+        });
+
+        #[cfg(not(feature = "short-critical-sections"))]
+        f()
+    }
+
+    /// Wait, outside of any critical section, for the ring buffer to have room for `len` bytes,
+    /// for up to the time set with [`set_wait_on_full`].
+    ///
+    /// # Safety
+    ///
+    /// Must be called after calling `acquire` and before calling `release`.
+    unsafe fn wait_for_room(&self, len: usize) {
+        let Some(timeout) =
+            Self::in_critical_section(|| unsafe { controller::CONTROLLER.wait_on_full() })
+        else {
+            return;
+        };
+        if Self::in_critical_section(|| unsafe { controller::CONTROLLER.has_room(len) }) {
+            return;
+        }
+
+        // SAFETY: The controller is accessed within a critical section.
+        unsafe { self.spin(timeout, || controller::CONTROLLER.has_room(len)) };
     }
 
+    /// Spin for up to `timeout` until `done` returns true, outside of any critical section held by
+    /// the logger, so that the USB task can run.
+    ///
+    /// `done` is called within a critical section. Frames logged by interrupt handlers in the
+    /// meantime are dropped.
+    ///
+    /// # Safety
+    ///
+    /// Must be called after calling `acquire` and before calling `release`.
+    unsafe fn spin(&self, timeout: Duration, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + timeout;
+
+        // SAFETY: We hold the critical section, and the restore state was stored by `acquire`.
+        // The boolean lock stays taken, so nothing else can use the encoder in the meantime.
+        #[cfg(not(feature = "short-critical-sections"))]
+        unsafe {
+            self.spinning.store(true, Ordering::Relaxed);
+            stats::critical_section_exiting();
+            critical_section::release(self.restore.get().read());
+        }
+
+        while Instant::now() < deadline && !critical_section::with(|_| done()) {
+            core::hint::spin_loop();
+        }
+
+        // SAFETY: The critical section was released above.
+        #[cfg(not(feature = "short-critical-sections"))]
+        unsafe {
+            self.restore.get().write(critical_section::acquire());
+            stats::critical_section_entered();
+            self.spinning.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Sink for the encoder's output.
+    ///
+    /// The encoder hands over its output a few bytes at a time, which are copied straight into the
+    /// ring buffer: this is the only copy of the encoded bytes.
     fn inner(bytes: &[u8]) {
-        // SAFETY: Always called from within a critical section by the defmt logger.
+        // SAFETY: Always called while the logger is taken, and the controller is only accessed
+        // within a critical section.
         unsafe {
-            controller::CONTROLLER.write(bytes);
+            USB_ENCODER.wait_for_room(bytes.len());
+            Self::in_critical_section(|| controller::CONTROLLER.write(bytes));
         }
     }
 }
@@ -0,0 +1,672 @@
+//! The queued frames as a file on a USB drive, for users without any tooling
+
+use embassy_usb::{
+    Builder, Handler,
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut},
+    types::InterfaceNumber,
+};
+
+use crate::{
+    controller::CONTROLLER,
+    task::MAX_PACKET_SIZE,
+    vendor::{self, VendorHandler},
+};
+
+/// Class code of mass storage interfaces.
+const USB_CLASS_MSC: u8 = 0x08;
+
+/// Subclass code of the SCSI transparent command set.
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+
+/// Protocol code of the Bulk-Only Transport.
+const MSC_PROTOCOL_BBB: u8 = 0x50;
+
+/// Class request that resets the Bulk-Only Transport.
+const REQUEST_MASS_STORAGE_RESET: u8 = 0xff;
+
+/// Class request that reads the highest logical unit number.
+const REQUEST_GET_MAX_LUN: u8 = 0xfe;
+
+/// Signature of a Command Block Wrapper.
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+
+/// Length of a Command Block Wrapper.
+const CBW_LEN: usize = 31;
+
+/// Signature of a Command Status Wrapper.
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+/// Size of a block of the drive, and of a sector and a cluster of its file system.
+const SECTOR_LEN: usize = 512;
+
+/// Number of copies of the file allocation table.
+const NUM_FATS: usize = 2;
+
+/// Number of entries of the root directory, which fill a sector.
+const ROOT_ENTRIES: usize = SECTOR_LEN / 32;
+
+/// Largest number of clusters of a FAT12 file system.
+const MAX_CLUSTERS: usize = 4084;
+
+/// Date of the directory entries, 1 January 1980, the earliest one FAT can represent.
+const FAT_DATE: u16 = (1 << 5) | 1;
+
+/// Status of a command, in a Command Status Wrapper.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    /// The command succeeded.
+    Passed = 0,
+    /// The command failed, and the host reads why with REQUEST SENSE.
+    Failed = 1,
+    /// The host expects a data stage that does not match the command.
+    PhaseError = 2,
+}
+
+/// Sense key and additional sense code of the last failed command, reported by REQUEST SENSE.
+#[derive(Clone, Copy)]
+struct Sense {
+    /// Sense key.
+    key: u8,
+    /// Additional sense code.
+    asc: u8,
+}
+
+impl Sense {
+    /// The last command succeeded.
+    const NONE: Self = Self {
+        key: 0x00,
+        asc: 0x00,
+    };
+    /// The command is not supported.
+    const INVALID_COMMAND: Self = Self {
+        key: 0x05,
+        asc: 0x20,
+    };
+    /// The command addresses blocks past the end of the drive.
+    const OUT_OF_RANGE: Self = Self {
+        key: 0x05,
+        asc: 0x21,
+    };
+    /// The drive is read-only.
+    const WRITE_PROTECTED: Self = Self {
+        key: 0x07,
+        asc: 0x27,
+    };
+}
+
+/// Control handler of the class requests of the host.
+struct MscHandler {
+    /// Number of the mass storage interface.
+    interface: InterfaceNumber,
+}
+
+impl Handler for MscHandler {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface)
+            || req.index != u16::from(self.interface.0)
+        {
+            return None;
+        }
+        // There is no transport state to reset, as every command is handled to completion.
+        (req.request == REQUEST_MASS_STORAGE_RESET).then_some(OutResponse::Accepted)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface)
+            || req.index != u16::from(self.interface.0)
+            || req.request != REQUEST_GET_MAX_LUN
+        {
+            return None;
+        }
+        buf[0] = 0;
+        Some(InResponse::Accepted(&buf[..1]))
+    }
+}
+
+/// State of a [`DefmtMscClass`], which must outlive it.
+pub struct MscState {
+    /// Control handler of the class requests.
+    handler: MscHandler,
+    /// Control handler of the vendor requests.
+    vendor: VendorHandler,
+}
+
+impl MscState {
+    /// Create the state.
+    pub const fn new() -> Self {
+        Self {
+            handler: MscHandler {
+                interface: InterfaceNumber(0),
+            },
+            vendor: VendorHandler,
+        }
+    }
+}
+
+impl Default for MscState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Layout of the FAT12 file system of the drive, which holds `LOG.TXT` and nothing else.
+///
+/// The file system is generated a sector at a time as the host reads it, with one sector per
+/// cluster, and the file in consecutive clusters from the first one.
+struct Volume {
+    /// Number of data clusters.
+    clusters: usize,
+    /// Number of sectors of each copy of the file allocation table.
+    fat_sectors: usize,
+    /// Length of the file.
+    file_len: usize,
+}
+
+impl Volume {
+    /// Lay out a file system for a file of up to `capacity` bytes, or as much of it as fits in
+    /// FAT12, empty for now.
+    fn new(capacity: usize) -> Self {
+        let clusters = capacity.div_ceil(SECTOR_LEN).clamp(1, MAX_CLUSTERS);
+        Self {
+            clusters,
+            // Each entry takes 12 bits, and the first two are reserved.
+            fat_sectors: ((clusters + 2) * 3).div_ceil(2).div_ceil(SECTOR_LEN),
+            file_len: 0,
+        }
+    }
+
+    /// First sector of the root directory.
+    fn root_start(&self) -> usize {
+        1 + NUM_FATS * self.fat_sectors
+    }
+
+    /// First sector of the data clusters.
+    fn data_start(&self) -> usize {
+        self.root_start() + 1
+    }
+
+    /// Number of sectors of the drive.
+    fn sectors(&self) -> usize {
+        self.data_start() + self.clusters
+    }
+
+    /// Number of clusters of the file.
+    fn file_clusters(&self) -> usize {
+        self.file_len.div_ceil(SECTOR_LEN)
+    }
+
+    /// Entry `n` of the file allocation table.
+    fn fat_entry(&self, n: usize) -> u16 {
+        match n {
+            0 => 0xff8,
+            1 => 0xfff,
+            _ if n - 2 + 1 == self.file_clusters() => 0xfff,
+            _ if n - 2 < self.file_clusters() => (n + 1) as u16,
+            _ => 0,
+        }
+    }
+
+    /// Copy sector `lba` into `out`, taking the contents of the file from `file`.
+    fn read_sector(&self, lba: usize, file: &[u8], out: &mut [u8; SECTOR_LEN]) {
+        out.fill(0);
+        if lba == 0 {
+            self.boot_sector(out);
+        } else if lba < self.root_start() {
+            let fat_offset = (lba - 1) % self.fat_sectors * SECTOR_LEN;
+            for (i, byte) in out.iter_mut().enumerate() {
+                // Two entries of 12 bits are packed into every three bytes.
+                let offset = fat_offset + i;
+                let first = self.fat_entry(offset / 3 * 2);
+                let second = self.fat_entry(offset / 3 * 2 + 1);
+                *byte = match offset % 3 {
+                    0 => first as u8,
+                    1 => ((first >> 8) | (second << 4)) as u8,
+                    _ => (second >> 4) as u8,
+                };
+            }
+        } else if lba == self.root_start() {
+            self.root_directory(out);
+        } else {
+            let start = (lba - self.data_start()) * SECTOR_LEN;
+            if let Some(data) = file.get(start..self.file_len) {
+                let len = data.len().min(SECTOR_LEN);
+                out[..len].copy_from_slice(&data[..len]);
+            }
+        }
+    }
+
+    /// Write the boot sector, with the BIOS Parameter Block of the file system, into `out`.
+    fn boot_sector(&self, out: &mut [u8; SECTOR_LEN]) {
+        out[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        out[3..11].copy_from_slice(b"MSWIN4.1");
+        out[11..13].copy_from_slice(&(SECTOR_LEN as u16).to_le_bytes());
+        out[13] = 1; // Sectors per cluster
+        out[14..16].copy_from_slice(&1u16.to_le_bytes()); // Reserved sectors
+        out[16] = NUM_FATS as u8;
+        out[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+        out[19..21].copy_from_slice(&(self.sectors() as u16).to_le_bytes());
+        out[21] = 0xf8; // Media descriptor of a fixed disk
+        out[22..24].copy_from_slice(&(self.fat_sectors as u16).to_le_bytes());
+        out[24..26].copy_from_slice(&32u16.to_le_bytes()); // Sectors per track
+        out[26..28].copy_from_slice(&2u16.to_le_bytes()); // Heads
+        out[36] = 0x80; // Drive number
+        out[38] = 0x29; // Extended boot signature
+        out[39..43].copy_from_slice(&0xdef7_0b0fu32.to_le_bytes()); // Volume serial number
+        out[43..54].copy_from_slice(b"DEFMT LOGS ");
+        out[54..62].copy_from_slice(b"FAT12   ");
+        out[510..].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    /// Write the root directory, with the volume label and the file, into `out`.
+    fn root_directory(&self, out: &mut [u8; SECTOR_LEN]) {
+        let (label, file) = out.split_at_mut(32);
+        label[..11].copy_from_slice(b"DEFMT LOGS ");
+        label[11] = 0x08; // Volume label
+        file[..11].copy_from_slice(b"LOG     TXT");
+        file[11] = 0x01; // Read-only
+        for date in [16, 18, 24] {
+            file[date..date + 2].copy_from_slice(&FAT_DATE.to_le_bytes());
+        }
+        let first_cluster: u16 = if self.file_len > 0 { 2 } else { 0 };
+        file[26..28].copy_from_slice(&first_cluster.to_le_bytes());
+        file[28..32].copy_from_slice(&(self.file_len as u32).to_le_bytes());
+    }
+}
+
+/// A read-only USB drive holding the queued defmt frames in a file, `LOG.TXT`, alongside the
+/// serial port.
+///
+/// Anyone can then retrieve the logs of a device by plugging it in and copying the file, without
+/// installing any tool, and send it to whoever has the firmware to decode it with
+/// `defmt-print -e firmware.elf < LOG.TXT`. The file holds the raw defmt stream, as it would be
+/// sent over the serial port: the device cannot decode the frames, as their format strings are
+/// only in the ELF file.
+///
+/// The file is a copy of the frames queued in the ring buffer when the host configures the
+/// device, which stay queued for the serial port. Frames queue up while no host tool has the
+/// serial port open, so with the default settings the file holds the frames logged since the
+/// device last started, or the newest ones if it was plugged into a host that read them. Unplug
+/// the device and plug it in again to see newer frames. `file` holds the copy, and should be as
+/// large as the ring buffer, up to about 2 MB, beyond which it is not used.
+///
+/// Build the device yourself, with the serial port of [`DefmtClass`] and the drive in a
+/// composite device, and run them all together:
+///
+/// ```ignore
+/// static STATE: StaticCell<MscState> = StaticCell::new();
+/// static FILE: StaticCell<[u8; 4096]> = StaticCell::new();
+///
+/// let mut builder = embassy_usb::Builder::new(driver, usb_config, ...);
+/// let defmt = DefmtClass::new(&mut builder, ...);
+/// let drive =
+///     DefmtMscClass::new(&mut builder, STATE.init(MscState::new()), FILE.init([0; 4096]), 64);
+/// let mut usb = builder.build();
+///
+/// join3(usb.run(), defmt.run(), drive.run()).await;
+/// ```
+///
+/// The drive holds a FAT12 file system, which every operating system reads, generated as the host
+/// reads it. It is read-only, so the host cannot corrupt it, and nothing needs to be written back.
+///
+/// [`DefmtClass`]: crate::DefmtClass
+pub struct DefmtMscClass<'d, D: Driver<'d>> {
+    /// Endpoint the host sends commands to.
+    read_ep: D::EndpointOut,
+    /// Endpoint the data and status of each command are sent from.
+    write_ep: D::EndpointIn,
+    /// Maximum packet size of the endpoints.
+    max_packet_size: usize,
+    /// Copy of the queued frames.
+    file: &'d mut [u8],
+    /// Layout of the file system, for the copy in `file`.
+    volume: Volume,
+    /// Why the last command failed.
+    sense: Sense,
+}
+
+impl<'d, D: Driver<'d>> DefmtMscClass<'d, D> {
+    /// Add the mass storage interface to `builder`, with bulk endpoints of `max_packet_size`
+    /// bytes, and the frames copied to `file`.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut MscState,
+        file: &'d mut [u8],
+        max_packet_size: u16,
+    ) -> Self {
+        vendor::add_handler(builder, &mut state.vendor);
+        let (read_ep, write_ep) = {
+            let mut function = builder.function(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BBB);
+            let mut interface = function.interface();
+            state.handler.interface = interface.interface_number();
+            let mut alt =
+                interface.alt_setting(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BBB, None);
+            (
+                alt.endpoint_bulk_out(None, max_packet_size),
+                alt.endpoint_bulk_in(None, max_packet_size),
+            )
+        };
+        builder.handler(&mut state.handler);
+
+        let volume = Volume::new(file.len());
+        Self {
+            read_ep,
+            write_ep,
+            max_packet_size: usize::from(max_packet_size).min(MAX_PACKET_SIZE),
+            file,
+            volume,
+            sense: Sense::NONE,
+        }
+    }
+
+    /// Answer the commands of the host, forever.
+    ///
+    /// This must run alongside the USB device, built from the same builder.
+    pub async fn run(mut self) {
+        loop {
+            // Wait for the host to configure the device.
+            self.read_ep.wait_enabled().await;
+            let len = (self.volume.clusters * SECTOR_LEN).min(self.file.len());
+            self.volume.file_len = CONTROLLER.snapshot(&mut self.file[..len]);
+            self.sense = Sense::NONE;
+            // Serve the same copy until the device is disabled, past packets too large for the
+            // endpoint.
+            while self.serve().await != Err(EndpointError::Disabled) {}
+        }
+    }
+
+    /// Answer the commands of the host until an endpoint fails, as it does when the device is
+    /// disabled.
+    async fn serve(&mut self) -> Result<(), EndpointError> {
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let len = self
+                .read_ep
+                .read(&mut packet[..self.max_packet_size])
+                .await?;
+            let cbw = &packet[..len];
+            // Ignore anything that is not a valid Command Block Wrapper.
+            if len != CBW_LEN
+                || u32::from_le_bytes([cbw[0], cbw[1], cbw[2], cbw[3]]) != CBW_SIGNATURE
+            {
+                continue;
+            }
+            let tag = [cbw[4], cbw[5], cbw[6], cbw[7]];
+            let expected = u32::from_le_bytes([cbw[8], cbw[9], cbw[10], cbw[11]]) as usize;
+            let data_in = cbw[12] & 0x80 != 0;
+            let mut command = [0u8; 16];
+            command.copy_from_slice(&cbw[15..31]);
+
+            let (status, sent) = self.execute(&command, expected, data_in).await?;
+            let mut csw = [0u8; 13];
+            csw[..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+            csw[4..8].copy_from_slice(&tag);
+            csw[8..12].copy_from_slice(&((expected - sent) as u32).to_le_bytes());
+            csw[12] = status as u8;
+            self.write_ep.write(&csw).await?;
+        }
+    }
+
+    /// Execute the SCSI `command`, for which the host expects to send, or to receive if `data_in`
+    /// is set, `expected` bytes of data. Returns its status and the number of bytes transferred.
+    async fn execute(
+        &mut self,
+        command: &[u8; 16],
+        expected: usize,
+        data_in: bool,
+    ) -> Result<(Status, usize), EndpointError> {
+        let mut response = [0u8; 36];
+        let response: &[u8] = match command[0] {
+            // TEST UNIT READY, START STOP UNIT, PREVENT ALLOW MEDIUM REMOVAL and VERIFY (10), which
+            // have nothing to do on a read-only drive that is always there.
+            0x00 | 0x1b | 0x1e | 0x2f => &[],
+            // REQUEST SENSE, in fixed format.
+            0x03 => {
+                let sense = core::mem::replace(&mut self.sense, Sense::NONE);
+                response[0] = 0x70;
+                response[2] = sense.key;
+                response[7] = 10;
+                response[12] = sense.asc;
+                &response[..18]
+            }
+            // INQUIRY, of a removable direct-access device.
+            0x12 => {
+                response[1] = 0x80;
+                response[2] = 0x04;
+                response[3] = 0x02;
+                response[4] = 31;
+                response[8..16].copy_from_slice(b"defmt   ");
+                response[16..32].copy_from_slice(b"Log file        ");
+                response[32..36].copy_from_slice(b"1.0 ");
+                &response
+            }
+            // MODE SENSE (6), with the write protect bit set.
+            0x1a => {
+                response[..4].copy_from_slice(&[3, 0, 0x80, 0]);
+                &response[..4]
+            }
+            // MODE SENSE (10), with the write protect bit set.
+            0x5a => {
+                response[..8].copy_from_slice(&[0, 6, 0, 0x80, 0, 0, 0, 0]);
+                &response[..8]
+            }
+            // READ FORMAT CAPACITIES, which Windows asks for.
+            0x23 => {
+                response[3] = 8;
+                response[4..8].copy_from_slice(&(self.volume.sectors() as u32).to_be_bytes());
+                response[8] = 0x02; // Formatted media
+                response[9..12].copy_from_slice(&(SECTOR_LEN as u32).to_be_bytes()[1..]);
+                &response[..12]
+            }
+            // READ CAPACITY (10).
+            0x25 => {
+                let last = self.volume.sectors() as u32 - 1;
+                response[..4].copy_from_slice(&last.to_be_bytes());
+                response[4..8].copy_from_slice(&(SECTOR_LEN as u32).to_be_bytes());
+                &response[..8]
+            }
+            // READ (10).
+            0x28 => return self.read_blocks(command, expected, data_in).await,
+            // WRITE (10).
+            0x2a => {
+                self.sense = Sense::WRITE_PROTECTED;
+                let transferred = self.transfer(&[], expected, data_in).await?;
+                return Ok((Status::Failed, transferred));
+            }
+            _ => {
+                self.sense = Sense::INVALID_COMMAND;
+                let transferred = self.transfer(&[], expected, data_in).await?;
+                return Ok((Status::Failed, transferred));
+            }
+        };
+        if !response.is_empty() && !data_in {
+            let transferred = self.transfer(&[], expected, data_in).await?;
+            return Ok((Status::PhaseError, transferred));
+        }
+        let sent = self.transfer(response, expected, data_in).await?;
+        Ok((Status::Passed, sent))
+    }
+
+    /// Send the blocks the READ (10) `command` asks for.
+    async fn read_blocks(
+        &mut self,
+        command: &[u8; 16],
+        expected: usize,
+        data_in: bool,
+    ) -> Result<(Status, usize), EndpointError> {
+        let lba = u32::from_be_bytes([command[2], command[3], command[4], command[5]]) as usize;
+        let count = usize::from(u16::from_be_bytes([command[7], command[8]]));
+        if lba + count > self.volume.sectors() {
+            self.sense = Sense::OUT_OF_RANGE;
+            let transferred = self.transfer(&[], expected, data_in).await?;
+            return Ok((Status::Failed, transferred));
+        }
+        if !data_in || count * SECTOR_LEN > expected {
+            let transferred = self.transfer(&[], expected, data_in).await?;
+            return Ok((Status::PhaseError, transferred));
+        }
+
+        let mut sector = [0u8; SECTOR_LEN];
+        for lba in lba..lba + count {
+            self.volume.read_sector(lba, self.file, &mut sector);
+            for chunk in sector.chunks(self.max_packet_size) {
+                self.write_ep.write(chunk).await?;
+            }
+        }
+        let sent = count * SECTOR_LEN;
+        self.end_data_in(sent, expected).await?;
+        Ok((Status::Passed, sent))
+    }
+
+    /// Go through the data stage of a command for which the host expects `expected` bytes: send
+    /// as much of `data` as it expects if `data_in` is set, and receive and drop the data it sends
+    /// otherwise. Returns the number of bytes transferred.
+    async fn transfer(
+        &mut self,
+        data: &[u8],
+        expected: usize,
+        data_in: bool,
+    ) -> Result<usize, EndpointError> {
+        if data_in {
+            let data = &data[..data.len().min(expected)];
+            for chunk in data.chunks(self.max_packet_size) {
+                self.write_ep.write(chunk).await?;
+            }
+            self.end_data_in(data.len(), expected).await?;
+            return Ok(data.len());
+        }
+
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let mut received = 0;
+        while received < expected {
+            let len = self
+                .read_ep
+                .read(&mut packet[..self.max_packet_size])
+                .await?;
+            received += len;
+            if len < self.max_packet_size {
+                break;
+            }
+        }
+        Ok(received.min(expected))
+    }
+
+    /// End a data stage in which `sent` of the `expected` bytes were sent.
+    async fn end_data_in(&mut self, sent: usize, expected: usize) -> Result<(), EndpointError> {
+        // The host waits for more data unless the last packet is short.
+        if sent < expected && sent.is_multiple_of(self.max_packet_size) {
+            self.write_ep.write(&[]).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    /// A file system holding a file of `file_len` bytes, with room for `capacity`.
+    fn layout(capacity: usize, file_len: usize) -> Volume {
+        let mut volume = Volume::new(capacity);
+        volume.file_len = file_len;
+        volume
+    }
+
+    /// Sector `lba` of `volume`.
+    fn sector(volume: &Volume, file: &[u8], lba: usize) -> [u8; SECTOR_LEN] {
+        let mut out = [0; SECTOR_LEN];
+        volume.read_sector(lba, file, &mut out);
+        out
+    }
+
+    /// The first copy of the file allocation table of `volume`.
+    fn fat(volume: &Volume) -> Vec<u8> {
+        (1..=volume.fat_sectors)
+            .flat_map(|lba| sector(volume, &[], lba))
+            .collect()
+    }
+
+    /// Entry `n` of the file allocation table `fat`, decoded as a host does.
+    fn entry(fat: &[u8], n: usize) -> u16 {
+        let offset = n * 3 / 2;
+        let pair = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+        if n.is_multiple_of(2) {
+            pair & 0xfff
+        } else {
+            pair >> 4
+        }
+    }
+
+    #[test]
+    fn fat_packs_12_bit_entries() {
+        let volume = layout(8 * SECTOR_LEN, 3 * SECTOR_LEN - 1);
+        let table = fat(&volume);
+        let entries: Vec<u16> = (0..8).map(|n| entry(&table, n)).collect();
+        assert_eq!(entries, [0xff8, 0xfff, 3, 4, 0xfff, 0, 0, 0]);
+        assert_eq!(table[..6], [0xf8, 0xff, 0xff, 0x03, 0x40, 0x00]);
+        // The second copy is the same.
+        assert_eq!(sector(&volume, &[], 1), sector(&volume, &[], 2));
+    }
+
+    #[test]
+    fn fat_chain_ends_with_an_end_of_chain_marker() {
+        // 400 clusters, whose entries span two sectors of the table.
+        let volume = layout(MAX_CLUSTERS * SECTOR_LEN, 400 * SECTOR_LEN);
+        let table = fat(&volume);
+        for n in 2..401 {
+            assert_eq!(usize::from(entry(&table, n)), n + 1);
+        }
+        assert_eq!(entry(&table, 401), 0xfff);
+        assert_eq!(entry(&table, 402), 0);
+
+        let single = layout(SECTOR_LEN, 1);
+        assert_eq!(entry(&fat(&single), 2), 0xfff);
+        let empty = layout(SECTOR_LEN, 0);
+        assert_eq!(entry(&fat(&empty), 2), 0);
+    }
+
+    #[test]
+    fn boot_sector_describes_a_fat12_volume_at_the_cluster_limit() {
+        let volume = layout(usize::MAX / 2, 0);
+        assert_eq!(volume.clusters, MAX_CLUSTERS);
+        let boot = sector(&volume, &[], 0);
+        let field =
+            |offset: usize| usize::from(u16::from_le_bytes([boot[offset], boot[offset + 1]]));
+        assert_eq!(field(11), SECTOR_LEN);
+        assert_eq!(boot[13], 1);
+        assert_eq!(boot[16], NUM_FATS as u8);
+        assert_eq!(boot[510..], [0x55, 0xaa]);
+
+        // The host counts the clusters from the BPB, and takes the volume to be FAT12 if there
+        // are fewer than 4085.
+        let (reserved, root_entries, sectors, fat_sectors) =
+            (field(14), field(17), field(19), field(22));
+        assert_eq!(fat_sectors, volume.fat_sectors);
+        let root_sectors = (root_entries * 32).div_ceil(SECTOR_LEN);
+        let clusters = sectors - reserved - NUM_FATS * fat_sectors - root_sectors;
+        assert_eq!(clusters, MAX_CLUSTERS);
+        // The table has room for an entry of every cluster, and the two reserved ones.
+        assert!(fat_sectors * SECTOR_LEN * 2 / 3 >= MAX_CLUSTERS + 2);
+    }
+
+    #[test]
+    fn root_directory_and_data_hold_the_file() {
+        let file: Vec<u8> = (0..SECTOR_LEN + 10).map(|i| i as u8).collect();
+        let volume = layout(4 * SECTOR_LEN, file.len());
+        let root = sector(&volume, &file, volume.root_start());
+        assert_eq!(root[32..43], *b"LOG     TXT");
+        assert_eq!(root[32 + 26..32 + 28], 2u16.to_le_bytes());
+        assert_eq!(root[32 + 28..32 + 32], (file.len() as u32).to_le_bytes());
+
+        let data = volume.data_start();
+        assert_eq!(sector(&volume, &file, data), file[..SECTOR_LEN]);
+        let mut last = vec![0; SECTOR_LEN];
+        last[..10].copy_from_slice(&file[SECTOR_LEN..]);
+        assert_eq!(sector(&volume, &file, data + 1)[..], last[..]);
+        assert_eq!(sector(&volume, &file, data + 2), [0; SECTOR_LEN]);
+    }
+}
@@ -0,0 +1,38 @@
+//! Microsoft OS 2.0 descriptors, with which Windows binds WinUSB to the vendor-specific functions
+
+use embassy_usb::{Builder, driver::Driver, msos::windows_version};
+
+/// Device interface GUID of the bulk interface of [`DefmtBulkClass`], under which Windows
+/// registers it for WinUSB, so that host tools can find defmt devices.
+///
+/// [`DefmtBulkClass`]: crate::DefmtBulkClass
+pub const DEFMT_INTERFACE_GUID: &str = "{3A8CE711-DEE0-4AAB-A798-A92EB700D4CA}";
+
+/// `bRequest` of the vendor control request with which Windows reads the MS OS 2.0 descriptors.
+pub const MSOS_VENDOR_CODE: u8 = 0xee;
+
+/// Device interface GUIDs of the bulk interface of [`DefmtBulkClass`].
+///
+/// [`DefmtBulkClass`]: crate::DefmtBulkClass
+pub(crate) static DEFAULT_GUIDS: [&str; 1] = [DEFMT_INTERFACE_GUID];
+
+/// Add the Microsoft OS 2.0 descriptor set to `builder`, to which [`DefmtBulkClass`] and
+/// [`add_dfu_runtime`] add the `WINUSB` compatible ID and the `DeviceInterfaceGUIDs` registry
+/// property of their interfaces, so that Windows binds WinUSB to them without an INF file.
+///
+/// Call this before adding any function to the builder, and give the builder an MS OS descriptor
+/// buffer of at least 256 bytes.
+///
+/// The defmt serial port has no such descriptors, and neither has the device built by [`run`]:
+/// Windows 10 and later bind their `usbser.sys` driver to CDC ACM functions by class, which
+/// registers the port as a COM port rather than under a GUID of its own, and the `WINUSB`
+/// compatible ID would replace that driver with WinUSB, leaving no COM port. Host tools find the
+/// port by its vendor and product IDs, or its product string, instead. Windows 8.1 and earlier
+/// still need an INF file for the serial port.
+///
+/// [`DefmtBulkClass`]: crate::DefmtBulkClass
+/// [`add_dfu_runtime`]: crate::add_dfu_runtime
+/// [`run`]: crate::run
+pub fn add_msos_descriptors<'d, D: Driver<'d>>(builder: &mut Builder<'d, D>) {
+    builder.msos_descriptor(windows_version::WIN8_1, MSOS_VENDOR_CODE);
+}
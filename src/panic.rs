@@ -0,0 +1,168 @@
+//! Sending the queued frames when the executor cannot run the USB task, such as after a panic
+
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    ptr::NonNull,
+    sync::atomic::Ordering,
+    task::{Context, Poll, Waker},
+};
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Instant};
+use portable_atomic::AtomicBool;
+
+use crate::controller::CONTROLLER;
+
+/// Pointer to the pinned future of the USB device and logger task, with its type erased.
+#[derive(Clone, Copy)]
+struct ErasedFuture {
+    /// The future.
+    future: NonNull<()>,
+    /// Polls the future, returning whether it is ready.
+    poll: unsafe fn(NonNull<()>, &mut Context<'_>) -> bool,
+}
+
+// SAFETY: The future is only polled by the task that owns it, or by the panic handler once that
+// task can no longer run.
+unsafe impl Send for ErasedFuture {}
+
+/// The future registered by [`Registered`], while it is alive, and the waker of the task that
+/// last polled it.
+static FUTURE: Mutex<RefCell<Option<(ErasedFuture, Waker)>>> = Mutex::new(RefCell::new(None));
+
+/// Whether the registered future is being polled.
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+/// Poll the pinned `F` at `future`, returning whether it is ready.
+///
+/// # Safety
+///
+/// `future` must point to a pinned, live `F` that nothing else accesses.
+unsafe fn poll_erased<F: Future>(future: NonNull<()>, cx: &mut Context<'_>) -> bool {
+    // SAFETY: Guaranteed by the caller.
+    let future = unsafe { Pin::new_unchecked(future.cast::<F>().as_mut()) };
+    future.poll(cx).is_ready()
+}
+
+/// Wrapper that makes a pinned future available to [`flush_after_panic`].
+pub(crate) struct Registered<'a, F: Future> {
+    /// The wrapped future.
+    future: Pin<&'a mut F>,
+}
+
+impl<'a, F: Future> Registered<'a, F> {
+    /// Wrap `future`.
+    pub(crate) fn new(future: Pin<&'a mut F>) -> Self {
+        Self { future }
+    }
+}
+
+impl<F: Future> Future for Registered<'_, F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: The future stays pinned, and is unregistered in `drop` before it can go away.
+        let future = NonNull::from(unsafe { self.future.as_mut().get_unchecked_mut() }).cast();
+        let erased = ErasedFuture {
+            future,
+            poll: poll_erased::<F>,
+        };
+        critical_section::with(|cs| {
+            let mut registered = FUTURE.borrow_ref_mut(cs);
+            match &mut *registered {
+                Some((_, waker)) if waker.will_wake(cx.waker()) => {}
+                _ => *registered = Some((erased, cx.waker().clone())),
+            }
+        });
+
+        // Wait for an interrupt handler on another core to finish polling the future.
+        while POLLING.swap(true, Ordering::AcqRel) {
+            core::hint::spin_loop();
+        }
+        let result = self.future.as_mut().poll(cx);
+        POLLING.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl<F: Future> Drop for Registered<'_, F> {
+    fn drop(&mut self) {
+        critical_section::with(|cs| FUTURE.borrow_ref_mut(cs).take());
+    }
+}
+
+/// Send the queued frames from a panic handler, by polling the USB device directly.
+///
+/// After a panic the executor no longer runs the USB task, so the frames logged by the panic
+/// handler are normally never sent. This takes over: it busy-polls the future returned by
+/// [`run`] until every queued frame has been sent or `timeout` expires, and returns whether
+/// everything was sent.
+///
+/// ```ignore
+/// #[panic_handler]
+/// fn panic(info: &core::panic::PanicInfo) -> ! {
+///     defmt::error!("{}", defmt::Display2Format(info));
+///     defmt_embassy_usbserial::flush_after_panic(embassy_time::Duration::from_millis(100));
+///     cortex_m::peripheral::SCB::sys_reset();
+/// }
+/// ```
+///
+/// This is a best-effort attempt. It does nothing and returns `false` if [`run`] is not running,
+/// or if the panic happened while the future returned by [`run`] was being polled. The USB driver
+/// must make progress when polled without its interrupt handler running, and `embassy-time` must
+/// still advance.
+///
+/// [`run`]: crate::run
+pub fn flush_after_panic(timeout: Duration) -> bool {
+    flush_blocking_with_budget(timeout)
+}
+
+/// Send the queued frames within `budget`, by polling the USB device directly, for example from
+/// the early warning interrupt of a watchdog that is about to reset the device.
+///
+/// This busy-polls the future returned by [`run`] until every queued frame has been sent or
+/// `budget` expires, and returns whether everything was sent. The task that runs [`run`] is woken
+/// afterwards, so that it carries on normally if the device is not reset after all.
+///
+/// As with [`flush_after_panic`], this is a best-effort attempt that does nothing and returns
+/// `false` if [`run`] is not running, or is being polled by the code this preempted. Call it from
+/// an interrupt handler of lower priority than the USB interrupt, or make sure the USB driver
+/// makes progress when polled without its interrupt handler running.
+///
+/// [`run`]: crate::run
+pub fn flush_blocking_with_budget(budget: Duration) -> bool {
+    let deadline = Instant::now() + budget;
+
+    let Some((future, waker)) = critical_section::with(|cs| FUTURE.borrow_ref(cs).clone()) else {
+        return false;
+    };
+    if POLLING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+
+    // Poll with the waker of the task, so that the USB driver and the controller keep waking it.
+    let mut cx = Context::from_waker(&waker);
+    let drained = loop {
+        // Send everything, regardless of the flush watermark.
+        // SAFETY: The controller is accessed within a critical section.
+        let drained = critical_section::with(|_| unsafe {
+            CONTROLLER.start_draining();
+            CONTROLLER.is_drained()
+        });
+        if drained || Instant::now() >= deadline {
+            break drained;
+        }
+
+        // SAFETY: The future is pinned and alive while it is registered, and nothing else polls
+        // it while the polling flag is set.
+        if unsafe { (future.poll)(future.future, &mut cx) } {
+            break false;
+        }
+    };
+
+    POLLING.store(false, Ordering::Release);
+    waker.wake();
+    drained
+}
@@ -0,0 +1,127 @@
+//! Ring buffer storage that survives a soft reset
+
+use core::{
+    mem::MaybeUninit,
+    ptr::{addr_of, addr_of_mut},
+};
+
+use crate::controller::{CONTROLLER, HEADER_LEN};
+
+/// Value of [`PersistentHeader::magic`] when the header describes valid ring buffer contents.
+const MAGIC: u32 = 0xdef7_0b0f;
+
+/// Ring buffer storage, with its bookkeeping, that can be placed in memory that is not
+/// initialized at startup so that queued frames survive a reset.
+///
+/// Declare it in a section that the runtime does not zero or initialize, such as `.uninit` with
+/// `cortex-m-rt`, and pass it to [`init_persistent`]:
+///
+/// ```no_run
+/// use core::mem::MaybeUninit;
+/// use defmt_embassy_usbserial::PersistentBuffer;
+///
+/// #[unsafe(link_section = ".uninit.defmt_log")]
+/// static mut LOG_BUFFER: MaybeUninit<PersistentBuffer<4096>> = MaybeUninit::uninit();
+///
+/// // SAFETY: This is the only reference to the buffer.
+/// let recovered = defmt_embassy_usbserial::init_persistent(unsafe {
+///     &mut *core::ptr::addr_of_mut!(LOG_BUFFER)
+/// });
+/// ```
+#[repr(C)]
+pub struct PersistentBuffer<const N: usize> {
+    /// Bookkeeping, kept up to date as frames are queued and sent.
+    header: PersistentHeader,
+    /// The ring buffer storage.
+    data: [u8; N],
+}
+
+/// Bookkeeping of a [`PersistentBuffer`], mirroring that of the controller.
+#[repr(C)]
+pub(crate) struct PersistentHeader {
+    /// [`MAGIC`] if the rest of the header is meaningful.
+    magic: u32,
+    /// Index of the oldest committed byte.
+    head: u32,
+    /// Number of committed bytes, including record headers.
+    committed: u32,
+    /// Number of frame bytes of the record at the head that had yet to be sent.
+    frame_remaining: u32,
+}
+
+impl PersistentHeader {
+    /// Record the given bookkeeping.
+    pub(crate) fn store(&mut self, head: usize, committed: usize, frame_remaining: usize) {
+        self.head = head as u32;
+        self.committed = committed as u32;
+        self.frame_remaining = frame_remaining as u32;
+        self.magic = MAGIC;
+    }
+}
+
+/// Use a [`PersistentBuffer`] as the ring buffer storage, recovering the frames it held before a
+/// reset.
+///
+/// If the buffer holds valid frames from before the reset, they are kept and sent once the host
+/// connects, ahead of anything logged since startup. Any frame that was part-way through being
+/// sent is dropped. Frames already queued in the previous storage are added after the recovered
+/// ones if they fit. Returns whether frames were recovered.
+///
+/// The bookkeeping is written to the buffer every time a frame is queued or sent, so the buffer
+/// should be in RAM that is fast to write. A reset in the middle of an update is detected, and
+/// the contents are then discarded.
+pub fn init_persistent<const N: usize>(buf: &'static mut MaybeUninit<PersistentBuffer<N>>) -> bool {
+    let buf = buf.as_mut_ptr();
+    // SAFETY: The buffer is valid forever and we have exclusive access to it. The header is read
+    // as plain integers, and validated before the contents are trusted.
+    unsafe {
+        let header = addr_of_mut!((*buf).header);
+        let data = addr_of_mut!((*buf).data).cast::<u8>();
+        CONTROLLER.replace_storage_persistent(header, data, N)
+    }
+}
+
+/// Read the bookkeeping stored in `header`, if it describes a valid sequence of records in the
+/// `len` bytes at `data`.
+///
+/// Returns the head, committed byte count, and number of bytes remaining in a partially sent frame.
+///
+/// # Safety
+///
+/// `header` and `data` must be valid for reads.
+pub(crate) unsafe fn recover(
+    header: *const PersistentHeader,
+    data: *const u8,
+    len: usize,
+) -> Option<(usize, usize, usize)> {
+    // SAFETY: Guaranteed by the caller. Volatile reads keep the compiler from assuming anything
+    // about memory that was not initialized by this program.
+    let (magic, head, committed, frame_remaining) = unsafe {
+        (
+            addr_of!((*header).magic).read_volatile(),
+            addr_of!((*header).head).read_volatile() as usize,
+            addr_of!((*header).committed).read_volatile() as usize,
+            addr_of!((*header).frame_remaining).read_volatile() as usize,
+        )
+    };
+    if magic != MAGIC || head >= len || committed > len || frame_remaining > committed {
+        return None;
+    }
+
+    // SAFETY: Guaranteed by the caller.
+    let byte = |offset: usize| unsafe { data.add((head + offset) % len).read_volatile() };
+
+    // Walk the records to check that they exactly cover the committed bytes.
+    let mut offset = frame_remaining;
+    while offset < committed {
+        if committed - offset < HEADER_LEN {
+            return None;
+        }
+        let frame_len = usize::from(u16::from_le_bytes([byte(offset), byte(offset + 1)]));
+        if frame_len == 0 {
+            return None;
+        }
+        offset += HEADER_LEN + frame_len;
+    }
+    (offset == committed).then_some((head, committed, frame_remaining))
+}
@@ -0,0 +1,65 @@
+//! Data sent by the host to the device over the serial port
+
+use embassy_usb::driver::{Driver, EndpointError};
+
+use crate::{
+    cdc_acm::Receiver,
+    controller::CONTROLLER,
+    flow_control,
+    handshake::{self, Matcher},
+    reliable,
+    task::MAX_PACKET_SIZE,
+};
+
+/// Hand each packet the host sends over the serial port to `on_receive`, forever.
+///
+/// The next packet is not read until `on_receive` returns, so the host is held off while it is
+/// busy rather than losing data. Packets larger than [`MAX_PACKET_SIZE`] are dropped.
+///
+/// This also watches for the start sequence set with [`set_start_handshake`], and does not pass
+/// on the data that comes before its end, nor the acknowledgements of [`set_retransmit_window`],
+/// nor the XON and XOFF bytes of [`set_software_flow_control`].
+///
+/// [`set_start_handshake`]: crate::set_start_handshake
+/// [`set_retransmit_window`]: crate::set_retransmit_window
+/// [`set_software_flow_control`]: crate::set_software_flow_control
+pub(crate) async fn receive<'d, D: Driver<'d>>(
+    mut receiver: Receiver<'d, D>,
+    mut on_receive: impl AsyncFnMut(&[u8]),
+) {
+    let mut packet = [0u8; MAX_PACKET_SIZE];
+    loop {
+        receiver.wait_connection().await;
+        let mut matcher = Matcher::new();
+        loop {
+            match receiver.read_packet(&mut packet).await {
+                Ok(mut len) => {
+                    if reliable::enabled()
+                        && let Some(sequence) = reliable::parse_ack(&packet[..len])
+                    {
+                        reliable::ack(sequence);
+                        continue;
+                    }
+                    if CONTROLLER.software_flow_control() {
+                        len = flow_control::strip(&mut packet[..len]);
+                    }
+                    let mut data = &packet[..len];
+                    if let Some((sequence, _)) = CONTROLLER.start_handshake()
+                        && !handshake::started()
+                    {
+                        let Some(rest) = matcher.find(sequence, data) else {
+                            continue;
+                        };
+                        handshake::start();
+                        data = rest;
+                    }
+                    if !data.is_empty() {
+                        on_receive(data).await;
+                    }
+                }
+                Err(EndpointError::BufferOverflow) => {}
+                Err(EndpointError::Disabled) => break,
+            }
+        }
+    }
+}
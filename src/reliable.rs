@@ -0,0 +1,325 @@
+//! Keeping sent packets until the host acknowledges them, to send them again after a reconnect
+
+use core::{cell::RefCell, future::poll_fn, task::Poll};
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+/// First byte of an acknowledgement sent by the host, see [`set_retransmit_window`].
+pub const RELIABLE_ACK: u8 = 0x06;
+
+/// Size of the length stored before each packet.
+const LEN_LEN: usize = 2;
+
+/// Packets sent but not acknowledged yet, each stored as its length as a little-endian `u16`
+/// followed by the packet, in a ring buffer.
+struct Window {
+    /// Storage of the ring buffer.
+    buf: &'static mut [u8],
+    /// Offset of the oldest packet.
+    head: usize,
+    /// Number of bytes in use.
+    used: usize,
+    /// Offset from `head` of the next packet to send again, if they are being sent again.
+    replay: Option<usize>,
+}
+
+impl Window {
+    /// Copy `out.len()` bytes from `offset` bytes after the oldest packet into `out`.
+    fn copy_out(&self, offset: usize, out: &mut [u8]) {
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.buf[(self.head + offset + i) % self.buf.len()];
+        }
+    }
+
+    /// Copy `bytes` to `offset` bytes after the oldest packet.
+    fn copy_in(&mut self, offset: usize, bytes: &[u8]) {
+        let len = self.buf.len();
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.buf[(self.head + offset + i) % len] = byte;
+        }
+    }
+
+    /// Length and sequence number of the packet stored at `offset` bytes after the oldest one.
+    fn record(&self, offset: usize) -> (usize, u16) {
+        let mut header = [0; LEN_LEN + 3];
+        self.copy_out(offset, &mut header);
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        (usize::from(len), u16::from_le_bytes([header[3], header[4]]))
+    }
+
+    /// Whether a packet of `len` bytes can be stored, or is too large to ever be.
+    fn has_room(&self, len: usize) -> bool {
+        let record = LEN_LEN + len;
+        record > self.buf.len() || record <= self.buf.len() - self.used
+    }
+
+    /// Store `packet`, if there is room for it.
+    fn push(&mut self, packet: &[u8]) {
+        let record = LEN_LEN + packet.len();
+        if record > self.buf.len() || record > self.buf.len() - self.used {
+            return;
+        }
+        let used = self.used;
+        self.copy_in(used, &(packet.len() as u16).to_le_bytes());
+        self.copy_in(used + LEN_LEN, packet);
+        self.used += record;
+    }
+
+    /// Drop the packets up to and including the sequence number `sequence`.
+    fn ack(&mut self, sequence: u16) {
+        while self.used > 0 {
+            let (len, oldest) = self.record(0);
+            if (sequence.wrapping_sub(oldest) as i16) < 0 {
+                break;
+            }
+            let record = LEN_LEN + len;
+            self.head = (self.head + record) % self.buf.len();
+            self.used -= record;
+            self.replay = self
+                .replay
+                .map(|offset| offset.saturating_sub(record))
+                .filter(|&offset| offset < self.used);
+        }
+    }
+
+    /// Send every stored packet again, oldest first.
+    fn start_replay(&mut self) {
+        self.replay = (self.used > 0).then_some(0);
+    }
+
+    /// Copy the next packet to send again into `out`, returning its length, if packets are being
+    /// sent again.
+    fn next_replay(&mut self, out: &mut [u8]) -> Option<usize> {
+        let offset = self.replay?;
+        let (record_len, _) = self.record(offset);
+        let len = record_len.min(out.len());
+        self.copy_out(offset + LEN_LEN, &mut out[..len]);
+        let next = offset + LEN_LEN + record_len;
+        self.replay = (next < self.used).then_some(next);
+        Some(len)
+    }
+}
+
+/// The window set with [`set_retransmit_window`], if any.
+static WINDOW: Mutex<RefCell<Option<Window>>> = Mutex::new(RefCell::new(None));
+
+/// The logger task, waiting in [`wait_for_room`].
+static WAKER: Mutex<RefCell<WakerRegistration>> =
+    Mutex::new(RefCell::new(WakerRegistration::new()));
+
+/// Keep every packet sent in `window` until the host acknowledges it, and send the packets it has
+/// not acknowledged again when it reconnects or opens the serial port again.
+///
+/// This makes sure that brief cable glitches and restarts of the host tool lose no data. Every
+/// packet starts with a chunk header, as with [`set_chunk_headers`], and the host acknowledges
+/// the packets it has received up to and including the sequence number `n` by sending
+/// [`RELIABLE_ACK`] followed by `n` as a little-endian `u16`, in a packet of its own. The host may
+/// receive a packet more than once, and must drop those with sequence numbers it has already
+/// seen. A packet is sent only once `window` has room for it, so logging stalls, and frames queue
+/// up, while the host does not acknowledge anything. `window` must hold at least a packet and
+/// two bytes of length, that is 66 bytes at full speed, and packets larger than that are not
+/// kept.
+///
+/// The host must read the acknowledgements with [`run_with_receiver`] or [`DefmtClass::run`].
+/// This does not work with [`set_log_cipher`], as packets sent again are not encrypted again for
+/// the new session.
+///
+/// [`set_chunk_headers`]: crate::set_chunk_headers
+/// [`run_with_receiver`]: crate::run_with_receiver
+/// [`DefmtClass::run`]: crate::DefmtClass::run
+/// [`set_log_cipher`]: crate::set_log_cipher
+pub fn set_retransmit_window(window: &'static mut [u8]) {
+    critical_section::with(|cs| {
+        *WINDOW.borrow_ref_mut(cs) = Some(Window {
+            buf: window,
+            head: 0,
+            used: 0,
+            replay: None,
+        });
+    });
+}
+
+/// Whether sent packets are kept until the host acknowledges them.
+pub(crate) fn enabled() -> bool {
+    critical_section::with(|cs| WINDOW.borrow_ref(cs).is_some())
+}
+
+/// Whether a packet of `len` bytes can be kept, or need not be.
+pub(crate) fn has_room(len: usize) -> bool {
+    critical_section::with(|cs| {
+        WINDOW
+            .borrow_ref(cs)
+            .as_ref()
+            .is_none_or(|window| window.has_room(len))
+    })
+}
+
+/// Wait until a packet of `len` bytes can be kept.
+pub(crate) async fn wait_for_room(len: usize) {
+    poll_fn(|cx| {
+        if has_room(len) {
+            return Poll::Ready(());
+        }
+        critical_section::with(|cs| WAKER.borrow_ref_mut(cs).register(cx.waker()));
+        // Check again, in case an acknowledgement came in before the waker was registered.
+        if has_room(len) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Keep `packet`, which starts with a chunk header, until the host acknowledges it, if there is
+/// room for it.
+pub(crate) fn push(packet: &[u8]) {
+    critical_section::with(|cs| {
+        if let Some(window) = WINDOW.borrow_ref_mut(cs).as_mut() {
+            window.push(packet);
+        }
+    });
+}
+
+/// Drop the packets up to and including the sequence number `sequence`, which the host has
+/// received.
+pub(crate) fn ack(sequence: u16) {
+    critical_section::with(|cs| {
+        let mut window = WINDOW.borrow_ref_mut(cs);
+        let Some(window) = window.as_mut() else {
+            return;
+        };
+        window.ack(sequence);
+        WAKER.borrow_ref_mut(cs).wake();
+    });
+}
+
+/// Send every packet that has not been acknowledged again, before anything else.
+pub(crate) fn start_replay() {
+    critical_section::with(|cs| {
+        if let Some(window) = WINDOW.borrow_ref_mut(cs).as_mut() {
+            window.start_replay();
+        }
+    });
+}
+
+/// Copy the next packet to send again into `out`, returning its length, if packets are being sent
+/// again.
+pub(crate) fn next_replay(out: &mut [u8]) -> Option<usize> {
+    critical_section::with(|cs| WINDOW.borrow_ref_mut(cs).as_mut()?.next_replay(out))
+}
+
+/// The sequence number acknowledged by `data`, if it is an acknowledgement.
+pub(crate) fn parse_ack(data: &[u8]) -> Option<u16> {
+    match *data {
+        [RELIABLE_ACK, low, high] => Some(u16::from_le_bytes([low, high])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    /// An empty window of `len` bytes.
+    fn window(len: usize) -> Window {
+        Window {
+            buf: vec![0; len].leak(),
+            head: 0,
+            used: 0,
+            replay: None,
+        }
+    }
+
+    /// A packet with the chunk header of `sequence`, followed by `payload`.
+    fn packet(sequence: u16, payload: &[u8]) -> Vec<u8> {
+        let [low, high] = sequence.to_le_bytes();
+        [&[payload.len() as u8, low, high], payload].concat()
+    }
+
+    /// The packets sent again, until there are none left.
+    fn replay(window: &mut Window) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut out = [0; 64];
+        while let Some(len) = window.next_replay(&mut out) {
+            packets.push(out[..len].to_vec());
+        }
+        packets
+    }
+
+    #[test]
+    fn packets_wrap_around_the_end_of_the_window() {
+        // Room for two records of 7 bytes, and a bit.
+        let mut window = window(20);
+        window.push(&packet(1, b"ab"));
+        window.push(&packet(2, b"cd"));
+        window.ack(1);
+        // This one starts at offset 14 and wraps around to the start.
+        assert!(window.has_room(5));
+        window.push(&packet(3, b"ef"));
+        assert_eq!(window.used, 14);
+        window.start_replay();
+        assert_eq!(replay(&mut window), [packet(2, b"cd"), packet(3, b"ef")]);
+    }
+
+    #[test]
+    fn packets_that_do_not_fit_are_not_kept() {
+        let mut window = window(20);
+        window.push(&packet(1, b"ab"));
+        window.push(&packet(2, b"cd"));
+        assert!(!window.has_room(5));
+        window.push(&packet(3, b"ef"));
+        // Too large to ever be kept, so not waited for.
+        assert!(window.has_room(30));
+        window.push(&packet(4, &[0; 27]));
+        window.start_replay();
+        assert_eq!(replay(&mut window), [packet(1, b"ab"), packet(2, b"cd")]);
+    }
+
+    #[test]
+    fn ack_handles_sequence_numbers_wrapping_around() {
+        let mut window = window(64);
+        for sequence in [0xfffe, 0xffff, 0, 1] {
+            window.push(&packet(sequence, b"x"));
+        }
+        // An old acknowledgement drops nothing.
+        window.ack(0xfffd);
+        assert_eq!(window.used, 4 * 6);
+        window.ack(0);
+        window.start_replay();
+        assert_eq!(replay(&mut window), [packet(1, b"x")]);
+        window.ack(1);
+        assert_eq!(window.used, 0);
+    }
+
+    #[test]
+    fn replay_moves_on_after_a_partial_ack() {
+        let mut window = window(64);
+        for sequence in 1..=3 {
+            window.push(&packet(sequence, b"xy"));
+        }
+        window.start_replay();
+        let mut out = [0; 64];
+        assert_eq!(window.next_replay(&mut out), Some(5));
+        // The packet being sent again, and the next one, are acknowledged meanwhile.
+        window.ack(2);
+        assert_eq!(replay(&mut window), [packet(3, b"xy")]);
+
+        // Nothing is left to send again once everything is acknowledged.
+        window.start_replay();
+        window.ack(3);
+        assert_eq!(window.next_replay(&mut out), None);
+    }
+
+    #[test]
+    fn parses_acknowledgements() {
+        assert_eq!(parse_ack(&[RELIABLE_ACK, 0x34, 0x12]), Some(0x1234));
+        assert_eq!(parse_ack(&[RELIABLE_ACK, 0x34]), None);
+        assert_eq!(parse_ack(&[0x15, 0x34, 0x12]), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! Mirroring of the defmt frames to an RTT up channel, for a debug probe
+
+use core::{
+    cell::UnsafeCell,
+    pin::pin,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use embassy_futures::select::{Either, select};
+use embassy_time::Timer;
+
+use crate::controller::CONTROLLER;
+
+/// Size of the buffer of the RTT up channel, in bytes.
+pub const RTT_BUFFER_LEN: usize = 1024;
+
+/// Name of the up channel, from which probe-rs knows to decode it as defmt.
+const NAME: &[u8] = b"defmt\0";
+
+/// Mask of the mode in the flags of the up channel.
+const MODE_MASK: usize = 0b11;
+
+/// Mode in which data that does not fit is cut short, until a probe selects another one.
+const MODE_NON_BLOCKING_TRIM: usize = 1;
+
+/// Mode in which the target waits for the probe to make room.
+const MODE_BLOCK_IF_FULL: usize = 2;
+
+/// The RTT control block, which the probe finds in RAM by its ID.
+#[repr(C)]
+struct ControlBlock {
+    /// `SEGGER RTT`, padded with zeros.
+    id: [u8; 16],
+    /// Number of up channels.
+    max_up_channels: usize,
+    /// Number of down channels.
+    max_down_channels: usize,
+    /// The only up channel.
+    up: UpChannel,
+}
+
+/// An RTT up channel, from the target to the probe.
+#[repr(C)]
+struct UpChannel {
+    /// Name of the channel, as a C string.
+    name: *const u8,
+    /// Start of the buffer.
+    buffer: *mut u8,
+    /// Size of the buffer.
+    size: usize,
+    /// Offset in the buffer at which the target writes next.
+    write: AtomicUsize,
+    /// Offset in the buffer at which the probe reads next.
+    read: AtomicUsize,
+    /// Mode of the channel, set by the probe.
+    flags: AtomicUsize,
+}
+
+// SAFETY: The pointers are to static data, and only the logger writes to the buffer, within a
+// critical section.
+unsafe impl Sync for ControlBlock {}
+
+/// Storage of the buffer of the up channel.
+struct Buffer(UnsafeCell<[u8; RTT_BUFFER_LEN]>);
+
+unsafe impl Sync for Buffer {}
+
+/// Buffer of the up channel.
+static BUFFER: Buffer = Buffer(UnsafeCell::new([0; RTT_BUFFER_LEN]));
+
+/// The RTT control block, under the symbol name the probe looks for.
+#[unsafe(no_mangle)]
+static _SEGGER_RTT: ControlBlock = ControlBlock {
+    id: *b"SEGGER RTT\0\0\0\0\0\0",
+    max_up_channels: 1,
+    max_down_channels: 0,
+    up: UpChannel {
+        name: NAME.as_ptr(),
+        buffer: BUFFER.0.get().cast(),
+        size: RTT_BUFFER_LEN,
+        write: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        flags: AtomicUsize::new(MODE_NON_BLOCKING_TRIM),
+    },
+};
+
+/// Write `bytes` to the up channel.
+///
+/// What does not fit is dropped, unless the probe has set the channel to block, in which case this
+/// waits for it to read. It must be called within a critical section.
+pub(crate) fn write(mut bytes: &[u8]) {
+    let up = &_SEGGER_RTT.up;
+    let blocking = up.flags.load(Ordering::Relaxed) & MODE_MASK == MODE_BLOCK_IF_FULL;
+    let mut write = up.write.load(Ordering::Relaxed);
+    while !bytes.is_empty() {
+        let read = up.read.load(Ordering::Acquire);
+        // Contiguous room after the write offset, leaving a byte free so that a full buffer is
+        // not mistaken for an empty one.
+        let room = if read > write {
+            read - write - 1
+        } else if read == 0 {
+            RTT_BUFFER_LEN - write - 1
+        } else {
+            RTT_BUFFER_LEN - write
+        };
+        if room == 0 {
+            if blocking {
+                continue;
+            }
+            return;
+        }
+        let len = room.min(bytes.len());
+        // SAFETY: The probe only reads the part of the buffer between the read and write offsets,
+        // and the caller holds a critical section, so nothing else writes to it.
+        unsafe {
+            let buffer = BUFFER.0.get().cast::<u8>();
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.add(write), len);
+        }
+        write = (write + len) % RTT_BUFFER_LEN;
+        up.write.store(write, Ordering::Release);
+        bytes = &bytes[len..];
+    }
+}
+
+/// Wait for `connection`, the USB device being connected, falling back to RTT if it takes longer
+/// than the window set with [`set_rtt_fallback`], until it is connected.
+///
+/// [`set_rtt_fallback`]: crate::set_rtt_fallback
+pub(crate) async fn wait_connection(connection: impl Future<Output = ()>) {
+    let Some(window) = CONTROLLER.rtt_fallback() else {
+        return connection.await;
+    };
+    let mut connection = pin!(connection);
+    if let Either::Second(()) = select(connection.as_mut(), Timer::after(window)).await {
+        CONTROLLER.fall_back_to_rtt(true);
+        connection.await;
+    }
+    CONTROLLER.fall_back_to_rtt(false);
+}
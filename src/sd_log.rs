@@ -0,0 +1,180 @@
+//! Logging the defmt frames to a file on an SD card, for long runs without a host
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use embassy_time::Duration;
+use embedded_sdmmc::{
+    BlockDevice, Error, Mode, RawFile, RawVolume, TimeSource, VolumeIdx, VolumeManager,
+};
+use static_cell::StaticCell;
+
+use crate::{
+    controller::CONTROLLER,
+    history::{self, Replay},
+    sink::{PipeSink, add_log_sink, coalesce},
+};
+
+/// Number of bytes of frames buffered in RAM until they are written to the SD card.
+pub const SD_LOG_BUFFER_LEN: usize = 2048;
+
+/// Size of a block of the SD card, the most written at once.
+const BLOCK_LEN: usize = 512;
+
+/// How long to wait for more frames to fill a block.
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Frames waiting to be written to the SD card.
+static FRAMES: Pipe<CriticalSectionRawMutex, SD_LOG_BUFFER_LEN> = Pipe::new();
+
+/// The sink that feeds [`FRAMES`].
+static SINK: StaticCell<PipeSink<SD_LOG_BUFFER_LEN>> = StaticCell::new();
+
+/// A log of the defmt frames in a file on a FAT-formatted SD card, through `embedded-sdmmc`, for
+/// long unattended runs during which the host is only connected now and then.
+///
+/// Create it with [`SdCardLog::new`], which opens the file, adds a [`LogSink`] that buffers every
+/// frame in [`SD_LOG_BUFFER_LEN`] bytes of RAM, and logs a session header, and run
+/// [`SdCardLog::run`] in a task of its own to append the frames to the file, along with
+/// [`run_log_sinks`], which feeds the sink from the queue set with [`set_log_sink_queue`]. Frames
+/// logged faster than the card is written are dropped once the buffer is full. Only one log may be
+/// created.
+///
+/// The file holds the stream that `defmt-print` decodes, with the frames of every boot appended to
+/// those of the previous ones, so it can be copied off the card and decoded with the ELF file of
+/// the firmware. Each boot starts with the session header, an info message with the reason for the
+/// reset set with [`set_reset_reason`], which is also sent over USB. A boot that was cut short by a
+/// reset or a power loss may leave an incomplete frame before the next header, which `defmt-print`
+/// reports as malformed.
+///
+/// `embedded-sdmmc` is blocking, so writing a block holds up the executor of the task for as long
+/// as the card takes, which is why the frames are written a block at a time: once no more have
+/// been logged for a second, or a block is full.
+///
+/// ```ignore
+/// let volume_mgr = VolumeManager::new(sdcard, time_source);
+/// let mut log = SdCardLog::new(&volume_mgr, VolumeIdx(0), "DEFMT.LOG").unwrap();
+/// let _error = log.run().await;
+/// defmt::error!("writing the SD card log failed");
+/// ```
+///
+/// [`LogSink`]: crate::LogSink
+/// [`run_log_sinks`]: crate::run_log_sinks
+/// [`set_log_sink_queue`]: crate::set_log_sink_queue
+/// [`set_reset_reason`]: crate::set_reset_reason
+pub struct SdCardLog<
+    'a,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+> where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    /// The volume manager of the SD card.
+    volume_mgr: &'a VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    /// The volume the file is on.
+    volume: RawVolume,
+    /// The file the frames are appended to.
+    file: RawFile,
+}
+
+impl<'a, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    SdCardLog<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    /// Open `file_name`, an 8.3 file name in the root directory of `volume`, creating it if needed,
+    /// and start passing it the frames after a session header.
+    pub fn new(
+        volume_mgr: &'a VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+        volume: VolumeIdx,
+        file_name: &str,
+    ) -> Result<Self, Error<D::Error>> {
+        let volume = volume_mgr.open_raw_volume(volume)?;
+        let file = volume_mgr.open_root_dir(volume).and_then(|dir| {
+            let file = volume_mgr.open_file_in_dir(dir, file_name, Mode::ReadWriteCreateOrAppend);
+            volume_mgr.close_dir(dir)?;
+            file
+        });
+        let file = match file {
+            Ok(file) => file,
+            Err(error) => {
+                let _ = volume_mgr.close_volume(volume);
+                return Err(error);
+            }
+        };
+        if let Some(sink) = SINK.try_init(PipeSink::new(&FRAMES)) {
+            add_log_sink(sink);
+        }
+        defmt::info!(
+            "SD card log session, reset reason: {=str}",
+            CONTROLLER.reset_reason().unwrap_or("unknown"),
+        );
+        Ok(Self {
+            volume_mgr,
+            volume,
+            file,
+        })
+    }
+
+    /// Append the frames to the file as they are logged, and replay the file when the host
+    /// requests it with [`dump_history`], until reading or writing fails, returning the error.
+    ///
+    /// The file is flushed after every write, so that its directory entry covers everything
+    /// written if the power is cut.
+    ///
+    /// [`dump_history`]: crate::dump_history
+    pub async fn run(&mut self) -> Error<D::Error> {
+        let mut data = [0u8; BLOCK_LEN];
+        loop {
+            let result = match select(FRAMES.read(&mut data), history::requested()).await {
+                Either::First(len) => {
+                    let len = coalesce(&FRAMES, &mut data, len, COALESCE_TIMEOUT).await;
+                    self.volume_mgr
+                        .write(self.file, &data[..len])
+                        .and_then(|()| self.volume_mgr.flush_file(self.file))
+                }
+                Either::Second(()) => self.replay().await,
+            };
+            if let Err(error) = result {
+                return error;
+            }
+        }
+    }
+
+    /// Replay the file to the host, from its start, and go back to its end.
+    async fn replay(&mut self) -> Result<(), Error<D::Error>> {
+        let mut replay = Replay::start();
+        self.volume_mgr.file_seek_from_start(self.file, 0)?;
+        let mut data = [0u8; BLOCK_LEN];
+        let mut complete = true;
+        loop {
+            let len = self.volume_mgr.read(self.file, &mut data)?;
+            if len == 0 {
+                break;
+            }
+            if !replay.feed(&data[..len]).await {
+                complete = false;
+                break;
+            }
+        }
+        self.volume_mgr.file_seek_from_end(self.file, 0)?;
+        replay.finish(complete);
+        Ok(())
+    }
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize> Drop
+    for SdCardLog<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn drop(&mut self) {
+        let _ = self.volume_mgr.close_file(self.file);
+        let _ = self.volume_mgr.close_volume(self.volume);
+    }
+}
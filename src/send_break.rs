@@ -0,0 +1,42 @@
+//! Taking a break condition sent by the host as a request to flush the queued frames
+
+use embassy_usb::{
+    Builder, Handler,
+    control::{OutResponse, Recipient, Request, RequestType},
+    driver::Driver,
+};
+
+use crate::controller::CONTROLLER;
+
+/// The CDC `SEND_BREAK` request.
+const REQ_SEND_BREAK: u8 = 0x23;
+
+/// Control handler that has the logger task send every queued frame when the host sends a break.
+pub(crate) struct SendBreakHandler;
+
+impl Handler for SendBreakHandler {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.request)
+            != (RequestType::Class, Recipient::Interface, REQ_SEND_BREAK)
+        {
+            return None;
+        }
+
+        // A duration of zero ends a break that was started without one.
+        if req.value != 0 {
+            // SAFETY: The controller is accessed within a critical section.
+            critical_section::with(|_| unsafe { CONTROLLER.start_draining() });
+        }
+        Some(OutResponse::Accepted)
+    }
+}
+
+/// Handle the `SEND_BREAK` requests of `builder` with `handler`, held in the state of the class.
+///
+/// This must be called before the CDC ACM class is added, which rejects the request.
+pub(crate) fn add_handler<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    handler: &'d mut SendBreakHandler,
+) {
+    builder.handler(handler);
+}
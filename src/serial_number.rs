@@ -0,0 +1,65 @@
+//! USB serial numbers derived from a unique ID of the chip
+
+use static_cell::StaticCell;
+
+/// Longest serial number [`serial_number`] can store.
+pub const MAX_SERIAL_NUMBER_LEN: usize = 32;
+
+/// Storage for the serial number, which the USB configuration refers to for as long as the device
+/// runs.
+static SERIAL_NUMBER: StaticCell<[u8; MAX_SERIAL_NUMBER_LEN]> = StaticCell::new();
+
+/// Supplies the USB serial number of the device, so that every board enumerates with a unique and
+/// stable identity.
+///
+/// Closures taking the buffer and returning the length written implement this, as does
+/// [`HexSerialNumber`] for unique IDs read as bytes.
+pub trait SerialNumberProvider {
+    /// Write the serial number to `buf` as UTF-8, and return its length in bytes.
+    fn write_serial_number(&self, buf: &mut [u8; MAX_SERIAL_NUMBER_LEN]) -> usize;
+}
+
+impl<F: Fn(&mut [u8; MAX_SERIAL_NUMBER_LEN]) -> usize> SerialNumberProvider for F {
+    fn write_serial_number(&self, buf: &mut [u8; MAX_SERIAL_NUMBER_LEN]) -> usize {
+        self(buf)
+    }
+}
+
+/// A [`SerialNumberProvider`] that writes the given bytes, such as the unique ID of the RP2040
+/// flash chip or the UID of an STM32, as upper-case hexadecimal.
+///
+/// Only the first `MAX_SERIAL_NUMBER_LEN / 2` bytes are used.
+#[derive(Clone, Copy, Debug)]
+pub struct HexSerialNumber<'a>(pub &'a [u8]);
+
+impl SerialNumberProvider for HexSerialNumber<'_> {
+    fn write_serial_number(&self, buf: &mut [u8; MAX_SERIAL_NUMBER_LEN]) -> usize {
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        let mut len = 0;
+        for &byte in self.0.iter().take(MAX_SERIAL_NUMBER_LEN / 2) {
+            buf[len] = DIGITS[usize::from(byte >> 4)];
+            buf[len + 1] = DIGITS[usize::from(byte & 0xf)];
+            len += 2;
+        }
+        len
+    }
+}
+
+/// Get the serial number from `provider`, and store it in a buffer owned by this crate so that it
+/// can be used in the USB configuration passed to [`run`].
+///
+/// ```ignore
+/// // For example with `embassy_rp::flash::Flash::blocking_unique_id` on the RP2040.
+/// let unique_id: [u8; 8] = read_unique_id();
+/// let mut usb_config = defmt_embassy_usbserial::default_config(0x1234, 0x5678);
+/// usb_config.serial_number = defmt_embassy_usbserial::serial_number(HexSerialNumber(&unique_id));
+/// ```
+///
+/// Returns `None` if the serial number is not valid UTF-8, or if this has already been called.
+///
+/// [`run`]: crate::run
+pub fn serial_number(provider: impl SerialNumberProvider) -> Option<&'static str> {
+    let buf = SERIAL_NUMBER.try_init([0; MAX_SERIAL_NUMBER_LEN])?;
+    let len = provider.write_serial_number(buf).min(MAX_SERIAL_NUMBER_LEN);
+    core::str::from_utf8(&buf[..len]).ok()
+}
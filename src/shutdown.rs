@@ -0,0 +1,56 @@
+//! Stopping the logger cleanly
+
+use core::sync::atomic::Ordering;
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Duration;
+use portable_atomic::AtomicBool;
+
+/// Shutdown requests, with how long to wait for the queued frames to be sent.
+static REQUEST: Signal<CriticalSectionRawMutex, Duration> = Signal::new();
+
+/// Signalled once the running device has stopped.
+static STOPPED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether a device started with [`run`](crate::run) or
+/// [`run_with_builder`](crate::run_with_builder) is running.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Stop logging cleanly, for example before entering ship mode or handing the USB peripheral to
+/// other code.
+///
+/// This asks the running logger to send the queued frames, waiting for up to `flush_timeout` for
+/// the host to read them, then disables the USB device, which detaches it from the bus where the
+/// driver supports it, and makes the future returned by [`run`] resolve. It returns once the
+/// device is disabled, or right away if the logger is not running. Frames logged afterwards stay
+/// queued until the logger is started again.
+///
+/// This does not stop a logger started with [`build`].
+///
+/// [`run`]: crate::run
+/// [`build`]: crate::build
+pub async fn shutdown(flush_timeout: Duration) {
+    if !RUNNING.load(Ordering::Acquire) {
+        return;
+    }
+    REQUEST.signal(flush_timeout);
+    STOPPED.wait().await;
+}
+
+/// Record that a device has started running, forgetting any earlier shutdown request.
+pub(crate) fn started() {
+    REQUEST.reset();
+    STOPPED.reset();
+    RUNNING.store(true, Ordering::Release);
+}
+
+/// Wait for a shutdown request, and return how long to wait for the queued frames to be sent.
+pub(crate) async fn requested() -> Duration {
+    REQUEST.wait().await
+}
+
+/// Record that the running device has stopped, and wake the task waiting in [`shutdown`].
+pub(crate) fn stopped() {
+    RUNNING.store(false, Ordering::Release);
+    STOPPED.signal(());
+}
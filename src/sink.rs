@@ -0,0 +1,367 @@
+//! Additional destinations of the defmt frames, alongside the USB serial port
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+use embassy_time::{Duration, with_timeout};
+
+use crate::controller::CONTROLLER;
+
+/// Largest number of sinks that can be added with [`add_log_sink`].
+pub const MAX_LOG_SINKS: usize = 4;
+
+/// The sinks added with [`add_log_sink`].
+static SINKS: Mutex<RefCell<Sinks>> = Mutex::new(RefCell::new(Sinks {
+    slots: [const { None }; MAX_LOG_SINKS],
+    len: 0,
+}));
+
+/// The sinks, each of which [`run_log_sinks`] takes out of its slot while it is called.
+struct Sinks {
+    /// The sinks, in the order they were added.
+    slots: [Option<&'static mut dyn LogSink>; MAX_LOG_SINKS],
+    /// Number of sinks added, so that a slot is never reused while its sink is taken out.
+    len: usize,
+}
+
+/// A destination that receives every defmt frame in addition to the USB serial port, such as an
+/// RTT channel, a UART or a log in flash.
+///
+/// Add it with [`add_log_sink`], and run [`run_log_sinks`] in a task of its own, which passes the
+/// frames to the sinks. Each frame is passed on in the format `defmt-print` decodes: rzcobs, with
+/// a `0x00` delimiter at the end of each frame. This is the stream before anything specific to the
+/// USB serial port, so the frame transform, CRC framing, channels and source tags are not applied,
+/// and data written to a [`Channel`] is not passed on. Frames that are filtered out, or logged
+/// while logging is disabled, are not passed on either.
+///
+/// Sinks are independent of the ring buffer of the USB serial port: they receive every frame, even
+/// when the ring buffer is full or the host is not reading. The logger only copies the frames into
+/// the queue set with [`set_log_sink_queue`], and the methods are called from [`run_log_sinks`],
+/// outside of any critical section, so they may take their time. Frames logged while the queue is
+/// full are not passed on.
+///
+/// ```ignore
+/// /// Counts the bytes logged.
+/// struct ByteCounter(usize);
+///
+/// impl defmt_embassy_usbserial::LogSink for ByteCounter {
+///     fn write(&mut self, bytes: &[u8]) {
+///         self.0 += bytes.len();
+///     }
+/// }
+/// ```
+///
+/// [`Channel`]: crate::Channel
+pub trait LogSink: Send {
+    /// Write encoded bytes of the current frame, in the order they are produced.
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Finish the current frame, whose bytes up to its delimiter have all been written. Nothing is
+    /// done by default.
+    fn end_frame(&mut self) {}
+}
+
+/// Pass every defmt frame to `sink` as well, from [`run_log_sinks`], returning whether it was
+/// added, which it is not if [`MAX_LOG_SINKS`] sinks have already been added.
+///
+/// Frames are only queued for the sinks once one has been added, and a queue has been set with
+/// [`set_log_sink_queue`], so do both before anything is logged, or the frames logged before are
+/// not passed on.
+pub fn add_log_sink(sink: &'static mut dyn LogSink) -> bool {
+    let added = critical_section::with(|cs| {
+        let mut sinks = SINKS.borrow_ref_mut(cs);
+        let len = sinks.len;
+        let Some(slot) = sinks.slots.get_mut(len) else {
+            return false;
+        };
+        *slot = Some(sink);
+        sinks.len += 1;
+        true
+    });
+    if added {
+        CONTROLLER.enable_sinks();
+    }
+    added
+}
+
+/// Queue the frames for the sinks in `queue` until [`run_log_sinks`] passes them on, dropping the
+/// frames queued before, if any.
+///
+/// The logger copies every frame into the queue while a sink is added, so make it large enough
+/// for the frames logged in a burst while [`run_log_sinks`] does not get to run, such as 512
+/// bytes. Frames that do not fit are not passed on. Without a queue, which is the default, no
+/// frame is.
+pub fn set_log_sink_queue(queue: &'static mut [u8]) {
+    CONTROLLER.set_sink_queue(queue);
+}
+
+/// Pass the frames logged to the sinks added with [`add_log_sink`], forever.
+///
+/// Run it in a task of its own, at a lower priority than the code that logs if the sinks are
+/// slow, as the sinks are called from it.
+///
+/// ```ignore
+/// #[embassy_executor::task]
+/// async fn log_sinks() -> ! {
+///     defmt_embassy_usbserial::run_log_sinks().await
+/// }
+/// ```
+pub async fn run_log_sinks() -> ! {
+    let mut chunk = [0u8; 64];
+    loop {
+        let len = CONTROLLER.read_sink_queue(&mut chunk).await;
+        let len_sinks = critical_section::with(|cs| SINKS.borrow_ref(cs).len);
+        for i in 0..len_sinks {
+            // Call the sink outside of the critical section, with its slot empty meanwhile.
+            let Some(sink) = critical_section::with(|cs| SINKS.borrow_ref_mut(cs).slots[i].take())
+            else {
+                continue;
+            };
+            for bytes in chunk[..len].split_inclusive(|&byte| byte == 0) {
+                sink.write(bytes);
+                if bytes.last() == Some(&0) {
+                    sink.end_frame();
+                }
+            }
+            critical_section::with(|cs| SINKS.borrow_ref_mut(cs).slots[i] = Some(sink));
+        }
+    }
+}
+
+/// The frames queued for the sinks, written by the logger and read by [`run_log_sinks`].
+pub(crate) struct SinkQueue {
+    /// The queued bytes, in the storage set with [`set_log_sink_queue`], if any.
+    buf: &'static mut [u8],
+    /// Index of the oldest committed byte.
+    head: usize,
+    /// Number of bytes of whole frames, which [`run_log_sinks`] may read.
+    committed: usize,
+    /// Number of bytes of the frame in progress, stored after the committed bytes.
+    pending: usize,
+    /// Whether the frame in progress is dropped because it does not fit.
+    dropping: bool,
+    /// Whether a sink has been added, so that frames are queued at all.
+    enabled: bool,
+    /// Waker of [`run_log_sinks`], woken when a frame is committed.
+    waker: WakerRegistration,
+}
+
+impl SinkQueue {
+    /// A queue without storage, which queues nothing until it is given some and enabled.
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: &mut [],
+            head: 0,
+            committed: 0,
+            pending: 0,
+            dropping: false,
+            enabled: false,
+            waker: WakerRegistration::new(),
+        }
+    }
+
+    /// Start queueing the frames, from the next one if a frame is in progress `mid_frame`.
+    pub(crate) fn enable(&mut self, mid_frame: bool) {
+        if !self.enabled {
+            self.enabled = true;
+            self.dropping = mid_frame;
+        }
+    }
+
+    /// Queue the frames in `storage` instead, dropping those queued, and the frame in progress if
+    /// `mid_frame`.
+    pub(crate) fn set_storage(&mut self, storage: &'static mut [u8], mid_frame: bool) {
+        self.buf = storage;
+        self.head = 0;
+        self.committed = 0;
+        self.pending = 0;
+        self.dropping = mid_frame;
+    }
+
+    /// Add `bytes` to the frame in progress, dropping the whole frame if they do not fit.
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        if !self.enabled || self.dropping || self.buf.is_empty() {
+            return;
+        }
+        if self.committed + self.pending + bytes.len() > self.buf.len() {
+            self.dropping = true;
+            self.pending = 0;
+            return;
+        }
+        let start = (self.head + self.committed + self.pending) % self.buf.len();
+        let first = bytes.len().min(self.buf.len() - start);
+        self.buf[start..start + first].copy_from_slice(&bytes[..first]);
+        self.buf[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.pending += bytes.len();
+    }
+
+    /// Drop the frame in progress.
+    pub(crate) fn discard_frame(&mut self) {
+        self.pending = 0;
+        self.dropping = false;
+    }
+
+    /// Finish the frame in progress, making it visible to [`run_log_sinks`] unless it was dropped.
+    pub(crate) fn end_frame(&mut self) {
+        if self.pending > 0 {
+            self.committed += self.pending;
+            self.waker.wake();
+        }
+        self.pending = 0;
+        self.dropping = false;
+    }
+
+    /// Move as many committed bytes as fit into `out`, returning how many, or register `waker` to
+    /// be woken once there are some.
+    pub(crate) fn read(&mut self, out: &mut [u8], waker: &core::task::Waker) -> usize {
+        if self.committed == 0 {
+            self.waker.register(waker);
+            return 0;
+        }
+        let len = out
+            .len()
+            .min(self.committed)
+            .min(self.buf.len() - self.head);
+        out[..len].copy_from_slice(&self.buf[self.head..self.head + len]);
+        self.head = (self.head + len) % self.buf.len();
+        self.committed -= len;
+        len
+    }
+}
+
+/// A sink that buffers the frames in a pipe, for a task that writes them to a slow device.
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+pub(crate) struct PipeSink<const N: usize> {
+    /// Where the frames are buffered.
+    pipe: &'static Pipe<CriticalSectionRawMutex, N>,
+    /// Whether the rest of the current frame is dropped, because the pipe is full.
+    dropping: bool,
+}
+
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+impl<const N: usize> PipeSink<N> {
+    /// A sink that buffers the frames in `pipe`.
+    pub(crate) const fn new(pipe: &'static Pipe<CriticalSectionRawMutex, N>) -> Self {
+        Self {
+            pipe,
+            dropping: false,
+        }
+    }
+}
+
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+impl<const N: usize> LogSink for PipeSink<N> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.dropping = self.dropping || bytes.len() > self.pipe.free_capacity();
+        if !self.dropping {
+            let _ = self.pipe.try_write(bytes);
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.dropping = false;
+    }
+}
+
+/// Read more frames from `pipe` into `buf`, after the `len` bytes already read, until `buf` is full
+/// or none have come for `timeout`, returning the number of bytes in `buf`.
+#[cfg(any(feature = "flash-log", feature = "sd-log"))]
+pub(crate) async fn coalesce<const N: usize>(
+    pipe: &Pipe<CriticalSectionRawMutex, N>,
+    buf: &mut [u8],
+    mut len: usize,
+    timeout: Duration,
+) -> usize {
+    while len < buf.len() {
+        match with_timeout(timeout, pipe.read(&mut buf[len..])).await {
+            Ok(more) => len += more,
+            Err(_) => break,
+        }
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::task::Waker;
+    use std::vec;
+
+    use super::*;
+
+    /// Length of the storage of the test queues.
+    const QUEUE_LEN: usize = 512;
+
+    /// An empty queue with storage of [`QUEUE_LEN`] bytes.
+    fn queue() -> SinkQueue {
+        let mut queue = SinkQueue::new();
+        queue.set_storage(vec![0; QUEUE_LEN].leak(), false);
+        queue
+    }
+
+    /// Read everything committed from `queue` into `out`, returning the number of bytes.
+    fn read_all(queue: &mut SinkQueue, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        loop {
+            match queue.read(&mut out[len..], Waker::noop()) {
+                0 => return len,
+                more => len += more,
+            }
+        }
+    }
+
+    #[test]
+    fn frames_are_queued_once_enabled_and_complete() {
+        let mut queue = queue();
+        queue.write(b"\x01\x00");
+        queue.end_frame();
+        queue.enable(false);
+        queue.write(b"\x02");
+        let mut out = [0; 8];
+        assert_eq!(read_all(&mut queue, &mut out), 0);
+        queue.write(b"\x03\x00");
+        queue.end_frame();
+        assert_eq!(read_all(&mut queue, &mut out), 3);
+        assert_eq!(out[..3], *b"\x02\x03\x00");
+    }
+
+    #[test]
+    fn frames_wrap_around_the_end_of_the_queue() {
+        let mut queue = queue();
+        queue.enable(false);
+        let mut out = [0; QUEUE_LEN];
+        let frame = [7; QUEUE_LEN / 2 + 1];
+        for _ in 0..3 {
+            queue.write(&frame);
+            queue.end_frame();
+            assert_eq!(read_all(&mut queue, &mut out), frame.len());
+            assert_eq!(out[..frame.len()], frame);
+        }
+    }
+
+    #[test]
+    fn frame_that_does_not_fit_is_dropped_whole() {
+        let mut queue = queue();
+        queue.enable(true);
+        queue.write(b"\x01\x00");
+        queue.end_frame();
+        let frame = [7; QUEUE_LEN / 2 + 1];
+        queue.write(&frame);
+        queue.end_frame();
+        queue.write(&frame[..QUEUE_LEN / 4]);
+        queue.write(&frame[QUEUE_LEN / 4..]);
+        queue.end_frame();
+        queue.write(b"\x02\x00");
+        queue.end_frame();
+        let mut out = [0; QUEUE_LEN];
+        let len = read_all(&mut queue, &mut out);
+        assert_eq!(len, frame.len() + 2);
+        assert_eq!(out[..frame.len()], frame);
+        assert_eq!(out[frame.len()..len], *b"\x02\x00");
+    }
+}
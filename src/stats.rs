@@ -0,0 +1,91 @@
+//! Logger statistics
+
+#[cfg(feature = "cs-instrumentation")]
+use core::cell::Cell;
+
+#[cfg(feature = "cs-instrumentation")]
+use critical_section::Mutex;
+
+/// Counters describing the activity of the logger, returned by [`stats`](crate::stats).
+///
+/// All counts start at zero and wrap around on overflow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct LoggerStats {
+    /// Number of encoded frame bytes queued in the ring buffer.
+    pub bytes_enqueued: u32,
+    /// Number of frames queued in the ring buffer.
+    pub frames_enqueued: u32,
+    /// Number of bytes written to the USB endpoint.
+    pub bytes_sent: u32,
+    /// Number of frames whose last byte was written to the USB endpoint.
+    pub frames_sent: u32,
+    /// Number of frames dropped because the ring buffer was full.
+    pub frames_dropped: u32,
+    /// Number of encoded frame bytes dropped because the ring buffer was full.
+    pub bytes_dropped: u32,
+    /// Number of packets written to the USB endpoint, that is the number of flushes.
+    pub packets_sent: u32,
+    /// Number of bytes queued in the ring buffer when the stats were taken, as returned by
+    /// [`fill_level`](crate::fill_level).
+    pub fill_level: usize,
+    /// Largest number of bytes ever queued in the ring buffer, as returned by
+    /// [`high_watermark`](crate::high_watermark).
+    pub high_watermark: usize,
+}
+
+/// A free-running cycle counter.
+#[cfg(feature = "cs-instrumentation")]
+type CycleCounter = fn() -> u32;
+
+/// User-supplied cycle counter used to time critical sections.
+#[cfg(feature = "cs-instrumentation")]
+static CYCLE_COUNTER: Mutex<Cell<Option<CycleCounter>>> = Mutex::new(Cell::new(None));
+
+/// Cycle count when the current critical section was entered.
+#[cfg(feature = "cs-instrumentation")]
+static CS_START: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Longest time spent in a critical section, in cycles.
+#[cfg(feature = "cs-instrumentation")]
+static CS_MAX: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Set the cycle counter used to measure the time spent in the logger's critical sections.
+///
+/// `counter` should return a free-running count that wraps at `u32::MAX`, such as the
+/// `DWT.CYCCNT` register on Cortex-M. It is called twice per critical section, so it must be fast,
+/// and must not log.
+#[cfg(feature = "cs-instrumentation")]
+pub fn set_cycle_counter(counter: CycleCounter) {
+    critical_section::with(|cs| CYCLE_COUNTER.borrow(cs).set(Some(counter)));
+}
+
+/// The longest time spent inside one of the logger's critical sections so far, in cycles of the
+/// counter passed to [`set_cycle_counter`].
+#[cfg(feature = "cs-instrumentation")]
+pub fn max_critical_section_cycles() -> u32 {
+    critical_section::with(|cs| CS_MAX.borrow(cs).get())
+}
+
+/// Record that the logger has entered a critical section.
+#[inline]
+pub(crate) fn critical_section_entered() {
+    #[cfg(feature = "cs-instrumentation")]
+    critical_section::with(|cs| {
+        if let Some(counter) = CYCLE_COUNTER.borrow(cs).get() {
+            CS_START.borrow(cs).set(counter());
+        }
+    });
+}
+
+/// Record that the logger is about to leave a critical section.
+#[inline]
+pub(crate) fn critical_section_exiting() {
+    #[cfg(feature = "cs-instrumentation")]
+    critical_section::with(|cs| {
+        if let Some(counter) = CYCLE_COUNTER.borrow(cs).get() {
+            let elapsed = counter().wrapping_sub(CS_START.borrow(cs).get());
+            let max = CS_MAX.borrow(cs);
+            max.set(max.get().max(elapsed));
+        }
+    });
+}
@@ -1,30 +1,281 @@
 //! Main task that runs the USB transport layer.
 
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_deadline, with_timeout};
 use embassy_usb::{
-    Builder, Config,
-    class::cdc_acm::{CdcAcmClass, ControlChanged, Sender, State},
+    Builder, Config, UsbDevice,
+    class::{
+        cdc_acm::{ControlChanged, Sender, State},
+        web_usb::{self, Url, WebUsb},
+    },
     driver::{Driver, EndpointError},
 };
 
-use static_cell::{ConstStaticCell, StaticCell};
+use core::{cell::UnsafeCell, mem::MaybeUninit, pin::pin, sync::atomic::Ordering};
 
-// TODO: Document the RAM usage of these buffers.
+use portable_atomic::AtomicBool;
 
-/// Config descriptor buffer
-static CONFIG_DESCRIPTOR_BUF: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0u8; 256]);
+use crate::{
+    cdc_acm::{LineStateChanges, SerialSender},
+    cipher,
+    class::{DefmtClass, DefmtState},
+    config::LoggerConfig,
+    connection::{self, LineState},
+    controller::{CONTROLLER, OnWriteTimeout, PacketTermination, drained, stats},
+    error::{self, ConfigError},
+    filter, flow_control, handshake, ident, reliable, shutdown,
+    stats::LoggerStats,
+};
+
+/// Size of the configuration descriptor buffer.
+#[cfg(feature = "minimal-ram")]
+const CONFIG_DESCRIPTOR_LEN: usize = 128;
+#[cfg(not(feature = "minimal-ram"))]
+const CONFIG_DESCRIPTOR_LEN: usize = 256;
+
+/// Size of the BOS descriptor buffer.
+#[cfg(feature = "minimal-ram")]
+const BOS_DESCRIPTOR_LEN: usize = 32;
+#[cfg(not(feature = "minimal-ram"))]
+const BOS_DESCRIPTOR_LEN: usize = 256;
+
+/// Size of the MS OS descriptor buffer, which the CDC ACM function alone does not use.
+#[cfg(feature = "minimal-ram")]
+const MSOS_DESCRIPTOR_LEN: usize = 0;
+#[cfg(not(feature = "minimal-ram"))]
+const MSOS_DESCRIPTOR_LEN: usize = 256;
+
+/// Size of the control buffer.
+#[cfg(feature = "minimal-ram")]
+const CONTROL_LEN: usize = 64;
+#[cfg(not(feature = "minimal-ram"))]
+const CONTROL_LEN: usize = 256;
+
+/// Descriptor and control buffers for the USB builder, which take up 1 KiB of RAM, or 224 bytes
+/// with the `minimal-ram` feature.
+///
+/// These are only ever written before being read, so they may be placed in a `NOLOAD` section.
+#[cfg_attr(feature = "link-section", unsafe(link_section = ".defmt_usbserial"))]
+static USB_BUFFERS: UsbBuffersCell = UsbBuffersCell(UnsafeCell::new(UsbBuffers {
+    config_descriptor: [0; CONFIG_DESCRIPTOR_LEN],
+    bos_descriptor: [0; BOS_DESCRIPTOR_LEN],
+    msos_descriptor: [0; MSOS_DESCRIPTOR_LEN],
+    control: [0; CONTROL_LEN],
+}));
+
+/// Whether [`USB_BUFFERS`] has been handed out.
+static USB_BUFFERS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Descriptor and control buffers for the USB builder.
+struct UsbBuffers {
+    /// Config descriptor buffer
+    config_descriptor: [u8; CONFIG_DESCRIPTOR_LEN],
+    /// BOS descriptor buffer
+    bos_descriptor: [u8; BOS_DESCRIPTOR_LEN],
+    /// MSOS descriptor buffer
+    msos_descriptor: [u8; MSOS_DESCRIPTOR_LEN],
+    /// Control buffer
+    control: [u8; CONTROL_LEN],
+}
+
+/// Wrapper to allow the USB buffers to be stored in a static.
+struct UsbBuffersCell(UnsafeCell<UsbBuffers>);
+
+unsafe impl Sync for UsbBuffersCell {}
+
+/// Take the USB buffers, unless they have already been taken.
+fn take_usb_buffers() -> Option<&'static mut UsbBuffers> {
+    if USB_BUFFERS_TAKEN.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    // SAFETY: The taken flag ensures this is the only reference.
+    Some(unsafe { &mut *USB_BUFFERS.0.get() })
+}
 
-/// BOS descriptor buffer
-static BOS_DESCRIPTOR_BUF: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0u8; 256]);
+/// Release the USB buffers, so that they can be taken again.
+///
+/// # Safety
+///
+/// Nothing may refer to the buffers any more.
+unsafe fn release_usb_buffers() {
+    USB_BUFFERS_TAKEN.store(false, Ordering::Release);
+}
+
+/// State of the defmt serial port.
+static STATE: DefmtStateCell = DefmtStateCell(UnsafeCell::new(MaybeUninit::uninit()));
 
-/// MSOS descriptor buffer
-static MSOS_DESCRIPTOR_BUF: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0u8; 256]);
+/// Whether [`STATE`] has been handed out.
+static STATE_TAKEN: AtomicBool = AtomicBool::new(false);
 
-/// Control buffer
-static CONTROL_BUF: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0u8; 256]);
+/// Wrapper to allow the state of the defmt serial port to be stored in a static.
+struct DefmtStateCell(UnsafeCell<MaybeUninit<DefmtState<'static>>>);
+
+unsafe impl Sync for DefmtStateCell {}
+
+/// Initialize and take the state of the defmt serial port, with the vendor-specific protocol if
+/// `modem_manager_ignore` is set, unless it has already been taken.
+fn take_state(modem_manager_ignore: bool) -> Option<&'static mut DefmtState<'static>> {
+    if STATE_TAKEN.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    let state = DefmtState::new().modem_manager_ignore(modem_manager_ignore);
+    // SAFETY: The taken flag ensures this is the only reference.
+    Some(unsafe { (*STATE.0.get()).write(state) })
+}
+
+/// Wrapper to allow the CDC ACM state to be stored in a static.
+struct StateCell(UnsafeCell<MaybeUninit<State<'static>>>);
+
+unsafe impl Sync for StateCell {}
+
+/// CDC ACM state of the console serial port.
+static CONSOLE_STATE: StateCell = StateCell(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Whether [`CONSOLE_STATE`] has been handed out.
+static CONSOLE_STATE_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Initialize and take the CDC ACM state of the console, unless it has already been taken.
+fn take_console_state() -> Option<&'static mut State<'static>> {
+    if CONSOLE_STATE_TAKEN.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    // SAFETY: The taken flag ensures this is the only reference.
+    Some(unsafe { (*CONSOLE_STATE.0.get()).write(State::new()) })
+}
 
-/// CDC ACM state.
-static STATE: StaticCell<State> = StaticCell::new();
+/// `bRequest` of the vendor control request with which browsers read the WebUSB landing page.
+const WEBUSB_VENDOR_CODE: u8 = 0x10;
+
+/// WebUSB state and configuration.
+struct WebUsbParts {
+    /// WebUSB state.
+    state: web_usb::State<'static>,
+    /// WebUSB configuration.
+    config: web_usb::Config<'static>,
+}
+
+/// WebUSB state and configuration, if the landing page is set.
+static WEBUSB: WebUsbCell = WebUsbCell(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Whether [`WEBUSB`] has been handed out.
+static WEBUSB_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Wrapper to allow the WebUSB state to be stored in a static.
+struct WebUsbCell(UnsafeCell<MaybeUninit<WebUsbParts>>);
+
+unsafe impl Sync for WebUsbCell {}
+
+/// Initialize and take the WebUSB state, with `config`, unless it has already been taken.
+fn take_webusb(config: web_usb::Config<'static>) -> Option<&'static mut WebUsbParts> {
+    if WEBUSB_TAKEN.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    let parts = WebUsbParts {
+        state: web_usb::State::new(),
+        config,
+    };
+    // SAFETY: The taken flag ensures this is the only reference.
+    Some(unsafe { (*WEBUSB.0.get()).write(parts) })
+}
+
+/// Drop the state of the defmt serial port, so that it can be taken again.
+///
+/// # Safety
+///
+/// The state must have been taken, and nothing may refer to it any more.
+unsafe fn release_state() {
+    // SAFETY: Guaranteed by the caller.
+    unsafe { (*STATE.0.get()).assume_init_drop() };
+    STATE_TAKEN.store(false, Ordering::Release);
+}
+
+/// Drop the CDC ACM state of the console, so that it can be taken again.
+///
+/// # Safety
+///
+/// The state must have been taken, and nothing may refer to it any more.
+unsafe fn release_console_state() {
+    // SAFETY: Guaranteed by the caller.
+    unsafe { (*CONSOLE_STATE.0.get()).assume_init_drop() };
+    CONSOLE_STATE_TAKEN.store(false, Ordering::Release);
+}
+
+/// What has been taken to build the USB device, released when dropped unless it is kept once the
+/// device has been built, so that a failed build can be tried again.
+#[derive(Default)]
+struct Taken {
+    /// Whether the USB buffers have been taken.
+    usb_buffers: bool,
+    /// Whether the state of the defmt serial port has been taken.
+    state: bool,
+    /// Whether the CDC ACM state of the console has been taken.
+    console_state: bool,
+    /// Whether the WebUSB state has been taken.
+    webusb: bool,
+}
+
+impl Taken {
+    /// Take the USB buffers.
+    fn usb_buffers(&mut self) -> Result<&'static mut UsbBuffers, ConfigError> {
+        let buffers = take_usb_buffers().ok_or(ConfigError::AlreadyRunning)?;
+        self.usb_buffers = true;
+        Ok(buffers)
+    }
+
+    /// Take the state of the defmt serial port, with the vendor-specific protocol if
+    /// `modem_manager_ignore` is set.
+    fn state(
+        &mut self,
+        modem_manager_ignore: bool,
+    ) -> Result<&'static mut DefmtState<'static>, ConfigError> {
+        let state = take_state(modem_manager_ignore).ok_or(ConfigError::AlreadyRunning)?;
+        self.state = true;
+        Ok(state)
+    }
+
+    /// Take the CDC ACM state of the console.
+    fn console_state(&mut self) -> Result<&'static mut State<'static>, ConfigError> {
+        let state = take_console_state().ok_or(ConfigError::AlreadyRunning)?;
+        self.console_state = true;
+        Ok(state)
+    }
+
+    /// Take the WebUSB state, with `config`.
+    fn webusb(
+        &mut self,
+        config: web_usb::Config<'static>,
+    ) -> Result<&'static mut WebUsbParts, ConfigError> {
+        let webusb = take_webusb(config).ok_or(ConfigError::AlreadyRunning)?;
+        self.webusb = true;
+        Ok(webusb)
+    }
+
+    /// Keep what has been taken, for the built device, until it is released when the device
+    /// stops.
+    fn keep(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for Taken {
+    fn drop(&mut self) {
+        // SAFETY: The device has not been built, so nothing refers to what was taken any more.
+        unsafe {
+            if self.usb_buffers {
+                release_usb_buffers();
+            }
+            if self.state {
+                release_state();
+            }
+            if self.console_state {
+                release_console_state();
+            }
+        }
+        if self.webusb {
+            WEBUSB_TAKEN.store(false, Ordering::Release);
+        }
+    }
+}
 
 /// Run the USB driver and defmt logger tasks.
 ///
@@ -33,69 +284,960 @@ static STATE: StaticCell<State> = StaticCell::new();
 ///
 /// Along with the usb driver implementation, users must pass a USB configuration that is properly
 /// set for USB-CDC. See [the library documentation][crate] for details about the requirements.
-pub async fn run<D: Driver<'static>>(driver: D, config: Config<'static>) {
-    // Create the state of the CDC ACM device.
-    let state: &'static mut State<'static> = STATE.init(State::new());
+/// `logger_config` is applied once the device is built, replacing any settings made before.
+///
+/// There is no separate packet size to keep in step with the configuration: the maximum packet
+/// size of the CDC ACM endpoints is taken from `max_packet_size_0`, unless it is set with
+/// [`LoggerConfig::max_packet_size`] for a high-speed device.
+///
+/// The USB configuration is checked before the device is built, and an error is returned if it
+/// would make `embassy-usb` panic, or if the logger is already running. The device has no other
+/// way to report the problem at this point, so handle the error with something the user can
+/// see, such as a blinking LED. Otherwise this does not return until [`shutdown`] is called.
+///
+/// Dropping the returned future stops the device and the logger and releases what they use, so
+/// that `run` can be called again, for example to restart logging after the USB peripheral has
+/// been power-cycled. Queued frames are kept, except for the rest of one that was part-way
+/// through being sent.
+///
+/// The frames are queued in the static ring buffer, or the one passed to [`init`]. To size the
+/// buffer at the call site instead, use [`run_with_ring_buffer`].
+///
+/// [`init`]: crate::init
+/// [`shutdown`]: crate::shutdown
+pub async fn run<D: Driver<'static>>(
+    driver: D,
+    config: Config<'static>,
+    logger_config: LoggerConfig,
+) -> Result<(), ConfigError> {
+    run_with_ring_buffer::<D, 0>(driver, config, logger_config).await
+}
+
+/// Run the USB driver and defmt logger tasks, with a ring buffer of `N` bytes stored in the
+/// returned future.
+///
+/// This is [`run`], except that the buffer replaces the static buffer (or the one passed to
+/// [`init`]) for as long as the future is alive, and frames queued before then are moved into
+/// it. This lets different binaries of a workspace pick different sizes without features, and
+/// shows the size at the call site. If `N` is zero, the existing buffer is used.
+///
+/// ```ignore
+/// let logger_config = LoggerConfig::new();
+/// defmt_embassy_usbserial::run_with_ring_buffer::<_, 1024>(driver, usb_config, logger_config)
+///     .await
+///     .unwrap();
+/// ```
+///
+/// [`init`]: crate::init
+pub async fn run_with_ring_buffer<D: Driver<'static>, const N: usize>(
+    driver: D,
+    config: Config<'static>,
+    logger_config: LoggerConfig,
+) -> Result<(), ConfigError> {
+    let (usb, class) = build(driver, config, logger_config)?;
+    run_device::<D, N>(usb, class.run(), true).await;
+    Ok(())
+}
+
+/// Run the USB driver and defmt logger tasks, handing the data the host sends over the serial port
+/// to `on_receive`.
+///
+/// This is [`run`] for a port that also carries simple commands from the host to the device.
+/// `on_receive` is called with the payload of each USB packet the host sends, which may hold part
+/// of a message or several of them, so any framing is up to the caller. The next packet is not
+/// read until it returns, which holds off the host instead of losing data. To handle the data in
+/// another task, forward it to an `embassy_sync` channel or pipe:
+///
+/// ```ignore
+/// static COMMANDS: Pipe<CriticalSectionRawMutex, 64> = Pipe::new();
+///
+/// defmt_embassy_usbserial::run_with_receiver(
+///     driver,
+///     usb_config,
+///     LoggerConfig::new(),
+///     async |data| COMMANDS.write_all(data).await,
+/// )
+/// .await
+/// .unwrap();
+/// ```
+///
+/// With [`build`], [`run_with_builder`] or a [`DefmtClass`] added to a caller-owned device, use
+/// [`DefmtClass::run_with_receiver`] instead.
+pub async fn run_with_receiver<D: Driver<'static>>(
+    driver: D,
+    config: Config<'static>,
+    logger_config: LoggerConfig,
+    on_receive: impl AsyncFnMut(&[u8]),
+) -> Result<(), ConfigError> {
+    let (usb, class) = build(driver, config, logger_config)?;
+    run_device::<D, 0>(usb, class.run_with_receiver(on_receive), true).await;
+    Ok(())
+}
+
+/// Descriptor and control buffers for the USB device, provided by the caller.
+///
+/// By default [`run`] uses four static buffers of 256 bytes each. On devices with little RAM,
+/// pass buffers sized for the device to [`run_with_buffers`] instead, and the static buffers are
+/// left out of the binary as long as [`run`] and [`build`] are not used. A device with only the
+/// defmt serial port needs under 128 bytes of configuration descriptor, 64 bytes of control buffer
+/// and 32 bytes of BOS descriptor, and its MS OS descriptor buffer may be empty.
+pub struct DescriptorBuffers<'a> {
+    /// Buffer for the configuration descriptor.
+    pub config_descriptor: &'a mut [u8],
+    /// Buffer for the BOS descriptor.
+    pub bos_descriptor: &'a mut [u8],
+    /// Buffer for the MS OS descriptors.
+    pub msos_descriptor: &'a mut [u8],
+    /// Buffer for control transfers.
+    pub control: &'a mut [u8],
+}
+
+/// Run the USB driver and defmt logger tasks, with descriptor and control buffers provided by the
+/// caller.
+///
+/// This is [`run`], except that the USB builder uses `buffers` instead of static buffers of a
+/// fixed size. `embassy-usb` panics if a descriptor does not fit in its buffer.
+pub async fn run_with_buffers<D: Driver<'static>>(
+    driver: D,
+    config: Config<'static>,
+    buffers: DescriptorBuffers<'static>,
+    logger_config: LoggerConfig,
+) -> Result<(), ConfigError> {
+    let packet_size = logger_config
+        .packet_size()
+        .unwrap_or(u16::from(config.max_packet_size_0));
+    error::validate(&config)?;
+    error::validate_console(&config, logger_config.console_enabled())?;
+    error::validate_packet_size(packet_size)?;
+
+    let builder = Builder::new(
+        driver,
+        config,
+        buffers.config_descriptor,
+        buffers.bos_descriptor,
+        buffers.msos_descriptor,
+        buffers.control,
+    );
+    let (usb, class) = build_with_builder(builder, packet_size, logger_config, Taken::default())?;
+    run_device::<D, 0>(usb, class.run(), false).await;
+    Ok(())
+}
+
+/// Run the USB driver and defmt logger tasks, with a USB builder created by the caller.
+///
+/// This is [`run`] for when the builder needs more than a configuration, such as custom BOS or
+/// MS OS descriptors, handlers or strings: the defmt serial port is added to `builder`, and the
+/// device is built and run along with the logger. The configuration of the builder is not
+/// checked, so it must be set as described in [the library documentation][crate]. The maximum
+/// packet size of the serial port is 64 bytes unless set with [`LoggerConfig::max_packet_size`].
+///
+/// To run other functions of the same device as well, use [`DefmtClass`] instead.
+///
+/// ```ignore
+/// let mut builder = embassy_usb::Builder::new(driver, usb_config, ...);
+/// builder.handler(&mut my_handler);
+/// defmt_embassy_usbserial::run_with_builder(builder, LoggerConfig::new())
+///     .await
+///     .unwrap();
+/// ```
+pub async fn run_with_builder<D: Driver<'static>>(
+    builder: Builder<'static, D>,
+    logger_config: LoggerConfig,
+) -> Result<(), ConfigError> {
+    let packet_size = logger_config.packet_size().unwrap_or(64);
+    error::validate_packet_size(packet_size)?;
+    let (usb, class) = build_with_builder(builder, packet_size, logger_config, Taken::default())?;
+    run_device::<D, 0>(usb, class.run(), false).await;
+    Ok(())
+}
+
+/// Build the USB device and the defmt logger without running them, so that they can be spawned
+/// separately.
+///
+/// This does everything [`run`] does up to running the device and the logger, and returns them
+/// so that they can run on different executors or at different priorities, for example the USB
+/// device on a high-priority interrupt executor and the logger in thread mode. Both must keep
+/// running:
+///
+/// ```ignore
+/// type UsbDriver = embassy_rp::usb::Driver<'static, USB>;
+///
+/// #[embassy_executor::task]
+/// async fn usb_task(mut usb: UsbDevice<'static, UsbDriver>) -> ! {
+///     usb.run().await
+/// }
+///
+/// #[embassy_executor::task]
+/// async fn logger_task(class: DefmtClass<'static, UsbDriver>) {
+///     class.run().await
+/// }
+///
+/// let (usb, class) = defmt_embassy_usbserial::build(driver, usb_config, LoggerConfig::new())?;
+/// interrupt_spawner.must_spawn(usb_task(usb));
+/// spawner.must_spawn(logger_task(class));
+/// ```
+///
+/// Unlike with [`run_with_ring_buffer`], the ring buffer cannot be stored in a future, so the
+/// static buffer or the one passed to [`init`] is used, and [`flush_after_panic`] cannot poll the
+/// device. The USB buffers and state are never reclaimed, so neither this nor [`run`] can be
+/// called again.
+///
+/// [`init`]: crate::init
+/// [`flush_after_panic`]: crate::flush_after_panic
+pub fn build<D: Driver<'static>>(
+    driver: D,
+    config: Config<'static>,
+    logger_config: LoggerConfig,
+) -> Result<(UsbDevice<'static, D>, DefmtClass<'static, D>), ConfigError> {
+    let packet_size = logger_config
+        .packet_size()
+        .unwrap_or(u16::from(config.max_packet_size_0));
+    error::validate(&config)?;
+    error::validate_console(&config, logger_config.console_enabled())?;
+    error::validate_packet_size(packet_size)?;
+    let mut taken = Taken::default();
+    let buffers = taken.usb_buffers()?;
 
     // Create the USB builder.
-    let mut builder = Builder::new(
+    let builder = Builder::new(
         driver,
         config,
-        CONFIG_DESCRIPTOR_BUF.take(),
-        BOS_DESCRIPTOR_BUF.take(),
-        MSOS_DESCRIPTOR_BUF.take(),
-        CONTROL_BUF.take(),
+        &mut buffers.config_descriptor,
+        &mut buffers.bos_descriptor,
+        &mut buffers.msos_descriptor,
+        &mut buffers.control,
     );
 
+    build_with_builder(builder, packet_size, logger_config, taken)
+}
+
+/// Add the defmt serial port to `builder`, with endpoints of `packet_size` bytes, apply
+/// `logger_config`, and build the device, keeping what has been `taken` for it.
+///
+/// Everything is released if this fails, and `logger_config` is only applied once it can no
+/// longer fail.
+fn build_with_builder<D: Driver<'static>>(
+    mut builder: Builder<'static, D>,
+    packet_size: u16,
+    logger_config: LoggerConfig,
+    mut taken: Taken,
+) -> Result<(UsbDevice<'static, D>, DefmtClass<'static, D>), ConfigError> {
+    // Take the state of the CDC ACM device, and of the console and WebUSB if requested.
+    let state = taken.state(logger_config.modem_manager_ignored())?;
+    let console_state = if logger_config.console_enabled() {
+        Some(taken.console_state()?)
+    } else {
+        None
+    };
+    let webusb = match logger_config.landing_page() {
+        Some(url) => Some(taken.webusb(web_usb::Config {
+            max_packet_size: packet_size,
+            vendor_code: WEBUSB_VENDOR_CODE,
+            landing_url: Some(Url::new(url)),
+        })?),
+        None => None,
+    };
+    taken.keep();
+    logger_config.apply();
+
     // Create the class on top of the builder.
-    let packet_size = config.max_packet_size_0 as u16;
-    let class = CdcAcmClass::new(&mut builder, state, packet_size);
+    let mut class = DefmtClass::new(&mut builder, state, packet_size);
+
+    // Add the console serial port, if requested.
+    if let Some(state) = console_state {
+        class.add_console(&mut builder, state, packet_size);
+    }
+
+    // Add the WebUSB descriptors, if requested.
+    if let Some(webusb) = webusb {
+        WebUsb::configure(&mut builder, &mut webusb.state, &webusb.config);
+    }
 
     // Build the USB.
-    let mut usb = builder.build();
+    Ok((builder.build(), class))
+}
+
+/// Run the USB device along with `class`, the future of the logger, with a ring buffer of `N`
+/// bytes stored in the returned future if `N` is not zero.
+///
+/// The CDC ACM state, and the USB buffers if `release_buffers` is set, are released when the
+/// future is dropped, so that the logger can be started again.
+async fn run_device<D: Driver<'static>, const N: usize>(
+    usb: UsbDevice<'static, D>,
+    class: impl Future<Output = ()>,
+    release_buffers: bool,
+) {
+    // Declared before the device and the logger are moved in, so that it is dropped after them.
+    let _teardown = Teardown { release_buffers };
+    let mut usb = usb;
+    let class = class;
+
+    // Use the ring buffer stored in this future, if one was requested.
+    let mut buffer = [0u8; N];
+    let _buffer_guard = (N > 0).then(|| {
+        // SAFETY: This future is pinned once it is polled, so the buffer will not move, and the
+        // guard restores the previous buffer before the future is dropped.
+        unsafe { BufferGuard::install(&mut buffer) }
+    });
+
+    // Run both futures concurrently, until a shutdown is requested and the queued frames have been
+    // sent, and then detach from the bus.
+    shutdown::started();
+    let future = async {
+        let run = embassy_futures::join::join(usb.run(), class);
+        let shutdown = async {
+            let flush_timeout = shutdown::requested().await;
+            let _ = with_timeout(flush_timeout, drained()).await;
+        };
+        select(run, shutdown).await;
+        usb.disable().await;
+    };
+
+    // Let the panic handler poll the futures once the executor no longer does.
+    #[cfg(feature = "panic-flush")]
+    let mut future = core::pin::pin!(future);
+    #[cfg(feature = "panic-flush")]
+    let future = crate::panic::Registered::new(future.as_mut());
+
+    future.await;
+}
+
+/// Releases what a running device and logger use when dropped, after them.
+struct Teardown {
+    /// Whether the USB buffers are to be released too.
+    release_buffers: bool,
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        CONTROLLER.stopped();
+        connection::set_connected(false);
+        connection::set_host_ready(false);
+        connection::set_line_state(LineState::default());
+        shutdown::stopped();
+        WEBUSB_TAKEN.store(false, Ordering::Release);
+        // SAFETY: The device and the logger, which referred to the state and the buffers, have
+        // been dropped.
+        unsafe {
+            release_state();
+            if CONSOLE_STATE_TAKEN.load(Ordering::Acquire) {
+                release_console_state();
+            }
+            if self.release_buffers {
+                release_usb_buffers();
+            }
+        }
+    }
+}
+
+/// Installs a ring buffer for as long as it is alive, restoring the previous buffer when dropped.
+struct BufferGuard {
+    /// The previous ring buffer storage.
+    previous: (*mut u8, usize),
+}
+
+// SAFETY: The ring buffer storage is only ever accessed within a critical section.
+unsafe impl Send for BufferGuard {}
 
-    // Get the sender.
-    let (sender, _, ctrl) = class.split_with_control();
+impl BufferGuard {
+    /// Switch the logger to use `buffer` as its ring buffer.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must not move or be accessed while the guard is alive, and must outlive it.
+    unsafe fn install(buffer: &mut [u8]) -> Self {
+        let previous = unsafe { CONTROLLER.replace_storage(buffer.as_mut_ptr(), buffer.len()) };
+        Self { previous }
+    }
+}
 
-    // Run both futures concurrently.
-    embassy_futures::join::join(usb.run(), logger(sender, ctrl)).await;
+impl Drop for BufferGuard {
+    fn drop(&mut self) {
+        let (buf, len) = self.previous;
+        // SAFETY: This is the storage that was in use before, which remains valid.
+        unsafe { CONTROLLER.replace_storage(buf, len) };
+    }
+}
+
+/// Line coding with which the host dropping DTR requests a reset.
+const TOUCH_BAUD_RATE: u32 = 1200;
+
+/// Publish the serial line state set by the host, and act on the baud rate.
+///
+/// This calls the handler set with [`set_baud_touch`] if the host has just dropped DTR at 1200
+/// baud, and selects the minimum level of the preset set with [`set_verbosity_presets`] if the
+/// host has just changed the baud rate from `data_rate`, which is updated.
+///
+/// [`set_baud_touch`]: crate::set_baud_touch
+/// [`set_verbosity_presets`]: crate::set_verbosity_presets
+fn update_line_state(sender: &impl SerialSender, data_rate: &mut u32) {
+    let state = LineState {
+        dtr: sender.dtr(),
+        rts: sender.rts(),
+    };
+    let previous = connection::set_line_state(state);
+    let rate = sender.data_rate();
+    if previous.dtr
+        && !state.dtr
+        && rate == TOUCH_BAUD_RATE
+        && let Some(handler) = CONTROLLER.baud_touch()
+    {
+        handler();
+    }
+
+    if core::mem::replace(data_rate, rate) != rate
+        && let Some(&(_, level)) = CONTROLLER
+            .verbosity_presets()
+            .iter()
+            .find(|&&(preset, _)| preset == rate)
+    {
+        filter::set_min_level(level);
+    }
+}
+
+/// When the queued frames go out of date if the host reads nothing more, with a stale `timeout`,
+/// given when it `last_sent` a packet.
+///
+/// There is none until the host has read a packet, so that a packet that was never sent is only
+/// subject to the write timeout, and the frames logged before the host connected are kept.
+fn stale_deadline(timeout: Option<Duration>, last_sent: Option<Instant>) -> Option<Instant> {
+    Some(last_sent? + timeout?)
+}
+
+/// Log a warning with the number of frames dropped since the last report, if any, so that data
+/// loss shows up in the host log where it happened, returning whether there was one.
+fn report_drops() -> bool {
+    let mut reported = false;
+    CONTROLLER.report_drops(|frames| {
+        defmt::warn!("{=u32} defmt frames dropped: log buffer full", frames);
+        reported = true;
+    });
+    reported
+}
+
+/// Longest time the logger task waits for the host to read an overrun notification.
+const NOTIFY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Await `write`, which sends a packet, reading the next packet into `next` meanwhile if
+/// `read_next` and double buffering is enabled.
+///
+/// The length of the next packet is passed to `on_read` as soon as it is read, so that it is not
+/// lost if the caller then gives up on `write`.
+async fn write_and_read_next<R>(
+    write: impl Future<Output = R>,
+    next: &mut [u8],
+    read_next: bool,
+    on_read: impl FnOnce(usize),
+) -> R {
+    if !read_next || !CONTROLLER.double_buffering() {
+        return write.await;
+    }
+    let mut write = pin!(write);
+    match select(write.as_mut(), CONTROLLER.read(next)).await {
+        Either::First(result) => result,
+        Either::Second(len) => {
+            on_read(len);
+            write.await
+        }
+    }
+}
+
+/// Send the queued frames with `write`, in packets of up to `packet.len()` bytes, until it fails
+/// because the USB device was disconnected.
+///
+/// With double buffering, the next packet is read into `spare`, which must be as large as
+/// `packet`, while a packet is being sent.
+///
+/// This is the logger of the transports other than the serial port, which have no line state to
+/// wait for: the host is taken to be ready as soon as the device is configured.
+pub(crate) async fn stream_packets(
+    mut packet: &mut [u8],
+    mut spare: &mut [u8],
+    mut write: impl AsyncFnMut(&[u8]) -> Result<(), EndpointError>,
+) {
+    connection::set_connected(true);
+    connection::set_host_ready(true);
+    CONTROLLER.resync();
+    // Length of the packet read into `spare` while the previous one was being sent.
+    let mut next = None;
+    loop {
+        report_drops();
+        let len = match next.take() {
+            Some(len) => {
+                core::mem::swap(&mut packet, &mut spare);
+                len
+            }
+            None => CONTROLLER.read(packet).await,
+        };
+        let write = write(&packet[..len]);
+        let result = write_and_read_next(write, spare, true, |len| next = Some(len)).await;
+        CONTROLLER.packet_done(result.is_ok());
+        match result {
+            Err(EndpointError::Disabled) => {
+                if next.take().is_some() {
+                    CONTROLLER.packet_done(false);
+                }
+                CONTROLLER.disconnected();
+                connection::set_connected(false);
+                connection::set_host_ready(false);
+                return;
+            }
+            Err(EndpointError::BufferOverflow) => {
+                unreachable!("Sent chunks are limited to the max packet size.")
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Logger task that writes the queued frames to `writer`, forever, instead of to a USB serial
+/// port.
+///
+/// This reuses the ring buffer, the overflow policy and the flush settings of the crate on boards
+/// without USB, or to log over a second transport, such as a UART or any other byte pipe that
+/// implements `embedded-io-async`. The frames are written as they would be to the serial port,
+/// in chunks of up to 64 bytes, or 512 with the `high-speed` feature, and with the CRC framing of
+/// the `crc-framing` feature. The host is taken to be always ready, so nothing specific to the
+/// serial port applies, such as the line state, the start handshake, flow control, encryption or
+/// chunk headers. Chunks that fail to be written are lost, with a warning in the log.
+///
+/// Apply a [`LoggerConfig`] yourself, as [`run`] is not used:
+///
+/// ```ignore
+/// let uart = BufferedUart::new(p.UART0, p.PIN_0, p.PIN_1, Irqs, tx_buf, rx_buf, uart_config);
+/// let (tx, _rx) = uart.split();
+/// LoggerConfig::new().apply();
+/// defmt_embassy_usbserial::logger_with_writer(tx).await;
+/// ```
+pub async fn logger_with_writer<W: embedded_io_async::Write>(mut writer: W) {
+    let mut buffers = [[0u8; MAX_PACKET_SIZE]; 2];
+    let [mut packet, mut spare] = buffers.each_mut();
+    connection::set_connected(true);
+    connection::set_host_ready(true);
+    CONTROLLER.resync();
+    // Length of the chunk read into `spare` while the previous one was being written.
+    let mut next = None;
+    loop {
+        report_drops();
+        let len = match next.take() {
+            Some(len) => {
+                core::mem::swap(&mut packet, &mut spare);
+                len
+            }
+            None => CONTROLLER.read(packet).await,
+        };
+        let write = writer.write_all(&packet[..len]);
+        let result = write_and_read_next(write, spare, true, |len| next = Some(len)).await;
+        CONTROLLER.packet_done(result.is_ok());
+        if result.is_err() {
+            defmt::warn!("logger: write error, {=usize} bytes lost", len);
+        }
+    }
+}
+
+/// Largest USB packet the logger task sends.
+#[cfg(feature = "high-speed")]
+pub(crate) const MAX_PACKET_SIZE: usize = 512;
+#[cfg(not(feature = "high-speed"))]
+pub(crate) const MAX_PACKET_SIZE: usize = 64;
+
+/// Size of the header of each chunk when chunk headers are enabled: the payload length as a `u8`,
+/// followed by the sequence number as a little-endian `u16`.
+const CHUNK_HEADER_LEN: usize = 3;
+
+/// State of the periodic self-report of the logger task.
+struct SelfReport {
+    /// When the next report is due.
+    at: Instant,
+    /// Stats at the time of the previous report.
+    previous: LoggerStats,
+}
+
+impl SelfReport {
+    /// Start reporting every `interval`.
+    fn new(interval: Duration) -> Self {
+        Self {
+            at: Instant::now() + interval,
+            previous: stats(),
+        }
+    }
+
+    /// Log a summary of the throughput and drops since the previous report, which was `interval`
+    /// ago, and schedule the next one.
+    fn log(&mut self, interval: Duration) {
+        let current = stats();
+        let bytes = current.bytes_sent.wrapping_sub(self.previous.bytes_sent);
+        let millis = interval.as_millis().max(1);
+        defmt::info!(
+            "USB logger: {=u64} B/s sent, {=u32} frames dropped ({=u32} total), {=usize} B queued",
+            u64::from(bytes) * 1000 / millis,
+            current
+                .frames_dropped
+                .wrapping_sub(self.previous.frames_dropped),
+            current.frames_dropped,
+            current.fill_level,
+        );
+        self.previous = current;
+        self.at = Instant::now() + interval;
+    }
 }
 
 /// USB logger task that writes messages out over USB.
-pub async fn logger<'d, D: Driver<'d>>(mut sender: Sender<'d, D>, ctrl: ControlChanged<'d>) {
-    // Get a reference to the controller.
-    let mut consumer = super::controller::RING_BUFFER.consumer();
+///
+/// The task does not poll: it sleeps until a frame is committed to the ring buffer, which wakes it
+/// from the logger, and then sends it right away, subject to [`set_flush_watermark`].
+///
+/// The CDC ACM class of `embassy-usb` keeps its notification endpoint to itself, so unlike with
+/// [`DefmtClass`], the host is not notified of dropped frames.
+///
+/// [`set_flush_watermark`]: crate::set_flush_watermark
+pub async fn logger<'d, D: Driver<'d>>(sender: Sender<'d, D>, ctrl: ControlChanged<'d>) {
+    serve_serial_port(sender, ctrl).await;
+}
+
+/// Write the queued frames to the serial port of `sender`, whose line state changes are notified
+/// by `ctrl`, forever.
+pub(crate) async fn serve_serial_port(mut sender: impl SerialSender, ctrl: impl LineStateChanges) {
+    // Buffers holding the USB packet being sent, and the next one with double buffering.
+    let mut buffers = [[0u8; MAX_PACKET_SIZE]; 2];
+    let [mut packet, mut spare] = buffers.each_mut();
+    let max_packet_size = usize::from(sender.max_packet_size()).min(packet.len());
+
+    // Length of a packet that timed out and is to be sent again.
+    let mut retained = 0;
+    // Length of the chunk header room and of the payload of the packet read into `spare` while
+    // the previous one was being sent.
+    let mut next: Option<(usize, usize)> = None;
+    // When the host last read a packet.
+    let mut last_sent: Option<Instant> = None;
+
+    // Sequence number of the next chunk, if chunk headers are enabled.
+    let mut sequence: u16 = 0;
+    // Number of connections since boot.
+    let mut session: u32 = 0;
+    // Baud rate last set by the host.
+    let mut data_rate: u32 = 0;
+    // State of the periodic self-report, if enabled.
+    let mut self_report: Option<SelfReport> = None;
 
     'main: loop {
-        // Wait for the device to be connected.
+        // Wait for the device to be connected, writing the frames to RTT if it takes too long.
+        #[cfg(feature = "rtt")]
+        crate::rtt::wait_connection(sender.wait_connection()).await;
+        #[cfg(not(feature = "rtt"))]
         sender.wait_connection().await;
+        connection::set_connected(true);
+
+        // Frames logged while the host was disconnected are out of date, unless they are to be
+        // kept. Frames logged before the first connection are kept, so that boot messages are not
+        // lost.
+        CONTROLLER.reconnected();
+
+        // Start with a frame delimiter, so that the host decoder resynchronizes right away even
+        // if the previous session ended part-way through a frame.
+        CONTROLLER.resync();
+
+        // Send the packets the host has not acknowledged again, if reliable delivery is enabled.
+        reliable::start_replay();
+
+        // Start an encrypted session, if a cipher is set.
+        let header = &mut packet[..max_packet_size];
+        let started = cipher::with_cipher(|cipher| cipher.start_session(header));
+        let encrypted = started.is_some();
+        // Length of the unencrypted header that starts an encrypted session, yet to be sent.
+        let mut session_header = started.unwrap_or(0).min(max_packet_size);
+
+        // Mark the start of the session, if requested.
+        session = session.wrapping_add(1);
+        if let Some(reset_reason) = CONTROLLER.session_marker() {
+            defmt::info!(
+                "USB logger session {=u32}, reset reason: {=str}",
+                session,
+                reset_reason.unwrap_or("unknown"),
+            );
+        }
+        if CONTROLLER.firmware_ident().is_some() {
+            ident::log_ident();
+        }
+
+        // Whether the host has had the serial port open during this connection.
+        let mut opened = false;
 
         // If we don't wait for both DTR and RTS before sending data, we may send data before the
         // host is ready to receive it, which will cause the host to drop the data.
         // Continually attempt to write buffered defmt bytes out over USB.
         loop {
-            while !(sender.dtr() && sender.rts()) {
-                ctrl.control_changed().await;
-                Timer::after(Duration::from_millis(10)).await;
+            update_line_state(&sender, &mut data_rate);
+            if !(sender.dtr() && sender.rts()) {
+                connection::set_host_ready(false);
+                handshake::reset();
+                flow_control::reset();
+                reliable::start_replay();
+
+                // Drop the queued frames if the host closed the port, unless they are to be kept.
+                if core::mem::take(&mut opened) && CONTROLLER.port_closed() {
+                    retained = 0;
+                    next = None;
+                }
+                while !(sender.dtr() && sender.rts()) {
+                    ctrl.control_changed().await;
+                    Timer::after(CONTROLLER.line_state_debounce()).await;
+                    update_line_state(&sender, &mut data_rate);
+                }
+
+                // Drop the frames queued since the host stopped reading if they are out of date.
+                if stale_deadline(CONTROLLER.stale_timeout(), last_sent)
+                    .is_some_and(|deadline| deadline <= Instant::now())
+                {
+                    CONTROLLER.clear();
+                    retained = 0;
+                    next = None;
+                }
+                CONTROLLER.port_opened();
+            }
+
+            // Keep quiet until the host sends the start sequence, if one is required.
+            if let Some((_, backlog)) = CONTROLLER.start_handshake()
+                && !handshake::started()
+            {
+                connection::set_host_ready(false);
+                if let Either::Second(()) = select(handshake::wait(), ctrl.control_changed()).await
+                {
+                    // Check the line state again once it has settled.
+                    Timer::after(CONTROLLER.line_state_debounce()).await;
+                    continue;
+                }
+                if !backlog {
+                    CONTROLLER.clear();
+                    retained = 0;
+                    next = None;
+                }
+            }
+
+            // Hold off while the host has paused the stream with XOFF.
+            if flow_control::paused()
+                && let Either::Second(()) =
+                    select(flow_control::wait_resumed(), ctrl.control_changed()).await
+            {
+                // Check the line state again once it has settled.
+                Timer::after(CONTROLLER.line_state_debounce()).await;
+                continue;
             }
 
-            // Wait for data to be available.
-            let readable = consumer.readable_bytes().await;
-            use embedded_io_async::Write;
-            match sender.write(&readable).await {
+            opened = true;
+            connection::set_host_ready(true);
+            if report_drops() {
+                // Tell the host that data was lost, without holding up the stream if it does not
+                // read the notification.
+                let _ = with_timeout(NOTIFY_TIMEOUT, sender.notify_overrun()).await;
+            }
+
+            // Keep every packet short, if requested.
+            let termination = CONTROLLER.packet_termination();
+            let mut packet_size = match termination {
+                PacketTermination::ShortPacket => max_packet_size - 1,
+                _ => max_packet_size,
+            };
+            let header_len = if CONTROLLER.chunk_headers() || reliable::enabled() {
+                // The length in the header must fit in a byte.
+                packet_size = packet_size.min(CHUNK_HEADER_LEN + usize::from(u8::MAX));
+                CHUNK_HEADER_LEN
+            } else {
+                0
+            };
+
+            let len = if retained > 0 {
+                core::mem::take(&mut retained)
+            } else if session_header > 0 {
+                core::mem::take(&mut session_header)
+            } else if let Some(len) = reliable::next_replay(&mut packet[..max_packet_size]) {
+                len
+            } else {
+                // Wait for data to be available, for the next self-report, or for the line state to
+                // change.
+                let interval = CONTROLLER.self_report_interval();
+                let report_at = match (interval, &mut self_report) {
+                    (Some(interval), Some(report)) => report.at.min(Instant::now() + interval),
+                    (Some(interval), report @ None) => report.insert(SelfReport::new(interval)).at,
+                    (None, report) => {
+                        *report = None;
+                        Instant::MAX
+                    }
+                };
+                // Wait until the packet can be kept until the host acknowledges it, if enabled.
+                if !reliable::has_room(packet_size) {
+                    let room = reliable::wait_for_room(packet_size);
+                    if let Either::Second(()) = select(room, ctrl.control_changed()).await {
+                        // Check the line state again once it has settled.
+                        Timer::after(CONTROLLER.line_state_debounce()).await;
+                    }
+                    continue;
+                }
+                // Send the packet read while the previous one was being sent, if any, with the
+                // chunk header room it was read with.
+                let (header_len, mut len) = if let Some((header_len, len)) = next.take() {
+                    core::mem::swap(&mut packet, &mut spare);
+                    (header_len, header_len + len)
+                } else {
+                    let read = CONTROLLER.read(&mut packet[header_len..packet_size]);
+                    let line_state_changed = ctrl.control_changed();
+                    match select3(read, Timer::at(report_at), line_state_changed).await {
+                        Either3::First(len) => (header_len, header_len + len),
+                        Either3::Second(()) => {
+                            if let (Some(interval), Some(report)) = (interval, &mut self_report) {
+                                report.log(interval);
+                            }
+                            continue;
+                        }
+                        Either3::Third(()) => {
+                            // Check the line state again once it has settled.
+                            Timer::after(CONTROLLER.line_state_debounce()).await;
+                            continue;
+                        }
+                    }
+                };
+
+                // Fill the rest of the packet with frames logged shortly after, if enabled.
+                if let Some(idle) = CONTROLLER.coalesce_timeout() {
+                    while len < packet_size {
+                        let more = CONTROLLER.read_any(&mut packet[len..packet_size]);
+                        match select(more, Timer::after(idle)).await {
+                            Either::First(more) => len += more,
+                            Either::Second(()) => break,
+                        }
+                    }
+                }
+
+                // Encrypt everything but the chunk header, if enabled.
+                if encrypted {
+                    let payload = &mut packet[header_len..len];
+                    cipher::with_cipher(|cipher| cipher.apply_keystream(payload));
+                }
+
+                // Prefix the chunk with its length and sequence number, if enabled.
+                if header_len > 0 {
+                    packet[0] = (len - header_len) as u8;
+                    packet[1..header_len].copy_from_slice(&sequence.to_le_bytes());
+                    sequence = sequence.wrapping_add(1);
+                }
+                reliable::push(&packet[..len]);
+                len
+            };
+
+            let write = async {
+                let mut result = sender.write_packet(&packet[..len]).await;
+                if result.is_ok()
+                    && len == max_packet_size
+                    && termination == PacketTermination::ZeroLengthPacket
+                    && CONTROLLER.is_empty()
+                {
+                    // End the transfer so that the host passes the data on.
+                    result = sender.write_packet(&[]).await;
+                }
+                result
+            };
+
+            // Read the next packet meanwhile, if double buffering is enabled.
+            let read_next = next.is_none();
+            let on_read = |len| next = Some((header_len, len));
+            let write = write_and_read_next(
+                write,
+                &mut spare[header_len..packet_size],
+                read_next,
+                on_read,
+            );
+
+            // Stop waiting for the host to read the packet when it changes the line state, in case
+            // it closed the port, unless frames are kept while the port is closed.
+            let watch_line_state = !CONTROLLER.buffer_while_closed();
+            let write = async {
+                if watch_line_state {
+                    match select(write, ctrl.control_changed()).await {
+                        Either::First(result) => Some(result),
+                        Either::Second(()) => None,
+                    }
+                } else {
+                    Some(write.await)
+                }
+            };
+
+            // Give up on the packet if the host takes too long to read it.
+            let now = Instant::now();
+            let write_timeout = CONTROLLER.write_timeout();
+            let write_deadline = write_timeout.map(|(timeout, _)| now + timeout);
+            let stale_deadline = stale_deadline(CONTROLLER.stale_timeout(), last_sent);
+            let result = match write_deadline.into_iter().chain(stale_deadline).min() {
+                Some(deadline) => match with_deadline(deadline, write).await {
+                    Ok(result) => result,
+                    Err(TimeoutError) => {
+                        if stale_deadline.is_some_and(|stale| stale <= deadline) {
+                            // The host has not read anything for long enough that the queued
+                            // frames are out of date.
+                            CONTROLLER.clear();
+                            retained = 0;
+                            next = None;
+                            last_sent = Some(Instant::now());
+                        } else if let Some((_, on_timeout)) = write_timeout {
+                            match on_timeout {
+                                OnWriteTimeout::Retain => retained = len,
+                                OnWriteTimeout::Discard => {
+                                    // This gives up on the next packet as well, if any.
+                                    CONTROLLER.abandon_packet();
+                                    next = None;
+                                }
+                            }
+                        }
+                        // Check that the host is still ready before trying again.
+                        continue;
+                    }
+                },
+                None => write.await,
+            };
+            let Some(result) = result else {
+                // Send the packet again if the port is still open once the line state settles.
+                Timer::after(CONTROLLER.line_state_debounce()).await;
+                retained = len;
+                continue;
+            };
+            CONTROLLER.packet_done(result.is_ok());
+            match result {
                 Err(EndpointError::Disabled) => {
-                    // USB endpoint is now disabled. Wait for reconnection and
-                    // hope we're using rzcobs encoding.
+                    // USB endpoint is now disabled. Drop everything queued so that we resume
+                    // with current frames at a frame boundary, unless the frames are to be kept,
+                    // and wait for reconnection.
+                    if next.take().is_some() {
+                        CONTROLLER.packet_done(false);
+                    }
+                    CONTROLLER.disconnected();
+                    connection::set_connected(false);
+                    connection::set_host_ready(false);
+                    connection::set_line_state(LineState::default());
+                    handshake::reset();
+                    flow_control::reset();
                     continue 'main;
                 }
                 Err(EndpointError::BufferOverflow) => {
                     unreachable!("Sent chunks are limited to Sender max packet size.")
                 }
-                Ok(bytes_written) => {
-                    // Mark the bytes as consumed.
-                    readable.consume(bytes_written);
-                }
+                Ok(()) => last_sent = Some(Instant::now()),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_deadline_starts_once_the_host_has_read() {
+        let timeout = Some(Duration::from_millis(100));
+        let sent = Instant::from_millis(5);
+        assert_eq!(stale_deadline(timeout, None), None);
+        assert_eq!(stale_deadline(None, Some(sent)), None);
+        assert_eq!(
+            stale_deadline(timeout, Some(sent)),
+            Some(Instant::from_millis(105))
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Transformation of each frame on its way into the ring buffer
+
+use crate::crc;
+
+/// Largest number of bytes [`FrameTransform::start_frame`] and [`FrameTransform::end_frame`] may
+/// add to a frame.
+pub const MAX_FRAME_AFFIX: usize = 8;
+
+/// A transformation applied to every defmt frame between the encoder and the ring buffer, to add
+/// a CRC, a tag or encryption without forking this crate.
+///
+/// Set it with [`set_frame_transform`]. Each frame is passed through as a stream: a prefix from
+/// [`start_frame`], the encoded bytes transformed in place in the order they are produced, and a
+/// suffix from [`end_frame`]. The transform therefore cannot change the length of the encoded
+/// bytes, which rules out compression. The whole stream, delimiters included, is passed through,
+/// so anything other than [`Identity`] needs matching support on the host before `defmt-print`
+/// can decode it.
+///
+/// The methods are called while the logger holds a critical section, so keep them cheap. To
+/// transform whole USB packets instead, after they leave the ring buffer, see [`LogCipher`].
+///
+/// ```ignore
+/// /// Prefixes every frame with the ID of the core that logged it.
+/// struct CoreTag;
+///
+/// impl defmt_embassy_usbserial::FrameTransform for CoreTag {
+///     fn start_frame(&mut self, prefix: &mut [u8]) -> usize {
+///         prefix[0] = current_core_id();
+///         1
+///     }
+/// }
+/// ```
+///
+/// [`set_frame_transform`]: crate::set_frame_transform
+/// [`start_frame`]: FrameTransform::start_frame
+/// [`end_frame`]: FrameTransform::end_frame
+/// [`LogCipher`]: crate::LogCipher
+pub trait FrameTransform: Send {
+    /// Start a frame, writing the bytes that precede it to `prefix` and returning their number.
+    ///
+    /// `prefix` is [`MAX_FRAME_AFFIX`] bytes long. Nothing is added by default.
+    fn start_frame(&mut self, prefix: &mut [u8]) -> usize {
+        let _ = prefix;
+        0
+    }
+
+    /// Transform encoded bytes of the frame in place. Nothing is changed by default.
+    fn transform(&mut self, bytes: &mut [u8]) {
+        let _ = bytes;
+    }
+
+    /// Finish a frame, writing the bytes that follow it to `suffix` and returning their number.
+    ///
+    /// `suffix` is [`MAX_FRAME_AFFIX`] bytes long. Nothing is added by default.
+    fn end_frame(&mut self, suffix: &mut [u8]) -> usize {
+        let _ = suffix;
+        0
+    }
+}
+
+/// A [`FrameTransform`] that leaves frames as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+impl FrameTransform for Identity {}
+
+/// A [`FrameTransform`] that follows every frame with its CRC-16/CCITT-FALSE, as a little-endian
+/// `u16`.
+///
+/// The CRC covers the encoded frame, which ends with its `0x00` delimiter. The CRC itself may
+/// contain zeros, so the host must read the two bytes after each delimiter as the CRC before
+/// looking for the next one.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc16 {
+    /// CRC of the frame so far.
+    crc: u16,
+}
+
+impl Crc16 {
+    /// Create the transform.
+    pub const fn new() -> Self {
+        Self { crc: crc::INIT }
+    }
+}
+
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameTransform for Crc16 {
+    fn start_frame(&mut self, _prefix: &mut [u8]) -> usize {
+        self.crc = crc::INIT;
+        0
+    }
+
+    fn transform(&mut self, bytes: &mut [u8]) {
+        self.crc = crc::update(self.crc, bytes);
+    }
+
+    fn end_frame(&mut self, suffix: &mut [u8]) -> usize {
+        suffix[..crc::CRC_LEN].copy_from_slice(&self.crc.to_le_bytes());
+        crc::CRC_LEN
+    }
+}
@@ -0,0 +1,125 @@
+//! Vendor control requests with which host tooling can reset the device, silence the logs or
+//! identify the firmware
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embassy_usb::{
+    Builder, Handler,
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    driver::Driver,
+};
+
+use crate::controller::{CONTROLLER, set_logging_enabled};
+
+/// `bRequest` of the vendor control request that resets the device.
+pub const VENDOR_REQUEST_RESET: u8 = 0x01;
+
+/// `bRequest` of the vendor control request that resets the device into its bootloader.
+pub const VENDOR_REQUEST_BOOTLOADER: u8 = 0x02;
+
+/// `bRequest` of the vendor control request that enables logging if `wValue` is not zero, and
+/// disables it otherwise, see [`set_logging_enabled`].
+///
+/// [`set_logging_enabled`]: crate::set_logging_enabled
+pub const VENDOR_REQUEST_SET_LOGGING: u8 = 0x03;
+
+/// `bRequest` of the vendor control request that reads the build ID set with [`set_build_id`].
+///
+/// The device stalls it if there is no build ID.
+///
+/// [`set_build_id`]: crate::set_build_id
+pub const VENDOR_REQUEST_BUILD_ID: u8 = 0x04;
+
+/// A reset requested by the host, see [`set_reset_handler`].
+///
+/// [`set_reset_handler`]: crate::set_reset_handler
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ResetRequest {
+    /// Reset the device, with [`VENDOR_REQUEST_RESET`].
+    Reset,
+    /// Reset the device into its bootloader, with [`VENDOR_REQUEST_BOOTLOADER`].
+    Bootloader,
+}
+
+/// Resets requested by the host, for [`handle_resets`] to act on.
+static RESET: Signal<CriticalSectionRawMutex, ResetRequest> = Signal::new();
+
+/// How long [`handle_resets`] waits after a reset request, for the status stage of the request to
+/// complete.
+const RESET_DELAY: Duration = Duration::from_millis(10);
+
+/// Have [`handle_resets`] call the handler set with [`set_reset_handler`] with `request`, once the
+/// control request that asked for it has been acknowledged.
+///
+/// [`set_reset_handler`]: crate::set_reset_handler
+pub(crate) fn request_reset(request: ResetRequest) {
+    RESET.signal(request);
+}
+
+/// Call the handler set with [`set_reset_handler`] with each reset requested by the host, shortly
+/// after the request, forever.
+///
+/// Control handlers only accept the requests: resetting in the handler would cut the status stage
+/// of the request short, and the host would see it fail. This runs in the logger task instead.
+///
+/// [`set_reset_handler`]: crate::set_reset_handler
+pub(crate) async fn handle_resets() {
+    loop {
+        let request = RESET.wait().await;
+        Timer::after(RESET_DELAY).await;
+        if let Some(handler) = CONTROLLER.reset_handler() {
+            handler(request);
+        }
+    }
+}
+
+/// Control handler of the vendor requests of the host.
+pub(crate) struct VendorHandler;
+
+impl Handler for VendorHandler {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient) != (RequestType::Vendor, Recipient::Device) {
+            return None;
+        }
+        let request = match req.request {
+            VENDOR_REQUEST_RESET => ResetRequest::Reset,
+            VENDOR_REQUEST_BOOTLOADER => ResetRequest::Bootloader,
+            VENDOR_REQUEST_SET_LOGGING => {
+                set_logging_enabled(req.value != 0);
+                return Some(OutResponse::Accepted);
+            }
+            _ => return None,
+        };
+
+        // Leave the request to other handlers if the application does not handle it.
+        CONTROLLER.reset_handler()?;
+        request_reset(request);
+        Some(OutResponse::Accepted)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.request)
+            != (
+                RequestType::Vendor,
+                Recipient::Device,
+                VENDOR_REQUEST_BUILD_ID,
+            )
+        {
+            return None;
+        }
+        let Some(build_id) = CONTROLLER.build_id() else {
+            return Some(InResponse::Rejected);
+        };
+        let len = build_id.len().min(buf.len());
+        buf[..len].copy_from_slice(&build_id[..len]);
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+/// Handle the vendor requests of `builder` with `handler`, held in the state of the class.
+pub(crate) fn add_handler<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    handler: &'d mut VendorHandler,
+) {
+    builder.handler(handler);
+}